@@ -0,0 +1,44 @@
+/*
+ * C interface to the anthropic-proxy converters. Build the shared library with
+ *
+ *     cargo rustc --release --lib --no-default-features --features ffi --crate-type cdylib
+ *
+ * and link against target/release/libanthropic_proxy.so (.dylib on macOS, .dll on Windows).
+ *
+ * All strings are NUL-terminated UTF-8 JSON. The functions are thread-safe.
+ */
+
+#ifndef ANTHROPIC_PROXY_H
+#define ANTHROPIC_PROXY_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Anthropic Messages request -> OpenAI chat completions request. The model name and
+ * parameters are kept as they are. Returns a string to release with
+ * anthropic_proxy_free_string, or NULL on error (see anthropic_proxy_last_error).
+ */
+char *anthropic_to_openai_json(const char *input);
+
+/*
+ * OpenAI chat completions response -> Anthropic Messages response. Same ownership and
+ * errors as anthropic_to_openai_json.
+ */
+char *openai_to_anthropic_json(const char *input);
+
+/* Releases a string returned by a converter. NULL is ignored. */
+void anthropic_proxy_free_string(char *s);
+
+/*
+ * Why the last converter call on this thread returned NULL, or NULL after a success. Owned
+ * by the library; valid until the next converter call on the same thread.
+ */
+const char *anthropic_proxy_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ANTHROPIC_PROXY_H */
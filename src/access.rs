@@ -0,0 +1,106 @@
+//! Source-IP access control: CIDR allow/deny lists, with the client address taken from
+//! `X-Forwarded-For` only when the connection comes from a trusted proxy.
+
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use anyhow::Context;
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Resolved address of the caller, attached to every request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// IP_ALLOWLIST / IP_DENYLIST / TRUSTED_PROXIES.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    /// When non-empty, only these networks may connect.
+    pub allow: Vec<IpNet>,
+    /// Always rejected, even when also allowed.
+    pub deny: Vec<IpNet>,
+    /// Proxies / load balancers whose `X-Forwarded-For` entries are believed.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Parses a comma-separated list of CIDRs or bare addresses (`10.0.0.0/8, 192.0.2.7`).
+    pub fn parse_list(raw: &str) -> anyhow::Result<Vec<IpNet>> {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .with_context(|| format!("Invalid IP or CIDR '{entry}'"))
+            })
+            .collect()
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+        nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The caller's address: the peer itself, or, when the peer is a trusted proxy, the
+    /// right-most `X-Forwarded-For` hop that is not a trusted proxy.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut ip = peer.to_canonical();
+        if !Self::contains(&self.trusted_proxies, ip) {
+            return ip;
+        }
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        for hop in hops.into_iter().rev() {
+            // An unparsable hop can't be trusted further; stop at the last proxy we believe.
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            ip = hop.to_canonical();
+            if !Self::contains(&self.trusted_proxies, ip) {
+                break;
+            }
+        }
+        ip
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !Self::contains(&self.deny, ip)
+            && (self.allow.is_empty() || Self::contains(&self.allow, ip))
+    }
+}
+
+/// Global middleware: resolve the caller's [`ClientIp`] and reject addresses outside the
+/// allowlist or inside the denylist with 403.
+pub async fn filter_ip(
+    Extension(config): Extension<Arc<Config>>,
+    mut request: Request,
+    next: Next,
+) -> ProxyResult<Response> {
+    let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>().copied()
+    else {
+        return Ok(next.run(request).await);
+    };
+    let ip = config.ip_filter.client_ip(peer.ip(), request.headers());
+    if !config.ip_filter.permits(ip) {
+        tracing::warn!("Rejected request from {} ({})", ip, request.uri().path());
+        return Err(ProxyError::Permission(format!("address {ip} is not allowed")));
+    }
+    request.extensions_mut().insert(ClientIp(ip));
+    Ok(next.run(request).await)
+}
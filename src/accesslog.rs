@@ -0,0 +1,417 @@
+//! Access log: one line per request (JSON or Apache combined) written to ACCESS_LOG, separate
+//! from the diagnostic log. The line is written when the response body finishes, so streaming
+//! requests report their full duration, stop reason and token usage. The same record feeds
+//! the persistent usage database (USAGE_DB), the slow-request log and the admin tap.
+//!
+//! Handlers fill in request details through [`with_current`], which finds the entry for the
+//! request being served without threading it through every call.
+
+use crate::alerts::Alerter;
+use crate::cache::CACHE_CONTROL_HEADER;
+use crate::config::Config;
+use crate::events::EventSink;
+use crate::logfile::{RotatingFile, RotationSettings};
+use crate::slowlog::SlowLog;
+use crate::tap::Tap;
+use crate::usagedb::UsageDb;
+use crate::usageevents::UsagePublisher;
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, Request},
+    http::header,
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use http_body::{Frame, SizeHint};
+use serde_json::{json, Value};
+use std::io::{LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Where access log lines go (ACCESS_LOG).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLogTarget {
+    Stdout,
+    Stderr,
+    /// Appended to; created if missing.
+    File(PathBuf),
+}
+
+/// Line format (ACCESS_LOG_FORMAT).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    /// Apache combined: `host - client [time] "request" status bytes "referer" "user-agent"`.
+    Combined,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogSettings {
+    pub target: AccessLogTarget,
+    pub format: AccessLogFormat,
+}
+
+impl AccessLogSettings {
+    pub fn parse(target: &str, format: &str) -> anyhow::Result<Option<Self>> {
+        let target = match target.trim() {
+            "" | "off" | "none" => return Ok(None),
+            "stdout" | "-" => AccessLogTarget::Stdout,
+            "stderr" => AccessLogTarget::Stderr,
+            path => AccessLogTarget::File(PathBuf::from(path)),
+        };
+        let format = match format.trim().to_ascii_lowercase().as_str() {
+            "" | "json" => AccessLogFormat::Json,
+            "combined" | "apache" => AccessLogFormat::Combined,
+            other => anyhow::bail!("ACCESS_LOG_FORMAT must be json or combined, got '{other}'"),
+        };
+        Ok(Some(Self { target, format }))
+    }
+}
+
+/// Writes access log lines from a dedicated thread so request handling never blocks on I/O.
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    lines: mpsc::Sender<String>,
+}
+
+impl AccessLogger {
+    /// File targets rotate per `rotation`.
+    pub fn open(settings: &AccessLogSettings, rotation: &RotationSettings) -> anyhow::Result<Self> {
+        let mut out: Box<dyn Write + Send> = match &settings.target {
+            AccessLogTarget::Stdout => Box::new(std::io::stdout()),
+            AccessLogTarget::Stderr => Box::new(std::io::stderr()),
+            AccessLogTarget::File(path) => Box::new(LineWriter::new(
+                RotatingFile::open(path, rotation)
+                    .map_err(|e| anyhow::anyhow!("Cannot open ACCESS_LOG {}: {e}", path.display()))?,
+            )),
+        };
+        let (lines, rx) = mpsc::channel::<String>();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(e) = writeln!(out, "{line}").and_then(|_| out.flush()) {
+                        tracing::error!("Failed to write access log: {}", e);
+                    }
+                }
+            })?;
+        Ok(Self {
+            format: settings.format,
+            lines,
+        })
+    }
+
+    fn write(&self, record: &Record, details: &Details) {
+        let line = match self.format {
+            AccessLogFormat::Json => record.json(details),
+            AccessLogFormat::Combined => record.combined(details),
+        };
+        let _ = self.lines.send(line);
+    }
+}
+
+/// Destinations for finished request records; the middleware runs when any is set.
+pub struct RequestSinks {
+    pub access_log: Option<AccessLogger>,
+    pub usage_db: Option<Arc<UsageDb>>,
+    pub usage_events: Option<UsagePublisher>,
+    pub slow_log: Option<SlowLog>,
+    pub tap: Option<Tap>,
+    /// Fed every request for the ALERT_ERROR_RATE check.
+    pub alerts: Option<Arc<Alerter>>,
+    /// Sends `request.completed` webhook events.
+    pub events: Option<Arc<EventSink>>,
+}
+
+/// What the handler learned about a request; empty fields are logged as null / `-`.
+#[derive(Debug, Default)]
+pub(crate) struct Details {
+    pub client: Option<String>,
+    pub incoming_model: Option<String>,
+    pub routed_model: Option<String>,
+    pub upstream: Option<String>,
+    pub stream: Option<bool>,
+    pub stop_reason: Option<String>,
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+    /// Estimated upstream cost in USD, when the routed model has a price.
+    pub cost_usd: Option<f64>,
+    /// `type: message` of an error response.
+    pub error: Option<String>,
+    /// Sanitized request body, kept only when slow requests are dumped.
+    pub payload: Option<Value>,
+    capture_payload: bool,
+}
+
+/// Details of the request being served, shared with the response body that logs them.
+#[derive(Debug, Clone, Default)]
+pub struct Entry(Arc<Mutex<Details>>);
+
+impl Entry {
+    fn update(&self, f: impl FnOnce(&mut Details)) {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    pub fn set_client(&self, name: &str) {
+        self.update(|d| d.client = Some(name.to_string()));
+    }
+
+    pub fn set_route(&self, incoming_model: &str, routed_model: &str, upstream: &str, stream: bool) {
+        self.update(|d| {
+            d.incoming_model = Some(incoming_model.to_string());
+            d.routed_model = Some(routed_model.to_string());
+            d.upstream = Some(upstream.to_string());
+            d.stream = Some(stream);
+        });
+    }
+
+    pub fn set_stop_reason(&self, reason: &str) {
+        self.update(|d| d.stop_reason = Some(reason.to_string()));
+    }
+
+    pub fn set_usage(&self, input_tokens: u32, output_tokens: u32) {
+        self.update(|d| {
+            d.input_tokens = Some(input_tokens);
+            d.output_tokens = Some(output_tokens);
+        });
+    }
+
+    pub fn set_cost(&self, cost_usd: f64) {
+        self.update(|d| d.cost_usd = Some(cost_usd));
+    }
+
+    pub fn set_error(&self, error_type: &str, message: &str) {
+        self.update(|d| d.error = Some(format!("{error_type}: {message}")));
+    }
+
+    /// Keeps the request body built by `payload` if a sink wants it; otherwise skips the work.
+    pub fn capture_payload(&self, payload: impl FnOnce() -> Value) {
+        self.update(|d| {
+            if d.capture_payload {
+                d.payload = Some(payload());
+            }
+        });
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Entry;
+}
+
+/// The entry for the request being served, when access logging is on. Capture it before
+/// handing work to a response stream, which is polled outside the request task.
+pub fn current() -> Option<Entry> {
+    CURRENT.try_with(Entry::clone).ok()
+}
+
+/// Updates the current request's entry, if access logging is on.
+pub fn with_current(f: impl FnOnce(&Entry)) {
+    let _ = CURRENT.try_with(f);
+}
+
+/// Request line and response facts gathered by the middleware.
+pub(crate) struct Record {
+    pub received: DateTime<Utc>,
+    remote: String,
+    pub method: String,
+    pub path: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    pub status: u16,
+    cache: Option<String>,
+    pub ttfb: Option<Duration>,
+    pub duration: Duration,
+    bytes: u64,
+}
+
+impl Record {
+    /// What a finished model request used and cost, for event consumers.
+    pub fn summary(&self, d: &Details) -> Value {
+        let millis = |d: Duration| (d.as_secs_f64() * 1000.0 * 10.0).round() / 10.0;
+        json!({
+            "received": self.received.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "path": self.path,
+            "status": self.status,
+            "client": d.client,
+            "incoming_model": d.incoming_model,
+            "routed_model": d.routed_model,
+            "upstream": d.upstream,
+            "stream": d.stream,
+            "stop_reason": d.stop_reason,
+            "input_tokens": d.input_tokens,
+            "output_tokens": d.output_tokens,
+            "cost_usd": d.cost_usd,
+            "error": d.error,
+            "ttfb_ms": self.ttfb.map(millis),
+            "duration_ms": millis(self.duration),
+        })
+    }
+
+    fn json(&self, d: &Details) -> String {
+        let millis = |d: Duration| (d.as_secs_f64() * 1000.0 * 10.0).round() / 10.0;
+        json!({
+            "timestamp": self.received.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "remote_addr": self.remote,
+            "method": self.method,
+            "path": self.path,
+            "status": self.status,
+            "client": d.client,
+            "incoming_model": d.incoming_model,
+            "routed_model": d.routed_model,
+            "upstream": d.upstream,
+            "stream": d.stream,
+            "stop_reason": d.stop_reason,
+            "input_tokens": d.input_tokens,
+            "output_tokens": d.output_tokens,
+            "cost_usd": d.cost_usd,
+            "cache": self.cache,
+            "ttfb_ms": self.ttfb.map(millis),
+            "duration_ms": millis(self.duration),
+            "bytes": self.bytes,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
+
+    fn combined(&self, d: &Details) -> String {
+        let quoted = |v: &Option<String>| v.as_deref().unwrap_or("-").replace('"', "\\\"");
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            self.remote,
+            d.client.as_deref().unwrap_or("-").replace(' ', "_"),
+            self.received.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.version,
+            self.status,
+            self.bytes,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+        )
+    }
+}
+
+/// Middleware: times the request, runs it with a fresh [`Entry`] in scope and logs a line
+/// once the response body has been sent (or the client went away).
+pub async fn log_request(
+    Extension(sinks): Extension<Arc<RequestSinks>>,
+    Extension(config): Extension<Arc<Config>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let headers = request.headers();
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let remote = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(peer)) => config.ip_filter.client_ip(peer.ip(), headers).to_string(),
+        None => "-".to_string(),
+    };
+    let mut record = Record {
+        received: Utc::now(),
+        remote,
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        version: format!("{:?}", request.version()),
+        referer: header(header::REFERER),
+        user_agent: header(header::USER_AGENT),
+        status: 0,
+        cache: None,
+        ttfb: None,
+        duration: Duration::ZERO,
+        bytes: 0,
+    };
+
+    let entry = Entry::default();
+    if sinks.slow_log.as_ref().is_some_and(SlowLog::dumps_payloads) {
+        entry.update(|d| d.capture_payload = true);
+    }
+    let response = CURRENT.scope(entry.clone(), next.run(request)).await;
+    record.status = response.status().as_u16();
+    record.cache = response
+        .headers()
+        .get(CACHE_CONTROL_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let (parts, body) = response.into_parts();
+    let body = LoggedBody {
+        inner: body,
+        started,
+        record: Some(record),
+        entry,
+        sinks,
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+/// Response body that measures time to first byte and size, and logs when dropped.
+struct LoggedBody {
+    inner: Body,
+    started: Instant,
+    record: Option<Record>,
+    entry: Entry,
+    sinks: Arc<RequestSinks>,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        if let (Poll::Ready(Some(Ok(frame))), Some(record)) = (&poll, this.record.as_mut()) {
+            if let Some(data) = frame.data_ref() {
+                record.ttfb.get_or_insert_with(|| this.started.elapsed());
+                record.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.duration = self.started.elapsed();
+            let details = self.entry.0.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(logger) = &self.sinks.access_log {
+                logger.write(&record, &details);
+            }
+            if let Some(db) = &self.sinks.usage_db {
+                db.record(&record, &details);
+            }
+            if let Some(publisher) = &self.sinks.usage_events {
+                publisher.observe(&record, &details);
+            }
+            if let Some(slow_log) = &self.sinks.slow_log {
+                slow_log.check(&record, &details);
+            }
+            if let Some(tap) = &self.sinks.tap {
+                tap.publish(&record, &details);
+            }
+            if let Some(alerter) = &self.sinks.alerts {
+                alerter.observe(&record, &details);
+            }
+            if let Some(events) = &self.sinks.events {
+                events.observe(&record, &details);
+            }
+        }
+    }
+}
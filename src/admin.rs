@@ -0,0 +1,185 @@
+//! Operational endpoints: Prometheus metrics and the token-protected admin API.
+
+use crate::accesslog::RequestSinks;
+use crate::cache::{CacheStats, ResponseCache};
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::keypool::KeyStatus;
+use crate::metrics;
+use crate::tokens::TokenCounter;
+use axum::{
+    extract::{Path, Request},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Rejects admin requests that do not carry `Authorization: Bearer <ADMIN_TOKEN>`.
+pub async fn require_admin(
+    Extension(config): Extension<Arc<Config>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> ProxyResult<Response> {
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match (config.admin_token.as_deref(), presented) {
+        (Some(expected), Some(token)) if expected == token => Ok(next.run(request).await),
+        _ => Err(ProxyError::Authentication("Invalid or missing admin token".to_string())),
+    }
+}
+
+#[derive(Serialize)]
+pub struct CacheReport {
+    pub response_cache: CacheStats,
+    pub token_cache: CacheStats,
+}
+
+/// `GET /admin/cache`: entries, hit ratio and memory for both caches.
+pub async fn cache_stats(
+    Extension(cache): Extension<Arc<ResponseCache>>,
+    Extension(counter): Extension<Arc<TokenCounter>>,
+) -> Json<CacheReport> {
+    Json(CacheReport {
+        response_cache: cache.stats(),
+        token_cache: counter.stats(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct InvalidateRequest {
+    pub model: Option<String>,
+    pub key_prefix: Option<String>,
+}
+
+/// `POST /admin/cache/invalidate`: drop response cache entries by model and/or key prefix.
+pub async fn cache_invalidate(
+    Extension(cache): Extension<Arc<ResponseCache>>,
+    Json(req): Json<InvalidateRequest>,
+) -> ProxyResult<Json<serde_json::Value>> {
+    if req.model.is_none() && req.key_prefix.is_none() {
+        return Err(ProxyError::Transform(
+            "Specify \"model\" and/or \"key_prefix\" (use DELETE /admin/cache to flush)".to_string(),
+        ));
+    }
+    let removed = cache.invalidate(req.model.as_deref(), req.key_prefix.as_deref());
+    tracing::info!(
+        "Cache invalidated: removed={} model={:?} key_prefix={:?}",
+        removed,
+        req.model,
+        req.key_prefix
+    );
+    Ok(Json(json!({ "removed": removed })))
+}
+
+/// `DELETE /admin/cache`: flush the response and token caches.
+pub async fn cache_flush(
+    Extension(cache): Extension<Arc<ResponseCache>>,
+    Extension(counter): Extension<Arc<TokenCounter>>,
+) -> Json<serde_json::Value> {
+    let responses = cache.clear();
+    let token_counts = counter.clear();
+    tracing::info!("Cache flushed: responses={} token_counts={}", responses, token_counts);
+    Json(json!({ "removed": { "response_cache": responses, "token_cache": token_counts } }))
+}
+
+/// `GET /admin/upstream-keys`: the global upstream key pool (ids and last four characters only).
+pub async fn upstream_keys(Extension(config): Extension<Arc<Config>>) -> Json<Vec<KeyStatus>> {
+    Json(config.upstream.keys.status())
+}
+
+#[derive(Deserialize)]
+pub struct AddKeyRequest {
+    pub key: String,
+}
+
+/// `POST /admin/upstream-keys`: add a key to the rotation.
+pub async fn upstream_key_add(
+    Extension(config): Extension<Arc<Config>>,
+    Json(req): Json<AddKeyRequest>,
+) -> ProxyResult<Json<serde_json::Value>> {
+    let id = config
+        .upstream
+        .keys
+        .add(&req.key)
+        .ok_or_else(|| ProxyError::Transform("\"key\" must not be empty".to_string()))?;
+    tracing::info!("Upstream key {} added to pool", id);
+    Ok(Json(json!({ "id": id, "keys": config.upstream.keys.len() })))
+}
+
+/// `DELETE /admin/upstream-keys/{id}`: take a key out of the rotation.
+pub async fn upstream_key_remove(
+    Extension(config): Extension<Arc<Config>>,
+    Path(id): Path<String>,
+) -> Json<serde_json::Value> {
+    let pool = &config.upstream.keys;
+    let removed = pool.remove(&id);
+    if removed {
+        tracing::info!("Upstream key {} removed from pool", id);
+        if pool.is_empty() {
+            tracing::warn!("Upstream key pool is empty; requests are sent without an API key");
+        }
+    }
+    Json(json!({ "removed": removed, "keys": pool.len() }))
+}
+
+/// `GET /metrics`: Prometheus text exposition.
+pub async fn metrics_handler(
+    Extension(cache): Extension<Arc<ResponseCache>>,
+    Extension(counter): Extension<Arc<TokenCounter>>,
+) -> Response {
+    refresh_gauges(&cache, &counter);
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        metrics::render(),
+    )
+        .into_response()
+}
+
+/// Samples the cache gauges; done per scrape, and periodically when exporting to StatsD.
+pub fn refresh_gauges(cache: &ResponseCache, counter: &TokenCounter) {
+    record_cache_gauges("response", &cache.stats());
+    record_cache_gauges("token", &counter.stats());
+}
+
+fn record_cache_gauges(name: &str, stats: &CacheStats) {
+    let labels = [("cache", name)];
+    metrics::set_gauge("proxy_cache_entries", &labels, stats.entries as f64);
+    metrics::set_gauge("proxy_cache_memory_bytes", &labels, stats.memory_bytes as f64);
+    metrics::set_gauge("proxy_cache_hit_ratio", &labels, stats.hit_ratio);
+}
+
+/// `GET /admin/tap`: finished requests as server-sent `request` events, live. A subscriber
+/// that falls behind gets a `lagged` event with the number of requests it missed.
+pub async fn tap(Extension(sinks): Extension<Arc<RequestSinks>>) -> ProxyResult<Response> {
+    let mut events = sinks
+        .tap
+        .as_ref()
+        .ok_or_else(|| ProxyError::Internal("Request tap is not enabled".to_string()))?
+        .subscribe();
+    let stream = async_stream::stream! {
+        loop {
+            match events.recv().await {
+                Ok(event) => match Event::default().event("request").json_data(&*event) {
+                    Ok(event) => yield Ok::<_, Infallible>(event),
+                    Err(e) => tracing::error!("Failed to serialize tap event: {}", e),
+                },
+                Err(RecvError::Lagged(missed)) => yield Ok(Event::default().event("lagged").data(missed.to_string())),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()).into_response())
+}
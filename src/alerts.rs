@@ -0,0 +1,187 @@
+//! Alerting webhooks: with ALERT_WEBHOOK_URLS set, operators are notified (Slack-compatible
+//! JSON, `{"text": ...}`) when the 5xx rate of model requests crosses ALERT_ERROR_RATE, when
+//! every key of an upstream is rotated out after rate limits or auth failures, and when a
+//! client exhausts a spend budget. Repeats of the same alert are suppressed for
+//! ALERT_COOLDOWN_SECS.
+//!
+//! Alerts are raised through [`raise`] so deep code can report conditions without holding a
+//! handle, as with [`crate::metrics`].
+
+use crate::accesslog::{Details, Record};
+use crate::metrics;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_ALERT_ERROR_RATE: f64 = 0.25;
+pub const DEFAULT_ALERT_ERROR_WINDOW_SECS: u64 = 300;
+pub const DEFAULT_ALERT_MIN_REQUESTS: u64 = 20;
+pub const DEFAULT_ALERT_COOLDOWN_SECS: u64 = 900;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ALERT_WEBHOOK_URLS / ALERT_ERROR_RATE / ALERT_ERROR_WINDOW_SECS / ALERT_MIN_REQUESTS /
+/// ALERT_COOLDOWN_SECS.
+#[derive(Debug, Clone)]
+pub struct AlertSettings {
+    pub webhook_urls: Vec<String>,
+    /// Fraction of requests answered with a 5xx that triggers an alert; `None` disables it.
+    pub error_rate: Option<f64>,
+    pub error_window: Duration,
+    /// Requests the window needs before its error rate counts.
+    pub min_requests: u64,
+    pub cooldown: Duration,
+}
+
+/// One notification.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    /// `error_rate`, `upstream_unavailable` or `budget_exhausted`.
+    pub kind: &'static str,
+    /// Alerts with the same key are sent at most once per cooldown.
+    pub key: String,
+    pub summary: String,
+    pub details: Value,
+}
+
+impl Alert {
+    pub fn error_rate(errors: u64, requests: u64, window: Duration) -> Self {
+        let rate = errors as f64 / requests as f64;
+        Self {
+            kind: "error_rate",
+            key: "error_rate".to_string(),
+            summary: format!(
+                "{:.0}% of model requests failed in the last {}s ({errors} of {requests})",
+                rate * 100.0,
+                window.as_secs()
+            ),
+            details: json!({ "errors": errors, "requests": requests, "window_secs": window.as_secs() }),
+        }
+    }
+
+    pub fn upstream_unavailable(upstream: &str, status: u16, keys: usize) -> Self {
+        Self {
+            kind: "upstream_unavailable",
+            key: format!("upstream_unavailable:{upstream}"),
+            summary: format!(
+                "Every upstream key for {upstream} is rotated out ({keys} key(s), last status {status})"
+            ),
+            details: json!({ "upstream": upstream, "status": status, "keys": keys }),
+        }
+    }
+
+    /// Sent once per client, period and reset.
+    pub fn budget_exhausted(client: &str, period: &str, budget_usd: f64, resets: DateTime<Utc>) -> Self {
+        let resets = resets.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        Self {
+            kind: "budget_exhausted",
+            key: format!("budget_exhausted:{client}:{period}:{resets}"),
+            summary: format!("Client '{client}' exhausted its {period} budget of ${budget_usd}; resets at {resets}"),
+            details: json!({ "client": client, "period": period, "budget_usd": budget_usd, "resets": resets }),
+        }
+    }
+}
+
+static ALERTER: OnceLock<Arc<Alerter>> = OnceLock::new();
+
+/// Makes `alerter` the target of [`raise`].
+pub fn install(alerter: Arc<Alerter>) {
+    let _ = ALERTER.set(alerter);
+}
+
+/// Sends `alert` if alerting is configured and it is not a repeat within the cooldown.
+pub fn raise(alert: Alert) {
+    if let Some(alerter) = ALERTER.get() {
+        alerter.send(alert);
+    }
+}
+
+pub struct Alerter {
+    settings: AlertSettings,
+    client: Client,
+    last_sent: Mutex<HashMap<String, Instant>>,
+    /// Per-second request and 5xx counts over the error window, oldest first.
+    window: Mutex<VecDeque<(u64, u64, u64)>>,
+    started: Instant,
+}
+
+impl Alerter {
+    pub fn new(settings: AlertSettings, client: Client) -> Self {
+        Self {
+            settings,
+            client,
+            last_sent: Mutex::default(),
+            window: Mutex::default(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Counts a finished model request towards the error rate.
+    pub fn observe(&self, record: &Record, details: &Details) {
+        let Some(threshold) = self.settings.error_rate else {
+            return;
+        };
+        if details.incoming_model.is_none() {
+            return;
+        }
+        let failed = u64::from(record.status >= 500);
+        let now = self.started.elapsed().as_secs();
+        let window_secs = self.settings.error_window.as_secs().max(1);
+        let (requests, errors) = {
+            let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+            while window.front().is_some_and(|(second, _, _)| second + window_secs <= now) {
+                window.pop_front();
+            }
+            match window.back_mut() {
+                Some((second, requests, errors)) if *second == now => {
+                    *requests += 1;
+                    *errors += failed;
+                }
+                _ => window.push_back((now, 1, failed)),
+            }
+            window.iter().fold((0, 0), |(r, e), (_, requests, errors)| (r + requests, e + errors))
+        };
+        if failed == 1 && requests >= self.settings.min_requests && errors as f64 >= threshold * requests as f64 {
+            self.send(Alert::error_rate(errors, requests, self.settings.error_window));
+        }
+    }
+
+    fn send(&self, alert: Alert) {
+        {
+            let mut last_sent = self.last_sent.lock().unwrap_or_else(|e| e.into_inner());
+            last_sent.retain(|_, at| at.elapsed() < self.settings.cooldown);
+            if last_sent.contains_key(&alert.key) {
+                metrics::increment("proxy_alerts_total", &[("kind", alert.kind), ("result", "suppressed")], 1);
+                return;
+            }
+            last_sent.insert(alert.key.clone(), Instant::now());
+        }
+        tracing::warn!("Alert ({}): {}", alert.kind, alert.summary);
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let payload = json!({
+            "text": format!(":rotating_light: anthropic-proxy: {}", alert.summary),
+            "kind": alert.kind,
+            "details": alert.details,
+            "timestamp": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        });
+        for url in &self.settings.webhook_urls {
+            let request = self.client.post(url).json(&payload).timeout(WEBHOOK_TIMEOUT);
+            let kind = alert.kind;
+            runtime.spawn(async move {
+                let result = match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => "sent",
+                    Err(e) => {
+                        tracing::error!("Alert webhook failed: {}", e.without_url());
+                        "failed"
+                    }
+                };
+                metrics::increment("proxy_alerts_total", &[("kind", kind), ("result", result)], 1);
+            });
+        }
+    }
+}
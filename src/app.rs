@@ -0,0 +1,573 @@
+//! The server and its subcommands, as run by the `anthropic-proxy` binary.
+
+use crate::cli::{Cli, Command};
+use crate::config::Config;
+use crate::plugin::Plugins;
+use crate::router::RouterBuilder;
+use crate::{bench, check, config, listeners, logfile, monitor, offline, probe, redact, replay, runtime, telemetry, tls};
+use clap::Parser;
+use daemonize::Daemonize;
+use std::net::SocketAddr;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+/// Runs the `anthropic-proxy` command line (the server and its subcommands) with `plugins`
+/// registered; the `anthropic-proxy` binary is this with no plugins.
+pub fn run(plugins: Plugins) -> anyhow::Result<()> {
+    let mut cli = Cli::parse();
+    cli.apply_env();
+
+    if let Some(command) = cli.command.take() {
+        match command {
+            Command::Stop { pid_file } => {
+                stop_daemon(&pid_file)?;
+                return Ok(());
+            }
+            Command::Status { pid_file } => {
+                check_status(&pid_file)?;
+                return Ok(());
+            }
+            Command::Serve => {}
+            Command::Check => {
+                if !check::run(cli.config) {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            Command::Transform { direction, input } => {
+                let config = Config::from_env_with_path(cli.config)?;
+                return offline::run(&config, direction, input);
+            }
+            Command::Probe => {
+                let config = Config::from_env_with_path(cli.config)?;
+                let runtime = tokio::runtime::Runtime::new()?;
+                if !runtime.block_on(probe::run(&config)) {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            Command::Monitor { url, admin_token } => {
+                let (addr, configured_token) = Config::local_target(cli.config)?;
+                let url = url.unwrap_or_else(|| format!("http://{addr}"));
+                let token = admin_token
+                    .or(configured_token)
+                    .ok_or_else(|| anyhow::anyhow!("The monitor needs the admin API: set ADMIN_TOKEN or pass --admin-token"))?;
+                let runtime = tokio::runtime::Runtime::new()?;
+                return runtime.block_on(monitor::run(url, token));
+            }
+            Command::Bench {
+                url,
+                api_key,
+                request_model,
+                requests,
+                concurrency,
+                stream,
+                prompt_bytes,
+                max_tokens,
+            } => {
+                let url = match url {
+                    Some(url) => url,
+                    None => format!("http://{}", Config::local_target(cli.config)?.0),
+                };
+                let settings = bench::BenchSettings {
+                    url,
+                    api_key: api_key.or_else(|| std::env::var("ANTHROPIC_API_KEY").ok()),
+                    model: request_model,
+                    requests,
+                    concurrency,
+                    stream,
+                    prompt_bytes,
+                    max_tokens,
+                };
+                let runtime = tokio::runtime::Runtime::new()?;
+                if !runtime.block_on(bench::run(settings))? {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+        }
+    }
+    
+    // Read before daemonizing so a bad setting is reported on the terminal.
+    let runtime_settings = Config::runtime_settings(cli.config.clone())?;
+    if cli.daemon {
+        use std::fs::OpenOptions;
+        
+        let stdout = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open("/tmp/anthropic-proxy.log")?;
+        
+        let stderr = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open("/tmp/anthropic-proxy.log")?;
+
+        let daemonize = Daemonize::new()
+            .pid_file(&cli.pid_file)
+            .working_directory(std::env::current_dir()?)
+            .stdout(stdout)
+            .stderr(stderr)
+            .umask(0o027);
+
+        match daemonize.start() {
+            Ok(_) => {},
+            Err(e) => {
+                eprintln!("✗ Failed to daemonize: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        eprintln!("✓ Starting proxy in foreground mode");
+    }
+
+    let runtime = runtime_settings.build()?;
+    runtime.block_on(async_main(cli, plugins))
+}
+
+async fn async_main(cli: Cli, plugins: Plugins) -> anyhow::Result<()> {
+    let config = match Config::from_env_with_path(cli.config.clone()) {
+        Ok(config) => config,
+        Err(e) if Config::strict_requested() => {
+            let problems = check::load_problems(&e, cli.config);
+            for problem in &problems {
+                eprintln!("✗ {problem}");
+            }
+            anyhow::bail!("Configuration has {} error(s)", problems.len());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let log_level = if config.verbose {
+        tracing::Level::TRACE
+    } else if let Some(level) = config.log_level {
+        level
+    } else if config.debug {
+        tracing::Level::DEBUG
+    } else {
+        tracing::Level::INFO
+    };
+
+    // Sampled requests log payloads at TRACE whatever the level of everything else.
+    let level_directives = |level: &str| match config.verbose_sampling {
+        Some(_) => format!("anthropic_proxy={level},{}=trace", redact::PAYLOAD_TARGET),
+        None => format!("anthropic_proxy={level}"),
+    };
+    let default_filter = || {
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| level_directives(log_level.as_str()).into())
+    };
+    let otel_layer = config.otel.as_ref().map(telemetry::otlp_layer).transpose()?;
+    let stdout_layer = config
+        .logging
+        .stdout
+        .then(|| tracing_subscriber::fmt::layer().with_filter(default_filter()));
+    // Held until shutdown so buffered lines reach the file.
+    let mut _log_file_guard = None;
+    let file_layer = match &config.logging.file {
+        Some(path) => {
+            let file = logfile::RotatingFile::open(path, &config.logging.rotation)
+                .map_err(|e| anyhow::anyhow!("Cannot open LOG_FILE {}: {e}", path.display()))?;
+            let filter = match &config.logging.file_level {
+                Some(level) if level.parse::<tracing::Level>().is_ok() => {
+                    tracing_subscriber::EnvFilter::try_new(level_directives(level))?
+                }
+                Some(directives) => tracing_subscriber::EnvFilter::try_new(directives)
+                    .map_err(|e| anyhow::anyhow!("Invalid LOG_FILE_LEVEL '{directives}': {e}"))?,
+                None => default_filter(),
+            };
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            _log_file_guard = Some(guard);
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(writer)
+                    .with_filter(filter),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(otel_layer.with_filter(default_filter()))
+        .with(stdout_layer)
+        .with(file_layer)
+        .init();
+
+    tracing::info!("Starting Anthropic Proxy v{}", env!("CARGO_PKG_VERSION"));
+    // Name lookups block; the pass runs once, before anything is served.
+    if config.strict_config && !runtime::block_in_place(|| check::strict(&config)) {
+        anyhow::bail!("Strict config validation failed; run `anthropic-proxy check` for the full report");
+    }
+    tracing::info!("Port: {}", config.port);
+    match config.runtime.flavor {
+        runtime::Flavor::MultiThread => tracing::info!(
+            "Runtime: multi-thread, {} worker thread(s)",
+            tokio::runtime::Handle::current().metrics().num_workers()
+        ),
+        runtime::Flavor::CurrentThread => tracing::info!("Runtime: current-thread"),
+    }
+    if let Some(profile) = config.config_file.as_ref().and_then(|file| file.profile.as_ref()) {
+        tracing::info!("Config profile: {}", profile);
+    }
+    if let Some(preset) = config.preset {
+        tracing::info!("Preset: {} ({})", preset.name, preset.description);
+    }
+    if config.upstream.is_mock() {
+        tracing::warn!(
+            "Upstream: built-in mock ({} scripted response(s)); no upstream is called",
+            config.mock_upstream.responses.len()
+        );
+    } else {
+        tracing::info!("Upstream URL: {}", config.upstream.base_url);
+    }
+    if config.upstream.path != config::DEFAULT_UPSTREAM_PATH {
+        tracing::info!("Upstream path: {}", config.upstream.path);
+    }
+    if let Some(proxy) = config.upstream.transport.proxy_display() {
+        tracing::info!("Upstream proxy: {}", proxy);
+    }
+    if let Some(version) = config.upstream.transport.http_version {
+        tracing::info!("Upstream HTTP version: {:?}", version);
+    }
+    if let Some(warmup) = config.warmup {
+        tracing::info!(
+            "Upstream warm-up: {} connection(s), again after {}s without requests",
+            warmup.connections,
+            warmup.interval.as_secs()
+        );
+    }
+    for (host, ips) in &config.upstream.transport.resolve {
+        let ips: Vec<String> = ips.iter().map(ToString::to_string).collect();
+        tracing::info!("Upstream host {} pinned to {}", host, ips.join(", "));
+    }
+    if !config.forward_headers.is_empty() {
+        tracing::info!("Forwarded client headers: {}", config.forward_headers.join(", "));
+    }
+    if let Some(ref cors) = config.cors {
+        tracing::info!("CORS: allowing origins {}", cors.describe_origins());
+    }
+    if config.verbose && config.log_content != redact::ContentLogging::Full {
+        tracing::info!("Verbose logs: message content {:?}", config.log_content);
+    }
+    if let Some(sampling) = config.verbose_sampling.filter(|_| !config.verbose) {
+        tracing::info!(
+            "Payload logging: {:.2}% of requests{}, message content {:?}",
+            sampling.rate * 100.0,
+            if sampling.debug_header { " and x-proxy-debug requests" } else { "" },
+            config.log_content
+        );
+    }
+    for route in &config.routes {
+        tracing::info!(
+            "Route: {} -> {}{}",
+            route.pattern,
+            route.model.as_deref().unwrap_or("(same model)"),
+            route.upstream_name.as_deref().map(|u| format!(" via {u}")).unwrap_or_default()
+        );
+    }
+    for params in &config.model_params {
+        tracing::info!("Model parameters: {} {:?}", params.pattern, params);
+    }
+    for rule in config.rewrite.rules() {
+        tracing::info!("Rewrite rule '{}': /{}/ ({:?}, roles {:?})", rule.name, rule.pattern, rule.direction, rule.roles);
+    }
+    if !config.system_prompt.is_empty() {
+        tracing::info!(
+            "System prompt: {} character prefix, {} character suffix added",
+            config.system_prompt.prefix.as_deref().map_or(0, |p| p.chars().count()),
+            config.system_prompt.suffix.as_deref().map_or(0, |s| s.chars().count())
+        );
+    }
+    if let Some(ref model) = config.reasoning_model {
+        tracing::info!("Reasoning Model Override: {}", model);
+    }
+    if let Some(ref model) = config.completion_model {
+        tracing::info!("Completion Model Override: {}", model);
+    }
+    if config.response_cache_ttl_secs > 0 {
+        tracing::info!(
+            "Response cache: {}s TTL, {} entries",
+            config.response_cache_ttl_secs,
+            config.response_cache_size
+        );
+    }
+    if !config.client_keys.is_empty() {
+        tracing::info!("Client auth: {} key(s) configured", config.client_keys.len());
+    }
+    if !config.default_quota.is_unlimited() {
+        tracing::info!("Default client quota: {:?}", config.default_quota);
+    }
+    if let Some(ref signing) = config.signing {
+        tracing::info!(
+            "Request signing: required ({} secret(s), {}s tolerance)",
+            signing.secrets.len(),
+            signing.tolerance_secs
+        );
+    }
+    if let Some(ref settings) = config.statsd {
+        tracing::info!("StatsD metrics: {} ({:?})", settings.addr, settings.format);
+    }
+    if let Some(ref alerts) = config.alerts {
+        tracing::info!(
+            "Alerts: {} webhook(s), error rate {}, cooldown {}s",
+            alerts.webhook_urls.len(),
+            alerts
+                .error_rate
+                .map(|r| format!("over {:.0}% of {}s", r * 100.0, alerts.error_window.as_secs()))
+                .unwrap_or_else(|| "off".to_string()),
+            alerts.cooldown.as_secs()
+        );
+    }
+    if let Some(ref events) = config.events {
+        tracing::info!(
+            "Event webhooks: {} URL(s), events {}",
+            events.webhook_urls.len(),
+            events.kinds.iter().map(|k| k.as_str()).collect::<Vec<_>>().join(", ")
+        );
+    }
+    if let Some(ref access_log) = config.access_log {
+        tracing::info!("Access log: {:?} ({:?})", access_log.target, access_log.format);
+    }
+    if let Some(ref path) = config.logging.file {
+        let rotation = &config.logging.rotation;
+        tracing::info!(
+            "Log file: {} (rotation {:?}{}, keeping {} file(s){})",
+            path.display(),
+            rotation.interval,
+            rotation.max_bytes.map(|b| format!(" or at {b} bytes")).unwrap_or_default(),
+            rotation.max_files,
+            rotation.max_age.map(|age| format!(" up to {} day(s) old", age.as_secs() / 86_400)).unwrap_or_default()
+        );
+    }
+    if !config.pricing.prices.is_empty() {
+        tracing::info!("Model prices: {} configured", config.pricing.prices.len());
+    }
+    if let Some(ref url) = config.pricing.sync_url {
+        tracing::info!("Model prices: syncing from {} every {}s", url, config.pricing.sync_interval_secs);
+    }
+    if let Some(ref dir) = config.capture_dir {
+        tracing::warn!(
+            "Debug capture: requests sent with x-proxy-capture: true are written verbatim to {}",
+            dir.display()
+        );
+    }
+    match config.traffic {
+        Some(replay::TrafficMode::Record(ref path)) => {
+            tracing::warn!("Traffic recording: exchanges are appended verbatim to {}", path.display())
+        }
+        Some(replay::TrafficMode::Replay(ref path)) => {
+            tracing::warn!("Traffic replay: serving recorded responses from {}; the upstream is not called", path.display())
+        }
+        None => {}
+    }
+    if let Some(ref chaos) = config.chaos {
+        let faults: Vec<&str> = chaos.faults.iter().map(|f| f.as_str()).collect();
+        tracing::warn!(
+            "Chaos mode: faults ({}) injected into {:.1}% of requests{}",
+            faults.join(", "),
+            chaos.rate * 100.0,
+            if chaos.header { ", and on request with x-proxy-chaos" } else { "" }
+        );
+    }
+    if let Some(ref slow) = config.slow_log {
+        let threshold = |d: Option<std::time::Duration>| d.map_or("off".to_string(), |d| format!("{d:?}"));
+        tracing::info!(
+            "Slow request log: duration >= {}, ttfb >= {}{}",
+            threshold(slow.duration),
+            threshold(slow.ttfb),
+            slow.dump_dir.as_ref().map(|d| format!(", dumps in {}", d.display())).unwrap_or_default()
+        );
+    }
+    if let Some(ref usage_db) = config.usage_db {
+        match usage_db.retention_days {
+            Some(days) => tracing::info!("Usage database: {} ({} day retention)", usage_db.path.display(), days),
+            None => tracing::info!("Usage database: {}", usage_db.path.display()),
+        }
+    }
+    if let Some(ref conversations) = config.conversations {
+        tracing::info!(
+            "Conversation history: {} keyed by {}{}",
+            conversations.target,
+            conversations.session_header,
+            conversations.retention_days.map(|days| format!(" ({days} day retention)")).unwrap_or_default()
+        );
+    }
+    if let Some(ref target) = config.usage_events {
+        tracing::info!("Usage events: {}", target);
+    }
+    if let Some(ref otel) = config.otel {
+        tracing::info!("OpenTelemetry: exporting traces to {} as '{}'", otel.endpoint, otel.service_name);
+    }
+    if let Some(ref moderation) = config.moderation {
+        tracing::info!(
+            "Moderation: {} (action={:?}, threshold={}, fail {})",
+            moderation.url,
+            moderation.action,
+            moderation.threshold.map_or("endpoint".to_string(), |t| t.to_string()),
+            if moderation.fail_closed { "closed" } else { "open" }
+        );
+    }
+    if let Some(ref pii) = config.pii {
+        tracing::info!(
+            "PII scrubbing: {:?}{}",
+            pii.kinds,
+            if pii.restore { " (restored in responses)" } else { "" }
+        );
+    }
+    if let Some(ref jwt) = config.jwt {
+        tracing::info!(
+            "JWT auth: jwks={} issuer={} audience={}",
+            jwt.jwks_url,
+            jwt.issuer.as_deref().unwrap_or("(any)"),
+            if jwt.audiences.is_empty() { "(any)".to_string() } else { jwt.audiences.join(",") }
+        );
+    } else if config.client_keys.is_empty() && config.secrets.client_keys.is_none() {
+        tracing::warn!("Client auth: disabled (set CLIENT_API_KEYS to require x-api-key)");
+    }
+    if config.ip_filter.is_restricted() {
+        tracing::info!(
+            "IP filter: {} allowed, {} denied network(s)",
+            config.ip_filter.allow.len(),
+            config.ip_filter.deny.len()
+        );
+    }
+    if config.rate_limits.is_enabled() {
+        tracing::info!(
+            "Rate limits: per IP {:?}, per key {:?}",
+            config.rate_limits.per_ip,
+            config.rate_limits.per_key
+        );
+        if config.rate_limits.redis_url.is_some() {
+            tracing::info!("Rate limits shared through Redis");
+        }
+    }
+    if !config.ip_filter.trusted_proxies.is_empty() {
+        tracing::info!(
+            "Trusting X-Forwarded-For from {} proxy network(s)",
+            config.ip_filter.trusted_proxies.len()
+        );
+    }
+    for path in &config.upstream_key_files {
+        tracing::info!("Watching upstream key file {}", path.display());
+    }
+    match config.upstream.keys.len() {
+        0 => tracing::info!("API Key: not set (using unauthenticated endpoint)"),
+        1 => tracing::info!("API Key: configured"),
+        n => tracing::info!("API Key: pool of {} keys", n),
+    }
+
+    let (app, config) = RouterBuilder::new(config).plugins(plugins).build_with_config().await?;
+
+    if !config.listeners.is_empty() {
+        for listener in &config.listeners {
+            tracing::info!("Listening on {}", listener);
+        }
+        tracing::info!("Proxy ready to accept requests");
+        return listeners::serve(&config.listeners, &config.sockets, app).await;
+    }
+
+    let addr = SocketAddr::new(config.host, config.port);
+
+    if let Some(ref settings) = config.acme {
+        tracing::info!(
+            "Listening on {} (HTTPS via ACME for {}{})",
+            addr,
+            settings.domains.join(", "),
+            if settings.staging { ", staging" } else { "" }
+        );
+        tracing::info!("Proxy ready to accept requests");
+        return tls::serve_acme(settings, addr, &config.sockets, app).await;
+    }
+
+    if let Some(ref settings) = config.tls {
+        tracing::info!("Listening on {} (HTTPS, cert {})", addr, settings.cert.display());
+        if let Some(ref ca) = settings.client_ca {
+            tracing::info!(
+                "Client certificates: {} (CA {})",
+                if settings.client_auth_optional { "optional" } else { "required" },
+                ca.display()
+            );
+        }
+        tracing::info!("Proxy ready to accept requests");
+        return tls::serve(settings, addr, &config.sockets, app).await;
+    }
+
+    tracing::info!("Listening on {}", addr);
+    tracing::info!("Proxy ready to accept requests");
+    listeners::serve_tcp(addr, &config.sockets, app).await
+}
+
+fn stop_daemon(pid_file: &std::path::Path) -> anyhow::Result<()> {
+    if !pid_file.exists() {
+        eprintln!("✗ PID file not found: {}", pid_file.display());
+        eprintln!("  Daemon is not running or PID file was removed");
+        std::process::exit(1);
+    }
+
+    let pid_str = std::fs::read_to_string(pid_file)?;
+    let pid: i32 = pid_str.trim().parse()
+        .map_err(|_| anyhow::anyhow!("Invalid PID in file: {}", pid_str))?;
+
+    #[cfg(unix)]
+    {
+        use std::process::Command;
+        let output = Command::new("kill")
+            .arg(pid.to_string())
+            .output()?;
+
+        if output.status.success() {
+            std::fs::remove_file(pid_file)?;
+            eprintln!("✓ Daemon stopped (PID: {})", pid);
+        } else {
+            eprintln!("✗ Failed to stop daemon (PID: {})", pid);
+            eprintln!("  Process may have already exited");
+            std::fs::remove_file(pid_file)?;
+            std::process::exit(1);
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        eprintln!("✗ Daemon stop is only supported on Unix systems");
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn check_status(pid_file: &std::path::Path) -> anyhow::Result<()> {
+    if !pid_file.exists() {
+        eprintln!("✗ Daemon is not running");
+        eprintln!("  PID file not found: {}", pid_file.display());
+        std::process::exit(1);
+    }
+
+    let pid_str = std::fs::read_to_string(pid_file)?;
+    let pid: i32 = pid_str.trim().parse()
+        .map_err(|_| anyhow::anyhow!("Invalid PID in file: {}", pid_str))?;
+
+    #[cfg(unix)]
+    {
+        use std::process::Command;
+        let output = Command::new("ps")
+            .arg("-p")
+            .arg(pid.to_string())
+            .output()?;
+
+        if output.status.success() {
+            eprintln!("✓ Daemon is running (PID: {})", pid);
+            eprintln!("  PID file: {}", pid_file.display());
+        } else {
+            eprintln!("✗ Daemon is not running");
+            eprintln!("  Stale PID file found: {} (PID: {})", pid_file.display(), pid);
+            std::process::exit(1);
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        eprintln!("✗ Daemon status check is only supported on Unix systems");
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,429 @@
+//! Ingress authentication: validates client API keys before requests reach the proxy handlers.
+
+use crate::config::{secret_var, wildcard_match, Config, Upstream, DEFAULT_UPSTREAM_PATH};
+use crate::error::{ProxyError, ProxyResult};
+use crate::jwt::{self, JwtVerifier};
+use crate::keypool::{self, KeyPool};
+use crate::listeners::ListenerAuth;
+use crate::metrics;
+use crate::quota::Quota;
+use crate::tls::ClientCertificate;
+use crate::sysprompt::SystemPromptEdits;
+use crate::transport::Transport;
+use crate::translate;
+use anyhow::Context;
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::Instrument;
+
+/// SHA-256 digest of a client key; only digests are kept in memory.
+pub type KeyDigest = [u8; 32];
+
+pub fn digest_key(key: &str) -> KeyDigest {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Who is calling: resolved from the presented key and attached to request extensions.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientIdentity {
+    pub name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Per-key quota; falls back to the CLIENT_QUOTA_* defaults when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Quota>,
+    /// Model tiers, incoming model patterns or routed model patterns this key may use;
+    /// empty allows every model.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_models: Vec<String>,
+    /// Upstream endpoint/credentials for this key; the global upstream when unset.
+    #[serde(skip)]
+    pub upstream: Option<Arc<Upstream>>,
+    /// System prompt prefix/suffix replacing SYSTEM_PROMPT_PREFIX / SYSTEM_PROMPT_SUFFIX.
+    #[serde(skip)]
+    pub system_prompt: SystemPromptEdits,
+}
+
+impl ClientIdentity {
+    /// An identity with only a name: no tags, expiry, quota override or model restrictions.
+    pub fn anonymous(name: String) -> Self {
+        Self {
+            name,
+            tags: Vec::new(),
+            expires_at: None,
+            quota: None,
+            allowed_models: Vec::new(),
+            upstream: None,
+            system_prompt: SystemPromptEdits::default(),
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|exp| exp <= now)
+    }
+
+    /// Whether the key may call `incoming` (as requested) routed to `routed` (upstream model).
+    /// Entries match the Claude tier (`haiku`), the incoming name or the routed name, with `*`
+    /// wildcards.
+    pub fn allows_model(&self, incoming: &str, routed: &str) -> bool {
+        if self.allowed_models.is_empty() {
+            return true;
+        }
+        let tier = translate::model_tier(incoming);
+        self.allowed_models.iter().any(|pattern| {
+            tier.is_some_and(|t| pattern.eq_ignore_ascii_case(t))
+                || wildcard_match(pattern, incoming)
+                || wildcard_match(pattern, routed)
+        })
+    }
+}
+
+/// One entry of the CLIENT_KEYS_PATH JSON file.
+#[derive(Debug, Deserialize)]
+struct ClientKeyEntry {
+    name: String,
+    #[serde(default)]
+    key: String,
+    /// Client certificate CN or full subject DN that authenticates as this identity (mTLS).
+    #[serde(default)]
+    cert_subject: Option<String>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    quota: Option<Quota>,
+    #[serde(default)]
+    allowed_models: Vec<String>,
+    /// Overrides UPSTREAM_BASE_URL for this key.
+    #[serde(default)]
+    upstream_base_url: Option<String>,
+    /// Upstream API key billed for this client's requests.
+    #[serde(default)]
+    upstream_api_key: Option<String>,
+    /// Name of an environment variable holding the upstream API key (keeps secrets out of the
+    /// file); `<name>_FILE` works as well.
+    #[serde(default)]
+    upstream_api_key_env: Option<String>,
+    /// Extra root CAs (PEM) for this key's upstream.
+    #[serde(default)]
+    upstream_ca_bundle: Option<PathBuf>,
+    /// Client certificate and key (PEM) presented to this key's upstream.
+    #[serde(default)]
+    upstream_client_cert: Option<PathBuf>,
+    #[serde(default)]
+    upstream_client_key: Option<PathBuf>,
+    /// Egress proxy for this key's upstream, or `none` to connect directly.
+    #[serde(default)]
+    upstream_proxy: Option<String>,
+    /// Chat completions path for this key's upstream.
+    #[serde(default)]
+    upstream_path: Option<String>,
+    /// Text added before and after this client's system prompts.
+    #[serde(default)]
+    system_prompt_prefix: Option<String>,
+    #[serde(default)]
+    system_prompt_suffix: Option<String>,
+}
+
+impl ClientKeyEntry {
+    /// The per-key upstream, if the entry overrides the URL, path, API key, TLS or proxy settings.
+    fn upstream(&self, default: &Upstream) -> anyhow::Result<Option<Upstream>> {
+        let api_key = match &self.upstream_api_key_env {
+            Some(var) => Some(secret_var(var)?.with_context(|| {
+                format!("Client key '{}': environment variable {var} is not set", self.name)
+            })?),
+            None => self.upstream_api_key.clone(),
+        };
+        let transport = Transport {
+            ca_bundle: self.upstream_ca_bundle.clone(),
+            client_cert: self.upstream_client_cert.clone(),
+            client_key: self.upstream_client_key.clone(),
+            proxy: self.upstream_proxy.clone(),
+            ..Transport::default()
+        };
+        if self.upstream_base_url.is_none()
+            && self.upstream_path.is_none()
+            && api_key.is_none()
+            && transport.is_default()
+        {
+            return Ok(None);
+        }
+        let keys = match (api_key, &self.upstream_base_url) {
+            (Some(key), _) => Arc::new(KeyPool::new([key])),
+            (None, Some(_)) => Arc::new(KeyPool::default()),
+            (None, None) => Arc::clone(&default.keys),
+        };
+        let transport = transport.or(&default.transport);
+        let client = if transport == default.transport {
+            default.client.clone()
+        } else {
+            transport
+                .client()
+                .with_context(|| format!("Client key '{}'", self.name))?
+        };
+        // The default upstream's static headers and path go with it, not to another base URL.
+        let (headers, path) = match self.upstream_base_url {
+            Some(_) => (Default::default(), DEFAULT_UPSTREAM_PATH),
+            None => (default.headers.clone(), default.path.as_str()),
+        };
+        let base_url = self.upstream_base_url.as_deref().unwrap_or(&default.base_url);
+        Upstream::new(base_url, keys, transport, client)
+            .and_then(|upstream| {
+                upstream
+                    .with_headers(headers)
+                    .with_path(self.upstream_path.as_deref().unwrap_or(path))
+            })
+            .with_context(|| format!("Client key '{}'", self.name))
+            .map(Some)
+    }
+}
+
+/// Identities by key digest and by client certificate subject.
+#[derive(Debug, Clone, Default)]
+struct IdentityIndex {
+    by_key: HashMap<KeyDigest, Arc<ClientIdentity>>,
+    by_subject: HashMap<String, Arc<ClientIdentity>>,
+}
+
+impl IdentityIndex {
+    fn len(&self) -> usize {
+        self.by_key.len() + self.by_subject.len()
+    }
+
+    /// Anonymous identities for a comma/newline-separated key list, named `key-<digest prefix>`.
+    fn add_list(&mut self, list: &str) {
+        for key in keypool::split_keys(list) {
+            let digest = digest_key(key);
+            let name = format!("key-{}", &hex::encode(digest)[..8]);
+            self.by_key.insert(digest, Arc::new(ClientIdentity::anonymous(name)));
+        }
+    }
+
+    /// Named identities from the JSON key file format.
+    fn add_json(&mut self, raw: &str, default: &Upstream) -> anyhow::Result<()> {
+        let entries: Vec<ClientKeyEntry> = serde_json::from_str(raw)?;
+        for entry in entries {
+            anyhow::ensure!(
+                !entry.key.is_empty() || entry.cert_subject.is_some(),
+                "Client key '{}' needs a key or a cert_subject",
+                entry.name
+            );
+            let upstream = entry.upstream(default)?.map(Arc::new);
+            let identity = Arc::new(ClientIdentity {
+                name: entry.name,
+                tags: entry.tags,
+                expires_at: entry.expires_at,
+                quota: entry.quota,
+                allowed_models: entry.allowed_models,
+                upstream,
+                system_prompt: SystemPromptEdits {
+                    prefix: entry.system_prompt_prefix,
+                    suffix: entry.system_prompt_suffix,
+                },
+            });
+            if !entry.key.is_empty() {
+                self.by_key.insert(digest_key(&entry.key), Arc::clone(&identity));
+            }
+            if let Some(subject) = entry.cert_subject {
+                self.by_subject.insert(subject, identity);
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_certificate(&self, cert: &ClientCertificate) -> Option<Arc<ClientIdentity>> {
+        cert.common_name
+            .as_ref()
+            .and_then(|cn| self.by_subject.get(cn))
+            .or_else(|| self.by_subject.get(&cert.subject))
+            .cloned()
+    }
+}
+
+/// The accepted client keys. Empty means authentication is disabled.
+#[derive(Debug, Clone, Default)]
+pub struct ClientKeys {
+    identities: IdentityIndex,
+    /// Keys fetched from a secret manager, replaced wholesale on every refresh.
+    managed: Arc<RwLock<IdentityIndex>>,
+}
+
+impl ClientKeys {
+    /// Adds a comma-separated list of anonymous keys, named `key-<digest prefix>`.
+    pub fn add_list(&mut self, list: &str) {
+        self.identities.add_list(list);
+    }
+
+    /// Adds named keys from a JSON file: `[{"name", "key", "cert_subject"?, "expires_at"?,
+    /// "tags"?, "quota"?, "allowed_models"?, "upstream_base_url"?, "upstream_path"?,
+    /// "upstream_api_key"?, "upstream_api_key_env"?, "upstream_ca_bundle"?,
+    /// "upstream_client_cert"?, "upstream_client_key"?, "upstream_proxy"?,
+    /// "system_prompt_prefix"?, "system_prompt_suffix"?}]`. Upstream settings an entry leaves
+    /// out come from `default`, except that a new base URL drops its headers, path and keys.
+    pub fn add_file(&mut self, path: &Path, default: &Upstream) -> anyhow::Result<()> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read client keys file {}", path.display()))?;
+        self.identities
+            .add_json(&raw, default)
+            .with_context(|| format!("Invalid client keys file {}", path.display()))
+    }
+
+    /// Replaces the secret-manager keys with `raw`: a JSON array in the key file format, or a
+    /// comma/newline-separated list. Returns the number of keys loaded.
+    pub fn set_managed(&self, raw: &str, default: &Upstream) -> anyhow::Result<usize> {
+        let mut identities = IdentityIndex::default();
+        if raw.trim_start().starts_with('[') {
+            identities.add_json(raw, default)?;
+        } else {
+            identities.add_list(raw);
+        }
+        anyhow::ensure!(identities.len() > 0, "secret contains no client keys");
+        let count = identities.len();
+        *self.managed.write().unwrap_or_else(|e| e.into_inner()) = identities;
+        Ok(count)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of accepted credentials (keys and certificate subjects).
+    pub fn len(&self) -> usize {
+        self.identities.len() + self.managed().len()
+    }
+
+    /// Looks up by digest, which keeps timing independent of how much of the key matched.
+    pub fn resolve(&self, key: &str) -> Option<Arc<ClientIdentity>> {
+        let digest = digest_key(key);
+        self.identities
+            .by_key
+            .get(&digest)
+            .cloned()
+            .or_else(|| self.managed().by_key.get(&digest).cloned())
+    }
+
+    /// The identity mapped to a verified client certificate's CN or full subject.
+    pub fn resolve_certificate(&self, cert: &ClientCertificate) -> Option<Arc<ClientIdentity>> {
+        self.identities
+            .resolve_certificate(cert)
+            .or_else(|| self.managed().resolve_certificate(cert))
+    }
+
+    /// The identities from CLIENT_API_KEYS and the key file, each once; secret-manager keys are
+    /// not included.
+    pub fn identities(&self) -> Vec<Arc<ClientIdentity>> {
+        let mut identities: Vec<Arc<ClientIdentity>> = Vec::new();
+        for identity in self.identities.by_key.values().chain(self.identities.by_subject.values()) {
+            if !identities.iter().any(|known| Arc::ptr_eq(known, identity)) {
+                identities.push(Arc::clone(identity));
+            }
+        }
+        identities.sort_by(|a, b| a.name.cmp(&b.name));
+        identities
+    }
+
+    fn managed(&self) -> std::sync::RwLockReadGuard<'_, IdentityIndex> {
+        self.managed.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Extracts the client key from `x-api-key` (Anthropic SDKs) or `Authorization: Bearer`.
+pub fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(key.trim());
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Middleware for the `/v1` routes: require a mapped client certificate, a configured client
+/// key or a valid JWT when any is set, and attach the resolved [`ClientIdentity`] to the
+/// request. Without key auth, a verified client certificate still names the caller. Listeners
+/// with `auth = "none"` skip all of this.
+pub async fn require_client_key(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(jwt_verifier): Extension<Option<Arc<JwtVerifier>>>,
+    mut request: Request,
+    next: Next,
+) -> ProxyResult<Response> {
+    if request.extensions().get::<ListenerAuth>() == Some(&ListenerAuth::None) {
+        return Ok(next.run(request).await);
+    }
+    let certificate = request
+        .extensions()
+        .get::<Option<ClientCertificate>>()
+        .cloned()
+        .flatten();
+
+    let identity = match certificate
+        .as_ref()
+        .and_then(|cert| config.client_keys.resolve_certificate(cert))
+    {
+        Some(identity) => unexpired(identity)?,
+        None if config.client_keys.is_empty() && jwt_verifier.is_none() => match certificate {
+            Some(cert) => Arc::new(ClientIdentity::anonymous(format!("cert:{}", cert.name()))),
+            None => return Ok(next.run(request).await),
+        },
+        None => authenticate_key(&config, jwt_verifier.as_deref(), request.headers()).await?,
+    };
+
+    metrics::increment("proxy_client_requests_total", &[("client", &identity.name)], 1);
+    let span = tracing::info_span!("client", name = %identity.name);
+    request.extensions_mut().insert(identity);
+    Ok(next.run(request).instrument(span).await)
+}
+
+/// Resolves the presented API key or JWT.
+async fn authenticate_key(
+    config: &Config,
+    jwt_verifier: Option<&JwtVerifier>,
+    headers: &HeaderMap,
+) -> ProxyResult<Arc<ClientIdentity>> {
+    let Some(key) = presented_key(headers) else {
+        return Err(ProxyError::Authentication(
+            "x-api-key header is required".to_string(),
+        ));
+    };
+    match jwt_verifier {
+        Some(verifier) if jwt::looks_like_jwt(key) => match verifier.verify(key).await {
+            Ok(identity) => Ok(Arc::new(identity)),
+            Err(reason) => {
+                tracing::warn!("Rejected bearer token: {}", reason);
+                Err(ProxyError::Authentication(reason))
+            }
+        },
+        _ => match config.client_keys.resolve(key) {
+            Some(identity) => unexpired(identity),
+            None => {
+                tracing::warn!("Rejected request with invalid client API key");
+                Err(ProxyError::Authentication("invalid x-api-key".to_string()))
+            }
+        },
+    }
+}
+
+fn unexpired(identity: Arc<ClientIdentity>) -> ProxyResult<Arc<ClientIdentity>> {
+    if identity.is_expired(Utc::now()) {
+        tracing::warn!("Rejected expired client key '{}'", identity.name);
+        return Err(ProxyError::Authentication("API key has expired".to_string()));
+    }
+    Ok(identity)
+}
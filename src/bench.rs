@@ -0,0 +1,252 @@
+//! `anthropic-proxy bench`: a small load generator. Sends synthetic Anthropic requests to a
+//! running proxy from `concurrency` workers and reports time to first byte, total latency and
+//! output token rates as percentiles, so regressions in the translation path show up as
+//! numbers. Exits non-zero when any request failed.
+
+use crate::cache::CACHE_CONTROL_HEADER;
+use futures::StreamExt;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+/// Filler repeated to reach the requested prompt size.
+const FILLER: &str = "The quick brown fox jumps over the lazy dog. ";
+
+/// What to send and how hard.
+#[derive(Debug, Clone)]
+pub struct BenchSettings {
+    /// Base URL of the proxy; requests go to `<url>/v1/messages`.
+    pub url: String,
+    /// Client key sent as `x-api-key`, if the proxy requires one.
+    pub api_key: Option<String>,
+    /// Anthropic model name in the requests.
+    pub model: String,
+    pub requests: usize,
+    pub concurrency: usize,
+    pub stream: bool,
+    /// Approximate size of each request's prompt.
+    pub prompt_bytes: usize,
+    pub max_tokens: u32,
+}
+
+/// One finished request.
+struct Sample {
+    ttfb: Duration,
+    total: Duration,
+    output_tokens: u64,
+}
+
+#[derive(Default)]
+struct Results {
+    samples: Vec<Sample>,
+    /// Failure descriptions (status and error type) with their counts.
+    failures: BTreeMap<String, usize>,
+}
+
+/// Runs the benchmark and prints the report; returns whether every request succeeded.
+pub async fn run(settings: BenchSettings) -> anyhow::Result<bool> {
+    let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+    let url = format!("{}/v1/messages", settings.url.trim_end_matches('/'));
+    let concurrency = settings.concurrency.clamp(1, settings.requests.max(1));
+    println!(
+        "Sending {} {} request(s) to {} from {} worker(s)",
+        settings.requests,
+        if settings.stream { "streaming" } else { "non-streaming" },
+        url,
+        concurrency
+    );
+
+    let settings = Arc::new(settings);
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let (client, url, settings, next) = (client.clone(), url.clone(), Arc::clone(&settings), Arc::clone(&next));
+            tokio::spawn(async move {
+                let mut results = Results::default();
+                loop {
+                    let n = next.fetch_add(1, Ordering::Relaxed);
+                    if n >= settings.requests {
+                        break results;
+                    }
+                    match send(&client, &url, &settings, n).await {
+                        Ok(sample) => results.samples.push(sample),
+                        Err(failure) => *results.failures.entry(failure).or_default() += 1,
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut results = Results::default();
+    for worker in workers {
+        let worker = worker.await?;
+        results.samples.extend(worker.samples);
+        for (failure, count) in worker.failures {
+            *results.failures.entry(failure).or_default() += count;
+        }
+    }
+    report(&results, started.elapsed());
+    Ok(results.failures.is_empty())
+}
+
+/// A request that cannot be answered from the proxy's response cache: the prompt starts with
+/// its number, and the cache is bypassed anyway.
+fn body(settings: &BenchSettings, n: usize) -> Value {
+    let mut prompt = format!("Benchmark request {n}. ");
+    while prompt.len() < settings.prompt_bytes {
+        prompt.push_str(FILLER);
+    }
+    json!({
+        "model": settings.model,
+        "max_tokens": settings.max_tokens,
+        "stream": settings.stream,
+        "messages": [{ "role": "user", "content": prompt }],
+    })
+}
+
+/// Sends request `n`; on failure returns a short description to count it under.
+async fn send(client: &Client, url: &str, settings: &BenchSettings, n: usize) -> Result<Sample, String> {
+    let mut request = client
+        .post(url)
+        .header(CACHE_CONTROL_HEADER, "bypass")
+        .header("anthropic-version", "2023-06-01")
+        .json(&body(settings, n));
+    if let Some(key) = &settings.api_key {
+        request = request.header("x-api-key", key);
+    }
+    let started = Instant::now();
+    let response = request.send().await.map_err(describe)?;
+    let status = response.status();
+    let mut chunks = response.bytes_stream();
+    let mut body = Vec::new();
+    let mut ttfb = None;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(describe)?;
+        ttfb.get_or_insert_with(|| started.elapsed());
+        body.extend_from_slice(&chunk);
+    }
+    let total = started.elapsed();
+    if !status.is_success() {
+        let error_type = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|v| v.pointer("/error/type").and_then(Value::as_str).map(str::to_string))
+            .unwrap_or_default();
+        return Err(format!("{status} {error_type}").trim_end().to_string());
+    }
+    let output_tokens = if settings.stream { stream_usage(&body)? } else { usage(&body)? };
+    Ok(Sample {
+        ttfb: ttfb.unwrap_or(total),
+        total,
+        output_tokens,
+    })
+}
+
+fn describe(e: reqwest::Error) -> String {
+    if e.is_timeout() {
+        "timeout".to_string()
+    } else if e.is_connect() {
+        "connection failed".to_string()
+    } else {
+        "transport error".to_string()
+    }
+}
+
+/// `usage.output_tokens` of a JSON response.
+fn usage(body: &[u8]) -> Result<u64, String> {
+    let response: Value = serde_json::from_slice(body).map_err(|_| "response is not JSON".to_string())?;
+    Ok(response.pointer("/usage/output_tokens").and_then(Value::as_u64).unwrap_or(0))
+}
+
+/// Output tokens from the stream's `message_delta`; an `error` event fails the request.
+fn stream_usage(body: &[u8]) -> Result<u64, String> {
+    let mut output_tokens = 0;
+    let mut stopped = false;
+    for line in String::from_utf8_lossy(body).lines() {
+        let Some(event) = line.strip_prefix("data: ").and_then(|data| serde_json::from_str::<Value>(data).ok())
+        else {
+            continue;
+        };
+        match event["type"].as_str() {
+            Some("message_delta") => {
+                output_tokens = event.pointer("/usage/output_tokens").and_then(Value::as_u64).unwrap_or(output_tokens);
+            }
+            Some("message_stop") => stopped = true,
+            Some("error") => {
+                let error_type = event.pointer("/error/type").and_then(Value::as_str).unwrap_or("error");
+                return Err(format!("stream {error_type}"));
+            }
+            _ => {}
+        }
+    }
+    if !stopped {
+        return Err("stream ended without message_stop".to_string());
+    }
+    Ok(output_tokens)
+}
+
+/// Nearest-rank percentile of sorted `values`.
+fn percentile<T: Copy>(sorted: &[T], p: f64) -> T {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn report(results: &Results, elapsed: Duration) {
+    let ok = results.samples.len();
+    let failed: usize = results.failures.values().sum();
+    let output_tokens: u64 = results.samples.iter().map(|s| s.output_tokens).sum();
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "\n{ok} succeeded, {failed} failed in {secs:.2}s: {:.1} requests/s, {:.0} output tokens/s",
+        ok as f64 / secs,
+        output_tokens as f64 / secs
+    );
+    if ok > 0 {
+        println!("\n{:<14}{:>10}{:>10}{:>10}{:>10}{:>10}", "", "min", "p50", "p90", "p99", "max");
+        let mut ttfb: Vec<_> = results.samples.iter().map(|s| s.ttfb).collect();
+        ttfb.sort();
+        print_latencies("TTFB", &ttfb);
+        let mut total: Vec<_> = results.samples.iter().map(|s| s.total).collect();
+        total.sort();
+        print_latencies("Total", &total);
+        let mut rates: Vec<f64> = results
+            .samples
+            .iter()
+            .filter(|s| s.output_tokens > 0)
+            .map(|s| s.output_tokens as f64 / s.total.as_secs_f64())
+            .collect();
+        if !rates.is_empty() {
+            rates.sort_by(f64::total_cmp);
+            print!("{:<14}", "Tokens/s");
+            for value in [rates[0], percentile(&rates, 50.0), percentile(&rates, 90.0), percentile(&rates, 99.0)] {
+                print!("{value:>10.1}");
+            }
+            println!("{:>10.1}", rates[rates.len() - 1]);
+        }
+    }
+    if !results.failures.is_empty() {
+        println!("\nFailures:");
+        for (failure, count) in &results.failures {
+            println!("  {count:>6}  {failure}");
+        }
+    }
+}
+
+fn print_latencies(label: &str, sorted: &[Duration]) {
+    print!("{label:<14}");
+    for value in [sorted[0], percentile(sorted, 50.0), percentile(sorted, 90.0), percentile(sorted, 99.0)] {
+        print!("{:>10}", format_duration(value));
+    }
+    println!("{:>10}", format_duration(sorted[sorted.len() - 1]));
+}
+
+fn format_duration(d: Duration) -> String {
+    if d < Duration::from_secs(1) {
+        format!("{:.1}ms", d.as_secs_f64() * 1000.0)
+    } else {
+        format!("{:.2}s", d.as_secs_f64())
+    }
+}
@@ -0,0 +1,299 @@
+//! Response cache for upstream completions, keyed by a hash of the translated request.
+
+use crate::config::Upstream;
+use crate::models::{anthropic, openai};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default number of cached responses when RESPONSE_CACHE_SIZE is not set.
+pub const DEFAULT_RESPONSE_CACHE_SIZE: usize = 1024;
+
+/// Request header clients use to control caching per request.
+pub const CACHE_CONTROL_HEADER: &str = "x-proxy-cache";
+
+/// Response header carrying the hex cache key (usable as an invalidation prefix).
+pub const CACHE_KEY_HEADER: &str = "x-proxy-cache-key";
+
+/// 32-byte SHA-256 digest used as a cache key.
+pub type CacheKey = [u8; 32];
+
+/// SHA-256 of a value's JSON encoding, streamed straight into the hasher.
+pub fn content_hash<T: serde::Serialize + ?Sized>(value: &T) -> Option<CacheKey> {
+    let mut hasher = Sha256::new();
+    serde_json::to_writer(HashWriter(&mut hasher), value).ok()?;
+    Some(hasher.finalize().into())
+}
+
+/// Adapter so serde_json can write into the hasher without an intermediate String.
+struct HashWriter<'a>(&'a mut Sha256);
+
+impl std::io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Per-request cache behaviour, from the `x-proxy-cache` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Read from and write to the cache (no header).
+    Default,
+    /// Neither read nor write the cache.
+    Bypass,
+    /// Skip the lookup but store the fresh upstream response.
+    Refresh,
+    /// Serve only from cache; fail if there is no entry.
+    Only,
+}
+
+impl CacheMode {
+    /// Parses the header value; unknown values fall back to `Default`.
+    pub fn from_header(value: Option<&str>) -> Self {
+        match value.map(str::trim) {
+            Some(v) if v.eq_ignore_ascii_case("bypass") => CacheMode::Bypass,
+            Some(v) if v.eq_ignore_ascii_case("refresh") => CacheMode::Refresh,
+            Some(v) if v.eq_ignore_ascii_case("only") => CacheMode::Only,
+            _ => CacheMode::Default,
+        }
+    }
+
+    #[inline]
+    pub fn reads(self) -> bool {
+        matches!(self, CacheMode::Default | CacheMode::Only)
+    }
+
+    #[inline]
+    pub fn writes(self) -> bool {
+        matches!(self, CacheMode::Default | CacheMode::Refresh)
+    }
+}
+
+/// Point-in-time cache statistics exposed on the admin API and as metrics.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+    /// Approximate bytes held by cached payloads.
+    pub memory_bytes: usize,
+}
+
+impl CacheStats {
+    pub fn new(entries: usize, capacity: usize, hits: u64, misses: u64, memory_bytes: usize) -> Self {
+        let lookups = hits + misses;
+        let hit_ratio = if lookups == 0 {
+            0.0
+        } else {
+            hits as f64 / lookups as f64
+        };
+        Self {
+            entries,
+            capacity,
+            hits,
+            misses,
+            hit_ratio,
+            memory_bytes,
+        }
+    }
+}
+
+struct Entry {
+    response: Arc<anthropic::AnthropicResponse>,
+    /// Routed upstream model, for invalidation by model.
+    model: String,
+    size: usize,
+    inserted: Instant,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<CacheKey, Entry>,
+    order: VecDeque<CacheKey>,
+    bytes: usize,
+}
+
+impl Store {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+/// Bounded in-memory cache of non-streaming Anthropic responses with a fixed TTL.
+///
+/// Streaming requests share the same key (`stream` and `stream_options` are excluded), so a
+/// cached response can be replayed as SSE to streaming clients.
+pub struct ResponseCache {
+    ttl: Duration,
+    capacity: usize,
+    store: Mutex<Store>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            store: Mutex::new(Store::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// True when a TTL and capacity are configured.
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.capacity > 0
+    }
+
+    /// Cache key for a translated upstream request (ignores `stream` and `stream_options`,
+    /// which model params add to streaming requests only). The upstream URL is part of the key
+    /// so clients routed to different providers never share entries.
+    pub fn key_for(upstream: &Upstream, req: &openai::OpenAIRequest) -> Option<CacheKey> {
+        let mut value = serde_json::to_value(req).ok()?;
+        if let Some(obj) = value.as_object_mut() {
+            obj.remove("stream");
+            obj.remove("stream_options");
+            obj.insert("upstream".to_string(), upstream.base_url.clone().into());
+        }
+        content_hash(&value)
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<anthropic::AnthropicResponse>> {
+        let mut store = self.lock();
+        match store.entries.get(key) {
+            Some(entry) if entry.inserted.elapsed() <= self.ttl => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(Arc::clone(&entry.response));
+            }
+            Some(_) => {
+                store.remove(key);
+                store.order.retain(|k| k != key);
+            }
+            None => {}
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub fn insert(&self, key: CacheKey, model: &str, response: anthropic::AnthropicResponse) {
+        let size = serde_json::to_vec(&response).map(|v| v.len()).unwrap_or(0);
+        let entry = Entry {
+            response: Arc::new(response),
+            model: model.to_string(),
+            size,
+            inserted: Instant::now(),
+        };
+        let mut store = self.lock();
+        store.bytes += size;
+        if let Some(old) = store.entries.insert(key, entry) {
+            store.bytes -= old.size;
+        } else {
+            store.order.push_back(key);
+            while store.order.len() > self.capacity {
+                if let Some(evicted) = store.order.pop_front() {
+                    store.remove(&evicted);
+                }
+            }
+        }
+    }
+
+    /// Removes entries for `model` and/or whose hex key starts with `key_prefix`.
+    /// With neither filter set nothing is removed; use [`ResponseCache::clear`] to flush.
+    pub fn invalidate(&self, model: Option<&str>, key_prefix: Option<&str>) -> usize {
+        if model.is_none() && key_prefix.is_none() {
+            return 0;
+        }
+        let key_prefix = key_prefix.map(str::to_ascii_lowercase);
+        let mut store = self.lock();
+        let doomed: HashSet<CacheKey> = store
+            .entries
+            .iter()
+            .filter(|(key, entry)| {
+                model.is_none_or(|m| entry.model == m)
+                    && key_prefix
+                        .as_deref()
+                        .is_none_or(|p| hex::encode(key).starts_with(p))
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &doomed {
+            store.remove(key);
+        }
+        store.order.retain(|k| !doomed.contains(k));
+        doomed.len()
+    }
+
+    /// Drops every entry; returns how many were removed.
+    pub fn clear(&self) -> usize {
+        let mut store = self.lock();
+        let n = store.entries.len();
+        *store = Store::default();
+        n
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let store = self.lock();
+        CacheStats::new(
+            store.entries.len(),
+            self.capacity,
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            store.bytes,
+        )
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypool::KeyPool;
+    use crate::transport::Transport;
+    use serde_json::json;
+
+    fn upstream(base_url: &str) -> Upstream {
+        let keys = Arc::new(KeyPool::new(["key"]));
+        Upstream::new(base_url, keys, Transport::default(), reqwest::Client::new()).unwrap()
+    }
+
+    fn request(extra: serde_json::Value) -> openai::OpenAIRequest {
+        let mut req = json!({ "model": "gpt-4o", "messages": [{ "role": "user", "content": "Hi" }] });
+        req.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(req).unwrap()
+    }
+
+    #[test]
+    fn key_ignores_streaming() {
+        let upstream = upstream("https://api.openai.com");
+        let plain = ResponseCache::key_for(&upstream, &request(json!({})));
+        let streaming = request(json!({ "stream": true, "stream_options": { "include_usage": true } }));
+        assert_eq!(plain, ResponseCache::key_for(&upstream, &streaming));
+        assert!(plain.is_some());
+    }
+
+    #[test]
+    fn key_separates_upstreams() {
+        let req = request(json!({}));
+        let openai = ResponseCache::key_for(&upstream("https://api.openai.com"), &req);
+        let local = ResponseCache::key_for(&upstream("http://localhost:11434"), &req);
+        assert_ne!(openai, local);
+    }
+}
@@ -0,0 +1,131 @@
+//! What the proxy knows about upstream models without being told: context window, output
+//! limit and support for tools, images and reasoning of well-known model families. They drive
+//! the context-window guard, `max_tokens` clamping, rejecting requests a model cannot serve
+//! and `check`'s routing warnings. `[model_params."<pattern>"]` (`context_window`,
+//! `max_tokens`, `supports_tools`, `supports_vision`, `supports_reasoning`) takes precedence
+//! and covers every other model.
+
+use crate::config::wildcard_match;
+use crate::error::{ProxyError, ProxyResult};
+use crate::metrics;
+use crate::models::openai;
+
+/// What an upstream model supports; `None` where unknown, which is never enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub context_window: Option<u32>,
+    pub max_output_tokens: Option<u32>,
+    pub tools: Option<bool>,
+    pub vision: Option<bool>,
+    pub reasoning: Option<bool>,
+}
+
+const UNKNOWN: Capabilities = Capabilities {
+    context_window: None,
+    max_output_tokens: None,
+    tools: None,
+    vision: None,
+    reasoning: None,
+};
+
+const TOOLS: u8 = 1;
+const VISION: u8 = 2;
+const REASONING: u8 = 4;
+
+/// A model whose limits and features are all known; `features` are `TOOLS | VISION | REASONING`.
+const fn known(context_window: u32, max_output_tokens: u32, features: u8) -> Capabilities {
+    Capabilities {
+        context_window: Some(context_window),
+        max_output_tokens: Some(max_output_tokens),
+        tools: Some(features & TOOLS != 0),
+        vision: Some(features & VISION != 0),
+        reasoning: Some(features & REASONING != 0),
+    }
+}
+
+/// Model name pattern → capabilities, first match wins. Patterns are matched against the name
+/// without an OpenRouter-style `vendor/` prefix.
+const MODELS: &[(&str, Capabilities)] = &[
+    ("gpt-5*", known(400_000, 128_000, TOOLS | VISION | REASONING)),
+    ("gpt-4.1*", known(1_047_576, 32_768, TOOLS | VISION)),
+    ("gpt-4o*", known(128_000, 16_384, TOOLS | VISION)),
+    ("gpt-4-turbo*", known(128_000, 4_096, TOOLS | VISION)),
+    ("gpt-3.5-turbo*", known(16_385, 4_096, TOOLS)),
+    ("o1-mini*", known(128_000, 65_536, REASONING)),
+    ("o1*", known(200_000, 100_000, TOOLS | VISION | REASONING)),
+    ("o3-mini*", known(200_000, 100_000, TOOLS | REASONING)),
+    ("o3*", known(200_000, 100_000, TOOLS | VISION | REASONING)),
+    ("o4-mini*", known(200_000, 100_000, TOOLS | VISION | REASONING)),
+    ("claude-opus-4*", known(200_000, 32_000, TOOLS | VISION | REASONING)),
+    ("claude-sonnet-4*", known(200_000, 64_000, TOOLS | VISION | REASONING)),
+    ("claude-3-7-sonnet*", known(200_000, 64_000, TOOLS | VISION | REASONING)),
+    ("claude-3.7-sonnet*", known(200_000, 64_000, TOOLS | VISION | REASONING)),
+    ("claude-3-5-*", known(200_000, 8_192, TOOLS | VISION)),
+    ("claude-3.5-*", known(200_000, 8_192, TOOLS | VISION)),
+    ("claude-3-*", known(200_000, 4_096, TOOLS | VISION)),
+    ("claude-*", Capabilities { context_window: Some(200_000), tools: Some(true), ..UNKNOWN }),
+    ("gemini-1.5-pro*", known(2_097_152, 8_192, TOOLS | VISION)),
+    ("gemini-1.5-flash*", known(1_048_576, 8_192, TOOLS | VISION)),
+    ("gemini-2.0-*", known(1_048_576, 8_192, TOOLS | VISION)),
+    ("gemini-2.5-*", known(1_048_576, 65_536, TOOLS | VISION | REASONING)),
+    ("gemini-2*", Capabilities { context_window: Some(1_048_576), ..UNKNOWN }),
+    ("deepseek-chat*", known(128_000, 8_192, TOOLS)),
+    (
+        "deepseek-reasoner*",
+        Capabilities {
+            context_window: Some(128_000),
+            max_output_tokens: Some(65_536),
+            vision: Some(false),
+            reasoning: Some(true),
+            ..UNKNOWN
+        },
+    ),
+    ("mistral-large*", Capabilities { context_window: Some(131_072), tools: Some(true), ..UNKNOWN }),
+];
+
+/// The built-in capabilities of upstream `model`; all unknown for models not in the table.
+pub fn builtin(model: &str) -> Capabilities {
+    let name = model.rsplit('/').next().unwrap_or(model);
+    MODELS
+        .iter()
+        .find(|(pattern, _)| wildcard_match(pattern, name))
+        .map_or(UNKNOWN, |&(_, capabilities)| capabilities)
+}
+
+/// Rejects a request using tools or images that upstream `req.model` is known not to support,
+/// with a message naming the model rather than whatever error the upstream would return.
+/// Thinking requested of a model without reasoning is let through and only counted.
+pub fn check(capabilities: &Capabilities, req: &openai::OpenAIRequest, thinking: bool) -> ProxyResult<()> {
+    let uses_tools = req.tools.as_ref().is_some_and(|tools| !tools.is_empty());
+    let uses_images = req.messages.iter().any(|m| match &m.content {
+        Some(openai::MessageContent::Parts(parts)) => {
+            parts.iter().any(|p| matches!(p, openai::ContentPart::ImageUrl { .. }))
+        }
+        _ => false,
+    });
+    let unsupported = [
+        ("tools", uses_tools && capabilities.tools == Some(false)),
+        ("vision", uses_images && capabilities.vision == Some(false)),
+        ("reasoning", thinking && capabilities.reasoning == Some(false)),
+    ];
+    for (capability, missing) in unsupported {
+        if !missing {
+            continue;
+        }
+        metrics::increment(
+            "proxy_capability_mismatches_total",
+            &[("model", &req.model), ("capability", capability)],
+            1,
+        );
+        if capability == "reasoning" {
+            tracing::debug!("Thinking requested of {}, which does not reason", req.model);
+            continue;
+        }
+        tracing::warn!("Rejected request using {} for {}, which does not support it", capability, req.model);
+        return Err(ProxyError::Unsupported {
+            model: req.model.clone(),
+            capability,
+        });
+    }
+    Ok(())
+}
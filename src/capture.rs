@@ -0,0 +1,149 @@
+//! Debug capture: with CAPTURE_DIR set, a request sent with `x-proxy-capture: true` has each
+//! stage written to `<CAPTURE_DIR>/<id>.<stage>` — the Anthropic request as received, the
+//! OpenAI request sent upstream, the raw upstream response or stream, and the Anthropic
+//! response or stream returned to the client. Payloads are written verbatim.
+
+use axum::http::HeaderMap;
+use bytes::Bytes;
+use chrono::Utc;
+use futures::Stream;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Request header opting a request into capture; echoed on the response with the capture id.
+pub const CAPTURE_HEADER: &str = "x-proxy-capture";
+
+/// The capture directory (CAPTURE_DIR).
+pub struct CaptureDir {
+    dir: Arc<Path>,
+    seq: AtomicU64,
+}
+
+impl CaptureDir {
+    pub fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow::anyhow!("Cannot create CAPTURE_DIR {}: {e}", dir.display()))?;
+        Ok(Self {
+            dir: dir.into(),
+            seq: AtomicU64::new(0),
+        })
+    }
+
+    /// A capture for this request, if it asked for one.
+    pub fn start(&self, headers: &HeaderMap) -> Option<Capture> {
+        let wanted = headers
+            .get(CAPTURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "true" | "1" | "yes"));
+        if !wanted {
+            return None;
+        }
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let id = format!("{}-{seq}", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+        tracing::info!("Capturing request {} to {}", id, self.dir.display());
+        Some(Capture {
+            dir: Arc::clone(&self.dir),
+            id,
+        })
+    }
+}
+
+/// One captured request.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    dir: Arc<Path>,
+    id: String,
+}
+
+impl Capture {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn write_json<T: Serialize>(&self, stage: &str, value: &T) {
+        match serde_json::to_vec_pretty(value) {
+            Ok(bytes) => self.write(stage, &bytes),
+            Err(e) => tracing::error!("Failed to serialize capture {}.{}: {}", self.id, stage, e),
+        }
+    }
+
+    pub fn write(&self, stage: &str, bytes: &[u8]) {
+        let path = self.dir.join(format!("{}.{stage}", self.id));
+        if let Err(e) = std::fs::write(&path, bytes) {
+            tracing::error!("Failed to write capture {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Passes `stream` through unchanged, writing everything it yielded to `stage` of `capture`
+/// (if any) when it ends or is dropped.
+pub fn tee<S>(capture: Option<&Capture>, stage: &'static str, stream: S) -> Tee<S> {
+    match capture.cloned() {
+        Some(capture) => Tee::new(stream, move |bytes, _| capture.write(stage, &bytes)),
+        None => Tee::passthrough(stream),
+    }
+}
+
+/// Callback given everything a teed stream yielded, and whether it reached its end.
+type OnEnd = Box<dyn FnOnce(Vec<u8>, bool) + Send>;
+
+/// Stream wrapper buffering what passes through and handing it to a callback once dropped.
+pub struct Tee<S> {
+    inner: Pin<Box<S>>,
+    captured: Vec<u8>,
+    finished: bool,
+    on_end: Option<OnEnd>,
+}
+
+impl<S> Tee<S> {
+    pub fn new(stream: S, on_end: impl FnOnce(Vec<u8>, bool) + Send + 'static) -> Self {
+        Self {
+            inner: Box::pin(stream),
+            captured: Vec::new(),
+            finished: false,
+            on_end: Some(Box::new(on_end)),
+        }
+    }
+
+    /// A tee that buffers nothing.
+    pub fn passthrough(stream: S) -> Self {
+        Self {
+            inner: Box::pin(stream),
+            captured: Vec::new(),
+            finished: false,
+            on_end: None,
+        }
+    }
+}
+
+impl<S, E> Stream for Tee<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        let poll = this.inner.as_mut().poll_next(cx);
+        if this.on_end.is_some() {
+            match &poll {
+                Poll::Ready(Some(Ok(bytes))) => this.captured.extend_from_slice(bytes),
+                Poll::Ready(None) => this.finished = true,
+                _ => {}
+            }
+        }
+        poll
+    }
+}
+
+impl<S> Drop for Tee<S> {
+    fn drop(&mut self) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(std::mem::take(&mut self.captured), self.finished);
+        }
+    }
+}
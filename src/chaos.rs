@@ -0,0 +1,210 @@
+//! Fault injection for resilience testing. With CHAOS_RATE set, that share of `/v1/messages`
+//! requests gets one of CHAOS_FAULTS, in turn; with CHAOS_HEADER=true a request picks its own
+//! with `x-proxy-chaos: latency,disconnect`. Meant for test deployments only.
+//!
+//! - `latency`: CHAOS_LATENCY_MS more before the upstream call.
+//! - `rate_limit`: a 429 instead of the upstream call, which the key pool and clients see as a
+//!   real one.
+//! - `disconnect`: the upstream body breaks off after a few stream chunks, or half way through a
+//!   response.
+//! - `malformed`: an invalid chunk in the stream, or a truncated response body.
+//!
+//! Faults are applied to the upstream response before it is translated, so the proxy's own
+//! error handling is exercised along with the client's.
+
+use crate::error::{ProxyError, ProxyResult};
+use crate::metrics;
+use axum::http::{self, header, HeaderMap, StatusCode};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde_json::json;
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+pub const CHAOS_HEADER: &str = "x-proxy-chaos";
+pub const DEFAULT_CHAOS_LATENCY_MS: u64 = 2000;
+
+/// Upstream stream chunks passed through before a `disconnect` or `malformed` fault.
+const FAULT_AFTER_CHUNKS: usize = 3;
+/// Cut off mid-event, so it never parses.
+const MALFORMED_CHUNK: &[u8] = b"data: {\"id\":\"chaos\",\"choices\":[{\"delta\":{\"content\":\n\n";
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Latency,
+    RateLimit,
+    Disconnect,
+    Malformed,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 4] = [Fault::Latency, Fault::RateLimit, Fault::Disconnect, Fault::Malformed];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fault::Latency => "latency",
+            Fault::RateLimit => "rate_limit",
+            Fault::Disconnect => "disconnect",
+            Fault::Malformed => "malformed",
+        }
+    }
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        if s == "429" {
+            return Ok(Fault::RateLimit);
+        }
+        Self::ALL.into_iter().find(|fault| fault.as_str() == s).ok_or_else(|| {
+            let known: Vec<_> = Self::ALL.iter().map(|fault| fault.as_str()).collect();
+            format!("unknown fault '{s}' (expected one of {})", known.join(", "))
+        })
+    }
+}
+
+/// CHAOS_RATE / CHAOS_FAULTS / CHAOS_LATENCY_MS / CHAOS_HEADER.
+#[derive(Debug, Clone)]
+pub struct ChaosSettings {
+    /// Share of requests (0–1) that get a fault.
+    pub rate: f64,
+    /// Faults `rate` picks from, in turn; every kind by default.
+    pub faults: Vec<Fault>,
+    pub latency: Duration,
+    /// Whether requests may choose faults with `x-proxy-chaos`.
+    pub header: bool,
+}
+
+impl ChaosSettings {
+    /// The faults for a request with `headers`.
+    pub fn pick(&self, headers: &HeaderMap) -> ProxyResult<Faults> {
+        let mut faults = Faults::default();
+        if let Some(requested) = headers.get(CHAOS_HEADER).filter(|_| self.header) {
+            let requested = requested
+                .to_str()
+                .map_err(|_| ProxyError::Transform(format!("{CHAOS_HEADER} must be a list of faults")))?;
+            for name in requested.split(',').filter(|name| !name.trim().is_empty()) {
+                let fault = name.parse().map_err(|e| ProxyError::Transform(format!("{CHAOS_HEADER}: {e}")))?;
+                faults.add(fault, self.latency);
+            }
+            return Ok(faults);
+        }
+        if self.rate <= 0.0 || self.faults.is_empty() {
+            return Ok(faults);
+        }
+        // Every request where the running count of `rate`-sized shares crosses an integer.
+        let n = SEQ.fetch_add(1, Ordering::Relaxed) as f64;
+        let (before, after) = ((n * self.rate).floor(), ((n + 1.0) * self.rate).floor());
+        if after > before {
+            faults.add(self.faults[before as usize % self.faults.len()], self.latency);
+        }
+        Ok(faults)
+    }
+}
+
+/// The faults chosen for one request; none by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    latency: Option<Duration>,
+    rate_limit: bool,
+    disconnect: bool,
+    malformed: bool,
+}
+
+impl Faults {
+    fn add(&mut self, fault: Fault, latency: Duration) {
+        match fault {
+            Fault::Latency => self.latency = Some(latency),
+            Fault::RateLimit => self.rate_limit = true,
+            Fault::Disconnect => self.disconnect = true,
+            Fault::Malformed => self.malformed = true,
+        }
+    }
+
+    /// Runs the upstream call `send` with the faults applied to it and its response.
+    pub async fn apply(
+        self,
+        streaming: bool,
+        send: impl Future<Output = reqwest::Result<reqwest::Response>>,
+    ) -> reqwest::Result<reqwest::Response> {
+        if let Some(latency) = self.latency {
+            injected(Fault::Latency);
+            tokio::time::sleep(latency).await;
+        }
+        if self.rate_limit {
+            injected(Fault::RateLimit);
+            let body = json!({
+                "error": { "message": "Rate limit reached (injected by chaos mode)", "type": "rate_limit_error" }
+            });
+            let mut response = http::Response::new(reqwest::Body::from(body.to_string()));
+            *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+            headers.insert(header::RETRY_AFTER, header::HeaderValue::from_static("1"));
+            return Ok(reqwest::Response::from(response));
+        }
+        let response = send.await?;
+        if !(self.disconnect || self.malformed) || !response.status().is_success() {
+            return Ok(response);
+        }
+
+        let mut parts = http::Response::builder().status(response.status()).version(response.version());
+        if let Some(headers) = parts.headers_mut() {
+            *headers = response.headers().clone();
+            headers.remove(header::CONTENT_LENGTH);
+        }
+        let Faults { disconnect, malformed, .. } = self;
+        let body = if streaming {
+            async_stream::stream! {
+                let mut upstream = response.bytes_stream();
+                let mut chunks = 0;
+                while let Some(chunk) = upstream.next().await {
+                    if chunks == FAULT_AFTER_CHUNKS {
+                        if malformed {
+                            injected(Fault::Malformed);
+                            yield Ok(Bytes::from_static(MALFORMED_CHUNK));
+                        }
+                        if disconnect {
+                            injected(Fault::Disconnect);
+                            yield Err(dropped());
+                            return;
+                        }
+                    }
+                    chunks += 1;
+                    yield chunk.map_err(io::Error::other);
+                }
+            }
+            .boxed()
+        } else {
+            let bytes = response.bytes().await?;
+            let half = bytes.slice(..bytes.len() / 2);
+            if disconnect {
+                injected(Fault::Disconnect);
+                futures::stream::iter([Ok(half), Err(dropped())]).boxed()
+            } else {
+                injected(Fault::Malformed);
+                futures::stream::iter([Ok(half)]).boxed()
+            }
+        };
+        let response = parts
+            .body(reqwest::Body::wrap_stream(body))
+            .expect("status, version and headers come from a valid response");
+        Ok(reqwest::Response::from(response))
+    }
+}
+
+fn dropped() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "upstream connection dropped (injected by chaos mode)")
+}
+
+fn injected(fault: Fault) {
+    tracing::info!("Chaos: injecting {}", fault.as_str());
+    metrics::increment("proxy_chaos_faults_total", &[("fault", fault.as_str())], 1);
+}
@@ -0,0 +1,457 @@
+//! `anthropic-proxy check`: loads the configuration the way `serve` would and opens the files
+//! it reads, printing one line per finding, without binding a port or calling the upstream.
+//! Exits non-zero when anything would stop the server from starting.
+//!
+//! It also runs a validation pass over the loaded configuration: upstream hosts must resolve,
+//! well-known hosted APIs need keys, routes must be reachable, model parameters must not
+//! contradict themselves, and spend budgets need prices. With STRICT_CONFIG the server runs
+//! the same pass at startup and refuses to start on errors. All problems are reported
+//! together, including every config file mistake rather than only the first.
+
+use crate::accesslog::AccessLogTarget;
+use crate::config::{Config, Upstream};
+use crate::configfile::Param;
+use crate::plugin::Plugins;
+use crate::redact::ContentLogging;
+use crate::replay::{Traffic, TrafficMode};
+use crate::{script, tls, wasmplugin};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Default)]
+pub(crate) struct Diagnostics {
+    findings: Vec<(Severity, String)>,
+}
+
+impl Diagnostics {
+    pub(crate) fn ok(&mut self, message: impl Display) {
+        self.findings.push((Severity::Ok, message.to_string()));
+    }
+
+    pub(crate) fn warn(&mut self, message: impl Display) {
+        self.findings.push((Severity::Warning, message.to_string()));
+    }
+
+    pub(crate) fn error(&mut self, message: impl Display) {
+        self.findings.push((Severity::Error, message.to_string()));
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.findings.iter().filter(|(s, _)| *s == severity).count()
+    }
+
+    /// Records an error when `result` failed, `ok` otherwise.
+    fn check<T>(&mut self, result: anyhow::Result<T>, ok: impl Display) {
+        match result {
+            Ok(_) => self.ok(ok),
+            Err(e) => self.error(format!("{e:#}")),
+        }
+    }
+
+    /// A file the server creates or appends to: its directory must exist (or be creatable)
+    /// and be writable.
+    fn writable(&mut self, what: &str, path: &Path) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let existing = dir.ancestors().find(|d| d.exists()).unwrap_or(Path::new("."));
+        match std::fs::metadata(existing) {
+            Ok(meta) if meta.permissions().readonly() => {
+                self.error(format!("{what} {}: {} is read-only", path.display(), existing.display()))
+            }
+            Ok(_) => self.ok(format!("{what}: {}", path.display())),
+            Err(e) => self.error(format!("{what} {}: {e}", path.display())),
+        }
+    }
+
+    /// Prints the findings and a summary about `subject`; returns whether there were no errors.
+    pub(crate) fn report(&self, subject: &str) -> bool {
+        for (severity, message) in &self.findings {
+            let mark = match severity {
+                Severity::Ok => "✓",
+                Severity::Warning => "!",
+                Severity::Error => "✗",
+            };
+            println!("{mark} {message}");
+        }
+        let (errors, warnings) = (self.count(Severity::Error), self.count(Severity::Warning));
+        if errors == 0 {
+            println!("{subject} OK ({warnings} warning(s))");
+        } else {
+            println!("{subject} has {errors} error(s) and {warnings} warning(s)");
+        }
+        errors == 0
+    }
+}
+
+/// Runs the checks and prints the report; returns whether the configuration is usable.
+pub fn run(custom_path: Option<PathBuf>) -> bool {
+    let mut diagnostics = Diagnostics::default();
+    match Config::from_env_with_path(custom_path.clone()) {
+        Ok(config) => {
+            inspect(&config, &mut diagnostics);
+            validate(&config, &mut diagnostics);
+        }
+        Err(e) => {
+            for problem in load_problems(&e, custom_path) {
+                diagnostics.error(problem);
+            }
+        }
+    }
+    diagnostics.report("Configuration")
+}
+
+/// The error loading stopped at, followed by the config file's other problems.
+pub fn load_problems(error: &anyhow::Error, custom_path: Option<PathBuf>) -> Vec<String> {
+    let first = format!("{error:#}");
+    let others = Config::file_problems(custom_path).into_iter().filter(|p| *p != first);
+    std::iter::once(first.clone()).chain(others).collect()
+}
+
+/// The validation pass at startup (STRICT_CONFIG): logs its warnings and errors and returns
+/// whether there were no errors.
+pub fn strict(config: &Config) -> bool {
+    let mut d = Diagnostics::default();
+    validate(config, &mut d);
+    for (severity, message) in &d.findings {
+        match severity {
+            Severity::Ok => {}
+            Severity::Warning => tracing::warn!("Config: {}", message),
+            Severity::Error => tracing::error!("Config: {}", message),
+        }
+    }
+    d.count(Severity::Error) == 0
+}
+
+fn inspect(config: &Config, d: &mut Diagnostics) {
+    d.ok(format!(
+        "Upstream {}{} ({} key(s))",
+        config.upstream.base_url,
+        config.upstream.path,
+        config.upstream.keys.len()
+    ));
+    if let Some(file) = &config.config_file {
+        if let Some(profile) = &file.profile {
+            d.ok(format!("Profile {profile} of {}", file.path.display()));
+        }
+    }
+    if let Some(preset) = config.preset {
+        d.ok(format!(
+            "Preset {}: haiku -> {}, sonnet -> {}, opus -> {}{}",
+            preset.name,
+            preset.haiku,
+            preset.sonnet,
+            preset.opus,
+            preset.reasoning.map(|m| format!(", thinking -> {m}")).unwrap_or_default()
+        ));
+    }
+    for route in &config.routes {
+        d.ok(format!(
+            "Route {} -> {}{}",
+            route.pattern,
+            route.model.as_deref().unwrap_or("(same model)"),
+            route.upstream_name.as_deref().map(|u| format!(" via {u}")).unwrap_or_default()
+        ));
+    }
+    for params in &config.model_params {
+        d.ok(format!("Model parameters for {}", params.pattern));
+    }
+    for rule in config.rewrite.rules() {
+        d.ok(format!("Rewrite rule '{}'", rule.name));
+    }
+    if config.listeners.is_empty() {
+        d.ok(format!("Listening on {}", SocketAddr::new(config.host, config.port)));
+    }
+    for listener in &config.listeners {
+        d.ok(format!("Listening on {listener}"));
+    }
+    if let Some(ref cors) = config.cors {
+        d.ok(format!("CORS origins: {}", cors.describe_origins()));
+    }
+
+    if config.jwt.is_none() && config.client_keys.is_empty() && config.secrets.client_keys.is_none() {
+        d.warn("Client auth is disabled: anyone who can reach the port can use the upstream");
+    } else if !config.client_keys.is_empty() {
+        d.ok(format!("Client auth: {} key(s)", config.client_keys.len()));
+    }
+    if config.secrets.vault.is_some()
+        || config.secrets.upstream_api_key.is_some()
+        || config.secrets.client_keys.is_some()
+    {
+        d.warn("Secrets from a secret manager are not fetched by check");
+    }
+
+    let listener_tls = config.listeners.iter().filter_map(|l| l.tls.as_ref());
+    for settings in config.tls.iter().chain(listener_tls) {
+        d.check(tls::validate(settings), format!("TLS certificate {}", settings.cert.display()));
+    }
+    if let Some(ref path) = config.logging.file {
+        d.writable("Log file", path);
+    }
+    if let Some(AccessLogTarget::File(ref path)) = config.access_log.as_ref().map(|a| &a.target) {
+        d.writable("Access log", path);
+    }
+    if let Some(ref usage_db) = config.usage_db {
+        d.writable("Usage database", &usage_db.path);
+    }
+    if let Some(dir) = config.slow_log.as_ref().and_then(|s| s.dump_dir.as_ref()) {
+        d.writable("Slow request dumps", &dir.join("dump"));
+    }
+    if let Some(ref dir) = config.capture_dir {
+        d.writable("Debug capture", &dir.join("capture"));
+        d.warn(format!("Debug capture writes payloads verbatim to {}", dir.display()));
+    }
+    match config.traffic {
+        Some(TrafficMode::Record(ref path)) => {
+            d.writable("Traffic recording", path);
+            d.warn(format!("Traffic recording writes payloads verbatim to {}", path.display()));
+        }
+        Some(ref mode @ TrafficMode::Replay(ref path)) => {
+            d.check(Traffic::open(mode), format!("Traffic replay: {}", path.display()));
+            d.warn("Traffic replay is on: the upstream is never called");
+        }
+        None => {}
+    }
+    for path in &config.wasm_plugins {
+        d.check(
+            wasmplugin::register(std::slice::from_ref(path), Plugins::new()),
+            format!("WASM plugin {}", path.display()),
+        );
+    }
+    if let Some(ref path) = config.script {
+        d.check(script::register(Some(path), Plugins::new()), format!("Script {}", path.display()));
+    }
+
+    if config.verbose && config.log_content == ContentLogging::Full {
+        d.warn("VERBOSE logs prompts and completions in full (see LOG_CONTENT)");
+    }
+    if config.verbose_sampling.is_some_and(|s| s.debug_header) && config.client_keys.is_empty() && config.jwt.is_none() {
+        d.warn("VERBOSE_DEBUG_HEADER lets any client have its payloads logged");
+    }
+}
+
+/// Hosted APIs that reject every request without a key.
+const KEYED_HOSTS: &[&str] = &[
+    "openrouter.ai",
+    "api.openai.com",
+    "*.openai.azure.com",
+    "api.anthropic.com",
+    "api.groq.com",
+    "api.together.xyz",
+    "api.mistral.ai",
+    "api.deepseek.com",
+    "api.fireworks.ai",
+    "generativelanguage.googleapis.com",
+];
+
+fn validate(config: &Config, d: &mut Diagnostics) {
+    validate_upstreams(config, d);
+    validate_routes(config, d);
+    validate_model_params(config, d);
+    validate_capabilities(config, d);
+    validate_prices(config, d);
+}
+
+/// Every upstream requests can go to, with what names it in messages and how to give it a key.
+fn upstreams(config: &Config) -> Vec<(String, &Upstream, String)> {
+    let mut upstreams = vec![(
+        "Upstream".to_string(),
+        &*config.upstream,
+        "set UPSTREAM_API_KEY".to_string(),
+    )];
+    let mut seen = BTreeSet::new();
+    for route in &config.routes {
+        if let (Some(name), Some(upstream)) = (&route.upstream_name, &route.upstream) {
+            if seen.insert(name) {
+                upstreams.push((
+                    format!("Upstream '{name}'"),
+                    &**upstream,
+                    format!("set api_key or api_key_env in [upstreams.{name}]"),
+                ));
+            }
+        }
+    }
+    upstreams
+}
+
+fn validate_upstreams(config: &Config, d: &mut Diagnostics) {
+    let identities = config.client_keys.identities();
+    let client_upstreams = identities.iter().filter_map(|identity| {
+        identity.upstream.as_deref().map(|upstream| {
+            (
+                format!("Client key '{}' upstream", identity.name),
+                upstream,
+                "set upstream_api_key or upstream_api_key_env in the client keys file".to_string(),
+            )
+        })
+    });
+    let mut resolved = BTreeSet::new();
+    for (label, upstream, key_hint) in upstreams(config).into_iter().chain(client_upstreams) {
+        let Some(host) = reqwest::Url::parse(&upstream.base_url)
+            .ok()
+            .and_then(|url| Some((url.host_str()?.trim_matches(['[', ']']).to_string(), url.port_or_known_default()?)))
+        else {
+            continue;
+        };
+        if resolved.insert(host.clone()) {
+            resolve(&label, upstream, &host, d);
+        }
+        let secret_key = label == "Upstream" && config.secrets.upstream_api_key.is_some();
+        if !upstream.keys.is_empty() || secret_key || is_local(&host.0) {
+            continue;
+        }
+        if KEYED_HOSTS.iter().any(|pattern| crate::config::wildcard_match(pattern, &host.0)) {
+            d.error(format!("{label} {} requires an API key: {key_hint}", upstream.base_url));
+        } else {
+            d.warn(format!("{label} {}: no API key, requests are sent unauthenticated", upstream.base_url));
+        }
+    }
+}
+
+/// Looks the host up the way the HTTP client will; behind an egress proxy the proxy may be
+/// the only one able to.
+fn resolve(label: &str, upstream: &Upstream, (host, port): &(String, u16), d: &mut Diagnostics) {
+    if host.parse::<IpAddr>().is_ok() {
+        return;
+    }
+    let Err(e) = (host.as_str(), *port).to_socket_addrs() else {
+        return;
+    };
+    let proxied = upstream.transport.proxy_display().is_some()
+        || ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+            .iter()
+            .any(|var| std::env::var(var).is_ok_and(|v| !v.trim().is_empty()));
+    if proxied {
+        d.warn(format!("{label}: {host} does not resolve here ({e}); the egress proxy has to resolve it"));
+    } else {
+        d.error(format!("{label}: cannot resolve {host} ({e}); check the host in {}", upstream.base_url));
+    }
+}
+
+fn is_local(host: &str) -> bool {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+        Ok(IpAddr::V6(ip)) => ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00,
+        Err(_) => {
+            host.eq_ignore_ascii_case("localhost")
+                || [".localhost", ".local", ".internal", ".lan"].iter().any(|suffix| host.ends_with(suffix))
+                || !host.contains('.')
+        }
+    }
+}
+
+/// Routes listed after one that already takes every model they match.
+fn validate_routes(config: &Config, d: &mut Diagnostics) {
+    for (i, route) in config.routes.iter().enumerate() {
+        let earlier = config.routes[..i]
+            .iter()
+            .find(|r| r.pattern == "*" || r.pattern.eq_ignore_ascii_case(&route.pattern));
+        if let Some(earlier) = earlier {
+            d.warn(format!(
+                "Route {} is never used: the earlier route {} matches the same models (routes come before [models])",
+                route.pattern, earlier.pattern
+            ));
+        }
+    }
+}
+
+fn validate_model_params(config: &Config, d: &mut Diagnostics) {
+    for params in &config.model_params {
+        let set_and_stripped = [
+            (Param::MaxTokens, "max_tokens", params.max_tokens.is_some()),
+            (Param::Temperature, "temperature", params.temperature.is_some()),
+            (Param::StreamOptions, "stream_options", params.stream_options.is_some()),
+        ];
+        for (param, name, set) in set_and_stripped {
+            if set && params.strip.contains(&param) {
+                d.warn(format!(
+                    "[model_params.\"{}\"] sets {name} but also strips it, so the setting has no effect",
+                    params.pattern
+                ));
+            }
+        }
+    }
+}
+
+/// Models requests are routed to that lack what those requests need, per the capability
+/// registry.
+fn validate_capabilities(config: &Config, d: &mut Diagnostics) {
+    if let Some(model) = config.reasoning_model.as_deref() {
+        if config.capabilities(model).reasoning == Some(false) {
+            d.warn(format!(
+                "REASONING_MODEL {model} does not reason; requests with thinking get answers without it"
+            ));
+        }
+    }
+    for model in known_models(config) {
+        if config.capabilities(model).tools == Some(false) {
+            d.warn(format!(
+                "{model} does not support tool use; requests with tools routed to it are rejected \
+                 (set supports_tools in [model_params] if it does)"
+            ));
+        }
+    }
+}
+
+/// Upstream models requests are known to be sent as.
+fn known_models(config: &Config) -> BTreeSet<&str> {
+    let mut models: BTreeSet<&str> = config
+        .routes
+        .iter()
+        .filter_map(|r| r.model.as_deref())
+        .chain(config.reasoning_model.as_deref())
+        .chain(config.completion_model.as_deref())
+        .collect();
+    if let Some(preset) = config.preset {
+        for model in preset.models() {
+            models.insert(model);
+        }
+    }
+    models
+}
+
+fn validate_prices(config: &Config, d: &mut Diagnostics) {
+    let pricing = &config.pricing;
+    let mut invalid: Vec<&String> = pricing
+        .prices
+        .iter()
+        .filter(|(_, p)| !(p.input.is_finite() && p.input >= 0.0 && p.output.is_finite() && p.output >= 0.0))
+        .map(|(model, _)| model)
+        .collect();
+    invalid.sort();
+    for model in invalid {
+        d.error(format!("MODEL_PRICES_PATH: the price of {model} must not be negative"));
+    }
+
+    let identities = config.client_keys.identities();
+    let budgeted = std::iter::once(&config.default_quota)
+        .chain(identities.iter().filter_map(|i| i.quota.as_ref()))
+        .any(|q| q.daily_budget_usd.is_some() || q.monthly_budget_usd.is_some());
+    if pricing.sync_url.is_some() {
+        return;
+    }
+    if pricing.prices.is_empty() {
+        if budgeted {
+            d.error("Spend budgets are set but no model prices are known, so nothing counts against them: set MODEL_PRICES_PATH or PRICE_SYNC");
+        }
+        return;
+    }
+    for model in known_models(config) {
+        if !pricing.prices.contains_key(model) {
+            d.warn(format!(
+                "No price for upstream model {model} in MODEL_PRICES_PATH: its requests are not costed{}",
+                if budgeted { " and do not count against budgets" } else { "" }
+            ));
+        }
+    }
+}
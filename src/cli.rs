@@ -1,4 +1,8 @@
+use crate::config::env_keys;
+use crate::mockupstream;
+use crate::offline::Direction;
 use clap::{Parser, Subcommand};
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -14,24 +18,63 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Command>,
 
-    /// Path to custom .env configuration file
-    #[arg(short, long, value_name = "FILE")]
+    /// Path to a custom .env file, or a .toml config file (same as PROXY_CONFIG)
+    #[arg(short, long, value_name = "FILE", global = true)]
     pub config: Option<PathBuf>,
 
+    /// `[profile.<name>]` of the TOML config file to apply (same as PROXY_PROFILE)
+    #[arg(long, value_name = "NAME", global = true)]
+    pub profile: Option<String>,
+
     /// Enable debug logging (same as DEBUG=true)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub debug: bool,
 
     /// Enable verbose logging (logs full request/response bodies)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub verbose: bool,
 
+    /// Log level (overrides LOG_LEVEL env var)
+    #[arg(long, value_name = "LEVEL", value_parser = ["error", "warn", "info", "debug", "trace"], global = true)]
+    pub log_level: Option<String>,
+
     /// Port to listen on (overrides PORT env var)
-    #[arg(short, long, value_name = "PORT")]
+    #[arg(short, long, value_name = "PORT", global = true)]
     pub port: Option<u16>,
 
+    /// Address to listen on (overrides HOST env var)
+    #[arg(long, value_name = "ADDR", global = true)]
+    pub host: Option<IpAddr>,
+
+    /// OpenAI-compatible upstream base URL (overrides UPSTREAM_BASE_URL env var)
+    #[arg(short, long, value_name = "URL", global = true)]
+    pub upstream: Option<String>,
+
+    /// Answer requests with the built-in mock upstream instead of calling one (sets
+    /// UPSTREAM_BASE_URL to mock://upstream)
+    #[arg(long, global = true, conflicts_with = "upstream")]
+    pub mock_upstream: bool,
+
+    /// Environment variable holding the upstream API key (overrides UPSTREAM_API_KEY_ENV)
+    #[arg(long, value_name = "VAR", global = true)]
+    pub api_key_env: Option<String>,
+
+    /// Built-in upstream and model mappings: openrouter-default, ollama-local or deepseek (same
+    /// as PRESET)
+    #[arg(long, value_name = "NAME", global = true)]
+    pub preset: Option<String>,
+
+    /// Send every request to this upstream model (sets REASONING_MODEL and COMPLETION_MODEL)
+    #[arg(short, long, value_name = "MODEL", global = true)]
+    pub model: Option<String>,
+
+    /// Validate the configuration fully before serving and refuse to start on errors (same as
+    /// STRICT_CONFIG=true)
+    #[arg(long, global = true)]
+    pub strict: bool,
+
     /// Run as background daemon
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub daemon: bool,
 
     /// PID file path (used with daemon commands)
@@ -41,6 +84,49 @@ pub struct Cli {
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
+    /// Run the proxy server (the default without a subcommand)
+    Serve,
+    /// Validate the configuration and exit non-zero if the server could not start
+    Check,
+    /// Convert a request or response offline, as the proxy would (a2o: Anthropic request to
+    /// OpenAI request; o2a: OpenAI response or event stream to Anthropic response)
+    Transform {
+        /// Conversion direction
+        #[arg(long, value_enum, default_value_t = Direction::A2o)]
+        direction: Direction,
+        /// JSON (or SSE, for o2a) file to read (default: stdin)
+        #[arg(value_name = "FILE")]
+        input: Option<PathBuf>,
+    },
+    /// Call the upstream with tiny test requests and report latency, auth and accepted parameters
+    Probe,
+    /// Send synthetic requests to a running proxy and report latency and throughput percentiles
+    Bench {
+        /// Base URL of the proxy (default: http://127.0.0.1:<PORT>)
+        #[arg(long, value_name = "URL")]
+        url: Option<String>,
+        /// Client key sent as x-api-key (default: ANTHROPIC_API_KEY)
+        #[arg(long, value_name = "KEY")]
+        api_key: Option<String>,
+        /// Anthropic model name in the requests
+        #[arg(long, value_name = "MODEL", default_value = "claude-3-5-haiku-latest")]
+        request_model: String,
+        /// Total number of requests
+        #[arg(long, value_name = "N", default_value_t = 100)]
+        requests: usize,
+        /// Requests in flight at once
+        #[arg(long, value_name = "N", default_value_t = 8)]
+        concurrency: usize,
+        /// Send streaming requests
+        #[arg(long)]
+        stream: bool,
+        /// Approximate prompt size of each request in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = 1024)]
+        prompt_bytes: usize,
+        /// max_tokens of each request
+        #[arg(long, value_name = "N", default_value_t = 64)]
+        max_tokens: u32,
+    },
     /// Stop running daemon
     Stop {
         /// PID file path
@@ -53,4 +139,43 @@ pub enum Command {
         #[arg(long, value_name = "FILE", default_value = "/tmp/anthropic-proxy.pid")]
         pid_file: PathBuf,
     },
+    /// Watch live requests, token throughput and errors of a running proxy
+    Monitor {
+        /// Base URL of the proxy (default: http://127.0.0.1:<PORT>)
+        #[arg(long, value_name = "URL")]
+        url: Option<String>,
+        /// Admin token (default: ADMIN_TOKEN)
+        #[arg(long, value_name = "TOKEN")]
+        admin_token: Option<String>,
+    },
+}
+
+impl Cli {
+    /// Sets the environment variables the flags stand for, so flags win over the environment,
+    /// .env files and config files alike. Runs before any other thread starts.
+    pub fn apply_env(&self) {
+        let flags = [
+            (env_keys::DEBUG, self.debug.then(|| "true".to_string())),
+            (env_keys::VERBOSE, self.verbose.then(|| "true".to_string())),
+            (env_keys::STRICT_CONFIG, self.strict.then(|| "true".to_string())),
+            (env_keys::LOG_LEVEL, self.log_level.clone()),
+            (env_keys::PORT, self.port.map(|p| p.to_string())),
+            (env_keys::HOST, self.host.map(|h| h.to_string())),
+            (env_keys::UPSTREAM_BASE_URL, self.upstream.clone()),
+            (
+                env_keys::UPSTREAM_BASE_URL,
+                self.mock_upstream.then(|| mockupstream::DEFAULT_MOCK_UPSTREAM_URL.to_string()),
+            ),
+            (env_keys::UPSTREAM_API_KEY_ENV, self.api_key_env.clone()),
+            (env_keys::PROXY_PROFILE, self.profile.clone()),
+            (env_keys::PRESET, self.preset.clone()),
+            (env_keys::REASONING_MODEL, self.model.clone()),
+            (env_keys::COMPLETION_MODEL, self.model.clone()),
+        ];
+        for (key, value) in flags {
+            if let Some(value) = value {
+                std::env::set_var(key, value);
+            }
+        }
+    }
 }
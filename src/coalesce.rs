@@ -0,0 +1,66 @@
+//! Stream delta coalescing (STREAM_COALESCE_MS / STREAM_COALESCE_BYTES): upstreams that send
+//! one SSE event per token make thousands of tiny writes; adjacent text and thinking deltas are
+//! held for a short window and sent as one larger `content_block_delta`.
+
+use std::time::{Duration, Instant};
+
+/// Default STREAM_COALESCE_BYTES: held text is sent once it reaches this size.
+pub const DEFAULT_COALESCE_BYTES: usize = 1024;
+
+/// STREAM_COALESCE_MS / STREAM_COALESCE_BYTES.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalesceSettings {
+    /// Longest a delta is held before it is sent.
+    pub window: Duration,
+    /// Held text is sent as soon as it reaches this many bytes.
+    pub max_bytes: usize,
+}
+
+/// Delta text held back for one content block, identified by `K`.
+pub struct Coalescer<K> {
+    settings: CoalesceSettings,
+    pending: Option<Pending<K>>,
+}
+
+struct Pending<K> {
+    key: K,
+    text: String,
+    deadline: Instant,
+}
+
+impl<K: Copy + PartialEq> Coalescer<K> {
+    pub fn new(settings: CoalesceSettings) -> Self {
+        Self { settings, pending: None }
+    }
+
+    /// Adds `text` for block `key`. Returns held text that is due now: all of it once it
+    /// reaches the size limit, or another block's text that `key` takes over from.
+    pub fn push(&mut self, key: K, text: &str) -> Option<(K, String)> {
+        let previous = match &mut self.pending {
+            Some(pending) if pending.key == key => {
+                pending.text.push_str(text);
+                None
+            }
+            _ => self.pending.replace(Pending {
+                key,
+                text: text.to_string(),
+                deadline: Instant::now() + self.settings.window,
+            }),
+        };
+        match previous {
+            Some(previous) => Some((previous.key, previous.text)),
+            None if self.pending.as_ref().is_some_and(|p| p.text.len() >= self.settings.max_bytes) => self.take(),
+            None => None,
+        }
+    }
+
+    /// All held text, to be sent before any other event.
+    pub fn take(&mut self) -> Option<(K, String)> {
+        self.pending.take().map(|pending| (pending.key, pending.text))
+    }
+
+    /// When the held text must be sent even if nothing else arrives.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.deadline)
+    }
+}
@@ -1,8 +1,63 @@
+use crate::access::IpFilter;
+use crate::accesslog::AccessLogSettings;
+use crate::alerts::{
+    AlertSettings, DEFAULT_ALERT_COOLDOWN_SECS, DEFAULT_ALERT_ERROR_RATE, DEFAULT_ALERT_ERROR_WINDOW_SECS,
+    DEFAULT_ALERT_MIN_REQUESTS,
+};
+use crate::auth::ClientKeys;
+use crate::cache::DEFAULT_RESPONSE_CACHE_SIZE;
+use crate::capabilities::{self, Capabilities};
+use crate::chaos::{ChaosSettings, Fault, DEFAULT_CHAOS_LATENCY_MS};
+use crate::coalesce::{CoalesceSettings, DEFAULT_COALESCE_BYTES};
+use crate::configfile::{self, ConfigFile, ModelParams, Route};
+use crate::conversations::{self, ConversationSettings, ConversationTarget};
+use crate::cors::{self, CorsSettings};
+use crate::dns;
+use crate::events::{EventKind, EventSettings, DEFAULT_EVENT_BUDGET_THRESHOLDS, DEFAULT_EVENT_WEBHOOK_RETRIES};
+use crate::jwt::JwtSettings;
+use crate::keypool::{self, KeyPool};
+use crate::limits::{RequestLimits, DEFAULT_MAX_REQUEST_BYTES};
+use crate::mockupstream::{self, MockSettings};
+use crate::listeners::{Listener, SocketSettings, DEFAULT_LISTEN_BACKLOG};
+use crate::logfile::{LogSettings, Rotation, RotationSettings, DEFAULT_MAX_LOG_FILES};
+use crate::moderation::{ModerationAction, ModerationSettings};
+use crate::pii::PiiSettings;
+use crate::presets::{self, Preset};
+use crate::pricing::{PricingSettings, DEFAULT_PRICE_SYNC_INTERVAL_SECS};
+use crate::quota::{BudgetSchedule, Quota};
+use crate::ratelimit::{RateLimit, RateLimitSettings};
+use crate::redact::{ContentLogging, VerboseSampling, DEFAULT_TRUNCATE_CHARS};
+use crate::replay::TrafficMode;
+use crate::rewrite::Rewriter;
+use crate::stops::DEFAULT_MAX_STOP_SEQUENCES;
+use crate::stream::ToolInputMode;
+use crate::runtime::{Flavor, RuntimeSettings};
+use crate::secrets::{SecretSettings, SecretSource, VaultSettings};
+use crate::signing::{SigningSettings, DEFAULT_TOLERANCE_SECS};
+use crate::slowlog::SlowLogSettings;
+use crate::statsd::StatsdSettings;
+use crate::summarize::{SummarySettings, DEFAULT_SUMMARY_MAX_TOKENS};
+use crate::sysprompt::{self, SystemPromptEdits};
+use crate::telemetry::{OtelSettings, DEFAULT_SERVICE_NAME};
+use crate::tls::{AcmeChallenge, AcmeSettings, TlsSettings};
+use crate::tokens::DEFAULT_TOKEN_CACHE_SIZE;
+use crate::transport::{HttpVersion, PoolSettings, Transport, DEFAULT_POOL_IDLE_TIMEOUT, DEFAULT_POOL_MAX_IDLE};
+use crate::usagedb::UsageDbSettings;
+use crate::usageevents::UsageEventTarget;
+use crate::warmup::{WarmupSettings, DEFAULT_WARM_INTERVAL};
 use anyhow::{Context, Result};
-use std::{env, path::PathBuf};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, env, path::PathBuf, sync::Arc, time::Duration};
 
 /// Default server port when PORT is not set.
 const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+/// Chat completions path used unless UPSTREAM_PATH (or an upstream's `path`) says otherwise.
+pub const DEFAULT_UPSTREAM_PATH: &str = "/v1/chat/completions";
+/// Placeholder in an upstream path replaced by the request's model.
+const MODEL_PLACEHOLDER: &str = "{model}";
 
 /// Environment variable names for upstream and config.
 pub mod env_keys {
@@ -10,28 +65,496 @@ pub mod env_keys {
     pub const UPSTREAM_BASE_URL: &str = "UPSTREAM_BASE_URL";
     pub const ANTHROPIC_PROXY_BASE_URL: &str = "ANTHROPIC_PROXY_BASE_URL";
     pub const UPSTREAM_API_KEY: &str = "UPSTREAM_API_KEY";
+    pub const UPSTREAM_API_KEYS: &str = "UPSTREAM_API_KEYS";
+    pub const UPSTREAM_CA_BUNDLE: &str = "UPSTREAM_CA_BUNDLE";
+    pub const UPSTREAM_CLIENT_CERT: &str = "UPSTREAM_CLIENT_CERT";
+    pub const UPSTREAM_CLIENT_KEY: &str = "UPSTREAM_CLIENT_KEY";
+    pub const UPSTREAM_PROXY: &str = "UPSTREAM_PROXY";
+    pub const UPSTREAM_HEADERS: &str = "UPSTREAM_HEADERS";
+    pub const UPSTREAM_PATH: &str = "UPSTREAM_PATH";
+    pub const UPSTREAM_HTTP_VERSION: &str = "UPSTREAM_HTTP_VERSION";
+    pub const UPSTREAM_POOL_MAX_IDLE: &str = "UPSTREAM_POOL_MAX_IDLE";
+    pub const UPSTREAM_POOL_IDLE_TIMEOUT_SECS: &str = "UPSTREAM_POOL_IDLE_TIMEOUT_SECS";
+    pub const UPSTREAM_TCP_KEEPALIVE_SECS: &str = "UPSTREAM_TCP_KEEPALIVE_SECS";
+    pub const UPSTREAM_TCP_KEEPALIVE_INTERVAL_SECS: &str = "UPSTREAM_TCP_KEEPALIVE_INTERVAL_SECS";
+    pub const UPSTREAM_TCP_KEEPALIVE_RETRIES: &str = "UPSTREAM_TCP_KEEPALIVE_RETRIES";
+    pub const UPSTREAM_TCP_NODELAY: &str = "UPSTREAM_TCP_NODELAY";
+    pub const UPSTREAM_DNS_CACHE_TTL_SECS: &str = "UPSTREAM_DNS_CACHE_TTL_SECS";
+    pub const UPSTREAM_RESOLVE: &str = "UPSTREAM_RESOLVE";
+    pub const UPSTREAM_WARM_CONNECTIONS: &str = "UPSTREAM_WARM_CONNECTIONS";
+    pub const UPSTREAM_WARM_INTERVAL_SECS: &str = "UPSTREAM_WARM_INTERVAL_SECS";
+    pub const UPSTREAM_HTTP2_KEEPALIVE_SECS: &str = "UPSTREAM_HTTP2_KEEPALIVE_SECS";
+    pub const FORWARD_HEADERS: &str = "FORWARD_HEADERS";
+    pub const CORS_ALLOWED_ORIGINS: &str = "CORS_ALLOWED_ORIGINS";
+    pub const CORS_ALLOWED_HEADERS: &str = "CORS_ALLOWED_HEADERS";
+    pub const CORS_ALLOWED_METHODS: &str = "CORS_ALLOWED_METHODS";
+    pub const CORS_MAX_AGE: &str = "CORS_MAX_AGE";
     pub const OPENROUTER_API_KEY: &str = "OPENROUTER_API_KEY";
+    pub const PROXY_CONFIG: &str = "PROXY_CONFIG";
+    pub const PROXY_PROFILE: &str = "PROXY_PROFILE";
+    pub const CONFIG_WATCH: &str = "CONFIG_WATCH";
+    pub const PRESET: &str = "PRESET";
+    pub const STRICT_CONFIG: &str = "STRICT_CONFIG";
+    pub const HOST: &str = "HOST";
+    pub const TCP_NODELAY: &str = "TCP_NODELAY";
+    pub const TCP_KEEPALIVE_SECS: &str = "TCP_KEEPALIVE_SECS";
+    pub const TCP_KEEPALIVE_INTERVAL_SECS: &str = "TCP_KEEPALIVE_INTERVAL_SECS";
+    pub const TCP_KEEPALIVE_RETRIES: &str = "TCP_KEEPALIVE_RETRIES";
+    pub const LISTEN_BACKLOG: &str = "LISTEN_BACKLOG";
+    pub const RUNTIME_FLAVOR: &str = "RUNTIME_FLAVOR";
+    pub const RUNTIME_WORKER_THREADS: &str = "RUNTIME_WORKER_THREADS";
+    pub const RUNTIME_MAX_BLOCKING_THREADS: &str = "RUNTIME_MAX_BLOCKING_THREADS";
+    pub const LOG_LEVEL: &str = "LOG_LEVEL";
+    pub const UPSTREAM_API_KEY_ENV: &str = "UPSTREAM_API_KEY_ENV";
     pub const REASONING_MODEL: &str = "REASONING_MODEL";
     pub const COMPLETION_MODEL: &str = "COMPLETION_MODEL";
     pub const DEBUG: &str = "DEBUG";
     pub const VERBOSE: &str = "VERBOSE";
+    pub const VERBOSE_SAMPLE_RATE: &str = "VERBOSE_SAMPLE_RATE";
+    pub const VERBOSE_DEBUG_HEADER: &str = "VERBOSE_DEBUG_HEADER";
+    pub const TOKEN_CACHE_SIZE: &str = "TOKEN_CACHE_SIZE";
+    pub const RESPONSE_CACHE_TTL: &str = "RESPONSE_CACHE_TTL";
+    pub const RESPONSE_CACHE_SIZE: &str = "RESPONSE_CACHE_SIZE";
+    pub const ADMIN_TOKEN: &str = "ADMIN_TOKEN";
+    pub const CLIENT_API_KEYS: &str = "CLIENT_API_KEYS";
+    pub const CLIENT_KEYS_PATH: &str = "CLIENT_KEYS_PATH";
+    pub const CLIENT_QUOTA_RPM: &str = "CLIENT_QUOTA_RPM";
+    pub const CLIENT_QUOTA_TOKENS_PER_DAY: &str = "CLIENT_QUOTA_TOKENS_PER_DAY";
+    pub const CLIENT_QUOTA_CONCURRENT_STREAMS: &str = "CLIENT_QUOTA_CONCURRENT_STREAMS";
+    pub const CLIENT_BUDGET_DAILY_USD: &str = "CLIENT_BUDGET_DAILY_USD";
+    pub const CLIENT_BUDGET_MONTHLY_USD: &str = "CLIENT_BUDGET_MONTHLY_USD";
+    pub const BUDGET_RESET_HOUR: &str = "BUDGET_RESET_HOUR";
+    pub const BUDGET_RESET_DAY: &str = "BUDGET_RESET_DAY";
+    pub const JWT_JWKS_URL: &str = "JWT_JWKS_URL";
+    pub const JWT_ISSUER: &str = "JWT_ISSUER";
+    pub const JWT_AUDIENCE: &str = "JWT_AUDIENCE";
+    pub const JWT_NAME_CLAIM: &str = "JWT_NAME_CLAIM";
+    pub const JWT_TAGS_CLAIM: &str = "JWT_TAGS_CLAIM";
+    pub const UPSTREAM_API_KEY_SECRET: &str = "UPSTREAM_API_KEY_SECRET";
+    pub const CLIENT_API_KEYS_SECRET: &str = "CLIENT_API_KEYS_SECRET";
+    pub const SECRET_REFRESH_INTERVAL: &str = "SECRET_REFRESH_INTERVAL";
+    pub const VAULT_ADDR: &str = "VAULT_ADDR";
+    pub const VAULT_TOKEN: &str = "VAULT_TOKEN";
+    pub const VAULT_NAMESPACE: &str = "VAULT_NAMESPACE";
+    pub const TLS_CERT: &str = "TLS_CERT";
+    pub const TLS_KEY: &str = "TLS_KEY";
+    pub const TLS_CLIENT_CA: &str = "TLS_CLIENT_CA";
+    pub const TLS_CLIENT_AUTH: &str = "TLS_CLIENT_AUTH";
+    pub const ACME_DOMAINS: &str = "ACME_DOMAINS";
+    pub const ACME_EMAIL: &str = "ACME_EMAIL";
+    pub const ACME_CACHE_DIR: &str = "ACME_CACHE_DIR";
+    pub const ACME_STAGING: &str = "ACME_STAGING";
+    pub const ACME_CHALLENGE: &str = "ACME_CHALLENGE";
+    pub const ACME_HTTP_PORT: &str = "ACME_HTTP_PORT";
+    pub const IP_ALLOWLIST: &str = "IP_ALLOWLIST";
+    pub const IP_DENYLIST: &str = "IP_DENYLIST";
+    pub const TRUSTED_PROXIES: &str = "TRUSTED_PROXIES";
+    pub const RATE_LIMIT_IP_RPM: &str = "RATE_LIMIT_IP_RPM";
+    pub const RATE_LIMIT_IP_BURST: &str = "RATE_LIMIT_IP_BURST";
+    pub const RATE_LIMIT_IP_TPM: &str = "RATE_LIMIT_IP_TPM";
+    pub const RATE_LIMIT_KEY_RPM: &str = "RATE_LIMIT_KEY_RPM";
+    pub const RATE_LIMIT_KEY_BURST: &str = "RATE_LIMIT_KEY_BURST";
+    pub const RATE_LIMIT_KEY_TPM: &str = "RATE_LIMIT_KEY_TPM";
+    pub const REQUEST_SIGNING_SECRET: &str = "REQUEST_SIGNING_SECRET";
+    pub const REQUEST_SIGNATURE_TOLERANCE: &str = "REQUEST_SIGNATURE_TOLERANCE";
+    pub const LOG_CONTENT: &str = "LOG_CONTENT";
+    pub const LOG_CONTENT_MAX_CHARS: &str = "LOG_CONTENT_MAX_CHARS";
+    pub const MAX_REQUEST_BYTES: &str = "MAX_REQUEST_BYTES";
+    pub const MAX_MESSAGES: &str = "MAX_MESSAGES";
+    pub const MAX_IMAGES: &str = "MAX_IMAGES";
+    pub const CONTEXT_WINDOW_GUARD: &str = "CONTEXT_WINDOW_GUARD";
+    pub const MAX_STOP_SEQUENCES: &str = "MAX_STOP_SEQUENCES";
+    pub const SUMMARY_MODEL: &str = "SUMMARY_MODEL";
+    pub const SUMMARY_MAX_TOKENS: &str = "SUMMARY_MAX_TOKENS";
+    pub const SYSTEM_PROMPT_PREFIX: &str = "SYSTEM_PROMPT_PREFIX";
+    pub const SYSTEM_PROMPT_SUFFIX: &str = "SYSTEM_PROMPT_SUFFIX";
+    pub const STREAM_COALESCE_MS: &str = "STREAM_COALESCE_MS";
+    pub const STREAM_COALESCE_BYTES: &str = "STREAM_COALESCE_BYTES";
+    pub const STREAM_INPUT_TOKENS_ESTIMATE: &str = "STREAM_INPUT_TOKENS_ESTIMATE";
+    pub const STREAM_TOOL_INPUT: &str = "STREAM_TOOL_INPUT";
+    pub const REDIS_URL: &str = "REDIS_URL";
+    pub const RATE_LIMIT_REDIS_PREFIX: &str = "RATE_LIMIT_REDIS_PREFIX";
+    pub const MODERATION_URL: &str = "MODERATION_URL";
+    pub const MODERATION_API_KEY: &str = "MODERATION_API_KEY";
+    pub const MODERATION_MODEL: &str = "MODERATION_MODEL";
+    pub const MODERATION_THRESHOLD: &str = "MODERATION_THRESHOLD";
+    pub const MODERATION_ACTION: &str = "MODERATION_ACTION";
+    pub const MODERATION_FAIL_CLOSED: &str = "MODERATION_FAIL_CLOSED";
+    pub const PII_SCRUB: &str = "PII_SCRUB";
+    pub const PII_RESTORE: &str = "PII_RESTORE";
+    pub const OTEL_EXPORTER_OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+    pub const OTEL_EXPORTER_OTLP_TRACES_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+    pub const OTEL_SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+    pub const STATSD_ADDR: &str = "STATSD_ADDR";
+    pub const STATSD_FORMAT: &str = "STATSD_FORMAT";
+    pub const STATSD_PREFIX: &str = "STATSD_PREFIX";
+    pub const STATSD_TAGS: &str = "STATSD_TAGS";
+    pub const ALERT_WEBHOOK_URLS: &str = "ALERT_WEBHOOK_URLS";
+    pub const ALERT_ERROR_RATE: &str = "ALERT_ERROR_RATE";
+    pub const ALERT_ERROR_WINDOW_SECS: &str = "ALERT_ERROR_WINDOW_SECS";
+    pub const ALERT_MIN_REQUESTS: &str = "ALERT_MIN_REQUESTS";
+    pub const ALERT_COOLDOWN_SECS: &str = "ALERT_COOLDOWN_SECS";
+    pub const EVENT_WEBHOOK_URLS: &str = "EVENT_WEBHOOK_URLS";
+    pub const EVENT_WEBHOOK_SECRET: &str = "EVENT_WEBHOOK_SECRET";
+    pub const EVENT_TYPES: &str = "EVENT_TYPES";
+    pub const EVENT_BUDGET_THRESHOLDS: &str = "EVENT_BUDGET_THRESHOLDS";
+    pub const EVENT_WEBHOOK_RETRIES: &str = "EVENT_WEBHOOK_RETRIES";
+    pub const ACCESS_LOG: &str = "ACCESS_LOG";
+    pub const ACCESS_LOG_FORMAT: &str = "ACCESS_LOG_FORMAT";
+    pub const LOG_FILE: &str = "LOG_FILE";
+    pub const LOG_FILE_LEVEL: &str = "LOG_FILE_LEVEL";
+    pub const LOG_STDOUT: &str = "LOG_STDOUT";
+    pub const LOG_ROTATION: &str = "LOG_ROTATION";
+    pub const LOG_MAX_SIZE_MB: &str = "LOG_MAX_SIZE_MB";
+    pub const LOG_MAX_FILES: &str = "LOG_MAX_FILES";
+    pub const LOG_MAX_AGE_DAYS: &str = "LOG_MAX_AGE_DAYS";
+    pub const USAGE_DB: &str = "USAGE_DB";
+    pub const USAGE_RETENTION_DAYS: &str = "USAGE_RETENTION_DAYS";
+    pub const USAGE_EVENTS: &str = "USAGE_EVENTS";
+    pub const CONVERSATIONS_DB: &str = "CONVERSATIONS_DB";
+    pub const CONVERSATIONS_SESSION_HEADER: &str = "CONVERSATIONS_SESSION_HEADER";
+    pub const CONVERSATIONS_RETENTION_DAYS: &str = "CONVERSATIONS_RETENTION_DAYS";
+    pub const CAPTURE_DIR: &str = "CAPTURE_DIR";
+    pub const WASM_PLUGINS: &str = "WASM_PLUGINS";
+    pub const SCRIPT_PATH: &str = "SCRIPT_PATH";
+    pub const TRAFFIC_RECORD: &str = "TRAFFIC_RECORD";
+    pub const TRAFFIC_REPLAY: &str = "TRAFFIC_REPLAY";
+    pub const MOCK_UPSTREAM_SCRIPT: &str = "MOCK_UPSTREAM_SCRIPT";
+    pub const MOCK_UPSTREAM_LATENCY_MS: &str = "MOCK_UPSTREAM_LATENCY_MS";
+    pub const MOCK_UPSTREAM_CHUNK_DELAY_MS: &str = "MOCK_UPSTREAM_CHUNK_DELAY_MS";
+    pub const CHAOS_RATE: &str = "CHAOS_RATE";
+    pub const CHAOS_FAULTS: &str = "CHAOS_FAULTS";
+    pub const CHAOS_LATENCY_MS: &str = "CHAOS_LATENCY_MS";
+    pub const CHAOS_HEADER: &str = "CHAOS_HEADER";
+    pub const SLOW_REQUEST_SECS: &str = "SLOW_REQUEST_SECS";
+    pub const SLOW_TTFB_SECS: &str = "SLOW_TTFB_SECS";
+    pub const SLOW_REQUEST_DUMP_DIR: &str = "SLOW_REQUEST_DUMP_DIR";
+    pub const MODEL_PRICES_PATH: &str = "MODEL_PRICES_PATH";
+    pub const PRICE_SYNC: &str = "PRICE_SYNC";
+    pub const PRICE_SYNC_INTERVAL: &str = "PRICE_SYNC_INTERVAL";
 }
 
+/// Default seconds between secret-manager refreshes.
+const DEFAULT_SECRET_REFRESH_SECS: u64 = 300;
+
+/// An OpenAI-compatible endpoint and the credentials used to call it.
 #[derive(Debug, Clone)]
-pub struct Config {
-    pub port: u16,
+pub struct Upstream {
     pub base_url: String,
+    /// Chat completions path appended to `base_url`; `{model}` is replaced by the request's model.
+    pub path: String,
     /// Cached URL for upstream chat completions (avoids format! on every request).
     pub(crate) chat_completions_url: String,
-    /// Cached "Bearer <key>" when API key is set (avoids format! on every request).
-    pub(crate) auth_header_value: Option<String>,
+    /// API keys rotated across requests; empty for unauthenticated endpoints.
+    pub keys: Arc<KeyPool>,
+    /// TLS and proxy settings `client` was built with.
+    pub(crate) transport: Transport,
+    /// HTTP client carrying `transport` (shared when it matches the default).
+    pub(crate) client: reqwest::Client,
+    /// Static headers sent with every request; they replace the proxy's own, `Authorization`
+    /// included.
+    pub headers: HeaderMap,
+}
+
+impl Upstream {
+    /// Validates `base_url` (trailing slashes are trimmed) and caches derived values.
+    pub fn new(
+        base_url: &str,
+        keys: Arc<KeyPool>,
+        transport: Transport,
+        client: reqwest::Client,
+    ) -> Result<Self> {
+        let base_url = base_url.trim().trim_end_matches('/').to_string();
+        reqwest::Url::parse(&base_url)
+            .with_context(|| format!("Invalid upstream URL '{base_url}'"))?;
+        let chat_completions_url = format!("{}{}", base_url, DEFAULT_UPSTREAM_PATH);
+        Ok(Self {
+            base_url,
+            path: DEFAULT_UPSTREAM_PATH.to_string(),
+            chat_completions_url,
+            keys,
+            transport,
+            client,
+            headers: HeaderMap::new(),
+        })
+    }
+
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Replaces the chat completions path (a leading `/` is added when missing).
+    pub fn with_path(mut self, path: &str) -> Result<Self> {
+        let path = path.trim();
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{path}") };
+        let url = format!("{}{}", self.base_url, path);
+        reqwest::Url::parse(&url.replace(MODEL_PLACEHOLDER, "model"))
+            .with_context(|| format!("Invalid upstream path '{path}'"))?;
+        self.path = path;
+        self.chat_completions_url = url;
+        Ok(self)
+    }
+
+    /// URL for the upstream chat completions endpoint, with `model` filled into the path.
+    #[inline]
+    pub fn chat_completions_url(&self, model: &str) -> Cow<'_, str> {
+        if self.chat_completions_url.contains(MODEL_PLACEHOLDER) {
+            Cow::Owned(self.chat_completions_url.replace(MODEL_PLACEHOLDER, &encode_path_segment(model)))
+        } else {
+            Cow::Borrowed(&self.chat_completions_url)
+        }
+    }
+
+    /// Whether requests are answered by the built-in mock instead of sent (`mock://` URLs).
+    pub fn is_mock(&self) -> bool {
+        mockupstream::is_mock(&self.base_url)
+    }
+
+    /// URL listing the upstream's models: the path with `chat/completions` replaced by `models`.
+    /// `None` when the path does not end that way or names the model.
+    pub fn models_url(&self) -> Option<String> {
+        if self.path.contains(MODEL_PLACEHOLDER) {
+            return None;
+        }
+        let prefix = self.path.strip_suffix("chat/completions")?;
+        Some(format!("{}{}models", self.base_url, prefix))
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, so a model id stays one path segment.
+fn encode_path_segment(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    /// Address to listen on (HOST), all interfaces by default.
+    pub host: IpAddr,
+    /// Default upstream; client keys may override it.
+    pub upstream: Arc<Upstream>,
+    /// `*_FILE` secrets feeding the upstream key pool, re-read when they change.
+    pub upstream_key_files: Vec<PathBuf>,
+    /// Connections to the default upstream kept open ahead of requests.
+    pub warmup: Option<WarmupSettings>,
     pub reasoning_model: Option<String>,
     pub completion_model: Option<String>,
+    /// Model renames and named upstreams from the config file, first match wins.
+    pub routes: Vec<Route>,
+    /// Request limits and defaults per upstream model from the config file, first match wins.
+    pub model_params: Vec<ModelParams>,
+    /// Built-in upstream, tier mappings and quirks (PRESET), used where nothing else is set.
+    pub preset: Option<&'static Preset>,
+    /// Text added before and after every system prompt, unless the client key sets its own.
+    pub system_prompt: SystemPromptEdits,
+    /// `[[rewrite]]` rules from the config file.
+    pub rewrite: Arc<Rewriter>,
+    /// Custom system prompt template variables (PROMPT_VARS_<NAME>).
+    pub prompt_vars: BTreeMap<String, String>,
+    /// Stop sequences sent upstream, unless `[model_params]` says otherwise (MAX_STOP_SEQUENCES;
+    /// 0 for no limit).
+    pub max_stop_sequences: usize,
+    /// Model that summarizes truncated history for `[model_params] summarize` (SUMMARY_MODEL).
+    pub summary: Option<SummarySettings>,
+    /// The preset's quirks, checked after `model_params`.
+    pub preset_params: Vec<ModelParams>,
+    /// Client header name patterns copied onto upstream requests (FORWARD_HEADERS).
+    pub forward_headers: Vec<String>,
     pub debug: bool,
+    /// Diagnostic log level (LOG_LEVEL); `verbose` still means TRACE, else `debug` DEBUG.
+    pub log_level: Option<tracing::Level>,
     pub verbose: bool,
+    /// Payload logging for sampled or `x-proxy-debug` requests while `verbose` is off.
+    pub verbose_sampling: Option<VerboseSampling>,
+    /// How message content appears in verbose logs; credentials are always masked.
+    pub log_content: ContentLogging,
+    /// Max cached token counts for count_tokens/preflight (0 disables the cache).
+    pub token_cache_size: usize,
+    /// Seconds a non-streaming response stays cached (0 disables response caching).
+    pub response_cache_ttl_secs: u64,
+    pub response_cache_size: usize,
+    /// Bearer token for /admin endpoints; the admin API is not mounted when unset.
+    pub admin_token: Option<String>,
+    /// Keys clients must present via x-api-key / Bearer; empty disables ingress auth.
+    pub client_keys: ClientKeys,
+    /// Quota applied to authenticated clients without their own `quota`.
+    pub default_quota: Quota,
+    /// When daily and monthly spend budgets reset.
+    pub budget_schedule: BudgetSchedule,
+    /// JWT bearer validation settings; enabled when JWT_JWKS_URL is set.
+    pub jwt: Option<JwtSettings>,
+    /// Vault / AWS Secrets Manager references for the upstream key and client keys.
+    pub secrets: SecretSettings,
+    /// Serve HTTPS directly when TLS_CERT and TLS_KEY are set.
+    pub tls: Option<TlsSettings>,
+    /// Serve HTTPS with Let's Encrypt certificates when ACME_DOMAINS is set.
+    pub acme: Option<AcmeSettings>,
+    /// `[[listeners]]` from the config file; when present they replace HOST, PORT and TLS_*.
+    pub listeners: Vec<Listener>,
+    /// Client connection socket options and listen backlog, for every TCP listener.
+    pub sockets: SocketSettings,
+    /// Scheduler and thread counts of the server's tokio runtime.
+    pub runtime: RuntimeSettings,
+    /// Cross-origin access for browser clients; enabled when CORS_ALLOWED_ORIGINS is set.
+    pub cors: Option<CorsSettings>,
+    /// Source-IP allow/deny lists and trusted forwarding proxies.
+    pub ip_filter: IpFilter,
+    /// Token-bucket request/token rates per source IP and per client key.
+    pub rate_limits: RateLimitSettings,
+    /// Body size and message/image count caps for `/v1/messages`.
+    pub limits: RequestLimits,
+    /// Merging of per-token text and thinking deltas in streams; enabled by STREAM_COALESCE_MS.
+    pub stream_coalesce: Option<CoalesceSettings>,
+    /// Report the local prompt token estimate as `input_tokens` in streamed `message_start`
+    /// events, which otherwise say 0 (STREAM_INPUT_TOKENS_ESTIMATE, default on).
+    pub stream_input_estimate: bool,
+    /// What streams do with tool call input that is not valid JSON (STREAM_TOOL_INPUT).
+    pub stream_tool_input: ToolInputMode,
+    /// Required HMAC body signatures; enabled when REQUEST_SIGNING_SECRET is set.
+    pub signing: Option<SigningSettings>,
+    /// Prompt pre-check against a moderation endpoint; enabled when MODERATION_URL is set.
+    pub moderation: Option<ModerationSettings>,
+    /// Masking of emails, phone numbers and keys in prompts; enabled by PII_SCRUB.
+    pub pii: Option<PiiSettings>,
+    /// OTLP trace export; enabled when an OTEL_EXPORTER_OTLP_*ENDPOINT is set.
+    pub otel: Option<OtelSettings>,
+    /// StatsD / DogStatsD metrics export; enabled when STATSD_ADDR is set.
+    pub statsd: Option<StatsdSettings>,
+    /// Webhook notifications on error spikes, unavailable upstreams and exhausted budgets;
+    /// enabled when ALERT_WEBHOOK_URLS is set.
+    pub alerts: Option<AlertSettings>,
+    /// Signed webhook events on completed requests, budget thresholds and key failovers;
+    /// enabled when EVENT_WEBHOOK_URLS is set.
+    pub events: Option<EventSettings>,
+    /// Per-request access log sink and format; enabled when ACCESS_LOG is set.
+    pub access_log: Option<AccessLogSettings>,
+    /// Diagnostic log destinations, and rotation for LOG_FILE and a file ACCESS_LOG.
+    pub logging: LogSettings,
+    /// Persistent per-request usage rows; enabled when USAGE_DB names a database file.
+    pub usage_db: Option<UsageDbSettings>,
+    /// Kafka or NATS destination for per-request usage events; enabled when USAGE_EVENTS is set.
+    pub usage_events: Option<UsageEventTarget>,
+    /// Per-session conversation history; enabled when CONVERSATIONS_DB is set.
+    pub conversations: Option<ConversationSettings>,
+    /// Slow-request thresholds and dump directory; enabled when a threshold is set.
+    pub slow_log: Option<SlowLogSettings>,
+    /// Where `x-proxy-capture` requests are written; capture is off when unset.
+    pub capture_dir: Option<PathBuf>,
+    /// WebAssembly plugin modules (WASM_PLUGINS), loaded at startup in this order.
+    pub wasm_plugins: Vec<PathBuf>,
+    /// Rhai script with `rewrite_request` / `route` functions (SCRIPT_PATH).
+    pub script: Option<PathBuf>,
+    /// Traffic archive to record exchanges to, or to replay them from.
+    pub traffic: Option<TrafficMode>,
+    /// Scripted replies and latency of `mock://` upstreams.
+    pub mock_upstream: MockSettings,
+    /// Injected upstream faults; enabled by CHAOS_RATE or CHAOS_HEADER.
+    pub chaos: Option<ChaosSettings>,
+    /// Model prices for cost estimates (MODEL_PRICES_PATH, PRICE_SYNC).
+    pub pricing: PricingSettings,
+    /// The TOML config file the settings came from.
+    pub config_file: Option<Arc<ConfigFile>>,
+    /// Apply changes to `config_file` while serving (CONFIG_WATCH, on by default).
+    pub config_watch: bool,
+    /// Refuse to start when `anthropic-proxy check`'s validation finds errors (STRICT_CONFIG).
+    pub strict_config: bool,
 }
 
 impl Config {
+    /// Loads the .env file, then the plain settings of a TOML config file named by `--config`
+    /// or PROXY_CONFIG; each only sets variables that are still unset.
+    fn load_sources(custom_path: Option<PathBuf>) -> Result<(Option<ConfigFile>, Option<PathBuf>)> {
+        let (mut config_file, env_path) = match Self::config_path(custom_path) {
+            Some(path) if configfile::is_structured(&path)? => (Some(ConfigFile::load(&path)?), None),
+            path => (None, path),
+        };
+        let dotenv = Self::load_dotenv(env_path);
+        if let Some(ref mut file) = config_file {
+            file.apply_settings();
+        }
+        Ok((config_file, dotenv))
+    }
+
+    /// `--config`, or else PROXY_CONFIG.
+    fn config_path(custom_path: Option<PathBuf>) -> Option<PathBuf> {
+        custom_path.or_else(|| {
+            env::var(env_keys::PROXY_CONFIG)
+                .ok()
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| PathBuf::from(p.trim()))
+        })
+    }
+
+    /// Every problem in the TOML config file's upstreams, routes and listeners, where loading
+    /// stops at the first. Empty when there is no such file or it does not parse.
+    pub fn file_problems(custom_path: Option<PathBuf>) -> Vec<String> {
+        let Some(path) = Self::config_path(custom_path).filter(|p| configfile::is_structured(p).unwrap_or(false))
+        else {
+            return Vec::new();
+        };
+        let (Ok(file), Ok(client)) = (ConfigFile::load(&path), Transport::default().client()) else {
+            return Vec::new();
+        };
+        // Named upstreams only inherit TLS and proxy settings from the default upstream.
+        match Upstream::new("http://localhost", Arc::default(), Transport::default(), client) {
+            Ok(default) => file.problems(&default),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Whether STRICT_CONFIG (or `--strict`) asks for full validation before serving; also
+    /// answers when loading the configuration failed, since the file's settings are applied
+    /// first.
+    pub fn strict_requested() -> bool {
+        Self::env_bool(env_keys::STRICT_CONFIG)
+    }
+
+    /// The first config file route matching the incoming `model`.
+    pub fn route(&self, model: &str) -> Option<&Route> {
+        self.routes.iter().find(|r| r.matches(model))
+    }
+
+    /// The first `[model_params]` entry matching the upstream `model`, then the preset's.
+    pub fn model_params(&self, model: &str) -> Option<&ModelParams> {
+        self.model_params.iter().chain(&self.preset_params).find(|p| p.matches(model))
+    }
+
+    /// What the upstream `model` supports: each of `context_window`, `max_tokens` and the
+    /// `supports_*` keys from the first matching `[model_params]` entry that sets it (then the
+    /// preset's), else the built-in capabilities of well-known models.
+    pub fn capabilities(&self, model: &str) -> Capabilities {
+        let builtin = capabilities::builtin(model);
+        let params: Vec<&ModelParams> =
+            self.model_params.iter().chain(&self.preset_params).filter(|p| p.matches(model)).collect();
+        Capabilities {
+            context_window: params.iter().find_map(|p| p.context_window).or(builtin.context_window),
+            max_output_tokens: params.iter().find_map(|p| p.max_tokens).or(builtin.max_output_tokens),
+            tools: params.iter().find_map(|p| p.supports_tools).or(builtin.tools),
+            vision: params.iter().find_map(|p| p.supports_vision).or(builtin.vision),
+            reasoning: params.iter().find_map(|p| p.supports_reasoning).or(builtin.reasoning),
+        }
+    }
+
+    /// How many stop sequences the upstream `model` accepts: the first matching
+    /// `[model_params]` entry that sets it, else MAX_STOP_SEQUENCES. 0 means no limit.
+    pub fn max_stop_sequences(&self, model: &str) -> usize {
+        self.model_params
+            .iter()
+            .chain(&self.preset_params)
+            .filter(|p| p.matches(model))
+            .find_map(|p| p.max_stop_sequences)
+            .unwrap_or(self.max_stop_sequences)
+    }
+
     /// Try to load .env from the given path; then from cwd, home, and /etc.
     fn load_dotenv(custom_path: Option<PathBuf>) -> Option<PathBuf> {
         if let Some(path) = custom_path {
@@ -73,69 +596,862 @@ impl Config {
             .unwrap_or(false)
     }
 
-    pub fn from_env() -> Result<Self> {
-        Self::from_env_with_path(None)
+    /// Like `env_bool` for a flag that is on unless set to `0`, `false` or `no`.
+    fn env_bool_on(key: &str) -> bool {
+        env::var(key)
+            .map(|v| {
+                let v = v.trim();
+                !(v == "0" || v.eq_ignore_ascii_case("false") || v.eq_ignore_ascii_case("no"))
+            })
+            .unwrap_or(true)
     }
 
-    pub fn from_env_with_path(custom_path: Option<PathBuf>) -> Result<Self> {
+    /// Path from `<key>_FILE` when `key` itself is not set.
+    fn secret_file(key: &str) -> Option<PathBuf> {
+        if env::var(key).is_ok() {
+            return None;
+        }
+        env::var(format!("{key}_FILE"))
+            .ok()
+            .map(|p| PathBuf::from(p.trim()))
+            .filter(|p| !p.as_os_str().is_empty())
+    }
+
+    /// Parse an env var with `FromStr`, ignoring unset or malformed values.
+    fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+        env::var(key).ok().and_then(|v| v.trim().parse().ok())
+    }
+
+    /// Address and ADMIN_TOKEN of the local proxy, from the same .env locations as the server,
+    /// for `anthropic-proxy monitor` and `bench`.
+    pub fn local_target(custom_path: Option<PathBuf>) -> Result<(SocketAddr, Option<String>)> {
+        use env_keys::*;
+
+        Self::load_sources(custom_path)?;
+        let port = Self::env_parse(PORT).unwrap_or(DEFAULT_PORT);
+        let host = match Self::env_parse::<IpAddr>(HOST) {
+            Some(host) if !host.is_unspecified() => host,
+            _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+        Ok((SocketAddr::new(host, port), secret_var(ADMIN_TOKEN)?))
+    }
+
+    /// The runtime settings alone, read before the server's runtime exists to load the rest.
+    pub fn runtime_settings(custom_path: Option<PathBuf>) -> Result<RuntimeSettings> {
+        Self::load_sources(custom_path)?;
+        Self::runtime_from_env()
+    }
+
+    fn runtime_from_env() -> Result<RuntimeSettings> {
         use env_keys::*;
 
-        if let Some(path) = Self::load_dotenv(custom_path) {
+        Ok(RuntimeSettings {
+            flavor: env::var(RUNTIME_FLAVOR)
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| Flavor::parse(&v))
+                .transpose()
+                .with_context(|| format!("Invalid {RUNTIME_FLAVOR}"))?
+                .unwrap_or_default(),
+            worker_threads: Self::env_parse(RUNTIME_WORKER_THREADS).filter(|&n: &usize| n > 0),
+            max_blocking_threads: Self::env_parse(RUNTIME_MAX_BLOCKING_THREADS).filter(|&n: &usize| n > 0),
+        })
+    }
+
+    /// EVENT_WEBHOOK_* settings; `None` unless EVENT_WEBHOOK_URLS is set.
+    fn event_settings() -> Result<Option<EventSettings>> {
+        use env_keys::*;
+
+        let list = |raw: Option<String>| -> Vec<String> {
+            raw.unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let webhook_urls = list(secret_var(EVENT_WEBHOOK_URLS)?);
+        if webhook_urls.is_empty() {
+            return Ok(None);
+        }
+        let secret = secret_var(EVENT_WEBHOOK_SECRET)?.with_context(|| {
+            format!("{EVENT_WEBHOOK_SECRET} is required with {EVENT_WEBHOOK_URLS}: receivers check the signature with it")
+        })?;
+        let kinds = list(env::var(EVENT_TYPES).ok())
+            .iter()
+            .map(|kind| kind.parse::<EventKind>().map_err(|e| anyhow::anyhow!("{EVENT_TYPES}: {e}")))
+            .collect::<Result<Vec<_>>>()?;
+        let budget_thresholds = list(env::var(EVENT_BUDGET_THRESHOLDS).ok())
+            .iter()
+            .map(|raw| match raw.parse::<f64>() {
+                Ok(t) if t > 0.0 && t <= 1.0 => Ok(t),
+                _ => anyhow::bail!("{EVENT_BUDGET_THRESHOLDS} must be fractions in (0, 1], got '{raw}'"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(EventSettings {
+            webhook_urls,
+            secret,
+            kinds: if kinds.is_empty() { EventKind::ALL.to_vec() } else { kinds },
+            budget_thresholds: if budget_thresholds.is_empty() {
+                DEFAULT_EVENT_BUDGET_THRESHOLDS.to_vec()
+            } else {
+                budget_thresholds
+            },
+            retries: Self::env_parse(EVENT_WEBHOOK_RETRIES).unwrap_or(DEFAULT_EVENT_WEBHOOK_RETRIES),
+        }))
+    }
+
+    pub fn from_env_with_path(custom_path: Option<PathBuf>) -> Result<Self> {
+        let (config_file, dotenv) = Self::load_sources(custom_path)?;
+        for path in dotenv.iter().chain(config_file.as_ref().map(|f| &f.path)) {
             eprintln!("Loaded config from: {}", path.display());
-        } else {
+        }
+        if dotenv.is_none() && config_file.is_none() {
             eprintln!("No .env file found, using environment variables only");
         }
+        Self::from_sources(config_file)
+    }
+
+    /// Builds the configuration from the environment once `config_file`'s settings are applied.
+    fn from_sources(config_file: Option<ConfigFile>) -> Result<Self> {
+        use env_keys::*;
 
         let port = env::var(PORT)
             .ok()
             .and_then(|p| p.parse().ok())
             .unwrap_or(DEFAULT_PORT);
+        let host = match env::var(HOST).ok().filter(|h| !h.trim().is_empty()) {
+            Some(host) => host
+                .trim()
+                .parse()
+                .with_context(|| format!("{HOST} must be an IP address, got '{}'", host.trim()))?,
+            None => DEFAULT_HOST,
+        };
+
+        let preset = match env::var(PRESET).ok().filter(|p| !p.trim().is_empty()) {
+            Some(name) => Some(presets::find(&name)?),
+            None => None,
+        };
 
         let raw_base_url = env::var(UPSTREAM_BASE_URL)
             .or_else(|_| env::var(ANTHROPIC_PROXY_BASE_URL))
+            .or_else(|e| preset.map(|p| p.base_url.to_string()).ok_or(e))
             .context(
                 "UPSTREAM_BASE_URL is required. Set it to your OpenAI-compatible endpoint (e.g. \
-                 https://openrouter.ai/api, https://api.openai.com, http://localhost:11434)",
+                 https://openrouter.ai/api, https://api.openai.com, http://localhost:11434), \
+                 or pick a PRESET",
             )?;
 
-        let base_url = raw_base_url.trim().trim_end_matches('/').to_string();
-        reqwest::Url::parse(&base_url).context("UPSTREAM_BASE_URL must be a valid URL")?;
+        let base_url = raw_base_url.trim().trim_end_matches('/');
+        reqwest::Url::parse(base_url).context("UPSTREAM_BASE_URL must be a valid URL")?;
 
-        if base_url.ends_with("/v1") {
+        let path = env::var(UPSTREAM_PATH).ok().filter(|p| !p.trim().is_empty());
+        if base_url.ends_with("/v1") && path.is_none() {
             eprintln!(
                 "WARNING: UPSTREAM_BASE_URL ends with '/v1'. The proxy adds /v1/chat/completions \
-                 itself. Prefer e.g. https://openrouter.ai/api (without /v1)."
+                 itself. Prefer e.g. https://openrouter.ai/api (without /v1), or set UPSTREAM_PATH."
             );
         }
 
-        let auth_header_value = env::var(UPSTREAM_API_KEY)
-            .or_else(|_| env::var(OPENROUTER_API_KEY))
-            .ok()
-            .filter(|k| !k.is_empty())
-            .map(|k| format!("Bearer {k}"));
+        let api_key_var = [Some(UPSTREAM_API_KEY), Some(OPENROUTER_API_KEY), preset.and_then(|p| p.api_key_env)]
+            .into_iter()
+            .flatten()
+            .find(|k| env::var(k).is_ok() || env::var(format!("{k}_FILE")).is_ok());
+        let api_key = match env::var(UPSTREAM_API_KEY_ENV).ok().filter(|v| !v.trim().is_empty()) {
+            Some(var) => Some(secret_var(var.trim())?.with_context(|| {
+                format!("{UPSTREAM_API_KEY_ENV}: environment variable {} is not set", var.trim())
+            })?),
+            None => api_key_var.map(secret_var).transpose()?.flatten(),
+        };
+        let extra_keys = secret_var(UPSTREAM_API_KEYS)?.unwrap_or_default();
+        let keys = KeyPool::new(
+            api_key
+                .iter()
+                .map(String::as_str)
+                .chain(keypool::split_keys(&extra_keys)),
+        );
+        let transport = Transport {
+            ca_bundle: env::var(UPSTREAM_CA_BUNDLE).ok().map(PathBuf::from),
+            client_cert: env::var(UPSTREAM_CLIENT_CERT).ok().map(PathBuf::from),
+            client_key: env::var(UPSTREAM_CLIENT_KEY).ok().map(PathBuf::from),
+            proxy: secret_var(UPSTREAM_PROXY)?,
+            http_version: env::var(UPSTREAM_HTTP_VERSION)
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| HttpVersion::parse(&v))
+                .transpose()
+                .with_context(|| format!("Invalid {UPSTREAM_HTTP_VERSION}"))?,
+            resolve: dns::parse_overrides(env::var(UPSTREAM_RESOLVE).unwrap_or_default().split(','))
+                .with_context(|| format!("Invalid {UPSTREAM_RESOLVE}"))?,
+            pool: PoolSettings {
+                max_idle_per_host: Self::env_parse(UPSTREAM_POOL_MAX_IDLE).unwrap_or(DEFAULT_POOL_MAX_IDLE),
+                // 0 keeps idle connections until the upstream closes them.
+                idle_timeout: match Self::env_parse::<u64>(UPSTREAM_POOL_IDLE_TIMEOUT_SECS) {
+                    Some(0) => None,
+                    Some(secs) => Some(Duration::from_secs(secs)),
+                    None => Some(DEFAULT_POOL_IDLE_TIMEOUT),
+                },
+                tcp_nodelay: Self::env_bool_on(UPSTREAM_TCP_NODELAY),
+                tcp_keepalive: Self::env_parse(UPSTREAM_TCP_KEEPALIVE_SECS)
+                    .filter(|&secs: &u64| secs > 0)
+                    .map(Duration::from_secs),
+                tcp_keepalive_interval: Self::env_parse(UPSTREAM_TCP_KEEPALIVE_INTERVAL_SECS)
+                    .filter(|&secs: &u64| secs > 0)
+                    .map(Duration::from_secs),
+                tcp_keepalive_retries: Self::env_parse(UPSTREAM_TCP_KEEPALIVE_RETRIES).filter(|&n: &u32| n > 0),
+                http2_keepalive: Self::env_parse(UPSTREAM_HTTP2_KEEPALIVE_SECS)
+                    .filter(|&secs: &u64| secs > 0)
+                    .map(Duration::from_secs),
+                dns_cache_ttl: Self::env_parse(UPSTREAM_DNS_CACHE_TTL_SECS)
+                    .filter(|&secs: &u64| secs > 0)
+                    .map(Duration::from_secs),
+            },
+        };
+        let client = transport.client()?;
+        let headers = match secret_var(UPSTREAM_HEADERS)? {
+            Some(raw) => header_map(raw.lines().filter(|l| !l.trim().is_empty()).map(|line| {
+                line.split_once(':')
+                    .with_context(|| format!("expected 'Name: value', got '{}'", line.trim()))
+            }))
+            .with_context(|| format!("Invalid {UPSTREAM_HEADERS}"))?,
+            None => HeaderMap::new(),
+        };
+        let mut upstream = Upstream::new(base_url, Arc::new(keys), transport, client)?.with_headers(headers);
+        if let Some(path) = &path {
+            upstream = upstream.with_path(path).with_context(|| format!("Invalid {UPSTREAM_PATH}"))?;
+        }
+        let upstream = Arc::new(upstream);
+        let upstream_key_files = api_key_var
+            .into_iter()
+            .chain([UPSTREAM_API_KEYS])
+            .filter_map(Self::secret_file)
+            .collect();
+        let warmup = Self::env_parse(UPSTREAM_WARM_CONNECTIONS)
+            .filter(|&n: &usize| n > 0)
+            .map(|connections| WarmupSettings {
+                connections,
+                // Two thirds of the pool's idle timeout refreshes connections before they expire.
+                interval: Self::env_parse(UPSTREAM_WARM_INTERVAL_SECS)
+                    .filter(|&secs: &u64| secs > 0)
+                    .map(Duration::from_secs)
+                    .or(upstream.transport.pool.idle_timeout.map(|t| t * 2 / 3))
+                    .unwrap_or(DEFAULT_WARM_INTERVAL),
+            });
 
+        let routes = match &config_file {
+            Some(file) => file.routes(&upstream)?,
+            None => Vec::new(),
+        };
+        let config_watch = Self::env_bool_on(CONFIG_WATCH);
+        let strict_config = Self::env_bool(STRICT_CONFIG);
+        let forward_headers = env::var(FORWARD_HEADERS)
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim().to_ascii_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        let model_params: Vec<ModelParams> = config_file.as_ref().map(|f| f.model_params.clone()).unwrap_or_default();
+        let rewrite = match &config_file {
+            Some(file) => Rewriter::new(file.rewrite_rules()?),
+            None => Rewriter::default(),
+        };
+        let summary = env::var(SUMMARY_MODEL)
+            .ok()
+            .filter(|m| !m.trim().is_empty())
+            .map(|model| SummarySettings {
+                model: model.trim().to_string(),
+                max_tokens: Self::env_parse(SUMMARY_MAX_TOKENS).unwrap_or(DEFAULT_SUMMARY_MAX_TOKENS),
+            });
+        let system_prompt = SystemPromptEdits {
+            prefix: env::var(SYSTEM_PROMPT_PREFIX).ok().filter(|p| !p.trim().is_empty()),
+            suffix: env::var(SYSTEM_PROMPT_SUFFIX).ok().filter(|s| !s.trim().is_empty()),
+        };
+        if let Some(params) = model_params.iter().find(|p| p.summarize) {
+            anyhow::ensure!(
+                summary.is_some(),
+                "[model_params.\"{}\"] summarize needs {SUMMARY_MODEL}, the model that writes the summaries",
+                params.pattern
+            );
+        }
         let reasoning_model = env::var(REASONING_MODEL).ok();
         let completion_model = env::var(COMPLETION_MODEL).ok();
         let debug = Self::env_bool(DEBUG);
+        let log_level = env::var(LOG_LEVEL)
+            .ok()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| {
+                l.trim()
+                    .parse::<tracing::Level>()
+                    .map_err(|_| anyhow::anyhow!("{LOG_LEVEL} must be error, warn, info, debug or trace, got '{}'", l.trim()))
+            })
+            .transpose()?;
         let verbose = Self::env_bool(VERBOSE);
+        let sample_rate = match env::var(VERBOSE_SAMPLE_RATE) {
+            Ok(raw) => {
+                let rate: f64 = raw
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("{VERBOSE_SAMPLE_RATE} must be a fraction, got '{}'", raw.trim()))?;
+                anyhow::ensure!(
+                    (0.0..=1.0).contains(&rate),
+                    "{VERBOSE_SAMPLE_RATE} must be between 0 and 1, got {rate}"
+                );
+                rate
+            }
+            Err(_) => 0.0,
+        };
+        let debug_header = Self::env_bool(VERBOSE_DEBUG_HEADER);
+        let verbose_sampling = (sample_rate > 0.0 || debug_header).then_some(VerboseSampling {
+            rate: sample_rate,
+            debug_header,
+        });
+        let log_content = ContentLogging::parse(
+            &env::var(LOG_CONTENT).unwrap_or_default(),
+            Self::env_parse(LOG_CONTENT_MAX_CHARS).unwrap_or(DEFAULT_TRUNCATE_CHARS),
+        )?;
+        let token_cache_size = Self::env_parse(TOKEN_CACHE_SIZE).unwrap_or(DEFAULT_TOKEN_CACHE_SIZE);
+        let response_cache_ttl_secs = Self::env_parse(RESPONSE_CACHE_TTL).unwrap_or(0);
+        let response_cache_size =
+            Self::env_parse(RESPONSE_CACHE_SIZE).unwrap_or(DEFAULT_RESPONSE_CACHE_SIZE);
+
+        let admin_token = secret_var(ADMIN_TOKEN)?;
+
+        let mut client_keys = ClientKeys::default();
+        if let Some(list) = secret_var(CLIENT_API_KEYS)? {
+            client_keys.add_list(&list);
+        }
+        if let Ok(path) = env::var(CLIENT_KEYS_PATH) {
+            client_keys.add_file(path.trim().as_ref(), &upstream)?;
+        }
+
+        let default_quota = Quota {
+            requests_per_minute: Self::env_parse(CLIENT_QUOTA_RPM),
+            tokens_per_day: Self::env_parse(CLIENT_QUOTA_TOKENS_PER_DAY),
+            concurrent_streams: Self::env_parse(CLIENT_QUOTA_CONCURRENT_STREAMS),
+            daily_budget_usd: Self::env_parse(CLIENT_BUDGET_DAILY_USD),
+            monthly_budget_usd: Self::env_parse(CLIENT_BUDGET_MONTHLY_USD),
+        };
+        let budget_schedule = BudgetSchedule {
+            hour: Self::env_parse(BUDGET_RESET_HOUR).unwrap_or(0),
+            day_of_month: Self::env_parse(BUDGET_RESET_DAY).unwrap_or(1),
+        };
+        anyhow::ensure!(budget_schedule.hour < 24, "BUDGET_RESET_HOUR must be 0-23");
+        anyhow::ensure!(
+            (1..=28).contains(&budget_schedule.day_of_month),
+            "BUDGET_RESET_DAY must be 1-28"
+        );
+
+        let jwt = env::var(JWT_JWKS_URL)
+            .ok()
+            .filter(|u| !u.trim().is_empty())
+            .map(|jwks_url| JwtSettings {
+                jwks_url: jwks_url.trim().to_string(),
+                issuer: env::var(JWT_ISSUER).ok().filter(|v| !v.is_empty()),
+                audiences: env::var(JWT_AUDIENCE)
+                    .map(|v| v.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect())
+                    .unwrap_or_default(),
+                name_claim: env::var(JWT_NAME_CLAIM).unwrap_or_else(|_| "sub".to_string()),
+                tags_claim: env::var(JWT_TAGS_CLAIM).ok().filter(|v| !v.is_empty()),
+            });
+
+        let secret_source = |key: &str| -> Result<Option<SecretSource>> {
+            env::var(key)
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| SecretSource::parse(&v).with_context(|| format!("Invalid {key}")))
+                .transpose()
+        };
+        let vault = match (env::var(VAULT_ADDR).ok(), secret_var(VAULT_TOKEN)?) {
+            (Some(addr), Some(token)) if !addr.trim().is_empty() => Some(VaultSettings {
+                addr: addr.trim().to_string(),
+                token,
+                namespace: env::var(VAULT_NAMESPACE).ok().filter(|v| !v.is_empty()),
+            }),
+            _ => None,
+        };
+        let secrets = SecretSettings {
+            upstream_api_key: secret_source(UPSTREAM_API_KEY_SECRET)?,
+            client_keys: secret_source(CLIENT_API_KEYS_SECRET)?,
+            vault,
+            refresh_secs: Self::env_parse(SECRET_REFRESH_INTERVAL)
+                .unwrap_or(DEFAULT_SECRET_REFRESH_SECS),
+        };
+
+        let tls = match (env::var(TLS_CERT).ok(), env::var(TLS_KEY).ok()) {
+            (Some(cert), Some(key)) => Some(TlsSettings {
+                cert: PathBuf::from(cert.trim()),
+                key: PathBuf::from(key.trim()),
+                client_ca: env::var(TLS_CLIENT_CA)
+                    .ok()
+                    .filter(|p| !p.trim().is_empty())
+                    .map(|p| PathBuf::from(p.trim())),
+                client_auth_optional: match env::var(TLS_CLIENT_AUTH).unwrap_or_default().trim() {
+                    "" | "required" => false,
+                    "optional" => true,
+                    other => anyhow::bail!("TLS_CLIENT_AUTH must be required or optional, got '{other}'"),
+                },
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("TLS_CERT and TLS_KEY must be set together"),
+        };
+
+        let split_list = |v: String| -> Vec<String> {
+            v.split(',').map(|d| d.trim().to_string()).filter(|d| !d.is_empty()).collect()
+        };
+        let acme_domains = env::var(ACME_DOMAINS).map(split_list).unwrap_or_default();
+        let acme = if acme_domains.is_empty() {
+            None
+        } else {
+            anyhow::ensure!(tls.is_none(), "ACME_DOMAINS cannot be combined with TLS_CERT/TLS_KEY");
+            let challenge = match env::var(ACME_CHALLENGE).unwrap_or_default().trim() {
+                "" | "tls-alpn-01" => AcmeChallenge::TlsAlpn01,
+                "http-01" => AcmeChallenge::Http01,
+                other => anyhow::bail!("ACME_CHALLENGE must be tls-alpn-01 or http-01, got '{other}'"),
+            };
+            Some(AcmeSettings {
+                domains: acme_domains,
+                contacts: env::var(ACME_EMAIL).map(split_list).unwrap_or_default(),
+                cache_dir: env::var(ACME_CACHE_DIR)
+                    .map(|d| PathBuf::from(d.trim()))
+                    .unwrap_or_else(|_| PathBuf::from("acme-cache")),
+                staging: Self::env_bool(ACME_STAGING),
+                challenge,
+                http_port: Self::env_parse(ACME_HTTP_PORT).unwrap_or(80),
+            })
+        };
+
+        let listeners = match &config_file {
+            Some(file) => file.listeners()?,
+            None => Vec::new(),
+        };
+        if !listeners.is_empty() {
+            anyhow::ensure!(acme.is_none(), "ACME_DOMAINS cannot be combined with [[listeners]]");
+            anyhow::ensure!(
+                tls.is_none(),
+                "TLS_CERT/TLS_KEY cannot be combined with [[listeners]]; set tls_cert and tls_key per listener"
+            );
+        }
+        let sockets = SocketSettings {
+            nodelay: Self::env_bool_on(TCP_NODELAY),
+            keepalive: Self::env_parse(TCP_KEEPALIVE_SECS)
+                .filter(|&secs: &u64| secs > 0)
+                .map(Duration::from_secs),
+            keepalive_interval: Self::env_parse(TCP_KEEPALIVE_INTERVAL_SECS)
+                .filter(|&secs: &u64| secs > 0)
+                .map(Duration::from_secs),
+            keepalive_retries: Self::env_parse(TCP_KEEPALIVE_RETRIES).filter(|&n: &u32| n > 0),
+            backlog: Self::env_parse(LISTEN_BACKLOG)
+                .filter(|&n: &u32| n > 0)
+                .unwrap_or(DEFAULT_LISTEN_BACKLOG),
+        };
+        let runtime = Self::runtime_from_env()?;
+
+        let cors = match env::var(CORS_ALLOWED_ORIGINS).ok().filter(|o| !o.trim().is_empty()) {
+            Some(origins) => Some(
+                CorsSettings::parse(
+                    &origins,
+                    &env::var(CORS_ALLOWED_HEADERS).unwrap_or_else(|_| cors::DEFAULT_HEADERS.to_string()),
+                    &env::var(CORS_ALLOWED_METHODS).unwrap_or_else(|_| cors::DEFAULT_METHODS.to_string()),
+                    Self::env_parse(CORS_MAX_AGE),
+                )
+                .context("Invalid CORS settings")?,
+            ),
+            None => None,
+        };
+
+        let ip_list = |key: &str| -> Result<_> {
+            IpFilter::parse_list(&env::var(key).unwrap_or_default()).with_context(|| format!("Invalid {key}"))
+        };
+        let ip_filter = IpFilter {
+            allow: ip_list(IP_ALLOWLIST)?,
+            deny: ip_list(IP_DENYLIST)?,
+            trusted_proxies: ip_list(TRUSTED_PROXIES)?,
+        };
 
-        let chat_completions_url = format!("{}/v1/chat/completions", base_url);
+        let rate_limits = RateLimitSettings {
+            per_ip: RateLimit {
+                requests_per_minute: Self::env_parse(RATE_LIMIT_IP_RPM).filter(|&n| n > 0),
+                burst: Self::env_parse(RATE_LIMIT_IP_BURST),
+                tokens_per_minute: Self::env_parse(RATE_LIMIT_IP_TPM).filter(|&n| n > 0),
+            },
+            per_key: RateLimit {
+                requests_per_minute: Self::env_parse(RATE_LIMIT_KEY_RPM).filter(|&n| n > 0),
+                burst: Self::env_parse(RATE_LIMIT_KEY_BURST),
+                tokens_per_minute: Self::env_parse(RATE_LIMIT_KEY_TPM).filter(|&n| n > 0),
+            },
+            redis_url: secret_var(REDIS_URL)?,
+            redis_prefix: env::var(RATE_LIMIT_REDIS_PREFIX)
+                .unwrap_or_else(|_| "anthropic-proxy:ratelimit:".to_string()),
+        };
+
+        let limits = RequestLimits {
+            max_body_bytes: Self::env_parse(MAX_REQUEST_BYTES).unwrap_or(DEFAULT_MAX_REQUEST_BYTES),
+            max_messages: Self::env_parse(MAX_MESSAGES),
+            max_images: Self::env_parse(MAX_IMAGES),
+            context_window_guard: Self::env_bool_on(CONTEXT_WINDOW_GUARD),
+        };
+        let stream_tool_input = match env::var(STREAM_TOOL_INPUT).unwrap_or_default().trim() {
+            "" | "pass" => ToolInputMode::Pass,
+            "repair" => ToolInputMode::Repair,
+            "error" => ToolInputMode::Error,
+            other => anyhow::bail!("STREAM_TOOL_INPUT must be pass, repair or error, got '{other}'"),
+        };
+        let stream_coalesce = Self::env_parse(STREAM_COALESCE_MS)
+            .filter(|&ms: &u64| ms > 0)
+            .map(|ms| CoalesceSettings {
+                window: Duration::from_millis(ms),
+                max_bytes: Self::env_parse(STREAM_COALESCE_BYTES)
+                    .filter(|&n: &usize| n > 0)
+                    .unwrap_or(DEFAULT_COALESCE_BYTES),
+            });
+
+        let signing = secret_var(REQUEST_SIGNING_SECRET)?.map(|raw| SigningSettings {
+            secrets: raw.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+            tolerance_secs: Self::env_parse(REQUEST_SIGNATURE_TOLERANCE).unwrap_or(DEFAULT_TOLERANCE_SECS),
+        });
+
+        let moderation = match env::var(MODERATION_URL).ok().filter(|u| !u.trim().is_empty()) {
+            None => None,
+            Some(url) => {
+                reqwest::Url::parse(url.trim()).with_context(|| format!("Invalid MODERATION_URL '{url}'"))?;
+                let action = match env::var(MODERATION_ACTION).unwrap_or_default().trim() {
+                    "" | "block" => ModerationAction::Block,
+                    "flag" => ModerationAction::Flag,
+                    other => anyhow::bail!("MODERATION_ACTION must be block or flag, got '{other}'"),
+                };
+                Some(ModerationSettings {
+                    url: url.trim().to_string(),
+                    api_key: secret_var(MODERATION_API_KEY)?,
+                    model: env::var(MODERATION_MODEL).ok().filter(|m| !m.trim().is_empty()),
+                    threshold: Self::env_parse(MODERATION_THRESHOLD),
+                    action,
+                    fail_closed: Self::env_bool(MODERATION_FAIL_CLOSED),
+                })
+            }
+        };
+
+        let pii = PiiSettings::parse(&env::var(PII_SCRUB).unwrap_or_default(), Self::env_bool(PII_RESTORE))?;
+
+        let otel = env::var(OTEL_EXPORTER_OTLP_TRACES_ENDPOINT)
+            .or_else(|_| env::var(OTEL_EXPORTER_OTLP_ENDPOINT))
+            .ok()
+            .filter(|e| !e.trim().is_empty())
+            .map(|endpoint| OtelSettings {
+                endpoint: endpoint.trim().to_string(),
+                service_name: env::var(OTEL_SERVICE_NAME)
+                    .ok()
+                    .filter(|n| !n.trim().is_empty())
+                    .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string()),
+            });
+
+        let statsd = StatsdSettings::parse(
+            &env::var(STATSD_ADDR).unwrap_or_default(),
+            &env::var(STATSD_FORMAT).unwrap_or_default(),
+            &env::var(STATSD_PREFIX).unwrap_or_default(),
+            &env::var(STATSD_TAGS).unwrap_or_default(),
+        )?;
+
+        let alert_urls: Vec<String> = secret_var(ALERT_WEBHOOK_URLS)?
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+            .map(str::to_string)
+            .collect();
+        let alerts = if alert_urls.is_empty() {
+            None
+        } else {
+            // 0 turns the error-rate alert off; the other alerts stay on.
+            let error_rate = match env::var(ALERT_ERROR_RATE) {
+                Ok(raw) => {
+                    let rate: f64 = raw
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("{ALERT_ERROR_RATE} must be a fraction, got '{}'", raw.trim()))?;
+                    anyhow::ensure!(
+                        (0.0..=1.0).contains(&rate),
+                        "{ALERT_ERROR_RATE} must be between 0 and 1, got {rate}"
+                    );
+                    Some(rate).filter(|r| *r > 0.0)
+                }
+                Err(_) => Some(DEFAULT_ALERT_ERROR_RATE),
+            };
+            Some(AlertSettings {
+                webhook_urls: alert_urls,
+                error_rate,
+                error_window: Duration::from_secs(
+                    Self::env_parse(ALERT_ERROR_WINDOW_SECS).unwrap_or(DEFAULT_ALERT_ERROR_WINDOW_SECS),
+                ),
+                min_requests: Self::env_parse(ALERT_MIN_REQUESTS).unwrap_or(DEFAULT_ALERT_MIN_REQUESTS),
+                cooldown: Duration::from_secs(Self::env_parse(ALERT_COOLDOWN_SECS).unwrap_or(DEFAULT_ALERT_COOLDOWN_SECS)),
+            })
+        };
+        let events = Self::event_settings()?;
+
+        let access_log = AccessLogSettings::parse(
+            &env::var(ACCESS_LOG).unwrap_or_default(),
+            &env::var(ACCESS_LOG_FORMAT).unwrap_or_default(),
+        )?;
+
+        let logging = LogSettings {
+            stdout: env::var(LOG_STDOUT).map_or(true, |_| Self::env_bool(LOG_STDOUT)),
+            file: env::var(LOG_FILE)
+                .ok()
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| PathBuf::from(p.trim())),
+            file_level: env::var(LOG_FILE_LEVEL)
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            rotation: RotationSettings {
+                interval: Rotation::parse(&env::var(LOG_ROTATION).unwrap_or_default())?,
+                max_bytes: Self::env_parse::<f64>(LOG_MAX_SIZE_MB)
+                    .filter(|mb| *mb > 0.0)
+                    .map(|mb| (mb * 1024.0 * 1024.0) as u64),
+                max_files: Self::env_parse(LOG_MAX_FILES).unwrap_or(DEFAULT_MAX_LOG_FILES),
+                max_age: Self::env_parse::<u64>(LOG_MAX_AGE_DAYS)
+                    .filter(|&days| days > 0)
+                    .map(|days| Duration::from_secs(days * 86_400)),
+            },
+        };
+
+        let usage_db = env::var(USAGE_DB)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .map(|path| UsageDbSettings {
+                path: PathBuf::from(path),
+                retention_days: Self::env_parse(USAGE_RETENTION_DAYS).filter(|&days: &u32| days > 0),
+            });
+        let usage_events = secret_var(USAGE_EVENTS)?
+            .map(|raw| UsageEventTarget::parse(&raw))
+            .transpose()?;
+        let conversations = secret_var(CONVERSATIONS_DB)?
+            .filter(|raw| !raw.trim().is_empty())
+            .map(|raw| -> anyhow::Result<_> {
+                let header = env::var(CONVERSATIONS_SESSION_HEADER)
+                    .ok()
+                    .map(|v| v.trim().to_ascii_lowercase())
+                    .filter(|v| !v.is_empty())
+                    .unwrap_or_else(|| conversations::DEFAULT_SESSION_HEADER.to_string());
+                Ok(ConversationSettings {
+                    target: ConversationTarget::parse(&raw),
+                    session_header: HeaderName::try_from(header.as_str())
+                        .map_err(|_| anyhow::anyhow!("Invalid {}: {}", CONVERSATIONS_SESSION_HEADER, header))?,
+                    retention_days: Self::env_parse(CONVERSATIONS_RETENTION_DAYS).filter(|&days: &u32| days > 0),
+                })
+            })
+            .transpose()?;
+
+        let secs = |key| Self::env_parse::<f64>(key).filter(|s| *s > 0.0).map(Duration::from_secs_f64);
+        let slow_log = SlowLogSettings {
+            duration: secs(SLOW_REQUEST_SECS),
+            ttfb: secs(SLOW_TTFB_SECS),
+            dump_dir: env::var(SLOW_REQUEST_DUMP_DIR)
+                .ok()
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| PathBuf::from(p.trim())),
+        };
+        let slow_log = if slow_log.duration.is_some() || slow_log.ttfb.is_some() {
+            Some(slow_log)
+        } else {
+            anyhow::ensure!(
+                slow_log.dump_dir.is_none(),
+                "SLOW_REQUEST_DUMP_DIR needs SLOW_REQUEST_SECS or SLOW_TTFB_SECS"
+            );
+            None
+        };
+
+        let path_var = |key| env::var(key).ok().filter(|p| !p.trim().is_empty()).map(|p| PathBuf::from(p.trim()));
+        let capture_dir = path_var(CAPTURE_DIR);
+        let wasm_plugins = env::var(WASM_PLUGINS)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        let script = path_var(SCRIPT_PATH);
+        let traffic = match (path_var(TRAFFIC_RECORD), path_var(TRAFFIC_REPLAY)) {
+            (Some(_), Some(_)) => anyhow::bail!("TRAFFIC_RECORD and TRAFFIC_REPLAY cannot both be set"),
+            (Some(path), None) => Some(TrafficMode::Record(path)),
+            (None, Some(path)) => Some(TrafficMode::Replay(path)),
+            (None, None) => None,
+        };
+        let millis = |key| Duration::from_millis(Self::env_parse(key).unwrap_or(0));
+        let mock_upstream = MockSettings::load(
+            path_var(MOCK_UPSTREAM_SCRIPT).as_deref(),
+            millis(MOCK_UPSTREAM_LATENCY_MS),
+            millis(MOCK_UPSTREAM_CHUNK_DELAY_MS),
+        )?;
+        let chaos_rate = match env::var(CHAOS_RATE) {
+            Ok(raw) => {
+                let rate: f64 = raw
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("{CHAOS_RATE} must be a fraction, got '{}'", raw.trim()))?;
+                anyhow::ensure!((0.0..=1.0).contains(&rate), "{CHAOS_RATE} must be between 0 and 1, got {rate}");
+                rate
+            }
+            Err(_) => 0.0,
+        };
+        let chaos_header = Self::env_bool(CHAOS_HEADER);
+        let chaos = if chaos_rate > 0.0 || chaos_header {
+            let faults = match env::var(CHAOS_FAULTS).ok().filter(|v| !v.trim().is_empty()) {
+                Some(raw) => raw
+                    .split(',')
+                    .filter(|name| !name.trim().is_empty())
+                    .map(|name| name.parse().map_err(|e| anyhow::anyhow!("{CHAOS_FAULTS}: {e}")))
+                    .collect::<Result<Vec<Fault>>>()?,
+                None => Fault::ALL.to_vec(),
+            };
+            Some(ChaosSettings {
+                rate: chaos_rate,
+                faults,
+                latency: Duration::from_millis(Self::env_parse(CHAOS_LATENCY_MS).unwrap_or(DEFAULT_CHAOS_LATENCY_MS)),
+                header: chaos_header,
+            })
+        } else {
+            None
+        };
+
+        let pricing = PricingSettings {
+            prices: match env::var(MODEL_PRICES_PATH).ok().filter(|p| !p.trim().is_empty()) {
+                Some(path) => PricingSettings::read_prices(path.trim().as_ref())?,
+                None => Default::default(),
+            },
+            sync_url: PricingSettings::parse_sync(&env::var(PRICE_SYNC).unwrap_or_default())?,
+            sync_interval_secs: Self::env_parse(PRICE_SYNC_INTERVAL).unwrap_or(DEFAULT_PRICE_SYNC_INTERVAL_SECS),
+        };
 
         Ok(Config {
             port,
-            base_url,
-            chat_completions_url,
-            auth_header_value,
+            host,
+            upstream,
+            upstream_key_files,
+            warmup,
             reasoning_model,
             completion_model,
+            routes,
+            model_params,
+            system_prompt,
+            rewrite: Arc::new(rewrite),
+            max_stop_sequences: Self::env_parse(MAX_STOP_SEQUENCES).unwrap_or(DEFAULT_MAX_STOP_SEQUENCES),
+            prompt_vars: sysprompt::prompt_vars(),
+            summary,
+            preset_params: preset.map(Preset::model_params).unwrap_or_default(),
+            preset,
+            forward_headers,
             debug,
+            log_level,
             verbose,
+            verbose_sampling,
+            log_content,
+            token_cache_size,
+            response_cache_ttl_secs,
+            response_cache_size,
+            admin_token,
+            client_keys,
+            default_quota,
+            budget_schedule,
+            jwt,
+            secrets,
+            tls,
+            acme,
+            listeners,
+            sockets,
+            runtime,
+            cors,
+            ip_filter,
+            rate_limits,
+            limits,
+            stream_coalesce,
+            stream_input_estimate: Self::env_bool_on(STREAM_INPUT_TOKENS_ESTIMATE),
+            stream_tool_input,
+            signing,
+            moderation,
+            pii,
+            otel,
+            statsd,
+            alerts,
+            events,
+            access_log,
+            logging,
+            usage_db,
+            usage_events,
+            conversations,
+            slow_log,
+            capture_dir,
+            wasm_plugins,
+            script,
+            traffic,
+            mock_upstream,
+            chaos,
+            pricing,
+            config_file: config_file.map(Arc::new),
+            config_watch,
+            strict_config,
         })
     }
 
-    /// URL for the upstream chat completions endpoint.
-    #[inline]
-    pub fn chat_completions_url(&self) -> &str {
-        &self.chat_completions_url
+    /// Re-reads the config file this configuration came from and builds a fresh
+    /// configuration from it. On failure the file's previous settings are put back.
+    pub fn reload(&self) -> Result<Self> {
+        let previous = self.config_file.as_deref().context("No config file to reload")?;
+        let mut file = ConfigFile::load(&previous.path)?;
+        file.reapply_settings(previous);
+        let attempted = file.clone();
+        Self::from_sources(Some(file)).inspect_err(|_| previous.clone().reapply_settings(&attempted))
+    }
+}
+
+/// Reads a secret from `key`, or from the file named by `<key>_FILE` (Docker/Kubernetes
+/// secrets). Surrounding whitespace is trimmed and empty values count as unset.
+pub fn secret_var(key: &str) -> Result<Option<String>> {
+    let value = match env::var(key) {
+        Ok(value) => value,
+        Err(_) => match env::var(format!("{key}_FILE")) {
+            Ok(path) => std::fs::read_to_string(path.trim())
+                .with_context(|| format!("Failed to read {key}_FILE ({})", path.trim()))?,
+            Err(_) => return Ok(None),
+        },
+    };
+    Ok(Some(value.trim().to_string()).filter(|v| !v.is_empty()))
+}
+
+/// Builds upstream headers from name/value pairs, trimming both.
+pub fn header_map<'a>(pairs: impl IntoIterator<Item = Result<(&'a str, &'a str)>>) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for pair in pairs {
+        let (name, value) = pair?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .with_context(|| format!("Invalid header name '{}'", name.trim()))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .with_context(|| format!("Invalid value for header {name}"))?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// Case-insensitive match of `text` against a pattern where `*` matches any run of characters.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let text = text.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
     }
+    rest.len() >= last.len() && rest.ends_with(last)
 }
@@ -0,0 +1,809 @@
+//! Structured config file: `--config` or PROXY_CONFIG naming a `.toml` file. Plain settings
+//! mirror the environment variables (`port = 8080` sets PORT, `[upstream] base_url = ...` sets
+//! UPSTREAM_BASE_URL), and variables already set in the environment or a .env file win. The
+//! file can also express what variables cannot: `[models]` renames, named
+//! `[upstreams.<name>]` endpoints, `[[routes]]` sending models to them, and
+//! `[model_params."<pattern>"]` request limits per upstream model, `[[listeners]]` serving
+//! on several addresses, and `[[rewrite]]` rules replacing text in messages.
+//!
+//! `[profile.<name>]` sections hold per-environment overrides. The one PROXY_PROFILE (or
+//! `--profile`) names is merged over the rest of the file: tables key by key, any other value
+//! (arrays such as `[[routes]]` included) replaced outright. Other profiles are ignored.
+//!
+//! String values may reference the environment as `${VAR}` or `${VAR:-default}` (the default
+//! also applies when VAR is empty), so secrets can stay out of a checked-in file; `$${` is a
+//! literal `${`.
+
+use crate::config::{env_keys, header_map, secret_var, wildcard_match, Upstream};
+use crate::dns;
+use crate::keypool::{self, KeyPool};
+use crate::listeners::{ListenAddress, Listener, ListenerAuth};
+use crate::rewrite::{self, RewriteRule};
+use crate::tls::TlsSettings;
+use crate::transport::{HttpVersion, Transport};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Where a model goes: a rename, another upstream, or both.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Incoming model pattern; `*` matches any run of characters, case-insensitively.
+    pub pattern: String,
+    /// Upstream model name; the usual model selection applies when unset.
+    pub model: Option<String>,
+    /// Named upstream; the client's or the default upstream when unset.
+    pub upstream: Option<Arc<Upstream>>,
+    /// Name of `upstream` in the file, for logs.
+    pub upstream_name: Option<String>,
+}
+
+impl Route {
+    pub fn matches(&self, model: &str) -> bool {
+        wildcard_match(&self.pattern, model)
+    }
+}
+
+/// `[model_params."<pattern>"]`: request adjustments for upstream models matching `pattern`,
+/// applied after model selection.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelParams {
+    /// Upstream model pattern, matched like route patterns.
+    #[serde(skip)]
+    pub pattern: String,
+    /// Upper bound for `max_tokens`; larger requests are lowered to it.
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// `temperature` for requests that don't set one.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Sent as `stream_options` on streaming requests (e.g. `{ include_usage = true }`).
+    #[serde(default)]
+    pub stream_options: Option<serde_json::Value>,
+    /// Parameters removed before the request is sent.
+    #[serde(default)]
+    pub strip: Vec<Param>,
+    /// Context window in tokens; longer prompts are rejected before they are sent.
+    #[serde(default)]
+    pub context_window: Option<u32>,
+    /// How to shorten the history of a prompt too long for the context window, instead of
+    /// rejecting it.
+    #[serde(default)]
+    pub truncate: Option<Truncation>,
+    /// Messages the `keep_recent` strategy keeps.
+    #[serde(default)]
+    pub keep_recent: Option<usize>,
+    /// Replace the history truncation drops with a summary by SUMMARY_MODEL.
+    #[serde(default)]
+    pub summarize: bool,
+    /// Stop sequences the upstream accepts; the proxy enforces the rest (0: no limit).
+    #[serde(default)]
+    pub max_stop_sequences: Option<usize>,
+    /// Whether the model accepts tools, overriding the built-in capabilities.
+    #[serde(default)]
+    pub supports_tools: Option<bool>,
+    /// Whether the model accepts images.
+    #[serde(default)]
+    pub supports_vision: Option<bool>,
+    /// Whether the model reasons (extended thinking).
+    #[serde(default)]
+    pub supports_reasoning: Option<bool>,
+}
+
+impl ModelParams {
+    pub fn matches(&self, model: &str) -> bool {
+        wildcard_match(&self.pattern, model)
+    }
+}
+
+/// An optional chat completions parameter `[model_params]` can strip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Param {
+    MaxTokens,
+    Temperature,
+    TopP,
+    Stop,
+    Tools,
+    ToolChoice,
+    StreamOptions,
+}
+
+/// A `[model_params]` history truncation strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Truncation {
+    Oldest,
+    MiddleOut,
+    KeepRecent,
+}
+
+impl Truncation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Truncation::Oldest => "oldest",
+            Truncation::MiddleOut => "middle_out",
+            Truncation::KeepRecent => "keep_recent",
+        }
+    }
+}
+
+/// Whether `path` is a structured config file rather than a .env file.
+pub fn is_structured(path: &Path) -> Result<bool> {
+    match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("toml") => Ok(true),
+        Some("yaml" | "yml") => anyhow::bail!(
+            "Config file {}: YAML is not supported, use TOML (.toml)",
+            path.display()
+        ),
+        _ => Ok(false),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawFile {
+    /// Incoming model pattern → upstream model, checked in file order after `routes`.
+    #[serde(default)]
+    models: toml::Table,
+    #[serde(default)]
+    upstreams: BTreeMap<String, UpstreamEntry>,
+    #[serde(default)]
+    routes: Vec<RouteEntry>,
+    /// Upstream model pattern → [`ModelParams`], checked in file order.
+    #[serde(default)]
+    model_params: toml::Table,
+    #[serde(default)]
+    listeners: Vec<ListenerEntry>,
+    #[serde(default)]
+    rewrite: Vec<RewriteEntry>,
+    /// Everything else: settings named like their environment variables.
+    #[serde(flatten)]
+    settings: toml::Table,
+}
+
+/// `[upstreams.<name>]`: an OpenAI-compatible endpoint routes can send models to.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamEntry {
+    base_url: String,
+    #[serde(default)]
+    api_key: Option<String>,
+    /// Rotated like UPSTREAM_API_KEYS.
+    #[serde(default)]
+    api_keys: Vec<String>,
+    /// Name of an environment variable holding the API key; `<name>_FILE` works as well.
+    #[serde(default)]
+    api_key_env: Option<String>,
+    #[serde(default)]
+    ca_bundle: Option<PathBuf>,
+    #[serde(default)]
+    client_cert: Option<PathBuf>,
+    #[serde(default)]
+    client_key: Option<PathBuf>,
+    /// Egress proxy, or `none` to connect directly.
+    #[serde(default)]
+    proxy: Option<String>,
+    /// Static headers for this upstream; the default upstream's are not inherited.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Chat completions path, `/v1/chat/completions` unless set; not inherited either.
+    #[serde(default)]
+    path: Option<String>,
+    /// `auto`, `http1`, `http2` or `http3`, like UPSTREAM_HTTP_VERSION.
+    #[serde(default)]
+    http_version: Option<HttpVersion>,
+    /// `host=ip` entries added to UPSTREAM_RESOLVE's.
+    #[serde(default)]
+    resolve: Vec<String>,
+}
+
+/// `[[routes]]`: models matching `model` go to `upstream`, renamed to `target`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteEntry {
+    model: String,
+    #[serde(default)]
+    upstream: Option<String>,
+    #[serde(default)]
+    target: Option<String>,
+}
+
+/// `[[rewrite]]`: replaces matches of `pattern` in message content.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RewriteEntry {
+    /// For logs and metrics; `rewrite <n>` when unset.
+    #[serde(default)]
+    name: Option<String>,
+    pattern: String,
+    replacement: String,
+    /// `request`, `response` or `both` (the default).
+    #[serde(default)]
+    direction: rewrite::Direction,
+    /// `system`, `user` and/or `assistant`; every role when empty.
+    #[serde(default)]
+    roles: Vec<rewrite::Role>,
+}
+
+/// `[[listeners]]`: an address to serve on, with its own TLS and client auth settings.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerEntry {
+    /// `ip:port` or `unix:/path/to.sock`.
+    address: String,
+    #[serde(default)]
+    tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    tls_key: Option<PathBuf>,
+    #[serde(default)]
+    tls_client_ca: Option<PathBuf>,
+    /// `required` (the default) or `optional`, like TLS_CLIENT_AUTH.
+    #[serde(default)]
+    tls_client_auth: Option<String>,
+    #[serde(default)]
+    auth: ListenerAuth,
+}
+
+impl ListenerEntry {
+    fn build(&self) -> Result<Listener> {
+        let address = ListenAddress::parse(&self.address)?;
+        let tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsSettings {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: self.tls_client_ca.clone(),
+                client_auth_optional: match self.tls_client_auth.as_deref().map(str::trim) {
+                    None | Some("required") => false,
+                    Some("optional") => true,
+                    Some(other) => anyhow::bail!("tls_client_auth must be required or optional, got '{other}'"),
+                },
+            }),
+            (None, None) => {
+                anyhow::ensure!(
+                    self.tls_client_ca.is_none() && self.tls_client_auth.is_none(),
+                    "tls_client_ca and tls_client_auth need tls_cert and tls_key"
+                );
+                None
+            }
+            _ => anyhow::bail!("tls_cert and tls_key must be set together"),
+        };
+        anyhow::ensure!(
+            tls.is_none() || matches!(address, ListenAddress::Tcp(_)),
+            "Unix socket listeners serve plain HTTP; remove the tls_* settings"
+        );
+        Ok(Listener {
+            address,
+            tls,
+            auth: self.auth,
+        })
+    }
+}
+
+/// A parsed config file.
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// The `[profile.<name>]` merged over the base settings.
+    pub profile: Option<String>,
+    /// Environment variable name and value for each plain setting.
+    settings: Vec<(String, String)>,
+    models: Vec<(String, String)>,
+    upstreams: BTreeMap<String, UpstreamEntry>,
+    routes: Vec<RouteEntry>,
+    pub model_params: Vec<ModelParams>,
+    listeners: Vec<ListenerEntry>,
+    rewrite: Vec<RewriteEntry>,
+    /// Settings this file put in the environment, i.e. those the environment did not override.
+    applied: BTreeSet<String>,
+}
+
+/// One difference between two versions of a config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Environment variable of a plain setting, or `models`, `routes`, `model_params`,
+    /// `listeners`, `rewrite` or `upstreams.<name>`.
+    pub setting: String,
+    pub description: String,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut table: toml::Table =
+            toml::from_str(&raw).with_context(|| format!("Invalid config file {}", path.display()))?;
+        // Before interpolation, so variables only other profiles reference need not be set.
+        let profile = select_profile(&mut table).with_context(|| format!("Config file {}", path.display()))?;
+        interpolate_table("", &mut table).with_context(|| format!("Config file {}", path.display()))?;
+        let mut file: RawFile = table
+            .try_into()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        let mut settings = Vec::new();
+        // `[upstream.headers]` keeps its header names, so it is not flattened like other tables.
+        let headers = file
+            .settings
+            .get_mut("upstream")
+            .and_then(toml::Value::as_table_mut)
+            .and_then(|upstream| upstream.remove("headers"));
+        if let Some(headers) = headers {
+            let headers: BTreeMap<String, String> = headers
+                .try_into()
+                .with_context(|| format!("Config file {}: [upstream.headers] must map names to strings", path.display()))?;
+            let lines: Vec<String> = headers.iter().map(|(name, value)| format!("{name}: {value}")).collect();
+            settings.push((env_keys::UPSTREAM_HEADERS.to_string(), lines.join("\n")));
+        }
+        flatten("", &file.settings, &mut settings).with_context(|| format!("Config file {}", path.display()))?;
+        let models = file
+            .models
+            .into_iter()
+            .map(|(pattern, model)| match model {
+                toml::Value::String(model) => Ok((pattern, model)),
+                other => anyhow::bail!("Config file {}: [models] '{pattern}' must map to a model name, got {other}", path.display()),
+            })
+            .collect::<Result<_>>()?;
+        let model_params = file
+            .model_params
+            .into_iter()
+            .map(|(pattern, entry)| {
+                let mut params: ModelParams = entry
+                    .try_into()
+                    .with_context(|| format!("Config file {}: [model_params.\"{pattern}\"]", path.display()))?;
+                anyhow::ensure!(
+                    params.max_tokens != Some(0),
+                    "Config file {}: [model_params.\"{pattern}\"] max_tokens must be positive",
+                    path.display()
+                );
+                anyhow::ensure!(
+                    params.temperature.is_none_or(|t| t >= 0.0),
+                    "Config file {}: [model_params.\"{pattern}\"] temperature must not be negative",
+                    path.display()
+                );
+                params.pattern = pattern;
+                Ok(params)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            profile,
+            settings,
+            models,
+            upstreams: file.upstreams,
+            routes: file.routes,
+            model_params,
+            listeners: file.listeners,
+            rewrite: file.rewrite,
+            applied: BTreeSet::new(),
+        })
+    }
+
+    /// Sets every plain setting not already present in the environment.
+    pub fn apply_settings(&mut self) {
+        for (name, value) in &self.settings {
+            if env::var_os(name).is_none() {
+                env::set_var(name, value);
+                self.applied.insert(name.clone());
+            }
+        }
+    }
+
+    /// Replaces the settings `previous` applied with this file's: values `previous` set are
+    /// overwritten or removed, and the environment still wins for everything else.
+    pub fn reapply_settings(&mut self, previous: &ConfigFile) {
+        for name in &previous.applied {
+            if !self.settings.iter().any(|(n, _)| n == name) {
+                env::remove_var(name);
+            }
+        }
+        for (name, value) in &self.settings {
+            if previous.applied.contains(name) || env::var_os(name).is_none() {
+                env::set_var(name, value);
+                self.applied.insert(name.clone());
+            }
+        }
+    }
+
+    /// A plain setting's value, if this file is what set it.
+    pub fn applied_setting(&self, name: &str) -> Option<&str> {
+        self.applied.contains(name).then(|| self.setting(name)).flatten()
+    }
+
+    fn setting(&self, name: &str) -> Option<&str> {
+        self.settings.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// What changed since `previous`. Plain settings the environment overrides are left out,
+    /// and values that look like credentials are not shown.
+    pub fn diff(&self, previous: &ConfigFile) -> Vec<Change> {
+        let mut changes = Vec::new();
+        let mut names: Vec<&str> = previous.applied.iter().chain(&self.applied).map(String::as_str).collect();
+        names.sort_unstable();
+        names.dedup();
+        for name in names {
+            let show = |value: &str| if is_secret(name) { "***".to_string() } else { format!("'{value}'") };
+            let description = match (previous.applied_setting(name), self.applied_setting(name)) {
+                (Some(old), Some(new)) if old == new => continue,
+                (Some(old), Some(new)) => format!("{name}: {} -> {}", show(old), show(new)),
+                (None, Some(new)) => format!("{name}: set to {}", show(new)),
+                (Some(_), None) => format!("{name}: removed"),
+                (None, None) => continue,
+            };
+            changes.push(Change { setting: name.to_string(), description });
+        }
+        for (pattern, model) in &self.models {
+            match previous.models.iter().find(|(p, _)| p == pattern) {
+                Some((_, old)) if old == model => {}
+                Some((_, old)) => changes.push(Change::models(format!("[models] {pattern}: {old} -> {model}"))),
+                None => changes.push(Change::models(format!("[models] {pattern}: added ({model})"))),
+            }
+        }
+        for (pattern, _) in &previous.models {
+            if !self.models.iter().any(|(p, _)| p == pattern) {
+                changes.push(Change::models(format!("[models] {pattern}: removed")));
+            }
+        }
+        if self.routes != previous.routes {
+            changes.push(Change {
+                setting: "routes".to_string(),
+                description: format!("[[routes]]: {} route(s), was {}", self.routes.len(), previous.routes.len()),
+            });
+        }
+        if self.model_params != previous.model_params {
+            changes.push(Change {
+                setting: "model_params".to_string(),
+                description: format!(
+                    "[model_params]: {} pattern(s), was {}",
+                    self.model_params.len(),
+                    previous.model_params.len()
+                ),
+            });
+        }
+        if self.listeners != previous.listeners {
+            changes.push(Change {
+                setting: "listeners".to_string(),
+                description: format!(
+                    "[[listeners]]: {} listener(s), was {}",
+                    self.listeners.len(),
+                    previous.listeners.len()
+                ),
+            });
+        }
+        if self.rewrite != previous.rewrite {
+            changes.push(Change {
+                setting: "rewrite".to_string(),
+                description: format!("[[rewrite]]: {} rule(s), was {}", self.rewrite.len(), previous.rewrite.len()),
+            });
+        }
+        let upstream_names = previous.upstreams.keys().chain(self.upstreams.keys()).collect::<BTreeSet<_>>();
+        for name in upstream_names {
+            let what = match (previous.upstreams.get(name), self.upstreams.get(name)) {
+                (Some(old), Some(new)) if old == new => continue,
+                (Some(_), Some(_)) => "changed",
+                (None, Some(_)) => "added",
+                (Some(_), None) => "removed",
+                (None, None) => continue,
+            };
+            changes.push(Change {
+                setting: format!("upstreams.{name}"),
+                description: format!("[upstreams.{name}]: {what}"),
+            });
+        }
+        changes
+    }
+
+    /// `[[listeners]]`, in file order.
+    pub fn listeners(&self) -> Result<Vec<Listener>> {
+        let mut addresses = BTreeSet::new();
+        self.listeners
+            .iter()
+            .map(|entry| self.build_listener(entry, &mut addresses))
+            .collect()
+    }
+
+    fn build_listener(&self, entry: &ListenerEntry, addresses: &mut BTreeSet<String>) -> Result<Listener> {
+        let listener = entry
+            .build()
+            .with_context(|| format!("Config file {}: listener '{}'", self.path.display(), entry.address))?;
+        anyhow::ensure!(
+            addresses.insert(listener.address.to_string()),
+            "Config file {}: listener '{}' is listed twice",
+            self.path.display(),
+            entry.address
+        );
+        Ok(listener)
+    }
+
+    /// `[[rewrite]]`, in file order.
+    pub fn rewrite_rules(&self) -> Result<Vec<RewriteRule>> {
+        self.rewrite
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| self.build_rewrite_rule(i, entry))
+            .collect()
+    }
+
+    fn build_rewrite_rule(&self, index: usize, entry: &RewriteEntry) -> Result<RewriteRule> {
+        let name = entry.name.clone().unwrap_or_else(|| format!("rewrite {}", index + 1));
+        entry
+            .build(name.clone())
+            .with_context(|| format!("Config file {}: [[rewrite]] '{name}'", self.path.display()))
+    }
+
+    fn build_upstream(&self, name: &str, entry: &UpstreamEntry, default: &Upstream) -> Result<Upstream> {
+        entry
+            .build(default)
+            .with_context(|| format!("Config file {}: upstream '{name}'", self.path.display()))
+    }
+
+    fn check_route(&self, entry: &RouteEntry) -> Result<()> {
+        anyhow::ensure!(
+            entry.upstream.is_some() || entry.target.is_some(),
+            "Config file {}: route for '{}' needs an upstream or a target",
+            self.path.display(),
+            entry.model
+        );
+        if let Some(name) = &entry.upstream {
+            anyhow::ensure!(
+                self.upstreams.contains_key(name),
+                "Config file {}: route for '{}' names unknown upstream '{name}'",
+                self.path.display(),
+                entry.model
+            );
+        }
+        Ok(())
+    }
+
+    /// Every error [`routes`](Self::routes), [`listeners`](Self::listeners) and
+    /// [`rewrite_rules`](Self::rewrite_rules) would stop at the first of, so they can be
+    /// reported together.
+    pub fn problems(&self, default: &Upstream) -> Vec<String> {
+        let upstreams = self
+            .upstreams
+            .iter()
+            .filter_map(|(name, entry)| self.build_upstream(name, entry, default).err());
+        let routes = self.routes.iter().filter_map(|entry| self.check_route(entry).err());
+        let mut addresses = BTreeSet::new();
+        let listeners = self
+            .listeners
+            .iter()
+            .filter_map(|entry| self.build_listener(entry, &mut addresses).err())
+            .collect::<Vec<_>>();
+        let rewrite = self
+            .rewrite
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| self.build_rewrite_rule(i, entry).err());
+        upstreams
+            .chain(routes)
+            .chain(listeners)
+            .chain(rewrite)
+            .map(|e| format!("{e:#}"))
+            .collect()
+    }
+
+    /// `[[routes]]`, then `[models]`, with named upstreams built on `default`'s transport.
+    pub fn routes(&self, default: &Upstream) -> Result<Vec<Route>> {
+        let mut upstreams = BTreeMap::new();
+        for (name, entry) in &self.upstreams {
+            upstreams.insert(name.as_str(), Arc::new(self.build_upstream(name, entry, default)?));
+        }
+        let mut routes = Vec::with_capacity(self.routes.len() + self.models.len());
+        for entry in &self.routes {
+            self.check_route(entry)?;
+            let upstream = entry.upstream.as_deref().map(|name| Arc::clone(&upstreams[name]));
+            routes.push(Route {
+                pattern: entry.model.clone(),
+                model: entry.target.clone(),
+                upstream,
+                upstream_name: entry.upstream.clone(),
+            });
+        }
+        routes.extend(self.models.iter().map(|(pattern, model)| Route {
+            pattern: pattern.clone(),
+            model: Some(model.clone()),
+            upstream: None,
+            upstream_name: None,
+        }));
+        Ok(routes)
+    }
+}
+
+impl Change {
+    fn models(description: String) -> Self {
+        Self { setting: "models".to_string(), description }
+    }
+}
+
+/// Whether a setting holds a credential, judging by its name.
+fn is_secret(name: &str) -> bool {
+    ["KEY", "TOKEN", "SECRET", "PASSWORD", "HEADERS"].iter().any(|word| name.contains(word))
+}
+
+impl RewriteEntry {
+    fn build(&self, name: String) -> Result<RewriteRule> {
+        let pattern = regex::Regex::new(&self.pattern).context("invalid pattern")?;
+        anyhow::ensure!(
+            self.direction != rewrite::Direction::Response
+                || self.roles.is_empty()
+                || self.roles.contains(&rewrite::Role::Assistant),
+            "direction 'response' rewrites the model's output, which is assistant content, but roles leave out assistant"
+        );
+        Ok(RewriteRule {
+            name,
+            pattern,
+            replacement: self.replacement.clone(),
+            direction: self.direction,
+            roles: self.roles.clone(),
+        })
+    }
+}
+
+impl UpstreamEntry {
+    fn build(&self, default: &Upstream) -> Result<Upstream> {
+        let api_key = match &self.api_key_env {
+            Some(var) => Some(secret_var(var)?.with_context(|| format!("environment variable {var} is not set"))?),
+            None => self.api_key.clone(),
+        };
+        let keys = KeyPool::new(
+            api_key
+                .iter()
+                .map(String::as_str)
+                .chain(self.api_keys.iter().flat_map(|k| keypool::split_keys(k))),
+        );
+        let transport = Transport {
+            ca_bundle: self.ca_bundle.clone(),
+            client_cert: self.client_cert.clone(),
+            client_key: self.client_key.clone(),
+            proxy: self.proxy.clone(),
+            http_version: self.http_version,
+            resolve: dns::parse_overrides(self.resolve.iter().map(String::as_str)).context("Invalid resolve")?,
+            ..Transport::default()
+        }
+        .or(&default.transport);
+        let client = if transport == default.transport {
+            default.client.clone()
+        } else {
+            transport.client()?
+        };
+        let headers = header_map(self.headers.iter().map(|(n, v)| Ok((n.as_str(), v.as_str()))))?;
+        let upstream = Upstream::new(&self.base_url, Arc::new(keys), transport, client)?.with_headers(headers);
+        match &self.path {
+            Some(path) => upstream.with_path(path),
+            None => Ok(upstream),
+        }
+    }
+}
+
+/// Expands `${VAR}` references in every string of `table`; `path` names it in errors.
+/// Removes the `[profile.*]` sections and merges the one PROXY_PROFILE names into `table`.
+fn select_profile(table: &mut toml::Table) -> Result<Option<String>> {
+    let profiles = match table.remove("profile") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => anyhow::bail!("'profile' must be a table of [profile.<name>] sections"),
+        None => toml::Table::new(),
+    };
+    let Some(name) = env::var(env_keys::PROXY_PROFILE).ok().map(|p| p.trim().to_string()).filter(|p| !p.is_empty())
+    else {
+        return Ok(None);
+    };
+    match profiles.get(&name) {
+        Some(toml::Value::Table(overrides)) => merge(table, overrides),
+        Some(_) => anyhow::bail!("[profile.{name}] must be a table"),
+        None if profiles.is_empty() => anyhow::bail!("{} is '{name}' but there are no [profile.*] sections", env_keys::PROXY_PROFILE),
+        None => {
+            let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            anyhow::bail!("Unknown profile '{name}'; available: {}", names.join(", "))
+        }
+    }
+    Ok(Some(name))
+}
+
+/// Merges `overrides` into `base`: nested tables key by key, other values replaced.
+fn merge(base: &mut toml::Table, overrides: &toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => merge(base, overrides),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn interpolate_table(path: &str, table: &mut toml::Table) -> Result<()> {
+    for (key, value) in table.iter_mut() {
+        let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+        interpolate_value(&path, value)?;
+    }
+    Ok(())
+}
+
+fn interpolate_value(path: &str, value: &mut toml::Value) -> Result<()> {
+    match value {
+        toml::Value::String(s) => *s = interpolate(s).with_context(|| format!("in {path}"))?,
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate_value(&format!("{path}[{i}]"), item)?;
+            }
+        }
+        toml::Value::Table(table) => interpolate_table(path, table)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `${VAR}` is VAR's value and must be set; `${VAR:-default}` falls back when VAR is unset or
+/// empty. `$${` stands for a literal `${`.
+fn interpolate(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = after.find('}').with_context(|| format!("unterminated '${{' in '{s}'"))?;
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        anyhow::ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "invalid variable reference '${{{}}}'",
+            &after[..end]
+        );
+        match (env::var(name).ok().filter(|v| !v.is_empty() || default.is_none()), default) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => anyhow::bail!("environment variable {name} is not set"),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Flattens plain settings to environment variable names: table path and key joined with
+/// `_` and upper-cased. Arrays become comma-separated lists.
+fn flatten(prefix: &str, table: &toml::Table, out: &mut Vec<(String, String)>) -> Result<()> {
+    for (key, value) in table {
+        let key = key.to_ascii_uppercase().replace('-', "_");
+        let name = if prefix.is_empty() { key } else { format!("{prefix}_{key}") };
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten(&name, table, out)?;
+                continue;
+            }
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| scalar(item).with_context(|| format!("{name} must be a list of plain values")))
+                .collect::<Result<Vec<_>>>()?
+                .join(","),
+            other => scalar(other).with_context(|| format!("{name} must be a plain value"))?,
+        };
+        out.push((name, value));
+    }
+    Ok(())
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(d) => Some(d.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => None,
+    }
+}
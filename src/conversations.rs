@@ -0,0 +1,576 @@
+//! Conversation persistence: with CONVERSATIONS_DB set, every `/v1/messages` exchange whose
+//! request carries a session header (CONVERSATIONS_SESSION_HEADER, `x-session-id` by default)
+//! is stored with the request as the client sent it and the response it received, streamed
+//! ones assembled into a single message. `GET /conversations/{id}` returns a session's
+//! exchanges in order, as an audit trail of what agents said and did.
+//!
+//! CONVERSATIONS_DB is a SQLite file (`sqlite` feature) or a `postgres://` URL (`postgres`
+//! feature). Exchanges are written by a background task, so a slow database never holds up a
+//! response; when its queue is full they are dropped and counted in
+//! `proxy_conversation_exchanges_total{result="dropped"}`. CONVERSATIONS_RETENTION_DAYS
+//! deletes older exchanges.
+
+use crate::capture::Tee;
+use crate::metrics;
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderName};
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+pub const DEFAULT_SESSION_HEADER: &str = "x-session-id";
+/// Longer session ids are ignored rather than stored.
+const MAX_SESSION_LEN: usize = 256;
+/// Exchanges waiting to be written; further exchanges are dropped.
+const QUEUE_CAPACITY: usize = 1000;
+/// How often the writer deletes exchanges past the retention period.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+const DAY_MS: i64 = 86_400_000;
+
+/// Where conversations are stored (CONVERSATIONS_DB).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversationTarget {
+    Sqlite(PathBuf),
+    Postgres(String),
+}
+
+impl ConversationTarget {
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.trim();
+        if raw.starts_with("postgres://") || raw.starts_with("postgresql://") {
+            Self::Postgres(raw.to_string())
+        } else {
+            Self::Sqlite(PathBuf::from(raw.strip_prefix("sqlite://").unwrap_or(raw)))
+        }
+    }
+}
+
+impl std::fmt::Display for ConversationTarget {
+    /// Without credentials, for logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sqlite(path) => write!(f, "SQLite {}", path.display()),
+            Self::Postgres(url) => {
+                let host = url.rsplit_once('@').map_or(url.as_str(), |(_, host)| host);
+                write!(f, "Postgres {}", host.trim_start_matches("postgres://").trim_start_matches("postgresql://"))
+            }
+        }
+    }
+}
+
+/// CONVERSATIONS_DB / CONVERSATIONS_SESSION_HEADER / CONVERSATIONS_RETENTION_DAYS.
+#[derive(Debug, Clone)]
+pub struct ConversationSettings {
+    pub target: ConversationTarget,
+    pub session_header: HeaderName,
+    /// Exchanges older than this many days are deleted; `None` keeps everything.
+    pub retention_days: Option<u32>,
+}
+
+/// One stored request and its response.
+#[derive(Debug, Clone, Serialize)]
+pub struct Exchange {
+    #[serde(skip)]
+    #[cfg_attr(not(any(feature = "sqlite", feature = "postgres")), allow(dead_code))]
+    pub session: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub model: String,
+    pub stream: bool,
+    pub status: u16,
+    /// False when the client went away before the response ended.
+    pub complete: bool,
+    pub request: Value,
+    pub response: Value,
+}
+
+/// A connected database.
+trait Backend: Send + Sync {
+    fn insert<'a>(&'a self, batch: &'a [Exchange]) -> BoxFuture<'a, anyhow::Result<()>>;
+    /// A session's exchanges, oldest first; only `client`'s when given.
+    fn load<'a>(&'a self, session: &'a str, client: Option<&'a str>) -> BoxFuture<'a, anyhow::Result<Vec<Exchange>>>;
+    /// Deletes exchanges older than `cutoff_ms`; returns how many.
+    fn prune(&self, cutoff_ms: i64) -> BoxFuture<'_, anyhow::Result<u64>>;
+}
+
+/// Stores exchanges in the background and reads them back for `/conversations/{id}`.
+pub struct ConversationStore {
+    backend: Arc<dyn Backend>,
+    exchanges: mpsc::Sender<Exchange>,
+    session_header: HeaderName,
+}
+
+impl ConversationStore {
+    /// Opens the database, creating its table if needed, and starts the writer.
+    pub async fn open(settings: &ConversationSettings) -> anyhow::Result<Self> {
+        let backend: Arc<dyn Backend> = match &settings.target {
+            ConversationTarget::Sqlite(path) => sqlite::open(path)?,
+            ConversationTarget::Postgres(url) => postgres::connect(url).await?,
+        };
+        let (exchanges, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let retention_ms = settings.retention_days.map(|days| i64::from(days) * DAY_MS);
+        tokio::spawn(write(Arc::clone(&backend), rx, retention_ms));
+        Ok(Self {
+            backend,
+            exchanges,
+            session_header: settings.session_header.clone(),
+        })
+    }
+
+    /// Starts recording a request sent with a session header; `None` without one.
+    pub fn start<T: Serialize>(
+        self: &Arc<Self>,
+        headers: &HeaderMap,
+        client: Option<&str>,
+        model: &str,
+        stream: bool,
+        request: &T,
+    ) -> Option<Turn> {
+        let session = headers
+            .get(&self.session_header)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|s| !s.is_empty() && s.len() <= MAX_SESSION_LEN)?;
+        Some(Turn {
+            store: Arc::clone(self),
+            exchange: Exchange {
+                session: session.to_string(),
+                timestamp: Utc::now(),
+                client: client.map(str::to_string),
+                model: model.to_string(),
+                stream,
+                status: 0,
+                complete: false,
+                request: serde_json::to_value(request).ok()?,
+                response: Value::Null,
+            },
+        })
+    }
+
+    pub async fn load(&self, session: &str, client: Option<&str>) -> anyhow::Result<Vec<Exchange>> {
+        self.backend.load(session, client).await
+    }
+
+    fn queue(&self, exchange: Exchange) {
+        if self.exchanges.try_send(exchange).is_err() {
+            metrics::increment("proxy_conversation_exchanges_total", &[("result", "dropped")], 1);
+        }
+    }
+}
+
+/// A request being recorded, waiting for its response.
+pub struct Turn {
+    store: Arc<ConversationStore>,
+    exchange: Exchange,
+}
+
+impl Turn {
+    /// Passes `response` through, storing the exchange once its body has been sent.
+    pub fn finish(self, response: Response) -> Response {
+        let Turn { store, mut exchange } = self;
+        exchange.status = response.status().as_u16();
+        let (parts, body) = response.into_parts();
+        let body = Tee::new(body.into_data_stream(), move |bytes, finished| {
+            exchange.complete = finished;
+            exchange.response = if exchange.stream {
+                assemble_stream(&bytes)
+            } else {
+                serde_json::from_slice(&bytes).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into()))
+            };
+            store.queue(exchange);
+        });
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+}
+
+/// [`Turn::finish`] when the request is being recorded; `response` unchanged otherwise.
+pub fn finish(turn: Option<Turn>, response: Response) -> Response {
+    match turn {
+        Some(turn) => turn.finish(response),
+        None => response,
+    }
+}
+
+async fn write(backend: Arc<dyn Backend>, mut rx: mpsc::Receiver<Exchange>, retention_ms: Option<i64>) {
+    let mut pruned: Option<Instant> = None;
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        while let Ok(exchange) = rx.try_recv() {
+            batch.push(exchange);
+        }
+        let n = batch.len() as u64;
+        match backend.insert(&batch).await {
+            Ok(()) => metrics::increment("proxy_conversation_exchanges_total", &[("result", "stored")], n),
+            Err(e) => {
+                tracing::error!("Failed to store {} conversation exchange(s): {:#}", n, e);
+                metrics::increment("proxy_conversation_exchanges_total", &[("result", "failed")], n);
+            }
+        }
+        if let Some(retention_ms) = retention_ms {
+            if pruned.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                pruned = Some(Instant::now());
+                match backend.prune(Utc::now().timestamp_millis() - retention_ms).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Pruned {} conversation exchange(s) past retention", n),
+                    Err(e) => tracing::error!("Failed to prune conversation exchanges: {:#}", e),
+                }
+            }
+        }
+    }
+}
+
+/// The message an Anthropic `/v1/messages` event stream adds up to.
+fn assemble_stream(body: &[u8]) -> Value {
+    let mut message = json!({});
+    let mut blocks: Vec<Value> = Vec::new();
+    let mut inputs: Vec<String> = Vec::new();
+    for line in body.split(|&b| b == b'\n') {
+        let Some(data) = line.strip_prefix(b"data: ") else {
+            continue;
+        };
+        let Ok(event) = serde_json::from_slice::<Value>(data) else {
+            continue;
+        };
+        let index = event["index"].as_u64().map(|i| i as usize);
+        match event["type"].as_str() {
+            Some("message_start") => message = event["message"].clone(),
+            Some("content_block_start") => {
+                if let Some(index) = index {
+                    if blocks.len() <= index {
+                        blocks.resize(index + 1, Value::Null);
+                        inputs.resize(index + 1, String::new());
+                    }
+                    blocks[index] = event["content_block"].clone();
+                }
+            }
+            Some("content_block_delta") => {
+                let Some(index) = index.filter(|&i| i < blocks.len()) else {
+                    continue;
+                };
+                let delta = &event["delta"];
+                match delta["type"].as_str() {
+                    Some("text_delta") => append(&mut blocks[index]["text"], &delta["text"]),
+                    Some("thinking_delta") => append(&mut blocks[index]["thinking"], &delta["thinking"]),
+                    Some("signature_delta") => blocks[index]["signature"] = delta["signature"].clone(),
+                    Some("input_json_delta") => inputs[index].push_str(delta["partial_json"].as_str().unwrap_or_default()),
+                    _ => {}
+                }
+            }
+            Some("message_delta") => {
+                if let Some(delta) = event["delta"].as_object() {
+                    for (key, value) in delta {
+                        message[key] = value.clone();
+                    }
+                }
+                if let Some(usage) = event["usage"].as_object() {
+                    for (key, value) in usage {
+                        message["usage"][key] = value.clone();
+                    }
+                }
+            }
+            Some("error") => message["error"] = event["error"].clone(),
+            _ => {}
+        }
+    }
+    for (block, input) in blocks.iter_mut().zip(inputs) {
+        if !input.is_empty() {
+            block["input"] = serde_json::from_str(&input).unwrap_or(Value::String(input));
+        }
+    }
+    message["content"] = Value::Array(blocks);
+    message
+}
+
+fn append(field: &mut Value, text: &Value) {
+    let text = text.as_str().unwrap_or_default();
+    match field {
+        Value::String(existing) => existing.push_str(text),
+        other => *other = Value::String(text.to_string()),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{Backend, Exchange};
+    use anyhow::Context;
+    use chrono::DateTime;
+    use futures::future::BoxFuture;
+    use rusqlite::{params, Connection};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS conversation_exchanges (
+            id INTEGER PRIMARY KEY,
+            session TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            client TEXT,
+            model TEXT NOT NULL,
+            stream INTEGER NOT NULL,
+            status INTEGER NOT NULL,
+            complete INTEGER NOT NULL,
+            request TEXT NOT NULL,
+            response TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS conversation_exchanges_session ON conversation_exchanges (session, id);
+        CREATE INDEX IF NOT EXISTS conversation_exchanges_ts ON conversation_exchanges (ts);
+    ";
+
+    struct Sqlite {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    pub fn open(path: &Path) -> anyhow::Result<Arc<dyn Backend>> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Cannot open CONVERSATIONS_DB {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Arc::new(Sqlite {
+            conn: Arc::new(Mutex::new(conn)),
+        }))
+    }
+
+    impl Sqlite {
+        /// Runs `f` on the connection off the async runtime.
+        async fn with<T: Send + 'static>(
+            &self,
+            f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        ) -> anyhow::Result<T> {
+            let conn = Arc::clone(&self.conn);
+            let result = tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap_or_else(|e| e.into_inner())))
+                .await?;
+            Ok(result?)
+        }
+    }
+
+    impl Backend for Sqlite {
+        fn insert<'a>(&'a self, batch: &'a [Exchange]) -> BoxFuture<'a, anyhow::Result<()>> {
+            let batch = batch.to_vec();
+            Box::pin(self.with(move |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut stmt = tx.prepare_cached(
+                        "INSERT INTO conversation_exchanges
+                             (session, ts, client, model, stream, status, complete, request, response)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    )?;
+                    for exchange in &batch {
+                        stmt.execute(params![
+                            exchange.session,
+                            exchange.timestamp.timestamp_millis(),
+                            exchange.client,
+                            exchange.model,
+                            exchange.stream,
+                            exchange.status,
+                            exchange.complete,
+                            exchange.request.to_string(),
+                            exchange.response.to_string(),
+                        ])?;
+                    }
+                }
+                tx.commit()
+            }))
+        }
+
+        fn load<'a>(&'a self, session: &'a str, client: Option<&'a str>) -> BoxFuture<'a, anyhow::Result<Vec<Exchange>>> {
+            let (session, client) = (session.to_string(), client.map(str::to_string));
+            Box::pin(self.with(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT ts, client, model, stream, status, complete, request, response
+                     FROM conversation_exchanges
+                     WHERE session = ?1 AND (?2 IS NULL OR client = ?2)
+                     ORDER BY id",
+                )?;
+                let rows = stmt.query_map(params![session, client], |row| {
+                    let json = |i| row.get::<_, String>(i).map(|raw| serde_json::from_str(&raw).unwrap_or_default());
+                    Ok(Exchange {
+                        session: session.clone(),
+                        timestamp: DateTime::from_timestamp_millis(row.get(0)?).unwrap_or_default(),
+                        client: row.get(1)?,
+                        model: row.get(2)?,
+                        stream: row.get(3)?,
+                        status: row.get(4)?,
+                        complete: row.get(5)?,
+                        request: json(6)?,
+                        response: json(7)?,
+                    })
+                })?;
+                rows.collect()
+            }))
+        }
+
+        fn prune(&self, cutoff_ms: i64) -> BoxFuture<'_, anyhow::Result<u64>> {
+            Box::pin(self.with(move |conn| {
+                conn.execute("DELETE FROM conversation_exchanges WHERE ts < ?1", [cutoff_ms])
+                    .map(|n| n as u64)
+            }))
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod sqlite {
+    use super::Backend;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    pub fn open(_path: &Path) -> anyhow::Result<Arc<dyn Backend>> {
+        anyhow::bail!("CONVERSATIONS_DB names a SQLite file but this build lacks SQLite support (rebuild with --features sqlite)")
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use super::{Backend, Exchange};
+    use anyhow::Context;
+    use chrono::DateTime;
+    use futures::future::BoxFuture;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tokio_postgres::{Client, NoTls};
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS conversation_exchanges (
+            id BIGSERIAL PRIMARY KEY,
+            session TEXT NOT NULL,
+            ts BIGINT NOT NULL,
+            client TEXT,
+            model TEXT NOT NULL,
+            stream BOOLEAN NOT NULL,
+            status INTEGER NOT NULL,
+            complete BOOLEAN NOT NULL,
+            request JSONB NOT NULL,
+            response JSONB NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS conversation_exchanges_session ON conversation_exchanges (session, id);
+        CREATE INDEX IF NOT EXISTS conversation_exchanges_ts ON conversation_exchanges (ts);
+    ";
+
+    /// Reconnects on the next use after the connection is lost.
+    struct Postgres {
+        url: String,
+        client: Mutex<Arc<Client>>,
+    }
+
+    async fn open(url: &str) -> anyhow::Result<Client> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::warn!("Conversations: Postgres connection lost: {}", e);
+            }
+        });
+        Ok(client)
+    }
+
+    pub async fn connect(url: &str) -> anyhow::Result<Arc<dyn Backend>> {
+        let client = open(url).await.context("Cannot connect to CONVERSATIONS_DB")?;
+        client.batch_execute(SCHEMA).await?;
+        Ok(Arc::new(Postgres {
+            url: url.to_string(),
+            client: Mutex::new(Arc::new(client)),
+        }))
+    }
+
+    impl Postgres {
+        async fn client(&self) -> anyhow::Result<Arc<Client>> {
+            let mut client = self.client.lock().await;
+            if client.is_closed() {
+                *client = Arc::new(open(&self.url).await.context("Cannot reconnect to CONVERSATIONS_DB")?);
+            }
+            Ok(Arc::clone(&client))
+        }
+    }
+
+    impl Backend for Postgres {
+        fn insert<'a>(&'a self, batch: &'a [Exchange]) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                let client = self.client().await?;
+                let stmt = client
+                    .prepare(
+                        "INSERT INTO conversation_exchanges
+                             (session, ts, client, model, stream, status, complete, request, response)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                    )
+                    .await?;
+                for exchange in batch {
+                    client
+                        .execute(
+                            &stmt,
+                            &[
+                                &exchange.session,
+                                &exchange.timestamp.timestamp_millis(),
+                                &exchange.client,
+                                &exchange.model,
+                                &exchange.stream,
+                                &i32::from(exchange.status),
+                                &exchange.complete,
+                                &exchange.request,
+                                &exchange.response,
+                            ],
+                        )
+                        .await?;
+                }
+                Ok(())
+            })
+        }
+
+        fn load<'a>(&'a self, session: &'a str, client: Option<&'a str>) -> BoxFuture<'a, anyhow::Result<Vec<Exchange>>> {
+            Box::pin(async move {
+                let rows = self
+                    .client()
+                    .await?
+                    .query(
+                        "SELECT ts, client, model, stream, status, complete, request, response
+                         FROM conversation_exchanges
+                         WHERE session = $1 AND ($2::TEXT IS NULL OR client = $2)
+                         ORDER BY id",
+                        &[&session, &client],
+                    )
+                    .await?;
+                Ok(rows
+                    .iter()
+                    .map(|row| Exchange {
+                        session: session.to_string(),
+                        timestamp: DateTime::from_timestamp_millis(row.get(0)).unwrap_or_default(),
+                        client: row.get(1),
+                        model: row.get(2),
+                        stream: row.get(3),
+                        status: u16::try_from(row.get::<_, i32>(4)).unwrap_or_default(),
+                        complete: row.get(5),
+                        request: row.get(6),
+                        response: row.get(7),
+                    })
+                    .collect())
+            })
+        }
+
+        fn prune(&self, cutoff_ms: i64) -> BoxFuture<'_, anyhow::Result<u64>> {
+            Box::pin(async move {
+                let client = self.client().await?;
+                Ok(client
+                    .execute("DELETE FROM conversation_exchanges WHERE ts < $1", &[&cutoff_ms])
+                    .await?)
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+mod postgres {
+    use super::Backend;
+    use std::sync::Arc;
+
+    pub async fn connect(_url: &str) -> anyhow::Result<Arc<dyn Backend>> {
+        anyhow::bail!("CONVERSATIONS_DB names Postgres but this build lacks Postgres support (rebuild with --features postgres)")
+    }
+}
@@ -0,0 +1,92 @@
+//! Optional CORS for browser clients calling the proxy directly; off unless
+//! CORS_ALLOWED_ORIGINS is set.
+
+use anyhow::Context;
+use axum::http::{HeaderName, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Request headers allowed when CORS_ALLOWED_HEADERS is unset: what the Anthropic SDKs send
+/// from a browser.
+pub const DEFAULT_HEADERS: &str =
+    "content-type,x-api-key,authorization,anthropic-version,anthropic-beta,anthropic-dangerous-direct-browser-access";
+/// Methods allowed when CORS_ALLOWED_METHODS is unset.
+pub const DEFAULT_METHODS: &str = "GET,POST";
+
+/// CORS_ALLOWED_ORIGINS, CORS_ALLOWED_HEADERS, CORS_ALLOWED_METHODS and CORS_MAX_AGE.
+#[derive(Debug, Clone)]
+pub struct CorsSettings {
+    /// Exact origins such as `https://app.example.com`; `None` allows any origin (`*`).
+    pub origins: Option<Vec<HeaderValue>>,
+    pub headers: Vec<HeaderName>,
+    pub methods: Vec<Method>,
+    /// How long browsers may cache a preflight response.
+    pub max_age: Option<Duration>,
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+impl CorsSettings {
+    /// Parses the comma-separated lists; `*` in `origins` allows any origin.
+    pub fn parse(origins: &str, headers: &str, methods: &str, max_age: Option<u64>) -> anyhow::Result<Self> {
+        let origins = if split(origins).any(|origin| origin == "*") {
+            None
+        } else {
+            Some(
+                split(origins)
+                    .map(|origin| {
+                        anyhow::ensure!(
+                            origin.starts_with("http://") || origin.starts_with("https://"),
+                            "origin '{origin}' must start with http:// or https://"
+                        );
+                        HeaderValue::from_str(origin.trim_end_matches('/'))
+                            .with_context(|| format!("invalid origin '{origin}'"))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            )
+        };
+        let headers = split(headers)
+            .map(|name| HeaderName::try_from(name).with_context(|| format!("invalid header name '{name}'")))
+            .collect::<anyhow::Result<_>>()?;
+        let methods = split(methods)
+            .map(|method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .with_context(|| format!("invalid method '{method}'"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            origins,
+            headers,
+            methods,
+            max_age: max_age.map(Duration::from_secs),
+        })
+    }
+
+    /// Origins as logged at startup.
+    pub fn describe_origins(&self) -> String {
+        match &self.origins {
+            None => "*".to_string(),
+            Some(origins) => origins
+                .iter()
+                .filter_map(|origin| origin.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+
+    pub fn layer(&self) -> CorsLayer {
+        let layer = CorsLayer::new()
+            .allow_headers(self.headers.clone())
+            .allow_methods(self.methods.clone());
+        let layer = match &self.origins {
+            None => layer.allow_origin(Any),
+            Some(origins) => layer.allow_origin(AllowOrigin::list(origins.clone())),
+        };
+        match self.max_age {
+            Some(max_age) => layer.max_age(max_age),
+            None => layer,
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Upstream name resolution: an in-process cache in front of the system resolver
+//! (UPSTREAM_DNS_CACHE_TTL_SECS) and static host overrides (UPSTREAM_RESOLVE, or `resolve` per
+//! upstream), like an /etc/hosts for the proxy's own connections.
+
+use anyhow::Context;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Pinned addresses by lower-cased host name.
+pub type HostOverrides = BTreeMap<String, Vec<IpAddr>>;
+
+/// Parses `host=ip` entries, e.g. `api.example.com=10.0.0.5`; a host listed more than once
+/// gets every address.
+pub fn parse_overrides<'a>(entries: impl IntoIterator<Item = &'a str>) -> anyhow::Result<HostOverrides> {
+    let mut overrides = HostOverrides::new();
+    for entry in entries.into_iter().map(str::trim).filter(|e| !e.is_empty()) {
+        let (host, ip) = entry
+            .split_once('=')
+            .with_context(|| format!("expected host=ip, got '{entry}'"))?;
+        let host = host.trim();
+        anyhow::ensure!(!host.is_empty(), "missing host name in '{entry}'");
+        let ip = ip.trim();
+        let ip: IpAddr = ip
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .with_context(|| format!("'{ip}' is not an IP address"))?;
+        overrides.entry(host.to_ascii_lowercase()).or_default().push(ip);
+    }
+    Ok(overrides)
+}
+
+/// Addresses from one lookup and when they were fetched.
+struct Entry {
+    fetched: Instant,
+    addrs: Arc<[SocketAddr]>,
+}
+
+/// Shared by every upstream client, so each host is looked up once per TTL.
+static CACHE: Mutex<BTreeMap<String, Entry>> = Mutex::new(BTreeMap::new());
+
+/// System resolver whose answers are reused for `ttl`. An expired answer is still used when a
+/// fresh lookup fails, so a flaky resolver doesn't fail requests to a host that was reachable.
+#[derive(Debug, Clone, Copy)]
+pub struct CachingResolver {
+    pub ttl: Duration,
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_ascii_lowercase();
+        let ttl = self.ttl;
+        Box::pin(async move {
+            let stale = {
+                let cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+                match cache.get(&host) {
+                    Some(entry) if entry.fetched.elapsed() < ttl => return Ok(addrs(&entry.addrs)),
+                    Some(entry) => Some(entry.addrs.clone()),
+                    None => None,
+                }
+            };
+            let lookup = tokio::net::lookup_host((host.as_str(), 0))
+                .await
+                .map(|found| found.collect::<Arc<[SocketAddr]>>());
+            match lookup {
+                Ok(found) => {
+                    tracing::debug!("Resolved {} to {:?}", host, found);
+                    CACHE.lock().unwrap_or_else(|e| e.into_inner()).insert(
+                        host,
+                        Entry {
+                            fetched: Instant::now(),
+                            addrs: found.clone(),
+                        },
+                    );
+                    Ok(addrs(&found))
+                }
+                Err(e) => match stale {
+                    Some(stale) => {
+                        tracing::warn!("Resolving {} failed, using the previous answer: {}", host, e);
+                        Ok(addrs(&stale))
+                    }
+                    None => Err(e.into()),
+                },
+            }
+        })
+    }
+}
+
+fn addrs(addrs: &Arc<[SocketAddr]>) -> Addrs {
+    let addrs = addrs.clone();
+    Box::new((0..addrs.len()).map(move |i| addrs[i]))
+}
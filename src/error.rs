@@ -1,7 +1,9 @@
 //! Proxy error types and HTTP response mapping.
 
+use crate::accesslog;
+use crate::plugin::ErrorInfo;
 use axum::{
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -11,6 +13,7 @@ use thiserror::Error;
 /// Application-specific errors for the Anthropic proxy.
 #[derive(Error, Debug)]
 pub enum ProxyError {
+    #[allow(dead_code)]
     #[error("Configuration error: {0}")]
     Config(String),
 
@@ -20,35 +23,134 @@ pub enum ProxyError {
     #[error("Upstream API error: {0}")]
     Upstream(String),
 
+    #[error("Authentication error: {0}")]
+    Authentication(String),
+
+    #[error("Permission denied: {0}")]
+    Permission(String),
+
+    #[error("Rate limited: {message}")]
+    RateLimited { message: String, headers: HeaderMap },
+
+    #[error("Budget exceeded: {message}")]
+    BudgetExceeded { message: String, headers: HeaderMap },
+
+    #[error("Request too large: {0}")]
+    TooLarge(String),
+
+    #[error("prompt is too long: {tokens} tokens > {window} maximum")]
+    ContextWindowExceeded { tokens: u32, window: u32 },
+
+    #[error("model '{model}' does not support {}", capability_name(capability))]
+    Unsupported { model: String, capability: &'static str },
+
+    #[error("Unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+
+    #[error("Cache miss: {0}")]
+    CacheMiss(String),
+
+    #[error("Replay miss: {0}")]
+    ReplayMiss(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
 
     #[error("HTTP error: {0}")]
     Http(#[from] reqwest::Error),
 
+    #[allow(dead_code)]
     #[error("Internal error: {0}")]
     Internal(String),
 }
 
+/// How a capability reads in [`ProxyError::Unsupported`].
+fn capability_name(capability: &str) -> &str {
+    match capability {
+        "tools" => "tool use",
+        "vision" => "image input",
+        other => other,
+    }
+}
+
+impl From<crate::transform::Error> for ProxyError {
+    fn from(e: crate::transform::Error) -> Self {
+        ProxyError::Transform(e.to_string())
+    }
+}
+
+impl ProxyError {
+    /// Anthropic error `type` reported to clients for this error.
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            ProxyError::Transform(_)
+            | ProxyError::TooLarge(_)
+            | ProxyError::ContextWindowExceeded { .. }
+            | ProxyError::Unsupported { .. }
+            | ProxyError::UnsupportedEncoding(_)
+            | ProxyError::Serialization(_) => {
+                "invalid_request_error"
+            }
+            ProxyError::Authentication(_) => "authentication_error",
+            ProxyError::Permission(_) => "permission_error",
+            ProxyError::RateLimited { .. } => "rate_limit_error",
+            ProxyError::BudgetExceeded { .. } => "billing_error",
+            ProxyError::ReplayMiss(_) | ProxyError::NotFound(_) => "not_found_error",
+            ProxyError::Config(_)
+            | ProxyError::Upstream(_)
+            | ProxyError::CacheMiss(_)
+            | ProxyError::Http(_)
+            | ProxyError::Internal(_) => "api_error",
+        }
+    }
+}
+
 impl IntoResponse for ProxyError {
     fn into_response(self) -> Response {
         let (status, message) = match &self {
             ProxyError::Config(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
             ProxyError::Transform(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
             ProxyError::Upstream(msg) => (StatusCode::BAD_GATEWAY, msg.clone()),
+            ProxyError::Authentication(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
+            ProxyError::Permission(msg) => (StatusCode::FORBIDDEN, msg.clone()),
+            ProxyError::RateLimited { message, .. } => (StatusCode::TOO_MANY_REQUESTS, message.clone()),
+            ProxyError::BudgetExceeded { message, .. } => (StatusCode::PAYMENT_REQUIRED, message.clone()),
+            ProxyError::TooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg.clone()),
+            // Anthropic's wording, which clients look for to compact the conversation.
+            ProxyError::ContextWindowExceeded { .. } => (StatusCode::BAD_REQUEST, self.to_string()),
+            ProxyError::Unsupported { .. } => (StatusCode::BAD_REQUEST, self.to_string()),
+            ProxyError::UnsupportedEncoding(msg) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg.clone()),
+            ProxyError::CacheMiss(msg) => (StatusCode::GATEWAY_TIMEOUT, msg.clone()),
+            ProxyError::ReplayMiss(msg) | ProxyError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
             ProxyError::Serialization(e) => (StatusCode::BAD_REQUEST, format!("JSON error: {e}")),
             ProxyError::Http(e) => (StatusCode::BAD_GATEWAY, format!("HTTP error: {e}")),
             ProxyError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
         };
 
+        accesslog::with_current(|entry| entry.set_error(self.error_type(), &message));
+
+        // Anthropic error envelope, so Anthropic SDKs surface the message as-is.
         let body = Json(json!({
+            "type": "error",
             "error": {
-                "type": "proxy_error",
-                "message": message,
+                "type": self.error_type(),
+                "message": &message,
             }
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        response.extensions_mut().insert(ErrorInfo {
+            status,
+            error_type: self.error_type(),
+            message,
+        });
+        if let ProxyError::RateLimited { headers, .. } | ProxyError::BudgetExceeded { headers, .. } = self {
+            response.headers_mut().extend(headers);
+        }
+        response
     }
 }
 
@@ -0,0 +1,252 @@
+//! Lifecycle event webhooks: with EVENT_WEBHOOK_URLS set, the proxy POSTs a signed JSON event
+//! when a model request completes, when a client's spend crosses a share of its budget
+//! (EVENT_BUDGET_THRESHOLDS) and when an upstream key is rotated out and traffic fails over to
+//! the others, so billing and alerting systems don't have to poll.
+//!
+//! Bodies are `{"id", "type", "timestamp", "data"}` and carry `x-proxy-signature:
+//! t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">` under EVENT_WEBHOOK_SECRET, the
+//! scheme REQUEST_SIGNING_SECRET checks on requests. Failed deliveries (network errors, 429 and
+//! 5xx) are retried with exponential backoff under the same event id.
+//!
+//! Like [`crate::alerts`], events are raised through [`emit`] so deep code can report them
+//! without holding a handle.
+
+use crate::accesslog::{Details, Record};
+use crate::metrics;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+
+pub const DEFAULT_EVENT_BUDGET_THRESHOLDS: [f64; 2] = [0.8, 1.0];
+pub const DEFAULT_EVENT_WEBHOOK_RETRIES: u32 = 5;
+
+pub const EVENT_HEADER: &str = "x-proxy-event";
+pub const EVENT_ID_HEADER: &str = "x-proxy-event-id";
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Deliveries in flight or waiting to retry; further events are dropped.
+const MAX_PENDING: usize = 1024;
+
+/// What happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    RequestCompleted,
+    BudgetThresholdCrossed,
+    UpstreamFailover,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 3] = [
+        EventKind::RequestCompleted,
+        EventKind::BudgetThresholdCrossed,
+        EventKind::UpstreamFailover,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::RequestCompleted => "request.completed",
+            EventKind::BudgetThresholdCrossed => "budget.threshold_crossed",
+            EventKind::UpstreamFailover => "upstream.failover",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == s).ok_or_else(|| {
+            let known: Vec<_> = Self::ALL.iter().map(|kind| kind.as_str()).collect();
+            format!("unknown event '{s}' (expected one of {})", known.join(", "))
+        })
+    }
+}
+
+/// EVENT_WEBHOOK_URLS / EVENT_WEBHOOK_SECRET / EVENT_TYPES / EVENT_BUDGET_THRESHOLDS /
+/// EVENT_WEBHOOK_RETRIES.
+#[derive(Debug, Clone)]
+pub struct EventSettings {
+    pub webhook_urls: Vec<String>,
+    pub secret: String,
+    /// Events to send; every kind by default.
+    pub kinds: Vec<EventKind>,
+    /// Shares of a budget (0–1] whose crossing is reported.
+    pub budget_thresholds: Vec<f64>,
+    /// Attempts after the first before a delivery is given up.
+    pub retries: u32,
+}
+
+static EVENTS: OnceLock<Arc<EventSink>> = OnceLock::new();
+
+/// Makes `sink` the target of [`emit`].
+pub fn install(sink: Arc<EventSink>) {
+    let _ = EVENTS.set(sink);
+}
+
+/// Sends an event of `kind` if event webhooks are configured and subscribed to it.
+pub fn emit(kind: EventKind, data: Value) {
+    if let Some(sink) = EVENTS.get() {
+        sink.send(kind, data);
+    }
+}
+
+/// Reports the thresholds a client's spend in a budget `period` crossed going from `before` to
+/// `after` USD.
+pub fn budget_spent(client: &str, period: &str, budget_usd: f64, before: f64, after: f64, resets: DateTime<Utc>) {
+    let Some(sink) = EVENTS.get() else { return };
+    for &threshold in &sink.settings.budget_thresholds {
+        let line = threshold * budget_usd;
+        if before < line && after >= line {
+            sink.send(
+                EventKind::BudgetThresholdCrossed,
+                json!({
+                    "client": client,
+                    "period": period,
+                    "threshold": threshold,
+                    "budget_usd": budget_usd,
+                    "spent_usd": after,
+                    "resets": resets.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                }),
+            );
+        }
+    }
+}
+
+pub struct EventSink {
+    settings: EventSettings,
+    client: Client,
+    pending: Arc<Semaphore>,
+    sequence: AtomicU64,
+}
+
+impl EventSink {
+    pub fn new(settings: EventSettings, client: Client) -> Self {
+        Self {
+            settings,
+            client,
+            pending: Arc::new(Semaphore::new(MAX_PENDING)),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Sends `request.completed` for a finished model request.
+    pub fn observe(&self, record: &Record, details: &Details) {
+        if details.incoming_model.is_none() {
+            return;
+        }
+        self.send(EventKind::RequestCompleted, record.summary(details));
+    }
+
+    fn send(&self, kind: EventKind, data: Value) {
+        if !self.settings.kinds.contains(&kind) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let id = format!("evt_{nanos:x}_{}", self.sequence.fetch_add(1, Ordering::Relaxed));
+        let body = json!({
+            "id": id,
+            "type": kind.as_str(),
+            "timestamp": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "data": data,
+        })
+        .to_string();
+        for url in &self.settings.webhook_urls {
+            let Ok(permit) = Arc::clone(&self.pending).try_acquire_owned() else {
+                tracing::warn!("Event webhook backlog is full; dropping {} {}", kind.as_str(), id);
+                metrics::increment("proxy_event_webhooks_total", &[("event", kind.as_str()), ("result", "dropped")], 1);
+                continue;
+            };
+            let delivery = Delivery {
+                client: self.client.clone(),
+                url: url.clone(),
+                secret: self.settings.secret.clone(),
+                kind,
+                id: id.clone(),
+                body: body.clone(),
+            };
+            let retries = self.settings.retries;
+            runtime.spawn(async move {
+                let result = if delivery.run(retries).await { "sent" } else { "failed" };
+                metrics::increment("proxy_event_webhooks_total", &[("event", kind.as_str()), ("result", result)], 1);
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// One event on its way to one URL.
+struct Delivery {
+    client: Client,
+    url: String,
+    secret: String,
+    kind: EventKind,
+    id: String,
+    body: String,
+}
+
+impl Delivery {
+    /// Whether the receiver accepted the event within `retries` retries.
+    async fn run(&self, retries: u32) -> bool {
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            let error = match self.attempt().await {
+                Ok(status) if status.is_success() => return true,
+                Ok(status) if status.as_u16() == 429 || status.is_server_error() => format!("status {status}"),
+                Ok(status) => {
+                    tracing::error!("Event webhook rejected {} {}: status {}", self.kind.as_str(), self.id, status);
+                    return false;
+                }
+                Err(e) => e.without_url().to_string(),
+            };
+            tracing::warn!(
+                "Event webhook delivery of {} {} failed (attempt {} of {}): {}",
+                self.kind.as_str(),
+                self.id,
+                attempt + 1,
+                retries + 1,
+                error
+            );
+        }
+        false
+    }
+
+    async fn attempt(&self) -> reqwest::Result<reqwest::StatusCode> {
+        let timestamp = Utc::now().timestamp();
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(self.body.as_bytes());
+        let signature = format!("t={timestamp},v1={}", hex::encode(mac.finalize().into_bytes()));
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(crate::signing::SIGNATURE_HEADER, signature)
+            .header(EVENT_HEADER, self.kind.as_str())
+            .header(EVENT_ID_HEADER, &self.id)
+            .body(self.body.clone())
+            .timeout(WEBHOOK_TIMEOUT)
+            .send()
+            .await?;
+        Ok(response.status())
+    }
+}
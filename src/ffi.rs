@@ -0,0 +1,91 @@
+//! C interface to the converters (`ffi` feature), for gateways written in Go, C or C++.
+//! JSON goes in and out as NUL-terminated UTF-8 strings; see `include/anthropic_proxy.h`.
+//!
+//! Strings returned by the converters belong to the caller and must be released with
+//! [`anthropic_proxy_free_string`]. On failure they return NULL and
+//! [`anthropic_proxy_last_error`] describes why.
+
+use crate::models::{anthropic, openai};
+use crate::transform;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+
+thread_local! {
+    /// Message of the last failed call on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Converts an Anthropic Messages request into an OpenAI chat completions request. The model
+/// name and parameters are kept as they are.
+///
+/// # Safety
+///
+/// `input` must be NULL or point to a NUL-terminated string that stays valid for the call.
+#[no_mangle]
+pub unsafe extern "C" fn anthropic_to_openai_json(input: *const c_char) -> *mut c_char {
+    convert(input, |json| {
+        let req: anthropic::AnthropicRequest = serde_json::from_str(json)?;
+        Ok(serde_json::to_string(&transform::anthropic_to_openai(req, &mut ()))?)
+    })
+}
+
+/// Converts an OpenAI chat completions response into an Anthropic Messages response.
+///
+/// # Safety
+///
+/// `input` must be NULL or point to a NUL-terminated string that stays valid for the call.
+#[no_mangle]
+pub unsafe extern "C" fn openai_to_anthropic_json(input: *const c_char) -> *mut c_char {
+    convert(input, |json| {
+        let resp: openai::OpenAIResponse = serde_json::from_str(json)?;
+        Ok(serde_json::to_string(&transform::openai_to_anthropic(resp, &mut ())?)?)
+    })
+}
+
+/// Releases a string returned by a converter. NULL is ignored.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by this library that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn anthropic_proxy_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Why the last converter call on this thread returned NULL, or NULL if it succeeded. The
+/// string belongs to the library and stays valid until the next converter call on the thread.
+#[no_mangle]
+pub extern "C" fn anthropic_proxy_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Runs `f` on the input string and hands its JSON to the caller, recording any error.
+unsafe fn convert(
+    input: *const c_char,
+    f: impl FnOnce(&str) -> Result<String, Box<dyn std::error::Error>>,
+) -> *mut c_char {
+    let result = if input.is_null() {
+        Err("input is NULL".into())
+    } else {
+        CStr::from_ptr(input).to_str().map_err(Into::into).and_then(f)
+    };
+    // serde_json escapes control characters, so the output has no interior NUL.
+    match result.and_then(|json| Ok(CString::new(json)?)) {
+        Ok(json) => {
+            set_last_error(None);
+            json.into_raw()
+        }
+        Err(e) => {
+            set_last_error(Some(e.to_string()));
+            ptr::null_mut()
+        }
+    }
+}
+
+fn set_last_error(message: Option<String>) {
+    let message = message.map(|m| CString::new(m.replace('\0', " ")).expect("no NUL bytes"));
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
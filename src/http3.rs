@@ -0,0 +1,200 @@
+//! Experimental HTTP/3 to upstreams (`http3` feature, UPSTREAM_HTTP_VERSION=http3). An origin is
+//! reached over QUIC only after it advertised `h3` in an Alt-Svc header and a background probe
+//! got an answer that way; until then, and for a while after QUIC fails, requests go over
+//! HTTP/2 or HTTP/1.1 as with `auto`.
+
+use crate::config::Upstream;
+use reqwest::{RequestBuilder, Response};
+
+/// Sends the request built by `build`, over HTTP/3 when the upstream's origin is known to
+/// answer on QUIC. A request that fails there without a response is sent again over TCP.
+pub async fn send(
+    upstream: &Upstream,
+    url: &str,
+    build: impl Fn() -> RequestBuilder,
+) -> reqwest::Result<Response> {
+    imp::send(upstream, url, build).await
+}
+
+#[cfg(feature = "http3")]
+mod imp {
+    use crate::config::Upstream;
+    use crate::metrics;
+    use crate::transport::{HttpVersion, Transport};
+    use reqwest::header::{HeaderMap, ALT_SVC};
+    use reqwest::{Client, RequestBuilder, Response, Url, Version};
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// How long an origin stays on TCP after QUIC failed to reach it.
+    const BROKEN_FOR: Duration = Duration::from_secs(300);
+    /// Alt-Svc lifetime when the header has no `ma` parameter (RFC 7838).
+    const DEFAULT_MAX_AGE: Duration = Duration::from_secs(86400);
+    /// Bound on the probe; a blocked UDP path otherwise waits out the QUIC idle timeout.
+    const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// What is known about reaching an origin over QUIC.
+    enum Route {
+        /// Advertised; a probe is in flight.
+        Probing,
+        /// Answered over HTTP/3 through `client`, built from `transport`; used until the
+        /// advertisement expires.
+        Quic { until: Instant, transport: Box<Transport>, client: Client },
+        /// QUIC failed; not retried before `until`.
+        Tcp { until: Instant },
+    }
+
+    /// Keyed by origin (`https://host:port`).
+    static ROUTES: Mutex<BTreeMap<String, Route>> = Mutex::new(BTreeMap::new());
+
+    fn origin(url: &Url) -> String {
+        url.origin().ascii_serialization()
+    }
+
+    /// The HTTP/3 client for `origin`, unless the route expired or `transport` changed since
+    /// the probe (a reload).
+    fn quic_client(origin: &str, transport: &Transport) -> Option<Client> {
+        let mut routes = ROUTES.lock().unwrap_or_else(|e| e.into_inner());
+        match routes.get(origin) {
+            Some(Route::Quic { until, transport: probed, client }) if *until > Instant::now() && **probed == *transport => {
+                Some(client.clone())
+            }
+            Some(Route::Quic { .. }) => {
+                routes.remove(origin);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn set_route(origin: &str, route: Route) {
+        ROUTES.lock().unwrap_or_else(|e| e.into_inner()).insert(origin.to_string(), route);
+    }
+
+    pub async fn send(
+        upstream: &Upstream,
+        url: &str,
+        build: impl Fn() -> RequestBuilder,
+    ) -> reqwest::Result<Response> {
+        let url = match Url::parse(url) {
+            // QUIC always runs TLS; Alt-Svc from a plain `http://` upstream is ignored.
+            Ok(url) if upstream.transport.http_version == Some(HttpVersion::Http3) && url.scheme() == "https" => url,
+            _ => return build().send().await,
+        };
+        let origin = origin(&url);
+        let response = if let Some(client) = quic_client(&origin, &upstream.transport) {
+            let request = build().version(Version::HTTP_3).build()?;
+            match client.execute(request).await {
+                Ok(response) => response,
+                // A timeout may mean the upstream is already generating; don't send it twice.
+                Err(e) if e.is_timeout() => return Err(e),
+                Err(e) => {
+                    tracing::warn!("HTTP/3 request to {} failed, falling back to TCP: {}", origin, e);
+                    metrics::increment("proxy_http3_fallbacks_total", &[], 1);
+                    set_route(&origin, Route::Tcp { until: Instant::now() + BROKEN_FOR });
+                    build().send().await?
+                }
+            }
+        } else {
+            build().send().await?
+        };
+        observe(&upstream.transport, &url, &origin, response.headers());
+        Ok(response)
+    }
+
+    /// Acts on the response's Alt-Svc: refreshes a working QUIC route, starts a probe for a
+    /// newly advertised one, or forgets the origin on `clear`.
+    fn observe(transport: &Transport, url: &Url, origin: &str, headers: &HeaderMap) {
+        let Some(value) = headers.get(ALT_SVC).and_then(|v| v.to_str().ok()) else {
+            return;
+        };
+        let mut routes = ROUTES.lock().unwrap_or_else(|e| e.into_inner());
+        if value.trim() == "clear" {
+            routes.remove(origin);
+            return;
+        }
+        let Some(max_age) = advertised_h3(value, url.port_or_known_default()) else {
+            return;
+        };
+        let now = Instant::now();
+        match routes.get_mut(origin) {
+            Some(Route::Probing) => {}
+            Some(Route::Tcp { until }) if *until > now => {}
+            Some(Route::Quic { until, transport: probed, .. }) if **probed == *transport => *until = now + max_age,
+            _ => {
+                routes.insert(origin.to_string(), Route::Probing);
+                tokio::spawn(probe(transport.clone(), url.clone(), origin.to_string(), max_age));
+            }
+        }
+    }
+
+    /// One request over HTTP/3 to the origin; any response, error statuses included, shows
+    /// QUIC gets through.
+    async fn probe(transport: Transport, mut url: Url, origin: String, max_age: Duration) {
+        let client = match transport.quic_client() {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("Upstream {} advertises HTTP/3 but {:#}, staying on TCP", origin, e);
+                set_route(&origin, Route::Tcp { until: Instant::now() + BROKEN_FOR });
+                return;
+            }
+        };
+        url.set_path("/");
+        url.set_query(None);
+        let result = client
+            .get(url)
+            .version(Version::HTTP_3)
+            .timeout(PROBE_TIMEOUT)
+            .send()
+            .await;
+        let now = Instant::now();
+        match result {
+            Ok(_) => {
+                tracing::info!("Upstream {} answers over HTTP/3, using it", origin);
+                set_route(&origin, Route::Quic { until: now + max_age, transport: Box::new(transport), client });
+            }
+            Err(e) => {
+                tracing::info!("Upstream {} advertises HTTP/3 but QUIC failed, staying on TCP: {}", origin, e);
+                set_route(&origin, Route::Tcp { until: now + BROKEN_FOR });
+            }
+        }
+    }
+
+    /// The lifetime of an `h3` alternative on the same port in an Alt-Svc value, e.g.
+    /// `h3=":443"; ma=86400, h2=":443"`. Alternatives on other hosts or ports are ignored:
+    /// the client connects to the URL's own host and port.
+    fn advertised_h3(value: &str, port: Option<u16>) -> Option<Duration> {
+        value.split(',').find_map(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            let (protocol, authority) = params.next()?.split_once('=')?;
+            if protocol != "h3" {
+                return None;
+            }
+            let authority = authority.trim_matches('"');
+            let (host, alt_port) = authority.rsplit_once(':')?;
+            if !host.is_empty() || alt_port.parse::<u16>().ok() != port {
+                return None;
+            }
+            let max_age = params
+                .filter_map(|param| param.strip_prefix("ma="))
+                .find_map(|secs| secs.parse().ok())
+                .map_or(DEFAULT_MAX_AGE, Duration::from_secs);
+            Some(max_age)
+        })
+    }
+}
+
+#[cfg(not(feature = "http3"))]
+mod imp {
+    use crate::config::Upstream;
+    use reqwest::{RequestBuilder, Response};
+
+    pub async fn send(
+        _upstream: &Upstream,
+        _url: &str,
+        build: impl Fn() -> RequestBuilder,
+    ) -> reqwest::Result<Response> {
+        build().send().await
+    }
+}
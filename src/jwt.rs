@@ -0,0 +1,186 @@
+//! JWT / OIDC bearer authentication: validates tokens against a JWKS endpoint and maps
+//! their claims to a [`ClientIdentity`]. The verifier requires the `jwt` cargo feature.
+
+use crate::auth::ClientIdentity;
+use crate::sysprompt::SystemPromptEdits;
+
+pub use imp::JwtVerifier;
+
+/// JWT validation settings from JWT_* environment variables.
+#[derive(Debug, Clone)]
+pub struct JwtSettings {
+    pub jwks_url: String,
+    /// Required `iss` value, when set.
+    pub issuer: Option<String>,
+    /// Accepted `aud` values; the audience is not checked when empty.
+    pub audiences: Vec<String>,
+    /// Claim used as the client name (default `sub`).
+    pub name_claim: String,
+    /// Optional claim holding a string or array of strings mapped to identity tags.
+    pub tags_claim: Option<String>,
+}
+
+/// Cheap shape check so opaque API keys are never sent through JWT parsing.
+pub fn looks_like_jwt(token: &str) -> bool {
+    token.starts_with("eyJ") && token.bytes().filter(|&b| b == b'.').count() == 2
+}
+
+/// Builds an identity from verified claims.
+#[cfg_attr(not(feature = "jwt"), allow(dead_code))]
+fn identity_from_claims(
+    settings: &JwtSettings,
+    claims: &serde_json::Value,
+) -> Result<ClientIdentity, String> {
+    let name = claims
+        .get(&settings.name_claim)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("token has no '{}' claim", settings.name_claim))?
+        .to_string();
+    let tags = settings
+        .tags_claim
+        .as_deref()
+        .and_then(|claim| claims.get(claim))
+        .map(|v| match v {
+            serde_json::Value::String(s) => vec![s.clone()],
+            serde_json::Value::Array(items) => items
+                .iter()
+                .filter_map(|i| i.as_str().map(str::to_string))
+                .collect(),
+            _ => Vec::new(),
+        })
+        .unwrap_or_default();
+    let expires_at = claims
+        .get("exp")
+        .and_then(|v| v.as_i64())
+        .and_then(|exp| chrono::DateTime::from_timestamp(exp, 0));
+    Ok(ClientIdentity {
+        name,
+        tags,
+        expires_at,
+        quota: None,
+        allowed_models: Vec::new(),
+        upstream: None,
+        system_prompt: SystemPromptEdits::default(),
+    })
+}
+
+#[cfg(feature = "jwt")]
+mod imp {
+    use super::{identity_from_claims, JwtSettings};
+    use crate::auth::ClientIdentity;
+    use anyhow::Context;
+    use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+    use reqwest::Client;
+    use std::sync::{Arc, RwLock};
+    use std::time::{Duration, Instant};
+
+    /// Minimum delay between JWKS re-fetches triggered by unknown key ids.
+    const JWKS_REFRESH_COOLDOWN: Duration = Duration::from_secs(60);
+
+    pub struct JwtVerifier {
+        settings: JwtSettings,
+        client: Client,
+        keys: RwLock<JwkSet>,
+        last_fetch: tokio::sync::Mutex<Instant>,
+    }
+
+    impl JwtVerifier {
+        /// Fetches the JWKS once; fails startup if the endpoint is unreachable.
+        pub async fn connect(settings: JwtSettings, client: Client) -> anyhow::Result<Arc<Self>> {
+            let keys = fetch_jwks(&client, &settings.jwks_url).await?;
+            tracing::info!("JWT auth: loaded {} key(s) from {}", keys.keys.len(), settings.jwks_url);
+            Ok(Arc::new(Self {
+                settings,
+                client,
+                keys: RwLock::new(keys),
+                last_fetch: tokio::sync::Mutex::new(Instant::now()),
+            }))
+        }
+
+        pub async fn verify(&self, token: &str) -> Result<ClientIdentity, String> {
+            let header = jsonwebtoken::decode_header(token).map_err(|e| format!("malformed token: {e}"))?;
+            if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+                return Err("symmetric token algorithms are not accepted".to_string());
+            }
+            let kid = header.kid.ok_or("token has no 'kid' header")?;
+
+            let key = match self.decoding_key(&kid)? {
+                Some(key) => key,
+                None => {
+                    self.refresh().await;
+                    self.decoding_key(&kid)?
+                        .ok_or_else(|| format!("unknown signing key '{kid}'"))?
+                }
+            };
+
+            let mut validation = Validation::new(header.alg);
+            if let Some(issuer) = &self.settings.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+            if self.settings.audiences.is_empty() {
+                validation.validate_aud = false;
+            } else {
+                validation.set_audience(&self.settings.audiences);
+            }
+
+            let data = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+                .map_err(|e| format!("invalid token: {e}"))?;
+            identity_from_claims(&self.settings, &data.claims)
+        }
+
+        fn decoding_key(&self, kid: &str) -> Result<Option<DecodingKey>, String> {
+            let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            keys.find(kid)
+                .map(|jwk| DecodingKey::from_jwk(jwk).map_err(|e| format!("unusable JWK '{kid}': {e}")))
+                .transpose()
+        }
+
+        /// Re-fetches the JWKS (key rotation), rate-limited by [`JWKS_REFRESH_COOLDOWN`].
+        async fn refresh(&self) {
+            let mut last = self.last_fetch.lock().await;
+            if last.elapsed() < JWKS_REFRESH_COOLDOWN {
+                return;
+            }
+            *last = Instant::now();
+            match fetch_jwks(&self.client, &self.settings.jwks_url).await {
+                Ok(keys) => {
+                    tracing::info!("JWT auth: refreshed JWKS ({} keys)", keys.keys.len());
+                    *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+                }
+                Err(e) => tracing::warn!("JWT auth: JWKS refresh failed: {e:#}"),
+            }
+        }
+    }
+
+    async fn fetch_jwks(client: &Client, url: &str) -> anyhow::Result<JwkSet> {
+        client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Failed to fetch JWKS from {url}"))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid JWKS document at {url}"))
+    }
+}
+
+#[cfg(not(feature = "jwt"))]
+mod imp {
+    use super::JwtSettings;
+    use crate::auth::ClientIdentity;
+    use reqwest::Client;
+    use std::sync::Arc;
+
+    pub struct JwtVerifier;
+
+    impl JwtVerifier {
+        pub async fn connect(_settings: JwtSettings, _client: Client) -> anyhow::Result<Arc<Self>> {
+            anyhow::bail!("JWT_JWKS_URL is set but this build lacks JWT support (rebuild with --features jwt)")
+        }
+
+        pub async fn verify(&self, _token: &str) -> Result<ClientIdentity, String> {
+            Err("JWT support is not compiled in".to_string())
+        }
+    }
+}
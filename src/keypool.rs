@@ -0,0 +1,264 @@
+//! Upstream API key pool: round-robin selection that skips keys cooling down after a
+//! rate limit or auth failure, with runtime add/remove for the admin API.
+
+use crate::auth::digest_key;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often secret files backing the pool are checked for rotation.
+const KEY_FILE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Cool-down after a 429 without a usable `retry-after` header.
+const RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+/// Cool-down after a 401/403, long enough to stop hammering a revoked key.
+const AUTH_FAILURE_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// One upstream key. Only the id (a digest prefix) and last four characters are ever exposed.
+#[derive(Debug)]
+pub struct PooledKey {
+    pub id: String,
+    hint: String,
+    header_value: String,
+    cooling_until: Mutex<Option<Instant>>,
+    requests: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl PooledKey {
+    fn new(key: &str) -> Self {
+        let key = key.trim();
+        let hint = key
+            .char_indices()
+            .rev()
+            .nth(3)
+            .map(|(i, _)| format!("...{}", &key[i..]))
+            .unwrap_or_default();
+        Self {
+            id: key_id(key),
+            hint,
+            header_value: format!("Bearer {key}"),
+            cooling_until: Mutex::new(None),
+            requests: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Value for the upstream `Authorization` header.
+    #[inline]
+    pub fn header_value(&self) -> &str {
+        &self.header_value
+    }
+
+    fn cooling_until(&self) -> Option<Instant> {
+        let mut until = self.cooling_until.lock().unwrap_or_else(|e| e.into_inner());
+        if until.is_some_and(|t| t <= Instant::now()) {
+            *until = None;
+        }
+        *until
+    }
+
+    fn cool_down(&self, duration: Duration) {
+        *self.cooling_until.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now() + duration);
+    }
+}
+
+/// Public view of a pooled key for `GET /admin/upstream-keys`.
+#[derive(Debug, Serialize)]
+pub struct KeyStatus {
+    pub id: String,
+    pub hint: String,
+    pub requests: u64,
+    pub failures: u64,
+    /// Seconds until the key is preferred again; 0 when available.
+    pub cooling_down_secs: u64,
+}
+
+/// Keys from a comma- or newline-separated list (env value or secret file).
+pub fn split_keys(raw: &str) -> impl Iterator<Item = &str> {
+    raw.split([',', '\n']).map(str::trim).filter(|k| !k.is_empty())
+}
+
+fn key_id(key: &str) -> String {
+    hex::encode(&digest_key(key)[..4])
+}
+
+#[derive(Debug, Default)]
+pub struct KeyPool {
+    keys: RwLock<Vec<Arc<PooledKey>>>,
+    cursor: AtomicUsize,
+}
+
+impl KeyPool {
+    /// Builds a pool from raw keys, skipping blanks and duplicates.
+    pub fn new<I, S>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let pool = Self::default();
+        for key in keys {
+            pool.add(key.as_ref());
+        }
+        pool
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a key and returns its id; adding an existing key is a no-op. `None` for blank keys.
+    pub fn add(&self, key: &str) -> Option<String> {
+        if key.trim().is_empty() {
+            return None;
+        }
+        let id = key_id(key.trim());
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        if !keys.iter().any(|k| k.id == id) {
+            keys.push(Arc::new(PooledKey::new(key)));
+        }
+        Some(id)
+    }
+
+    /// Removes the key with this id; in-flight requests using it finish normally.
+    pub fn remove(&self, id: &str) -> bool {
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        let before = keys.len();
+        keys.retain(|k| k.id != id);
+        keys.len() != before
+    }
+
+    /// Next key in round-robin order, skipping keys that are cooling down. When every key is
+    /// cooling down, the one that was limited least recently (soonest available) is used.
+    pub fn next(&self) -> Option<Arc<PooledKey>> {
+        let keys = self.read();
+        if keys.is_empty() {
+            return None;
+        }
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed) % keys.len();
+        let ordered = keys.iter().cycle().skip(start).take(keys.len());
+        let mut fallback: Option<(Instant, &Arc<PooledKey>)> = None;
+        for key in ordered {
+            match key.cooling_until() {
+                None => {
+                    key.requests.fetch_add(1, Ordering::Relaxed);
+                    return Some(Arc::clone(key));
+                }
+                Some(until) if fallback.is_none_or(|(best, _)| until < best) => {
+                    fallback = Some((until, key));
+                }
+                Some(_) => {}
+            }
+        }
+        fallback.map(|(_, key)| {
+            key.requests.fetch_add(1, Ordering::Relaxed);
+            Arc::clone(key)
+        })
+    }
+
+    /// Records the upstream's answer for `key`: rate limits and auth failures put it on a
+    /// cool-down so the next requests rotate to other keys. Returns whether it did.
+    pub fn report(&self, key: &PooledKey, response: &reqwest::Response) -> bool {
+        let cooldown = match response.status() {
+            StatusCode::TOO_MANY_REQUESTS => response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(RATE_LIMIT_COOLDOWN),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => AUTH_FAILURE_COOLDOWN,
+            _ => return false,
+        };
+        key.failures.fetch_add(1, Ordering::Relaxed);
+        if self.len() > 1 {
+            tracing::warn!(
+                "Upstream key {} returned {}; rotating it out for {}s",
+                key.id,
+                response.status(),
+                cooldown.as_secs()
+            );
+        }
+        key.cool_down(cooldown);
+        true
+    }
+
+    /// Whether every key is cooling down, leaving none the upstream currently accepts.
+    pub fn exhausted(&self) -> bool {
+        let keys = self.read();
+        !keys.is_empty() && keys.iter().all(|k| k.cooling_until().is_some())
+    }
+
+    pub fn status(&self) -> Vec<KeyStatus> {
+        let now = Instant::now();
+        self.read()
+            .iter()
+            .map(|k| KeyStatus {
+                id: k.id.clone(),
+                hint: k.hint.clone(),
+                requests: k.requests.load(Ordering::Relaxed),
+                failures: k.failures.load(Ordering::Relaxed),
+                cooling_down_secs: k
+                    .cooling_until()
+                    .map(|t| t.saturating_duration_since(now).as_secs().max(1))
+                    .unwrap_or(0),
+            })
+            .collect()
+    }
+
+    /// Replaces the keys listed in `old` with those in `new` (both comma/newline-separated),
+    /// leaving keys from other sources alone. Returns how many keys were removed.
+    pub fn swap_keys(&self, old: &str, new: &str) -> usize {
+        let keep: HashSet<String> = split_keys(new).filter_map(|k| self.add(k)).collect();
+        split_keys(old)
+            .map(key_id)
+            .filter(|id| !keep.contains(id) && self.remove(id))
+            .count()
+    }
+
+    /// Polls a secret file and swaps its keys in the pool when the content changes (e.g. a
+    /// rotated Kubernetes secret). Keys from other sources are left alone.
+    pub fn watch_file(self: Arc<Self>, path: PathBuf) {
+        tokio::spawn(async move {
+            let mut current = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+            let mut interval = tokio::time::interval(KEY_FILE_POLL_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let raw = match tokio::fs::read_to_string(&path).await {
+                    Ok(raw) => raw,
+                    Err(e) => {
+                        tracing::warn!("Cannot re-read upstream key file {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if raw == current {
+                    continue;
+                }
+                if split_keys(&raw).next().is_none() {
+                    tracing::warn!("Upstream key file {} is empty; keeping current keys", path.display());
+                    continue;
+                }
+                let removed = self.swap_keys(&current, &raw);
+                tracing::info!(
+                    "Reloaded upstream keys from {} ({} removed)",
+                    path.display(),
+                    removed
+                );
+                current = raw;
+            }
+        });
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Vec<Arc<PooledKey>>> {
+        self.keys.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,170 @@
+//! Latency breakdown metrics: upstream connection setup, upstream time to headers, first
+//! chunk and completion, time to the client's first byte, and CPU time spent translating.
+//! Together they show whether a slow request was waiting on the upstream or on the proxy.
+
+use crate::metrics;
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+/// Bucket bounds for wall-clock latencies, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Bucket bounds for translation CPU time, in seconds.
+const CPU_BUCKETS: &[f64] = &[0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05];
+
+/// The `mode` label: `stream` or `complete`, as on the response-shape metrics.
+pub fn mode(streaming: bool) -> &'static str {
+    if streaming {
+        "stream"
+    } else {
+        "complete"
+    }
+}
+
+/// Records a wall-clock latency histogram labelled by mode.
+pub fn observe(name: &'static str, streaming: bool, elapsed: Duration) {
+    metrics::observe(name, &[("mode", mode(streaming))], LATENCY_BUCKETS, elapsed.as_secs_f64());
+}
+
+/// Records time spent translating a request or response (`direction`).
+pub fn observe_translation(direction: &str, streaming: bool, elapsed: Duration) {
+    metrics::observe(
+        "proxy_translation_seconds",
+        &[("direction", direction), ("mode", mode(streaming))],
+        CPU_BUCKETS,
+        elapsed.as_secs_f64(),
+    );
+}
+
+/// reqwest connector layer timing new connections (DNS, TCP, egress proxy and TLS).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectTimer;
+
+impl<S> Layer<S> for ConnectTimer {
+    type Service = TimedConnect<S>;
+
+    fn layer(&self, inner: S) -> TimedConnect<S> {
+        TimedConnect(inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimedConnect<S>(S);
+
+impl<S, R> Service<R> for TimedConnect<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: R) -> Self::Future {
+        let started = Instant::now();
+        let connecting = self.0.call(dst);
+        Box::pin(async move {
+            let conn = connecting.await;
+            if conn.is_ok() {
+                metrics::observe("proxy_connect_seconds", &[], LATENCY_BUCKETS, started.elapsed().as_secs_f64());
+            } else {
+                metrics::increment("proxy_connect_errors_total", &[], 1);
+            }
+            conn
+        })
+    }
+}
+
+/// Upstream body stream that records when its first chunk arrived and when it ended,
+/// measured from when the request was sent.
+pub struct UpstreamTimer<S> {
+    inner: Pin<Box<S>>,
+    sent: Instant,
+    first_chunk: bool,
+    finished: bool,
+}
+
+impl<S> UpstreamTimer<S> {
+    pub fn new(inner: S, sent: Instant) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            sent,
+            first_chunk: false,
+            finished: false,
+        }
+    }
+}
+
+impl<S: Stream> Stream for UpstreamTimer<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        let poll = this.inner.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(_)) if !this.first_chunk => {
+                this.first_chunk = true;
+                observe("proxy_upstream_first_chunk_seconds", true, this.sent.elapsed());
+            }
+            Poll::Ready(None) if !this.finished => {
+                this.finished = true;
+                observe("proxy_upstream_duration_seconds", true, this.sent.elapsed());
+            }
+            _ => {}
+        }
+        poll
+    }
+}
+
+/// Translated client stream that records the time to its first event (from `received`)
+/// and, once dropped, the CPU time spent inside its polls. Polls never block, so that time
+/// is the proxy's own translation work rather than waiting on the upstream.
+pub struct TranslationTimer<S> {
+    inner: Pin<Box<S>>,
+    received: Instant,
+    busy: Duration,
+    first_event: bool,
+}
+
+impl<S> TranslationTimer<S> {
+    pub fn new(inner: S, received: Instant) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            received,
+            busy: Duration::ZERO,
+            first_event: false,
+        }
+    }
+}
+
+impl<S: Stream> Stream for TranslationTimer<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        let started = Instant::now();
+        let poll = this.inner.as_mut().poll_next(cx);
+        this.busy += started.elapsed();
+        if matches!(poll, Poll::Ready(Some(_))) && !this.first_event {
+            this.first_event = true;
+            observe("proxy_client_ttfb_seconds", true, this.received.elapsed());
+        }
+        poll
+    }
+}
+
+impl<S> Drop for TranslationTimer<S> {
+    fn drop(&mut self) {
+        if self.first_event {
+            observe_translation("response", true, self.busy);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Anthropic Messages API ⇄ OpenAI chat completions translation, as used by the
+//! `anthropic-proxy` server, without the server.
+//!
+//! - [`models`]: serde types for both APIs.
+//! - [`transform`]: request and response conversion.
+//! - [`stream`]: OpenAI stream chunks to Anthropic SSE events, sans I/O.
+//! - `ffi` (`ffi` feature): the converters as C functions, for a `cdylib` build.
+//! - With the `pyo3` feature, a `cdylib` build is also the Python module `anthropic_proxy`.
+//! - `RouterBuilder` (`server` feature): the whole proxy as an axum router, to mount in your own
+//!   application.
+//!
+//! Depend on the crate with `default-features = false` to leave out the server and its
+//! dependencies (axum, reqwest, tokio). That build also targets `wasm32-unknown-unknown`, for
+//! browsers and edge workers; stream coalescing needs a clock and is left out there.
+//!
+//! ```
+//! use anthropic_proxy::models::anthropic::AnthropicRequest;
+//! use anthropic_proxy::transform;
+//!
+//! let req: AnthropicRequest = serde_json::from_str(
+//!     r#"{"model": "gpt-4o", "max_tokens": 64, "messages": [{"role": "user", "content": "Hi"}]}"#,
+//! )?;
+//! let openai_req = transform::anthropic_to_openai(req, &mut ());
+//! assert_eq!(openai_req.messages.len(), 1);
+//! # Ok::<_, serde_json::Error>(())
+//! ```
+
+pub mod coalesce;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod models;
+#[cfg(feature = "pyo3")]
+mod python;
+pub mod stream;
+pub mod transform;
+
+/// Declares the server's modules, which need the `server` feature.
+macro_rules! server_modules {
+    ($($vis:vis mod $name:ident;)*) => {
+        $(
+            #[cfg(feature = "server")]
+            $vis mod $name;
+        )*
+    };
+}
+
+server_modules! {
+    mod access;
+    mod accesslog;
+    mod admin;
+    mod alerts;
+    mod app;
+    mod auth;
+    mod bench;
+    mod cache;
+    mod capabilities;
+    mod capture;
+    mod chaos;
+    mod check;
+    mod cli;
+    mod config;
+    mod configfile;
+    mod conversations;
+    mod cors;
+    mod dns;
+    mod error;
+    mod events;
+    mod http3;
+    mod jwt;
+    mod keypool;
+    mod latency;
+    mod limits;
+    mod listeners;
+    mod logfile;
+    mod metrics;
+    mod mockupstream;
+    mod moderation;
+    mod monitor;
+    mod offline;
+    mod pii;
+    pub mod plugin;
+    mod presets;
+    mod pricing;
+    mod probe;
+    mod proxy;
+    mod quota;
+    mod ratelimit;
+    mod redact;
+    mod reload;
+    mod rewrite;
+    mod router;
+    mod replay;
+    mod runtime;
+    mod script;
+    mod secrets;
+    mod signing;
+    mod slowlog;
+    mod statsd;
+    mod stops;
+    mod summarize;
+    mod sysprompt;
+    mod tap;
+    mod telemetry;
+    mod tls;
+    mod tokens;
+    mod translate;
+    mod truncate;
+    mod transport;
+    mod usagedb;
+    mod usageevents;
+    mod warmup;
+    mod wasmplugin;
+}
+
+#[cfg(feature = "server")]
+pub use app::run;
+#[cfg(feature = "server")]
+pub use config::Config;
+#[cfg(feature = "server")]
+pub use error::ProxyError;
+#[cfg(feature = "server")]
+pub use router::{router, RouterBuilder};
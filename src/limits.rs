@@ -0,0 +1,193 @@
+//! Request size limits: a body-size cap enforced while reading (before JSON parsing), applied
+//! to the decompressed size of `gzip` / `zstd` bodies, optional caps on message and image
+//! counts, and the context-window guard.
+
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::metrics;
+use crate::models::anthropic::{AnthropicRequest, ContentBlock, MessageContent};
+use axum::{
+    body::{Body, Bytes},
+    extract::{
+        rejection::{BytesRejection, FailedToBufferBody},
+        FromRequest, Request,
+    },
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use axum::http::header::{HeaderMap, CONTENT_ENCODING, CONTENT_LENGTH};
+use std::io::Read;
+use std::sync::Arc;
+
+/// Default MAX_REQUEST_BYTES: 32 MiB, the Messages API's own limit.
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 32 * 1024 * 1024;
+
+/// MAX_REQUEST_BYTES / MAX_MESSAGES / MAX_IMAGES / CONTEXT_WINDOW_GUARD.
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub max_body_bytes: usize,
+    pub max_messages: Option<usize>,
+    pub max_images: Option<usize>,
+    /// Reject prompts estimated to exceed the routed model's context window.
+    pub context_window_guard: bool,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_messages: None,
+            max_images: None,
+            context_window_guard: true,
+        }
+    }
+}
+
+impl RequestLimits {
+    /// Rejects a prompt of an estimated `tokens` that cannot fit the context window of upstream
+    /// `model`, as Anthropic would, instead of letting the upstream fail it.
+    pub fn check_context_window(&self, config: &Config, model: &str, tokens: u32) -> ProxyResult<()> {
+        let Some(window) = config.capabilities(model).context_window.filter(|_| self.context_window_guard) else {
+            return Ok(());
+        };
+        if tokens <= window {
+            return Ok(());
+        }
+        tracing::warn!("Prompt of ~{} tokens exceeds the {}-token context window of {}", tokens, window, model);
+        metrics::increment("proxy_context_window_rejections_total", &[("model", model)], 1);
+        Err(ProxyError::ContextWindowExceeded { tokens, window })
+    }
+
+    /// Rejects requests with more messages or image blocks than allowed.
+    pub fn check(&self, req: &AnthropicRequest) -> ProxyResult<()> {
+        if let Some(max) = self.max_messages {
+            if req.messages.len() > max {
+                return Err(ProxyError::Transform(format!(
+                    "Request has {} messages; the maximum is {max}",
+                    req.messages.len()
+                )));
+            }
+        }
+        if let Some(max) = self.max_images {
+            let images = req
+                .messages
+                .iter()
+                .filter_map(|m| match &m.content {
+                    MessageContent::Blocks(blocks) => Some(blocks),
+                    MessageContent::Text(_) => None,
+                })
+                .flatten()
+                .filter(|b| matches!(b, ContentBlock::Image { .. }))
+                .count();
+            if images > max {
+                return Err(ProxyError::Transform(format!(
+                    "Request has {images} images; the maximum is {max}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Middleware for the `/v1` routes: buffer the body up to MAX_REQUEST_BYTES (the route's
+/// `DefaultBodyLimit`) and decompress it if it has a `Content-Encoding`, so oversized uploads
+/// fail with an Anthropic error before any parsing. A compressed body is held to the same
+/// limit once decompressed; later layers (signature checks, the JSON extractor) see plain JSON.
+pub async fn limit_body(
+    Extension(config): Extension<Arc<Config>>,
+    request: Request,
+    next: Next,
+) -> ProxyResult<Response> {
+    let max = config.limits.max_body_bytes;
+    let (mut parts, body) = request.into_parts();
+    let bytes = match Bytes::from_request(Request::from_parts(parts.clone(), body), &()).await {
+        Ok(bytes) => bytes,
+        Err(BytesRejection::FailedToBufferBody(FailedToBufferBody::LengthLimitError(_))) => {
+            tracing::warn!("Rejected request body over {} bytes", max);
+            return Err(too_large(max));
+        }
+        Err(rejection) => return Err(ProxyError::Transform(rejection.body_text())),
+    };
+    let encodings = encodings(&parts.headers)?;
+    let bytes = if encodings.is_empty() {
+        bytes
+    } else {
+        let compressed = bytes.len();
+        let bytes = tokio::task::spawn_blocking(move || decompress(bytes, &encodings, max))
+            .await
+            .map_err(|e| ProxyError::Internal(format!("Decompressing the request body failed: {e}")))??;
+        tracing::debug!("Decompressed request body from {} to {} bytes", compressed, bytes.len());
+        parts.headers.remove(CONTENT_ENCODING);
+        parts.headers.insert(CONTENT_LENGTH, bytes.len().into());
+        bytes
+    };
+    Ok(next.run(Request::from_parts(parts, Body::from(bytes))).await)
+}
+
+fn too_large(max: usize) -> ProxyError {
+    ProxyError::TooLarge(format!("Request body exceeds the maximum of {max} bytes"))
+}
+
+/// A `Content-Encoding` the proxy can undo.
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// The body's encodings in the order they were applied; `identity` is skipped.
+fn encodings(headers: &HeaderMap) -> ProxyResult<Vec<Encoding>> {
+    let mut encodings = Vec::new();
+    for value in headers.get_all(CONTENT_ENCODING) {
+        let value = value
+            .to_str()
+            .map_err(|_| ProxyError::UnsupportedEncoding("Content-Encoding is not valid text".into()))?;
+        for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name.to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" => encodings.push(Encoding::Gzip),
+                "zstd" => encodings.push(Encoding::Zstd),
+                "identity" => {}
+                _ => {
+                    return Err(ProxyError::UnsupportedEncoding(format!(
+                        "Content-Encoding '{name}' is not supported; use gzip or zstd"
+                    )))
+                }
+            }
+        }
+    }
+    Ok(encodings)
+}
+
+/// Undoes `encodings` last to first, reading at most `max` bytes of output from each step.
+fn decompress(mut bytes: Bytes, encodings: &[Encoding], max: usize) -> ProxyResult<Bytes> {
+    for encoding in encodings.iter().rev() {
+        let decoder: Box<dyn Read + '_> = match encoding {
+            Encoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(&bytes[..])),
+            Encoding::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(&bytes[..])
+                    .map_err(|e| ProxyError::Transform(format!("Invalid zstd request body: {e}")))?,
+            ),
+        };
+        let mut decoded = Vec::new();
+        decoder
+            .take(max as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|e| ProxyError::Transform(format!("Invalid {} request body: {e}", encoding.name())))?;
+        if decoded.len() > max {
+            tracing::warn!("Rejected request body over {} bytes after decompression", max);
+            return Err(too_large(max));
+        }
+        bytes = decoded.into();
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,246 @@
+//! `[[listeners]]`: serving on several addresses at once, e.g. plain HTTP on localhost for dev
+//! tools next to HTTPS on a public interface. TCP listeners may terminate TLS with their own
+//! certificate and client CA; `unix:/path` listeners serve plain HTTP on a Unix socket. Each
+//! listener either applies the configured client auth or, with `auth = "none"`, skips it.
+//! Every TCP listener shares one set of socket options (TCP_NODELAY, TCP_KEEPALIVE_* and
+//! LISTEN_BACKLOG).
+
+use crate::tls::{self, TlsSettings};
+use anyhow::Context;
+use axum::{Extension, Router};
+use axum_server::accept::{Accept, DefaultAcceptor};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// Socket options for client connections: TCP_NODELAY, TCP_KEEPALIVE_SECS,
+/// TCP_KEEPALIVE_INTERVAL_SECS, TCP_KEEPALIVE_RETRIES and LISTEN_BACKLOG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketSettings {
+    /// Disables Nagle's algorithm, so each streamed event leaves as soon as it is written.
+    pub nodelay: bool,
+    /// Idle time before the first keepalive probe; `None` leaves keepalive off.
+    pub keepalive: Option<Duration>,
+    /// Time between unanswered keepalive probes.
+    pub keepalive_interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped.
+    pub keepalive_retries: Option<u32>,
+    /// Connections the kernel queues until they are accepted.
+    pub backlog: u32,
+}
+
+impl Default for SocketSettings {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+            backlog: DEFAULT_LISTEN_BACKLOG,
+        }
+    }
+}
+
+impl SocketSettings {
+    /// Binds a listening socket with `backlog`, reusing the address like tokio's own bind.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog.try_into().unwrap_or(i32::MAX))?;
+        Ok(socket.into())
+    }
+
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            #[allow(unused_mut)]
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            // Probe interval and count are not adjustable everywhere; elsewhere the OS defaults apply.
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "windows"))]
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "windows"))]
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies [`SocketSettings`] to each accepted connection, then hands it to `inner` (TLS,
+/// or nothing for plain HTTP).
+#[derive(Debug, Clone)]
+pub struct SocketAcceptor<A = DefaultAcceptor> {
+    settings: SocketSettings,
+    inner: A,
+}
+
+impl SocketAcceptor {
+    pub fn new(settings: SocketSettings) -> Self {
+        Self { settings, inner: DefaultAcceptor::new() }
+    }
+}
+
+impl<A> SocketAcceptor<A> {
+    /// Hands connections to `inner` instead, e.g. the ACME acceptor.
+    #[cfg_attr(not(feature = "acme"), allow(dead_code))]
+    pub fn wrap<B>(self, inner: B) -> SocketAcceptor<B> {
+        SocketAcceptor { settings: self.settings, inner }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for SocketAcceptor<A>
+where
+    A: Accept<TcpStream, S>,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = A::Future;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        if let Err(e) = self.settings.apply(&stream) {
+            tracing::debug!("Setting client socket options failed: {}", e);
+        }
+        self.inner.accept(stream, service)
+    }
+}
+
+/// Client auth on one listener, attached to its requests as an extension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerAuth {
+    /// Client keys, JWTs and client certificates, as configured.
+    #[default]
+    Default,
+    /// No client auth, for listeners only trusted callers can reach.
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    /// `ip:port`, `[ipv6]:port` or `unix:/path/to.sock`.
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        if let Some(path) = value.strip_prefix("unix:") {
+            anyhow::ensure!(!path.is_empty(), "unix: needs a socket path");
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        value
+            .parse()
+            .map(Self::Tcp)
+            .with_context(|| format!("expected ip:port or unix:/path, got '{value}'"))
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub address: ListenAddress,
+    /// HTTPS settings; TCP listeners only.
+    pub tls: Option<TlsSettings>,
+    pub auth: ListenerAuth,
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+        match &self.tls {
+            Some(tls) if tls.client_ca.is_some() => write!(f, " (HTTPS with client certificates)")?,
+            Some(_) => write!(f, " (HTTPS)")?,
+            None => {}
+        }
+        if self.auth == ListenerAuth::None {
+            write!(f, ", no client auth")?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves `app` on every listener; returns when one of them fails.
+pub async fn serve(listeners: &[Listener], sockets: &SocketSettings, app: Router) -> anyhow::Result<()> {
+    let servers = listeners.iter().map(|listener| {
+        let app = app.clone().layer(Extension(listener.auth));
+        async move {
+            match (&listener.address, &listener.tls) {
+                (ListenAddress::Tcp(addr), Some(settings)) => tls::serve(settings, *addr, sockets, app).await,
+                (ListenAddress::Tcp(addr), None) => serve_tcp(*addr, sockets, app).await,
+                (ListenAddress::Unix(path), _) => serve_unix(path, app).await,
+            }
+            .with_context(|| format!("Listener {}", listener.address))
+        }
+    });
+    futures::future::try_join_all(servers).await?;
+    Ok(())
+}
+
+/// Plain HTTP on a TCP address.
+pub async fn serve_tcp(addr: SocketAddr, sockets: &SocketSettings, app: Router) -> anyhow::Result<()> {
+    axum_server::from_tcp(sockets.bind(addr)?)?
+        .acceptor(SocketAcceptor::new(*sockets))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+/// Plain HTTP on a Unix socket. Requests carry no peer address, so IP filtering does not
+/// apply to them.
+#[cfg(unix)]
+async fn serve_unix(path: &Path, app: Router) -> anyhow::Result<()> {
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto::Builder;
+    use hyper_util::service::TowerToHyperService;
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket left behind by a previous run would make bind fail.
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Accepting on unix:{} failed: {}", path.display(), e);
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let connection = Builder::new(TokioExecutor::new());
+            if let Err(e) = connection.serve_connection_with_upgrades(TokioIo::new(stream), service).await {
+                tracing::debug!("Unix socket connection ended: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_path: &Path, _app: Router) -> anyhow::Result<()> {
+    anyhow::bail!("Unix socket listeners are only supported on Unix systems")
+}
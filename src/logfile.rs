@@ -0,0 +1,172 @@
+//! Log files: the diagnostic log can go to LOG_FILE, at its own level and with or without
+//! stdout, and both it and a file ACCESS_LOG rotate by age (LOG_ROTATION) and size
+//! (LOG_MAX_SIZE_MB), keeping LOG_MAX_FILES rotated files no older than LOG_MAX_AGE_DAYS.
+//! Rotated files are renamed to `<file>.<YYYYMMDD-HHMMSS>`; the live file keeps its name.
+
+use chrono::{DateTime, Datelike, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Rotated files kept by default.
+pub const DEFAULT_MAX_LOG_FILES: usize = 7;
+
+/// Age-based rotation (LOG_ROTATION), on UTC hour or day boundaries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "" | "never" | "off" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            other => anyhow::bail!("LOG_ROTATION must be never, hourly or daily, got '{other}'"),
+        }
+    }
+
+    /// The rotation period `at` falls in; a file is rotated when this changes.
+    fn period(self, at: DateTime<Utc>) -> Option<i64> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(at.timestamp().div_euclid(3600)),
+            Rotation::Daily => Some(i64::from(at.num_days_from_ce())),
+        }
+    }
+}
+
+/// LOG_ROTATION / LOG_MAX_SIZE_MB / LOG_MAX_FILES / LOG_MAX_AGE_DAYS.
+#[derive(Debug, Clone)]
+pub struct RotationSettings {
+    pub interval: Rotation,
+    /// Rotate before a write would take the file past this size.
+    pub max_bytes: Option<u64>,
+    pub max_files: usize,
+    /// Rotated files older than this are deleted.
+    pub max_age: Option<Duration>,
+}
+
+/// LOG_FILE / LOG_FILE_LEVEL / LOG_STDOUT and the shared rotation settings.
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    pub stdout: bool,
+    pub file: Option<PathBuf>,
+    /// Filter for the file, as a level or RUST_LOG-style directives; defaults to stdout's.
+    pub file_level: Option<String>,
+    pub rotation: RotationSettings,
+}
+
+/// An append-only file that rotates itself before writes, per [`RotationSettings`].
+pub struct RotatingFile {
+    path: PathBuf,
+    settings: RotationSettings,
+    file: File,
+    size: u64,
+    period: Option<i64>,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, settings: &RotationSettings) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        // A file last written in an earlier period is rotated on the first write.
+        let modified = meta.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+        Ok(Self {
+            path: path.to_path_buf(),
+            settings: settings.clone(),
+            file,
+            size: meta.len(),
+            period: settings.interval.period(modified),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let stamp = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut target = self.rotated_path(&stamp);
+        let mut n = 1;
+        while target.exists() {
+            target = self.rotated_path(&format!("{stamp}.{n}"));
+            n += 1;
+        }
+        fs::rename(&self.path, &target)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.prune()
+    }
+
+    fn rotated_path(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    /// Deletes rotated files beyond LOG_MAX_FILES or older than LOG_MAX_AGE_DAYS.
+    fn prune(&self) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!("{}.", self.path.file_name().unwrap_or_default().to_string_lossy());
+        let mut rotated: Vec<(String, u32, PathBuf)> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let suffix = name.strip_prefix(&prefix)?;
+                let (stamp, n) = match suffix.split_once('.') {
+                    Some((stamp, n)) => (stamp, n.parse().ok()?),
+                    None => (suffix, 0),
+                };
+                Some((stamp.to_string(), n, entry.path()))
+            })
+            .collect();
+        // Stamps sort chronologically, then by same-second counter: the newest files are last.
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.settings.max_files);
+        for (i, (_, _, path)) in rotated.iter().enumerate() {
+            let expired = self.settings.max_age.is_some_and(|max_age| {
+                fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .is_ok_and(|modified| SystemTime::now().duration_since(modified).unwrap_or_default() > max_age)
+            });
+            if i < excess || expired {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.settings.interval.period(Utc::now());
+        let too_big = self
+            .settings
+            .max_bytes
+            .is_some_and(|max| self.size > 0 && self.size + buf.len() as u64 > max);
+        if period != self.period || too_big {
+            self.period = period;
+            // Not through tracing: this may be the diagnostic log's own writer.
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
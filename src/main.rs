@@ -1,230 +1,3 @@
-mod cli;
-mod config;
-mod error;
-mod models;
-mod proxy;
-mod transform;
-
-use axum::{
-    routing::post,
-    Extension, Router,
-};
-use clap::Parser;
-use cli::{Cli, Command};
-use config::Config;
-use daemonize::Daemonize;
-use reqwest::Client;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tower_http::{
-    cors::{Any, CorsLayer},
-    trace::TraceLayer,
-};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-
-    if let Some(command) = cli.command {
-        match command {
-            Command::Stop { pid_file } => {
-                stop_daemon(&pid_file)?;
-                return Ok(());
-            }
-            Command::Status { pid_file } => {
-                check_status(&pid_file)?;
-                return Ok(());
-            }
-        }
-    }
-    
-    if cli.daemon {
-        use std::fs::OpenOptions;
-        
-        let stdout = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("/tmp/anthropic-proxy.log")?;
-        
-        let stderr = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("/tmp/anthropic-proxy.log")?;
-
-        let daemonize = Daemonize::new()
-            .pid_file(&cli.pid_file)
-            .working_directory(std::env::current_dir()?)
-            .stdout(stdout)
-            .stderr(stderr)
-            .umask(0o027);
-
-        match daemonize.start() {
-            Ok(_) => {},
-            Err(e) => {
-                eprintln!("✗ Failed to daemonize: {}", e);
-                std::process::exit(1);
-            }
-        }
-    } else {
-        eprintln!("✓ Starting proxy in foreground mode");
-    }
-
-    let runtime = tokio::runtime::Runtime::new()?;
-    runtime.block_on(async_main(cli))
-}
-
-async fn async_main(cli: Cli) -> anyhow::Result<()> {
-    let mut config = Config::from_env_with_path(cli.config)?;
-
-    if cli.debug {
-        config.debug = true;
-    }
-    if cli.verbose {
-        config.verbose = true;
-    }
-    if let Some(port) = cli.port {
-        config.port = port;
-    }
-
-    let log_level = if config.verbose {
-        tracing::Level::TRACE
-    } else if config.debug {
-        tracing::Level::DEBUG
-    } else {
-        tracing::Level::INFO
-    };
-
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("anthropic_proxy={}", log_level).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    tracing::info!("Starting Anthropic Proxy v{}", env!("CARGO_PKG_VERSION"));
-    tracing::info!("Port: {}", config.port);
-    tracing::info!("Upstream URL: {}", config.base_url);
-    if let Some(ref model) = config.reasoning_model {
-        tracing::info!("Reasoning Model Override: {}", model);
-    }
-    if let Some(ref model) = config.completion_model {
-        tracing::info!("Completion Model Override: {}", model);
-    }
-    if config.auth_header_value.is_some() {
-        tracing::info!("API Key: configured");
-    } else {
-        tracing::info!("API Key: not set (using unauthenticated endpoint)");
-    }
-
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(300))
-        .connect_timeout(std::time::Duration::from_secs(10))
-        .pool_max_idle_per_host(10)
-        .build()?;
-
-    let config = Arc::new(config);
-
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    let app = Router::new()
-        .route("/v1/messages", post(proxy::proxy_handler))
-        .route("/health", axum::routing::get(health_handler))
-        .layer(Extension(Arc::clone(&config)))
-        .layer(Extension(client))
-        .layer(TraceLayer::new_for_http())
-        .layer(cors);
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-
-    tracing::info!("Listening on {}", addr);
-    tracing::info!("Proxy ready to accept requests");
-
-    axum::serve(listener, app).await?;
-
-    Ok(())
-}
-
-async fn health_handler() -> &'static str {
-    "OK"
-}
-
-fn stop_daemon(pid_file: &std::path::Path) -> anyhow::Result<()> {
-    if !pid_file.exists() {
-        eprintln!("✗ PID file not found: {}", pid_file.display());
-        eprintln!("  Daemon is not running or PID file was removed");
-        std::process::exit(1);
-    }
-
-    let pid_str = std::fs::read_to_string(pid_file)?;
-    let pid: i32 = pid_str.trim().parse()
-        .map_err(|_| anyhow::anyhow!("Invalid PID in file: {}", pid_str))?;
-
-    #[cfg(unix)]
-    {
-        use std::process::Command;
-        let output = Command::new("kill")
-            .arg(pid.to_string())
-            .output()?;
-
-        if output.status.success() {
-            std::fs::remove_file(pid_file)?;
-            eprintln!("✓ Daemon stopped (PID: {})", pid);
-        } else {
-            eprintln!("✗ Failed to stop daemon (PID: {})", pid);
-            eprintln!("  Process may have already exited");
-            std::fs::remove_file(pid_file)?;
-            std::process::exit(1);
-        }
-    }
-
-    #[cfg(not(unix))]
-    {
-        eprintln!("✗ Daemon stop is only supported on Unix systems");
-        std::process::exit(1);
-    }
-
-    Ok(())
-}
-
-fn check_status(pid_file: &std::path::Path) -> anyhow::Result<()> {
-    if !pid_file.exists() {
-        eprintln!("✗ Daemon is not running");
-        eprintln!("  PID file not found: {}", pid_file.display());
-        std::process::exit(1);
-    }
-
-    let pid_str = std::fs::read_to_string(pid_file)?;
-    let pid: i32 = pid_str.trim().parse()
-        .map_err(|_| anyhow::anyhow!("Invalid PID in file: {}", pid_str))?;
-
-    #[cfg(unix)]
-    {
-        use std::process::Command;
-        let output = Command::new("ps")
-            .arg("-p")
-            .arg(pid.to_string())
-            .output()?;
-
-        if output.status.success() {
-            eprintln!("✓ Daemon is running (PID: {})", pid);
-            eprintln!("  PID file: {}", pid_file.display());
-        } else {
-            eprintln!("✗ Daemon is not running");
-            eprintln!("  Stale PID file found: {} (PID: {})", pid_file.display(), pid);
-            std::process::exit(1);
-        }
-    }
-
-    #[cfg(not(unix))]
-    {
-        eprintln!("✗ Daemon status check is only supported on Unix systems");
-        std::process::exit(1);
-    }
-
-    Ok(())
+    anthropic_proxy::run(anthropic_proxy::plugin::Plugins::new())
 }
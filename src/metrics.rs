@@ -0,0 +1,163 @@
+//! Process-wide metrics registry rendered in the Prometheus text exposition format.
+//!
+//! Metrics are recorded through free functions so deep code (transform, stream translation)
+//! can instrument itself without threading a handle through every call. With STATSD_ADDR
+//! set, each recording is also forwarded to StatsD.
+
+use crate::statsd::{Kind, StatsdSink};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+
+type Labels = Vec<(&'static str, String)>;
+
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+    Histogram(Histogram),
+}
+
+struct Histogram {
+    /// Upper bounds of the buckets, ascending; `+Inf` is implicit.
+    bounds: &'static [f64],
+    /// Non-cumulative count per bucket, plus one for `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Registry {
+    series: BTreeMap<(&'static str, Labels), Value>,
+}
+
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+static STATSD: OnceLock<StatsdSink> = OnceLock::new();
+
+/// Forwards every later recording to StatsD as well.
+pub fn export_to_statsd(sink: StatsdSink) {
+    let _ = STATSD.set(sink);
+}
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY
+        .get_or_init(|| Mutex::new(Registry::default()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+fn labels(pairs: &[(&'static str, &str)]) -> Labels {
+    pairs.iter().map(|(k, v)| (*k, (*v).to_string())).collect()
+}
+
+/// Adds `by` to a counter.
+pub fn increment(name: &'static str, pairs: &[(&'static str, &str)], by: u64) {
+    if let Some(statsd) = STATSD.get() {
+        statsd.record(name, pairs, Kind::Counter, by as f64);
+    }
+    let mut reg = registry();
+    let value = reg
+        .series
+        .entry((name, labels(pairs)))
+        .or_insert(Value::Counter(0));
+    if let Value::Counter(c) = value {
+        *c += by;
+    }
+}
+
+/// Sets a gauge to an absolute value.
+pub fn set_gauge(name: &'static str, pairs: &[(&'static str, &str)], v: f64) {
+    if let Some(statsd) = STATSD.get() {
+        statsd.record(name, pairs, Kind::Gauge, v);
+    }
+    registry()
+        .series
+        .insert((name, labels(pairs)), Value::Gauge(v));
+}
+
+/// Records `v` in a histogram with the given bucket upper bounds.
+pub fn observe(name: &'static str, pairs: &[(&'static str, &str)], bounds: &'static [f64], v: f64) {
+    if let Some(statsd) = STATSD.get() {
+        statsd.record(name, pairs, Kind::Histogram, v);
+    }
+    let mut reg = registry();
+    let value = reg.series.entry((name, labels(pairs))).or_insert_with(|| {
+        Value::Histogram(Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        })
+    });
+    if let Value::Histogram(h) = value {
+        let bucket = h.bounds.iter().position(|&b| v <= b).unwrap_or(h.bounds.len());
+        h.counts[bucket] += 1;
+        h.sum += v;
+    }
+}
+
+fn write_labels(out: &mut String, labels: &Labels) {
+    if labels.is_empty() {
+        return;
+    }
+    out.push('{');
+    let mut first = true;
+    for (k, v) in labels {
+        if !first {
+            out.push(',');
+        }
+        first = false;
+        let escaped = v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        let _ = write!(out, "{k}=\"{escaped}\"");
+    }
+    out.push('}');
+}
+
+/// Renders every series in Prometheus text format.
+pub fn render() -> String {
+    let reg = registry();
+    let mut out = String::new();
+    let mut last_name = "";
+
+    for ((name, labels), value) in &reg.series {
+        if *name != last_name {
+            let type_name = match value {
+                Value::Counter(_) => "counter",
+                Value::Gauge(_) => "gauge",
+                Value::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# TYPE {name} {type_name}");
+            last_name = name;
+        }
+        match value {
+            Value::Counter(c) => {
+                out.push_str(name);
+                write_labels(&mut out, labels);
+                let _ = writeln!(out, " {c}");
+            }
+            Value::Gauge(g) => {
+                out.push_str(name);
+                write_labels(&mut out, labels);
+                let _ = writeln!(out, " {g}");
+            }
+            Value::Histogram(h) => {
+                let mut cumulative = 0;
+                for (i, count) in h.counts.iter().enumerate() {
+                    cumulative += count;
+                    let le = h.bounds.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+                    let mut bucket_labels = labels.clone();
+                    bucket_labels.push(("le", le));
+                    let _ = write!(out, "{name}_bucket");
+                    write_labels(&mut out, &bucket_labels);
+                    let _ = writeln!(out, " {cumulative}");
+                }
+                let _ = write!(out, "{name}_sum");
+                write_labels(&mut out, labels);
+                let _ = writeln!(out, " {}", h.sum);
+                let _ = write!(out, "{name}_count");
+                write_labels(&mut out, labels);
+                let _ = writeln!(out, " {cumulative}");
+            }
+        }
+    }
+    out
+}
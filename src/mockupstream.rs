@@ -0,0 +1,302 @@
+//! Built-in mock upstream: an upstream whose base URL is `mock://...` (`--mock-upstream` sets
+//! UPSTREAM_BASE_URL to [`DEFAULT_MOCK_UPSTREAM_URL`]) is answered in process with canned OpenAI
+//! chat completions, so client integrations and the proxy's own translation and streaming can
+//! be tested without a network or an API key.
+//!
+//! Replies are deterministic: the first entry of MOCK_UPSTREAM_SCRIPT (a JSON array) matching
+//! the request's last message and model, else a fixed text. Entries can return text, tool
+//! calls or an error status. MOCK_UPSTREAM_LATENCY_MS delays the response headers and
+//! MOCK_UPSTREAM_CHUNK_DELAY_MS every stream chunk. The response goes through the same key
+//! pool, translation and stream handling as one read off the network.
+
+use crate::models::openai::{ContentPart, MessageContent, OpenAIRequest};
+use anyhow::Context;
+use axum::http::{self, header, StatusCode};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub const MOCK_SCHEME: &str = "mock";
+pub const DEFAULT_MOCK_UPSTREAM_URL: &str = "mock://upstream";
+const DEFAULT_TEXT: &str = "Hello! This is a canned response from the proxy's mock upstream.";
+const RESPONSE_ID: &str = "chatcmpl-mock";
+/// Tool call arguments are streamed in pieces of this many bytes.
+const ARGUMENTS_PIECE: usize = 32;
+
+/// MOCK_UPSTREAM_SCRIPT / MOCK_UPSTREAM_LATENCY_MS / MOCK_UPSTREAM_CHUNK_DELAY_MS.
+#[derive(Debug, Clone, Default)]
+pub struct MockSettings {
+    /// Scripted replies, first match wins.
+    pub responses: Vec<MockResponse>,
+    /// Delay before the response headers.
+    pub latency: Duration,
+    /// Delay before each stream chunk.
+    pub chunk_delay: Duration,
+}
+
+/// One scripted reply.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockResponse {
+    /// Text the request's last message must contain; every request matches without one.
+    #[serde(rename = "match")]
+    pub pattern: Option<String>,
+    /// Upstream model the request must be for.
+    pub model: Option<String>,
+    pub text: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<MockToolCall>,
+    /// `tool_calls` when the reply calls tools, else `stop`.
+    pub finish_reason: Option<String>,
+    /// Answer with this error status (and `error` as the message) instead.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    /// Replaces MOCK_UPSTREAM_LATENCY_MS for this reply.
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockToolCall {
+    pub name: String,
+    /// A JSON object, or a string sent verbatim (to test malformed arguments).
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+impl MockSettings {
+    /// Reads the scripted replies at `script`, if any.
+    pub fn load(script: Option<&Path>, latency: Duration, chunk_delay: Duration) -> anyhow::Result<Self> {
+        let responses = match script {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .with_context(|| format!("Cannot read MOCK_UPSTREAM_SCRIPT {}", path.display()))?;
+                let responses: Vec<MockResponse> = serde_json::from_str(&raw)
+                    .with_context(|| format!("Invalid MOCK_UPSTREAM_SCRIPT {}", path.display()))?;
+                for (i, response) in responses.iter().enumerate() {
+                    if let Some(status) = response.status {
+                        anyhow::ensure!(
+                            (400..=599).contains(&status),
+                            "MOCK_UPSTREAM_SCRIPT entry {} has status {}; only error statuses (400-599) are allowed",
+                            i + 1,
+                            status
+                        );
+                    }
+                }
+                responses
+            }
+            None => Vec::new(),
+        };
+        Ok(Self {
+            responses,
+            latency,
+            chunk_delay,
+        })
+    }
+}
+
+impl MockResponse {
+    fn matches(&self, model: &str, last_message: &str) -> bool {
+        self.model.as_deref().is_none_or(|m| m == model)
+            && self.pattern.as_deref().is_none_or(|p| last_message.contains(p))
+    }
+}
+
+static SETTINGS: OnceLock<MockSettings> = OnceLock::new();
+
+/// Makes `settings` the script of every mock upstream.
+pub fn install(settings: MockSettings) {
+    let _ = SETTINGS.set(settings);
+}
+
+/// Whether `base_url` names the mock upstream.
+pub fn is_mock(base_url: &str) -> bool {
+    base_url
+        .split_once(':')
+        .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case(MOCK_SCHEME))
+}
+
+/// The mock upstream's answer to `req`, as if it had come over HTTP.
+pub async fn respond(req: &OpenAIRequest) -> reqwest::Response {
+    let settings = SETTINGS.get_or_init(MockSettings::default);
+    let last_message = req.messages.last().map(|m| content_text(m.content.as_ref())).unwrap_or_default();
+    let reply = settings.responses.iter().find(|r| r.matches(&req.model, &last_message));
+    let latency = reply
+        .and_then(|r| r.latency_ms)
+        .map_or(settings.latency, Duration::from_millis);
+    if !latency.is_zero() {
+        tokio::time::sleep(latency).await;
+    }
+
+    if let Some(status) = reply.and_then(|r| r.status) {
+        let message = reply.and_then(|r| r.error.as_deref()).unwrap_or("Mock upstream error");
+        let body = json!({ "error": { "message": message, "type": "mock_error", "code": status } });
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        return response(status, "application/json", body.to_string());
+    }
+
+    let tool_calls: Vec<(String, String, String)> = reply
+        .map(|r| r.tool_calls.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+        .map(|(i, call)| {
+            let arguments = match &call.arguments {
+                Value::Null => "{}".to_string(),
+                Value::String(raw) => raw.clone(),
+                other => other.to_string(),
+            };
+            (format!("call_mock_{i}"), call.name.clone(), arguments)
+        })
+        .collect();
+    let text = match reply {
+        Some(reply) if reply.text.is_some() || !tool_calls.is_empty() => reply.text.clone(),
+        _ => Some(DEFAULT_TEXT.to_string()),
+    };
+    let finish_reason = reply
+        .and_then(|r| r.finish_reason.clone())
+        .unwrap_or_else(|| if tool_calls.is_empty() { "stop" } else { "tool_calls" }.to_string());
+    let prompt_chars: usize = req.messages.iter().map(|m| content_text(m.content.as_ref()).len()).sum();
+    let completion_chars = text.as_deref().map_or(0, str::len)
+        + tool_calls.iter().map(|(_, name, args)| name.len() + args.len()).sum::<usize>();
+    let usage = json!({
+        "prompt_tokens": estimate_tokens(prompt_chars),
+        "completion_tokens": estimate_tokens(completion_chars),
+        "total_tokens": estimate_tokens(prompt_chars) + estimate_tokens(completion_chars),
+    });
+
+    if !req.stream.unwrap_or(false) {
+        let tool_calls: Vec<Value> = tool_calls
+            .iter()
+            .map(|(id, name, arguments)| {
+                json!({ "id": id, "type": "function", "function": { "name": name, "arguments": arguments } })
+            })
+            .collect();
+        let body = json!({
+            "id": RESPONSE_ID,
+            "object": "chat.completion",
+            "created": 0,
+            "model": req.model,
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": text,
+                    "tool_calls": (!tool_calls.is_empty()).then_some(tool_calls),
+                },
+                "finish_reason": finish_reason,
+            }],
+            "usage": usage,
+        });
+        return response(StatusCode::OK, "application/json", body.to_string());
+    }
+
+    let chunk = |delta: Value, finish_reason: Option<&str>| {
+        json!({
+            "id": RESPONSE_ID,
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": req.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    };
+    let mut chunks = vec![chunk(json!({ "role": "assistant", "content": "" }), None)];
+    for word in text.as_deref().unwrap_or_default().split_inclusive(' ') {
+        chunks.push(chunk(json!({ "content": word }), None));
+    }
+    for (index, (id, name, arguments)) in tool_calls.iter().enumerate() {
+        chunks.push(chunk(
+            json!({ "tool_calls": [{
+                "index": index,
+                "id": id,
+                "type": "function",
+                "function": { "name": name, "arguments": "" },
+            }] }),
+            None,
+        ));
+        for piece in pieces(arguments, ARGUMENTS_PIECE) {
+            chunks.push(chunk(
+                json!({ "tool_calls": [{ "index": index, "function": { "arguments": piece } }] }),
+                None,
+            ));
+        }
+    }
+    chunks.push(chunk(json!({}), Some(&finish_reason)));
+    let include_usage = req
+        .stream_options
+        .as_ref()
+        .and_then(|o| o.get("include_usage"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if include_usage {
+        chunks.push(json!({
+            "id": RESPONSE_ID,
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": req.model,
+            "choices": [],
+            "usage": usage,
+        }));
+    }
+
+    let mut events: Vec<String> = chunks.iter().map(|chunk| format!("data: {chunk}\n\n")).collect();
+    events.push("data: [DONE]\n\n".to_string());
+    let delay = settings.chunk_delay;
+    let body = futures::stream::iter(events).then(move |event| async move {
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok::<_, Infallible>(Bytes::from(event))
+    });
+    response(StatusCode::OK, "text/event-stream", reqwest::Body::wrap_stream(body))
+}
+
+fn response(status: StatusCode, content_type: &'static str, body: impl Into<reqwest::Body>) -> reqwest::Response {
+    let mut response = http::Response::new(body.into());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static(content_type));
+    reqwest::Response::from(response)
+}
+
+fn content_text(content: Option<&MessageContent>) -> String {
+    match content {
+        Some(MessageContent::Text(text)) => text.clone(),
+        Some(MessageContent::Parts(parts)) => parts
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::Text { text } => Some(text.as_str()),
+                ContentPart::ImageUrl { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    }
+}
+
+/// Roughly four characters a token, at least one.
+fn estimate_tokens(chars: usize) -> usize {
+    chars.div_ceil(4).max(1)
+}
+
+/// `text` split into pieces of about `size` bytes, on character boundaries.
+fn pieces(text: &str, size: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+    pieces
+}
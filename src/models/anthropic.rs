@@ -1,5 +1,8 @@
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use serde_json::Value;
+use std::fmt;
 
 /// Anthropic API request structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +30,25 @@ pub struct AnthropicRequest {
     pub extra: Value,
 }
 
+/// Request body for `/v1/messages/count_tokens` (a Messages request without `max_tokens`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CountTokensRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemPrompt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(flatten)]
+    pub extra: Value,
+}
+
+/// Response body for `/v1/messages/count_tokens`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CountTokensResponse {
+    pub input_tokens: u32,
+}
+
 /// System prompt can be a string or array of strings
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
@@ -52,16 +74,50 @@ pub struct Message {
 }
 
 /// Message content can be a string or array of content blocks
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum MessageContent {
     Text(String),
     Blocks(Vec<ContentBlock>),
 }
 
+// Deserialized by hand: `untagged` buffers the whole value first, which the `RawValue` tool
+// inputs inside the blocks cannot be read from.
+impl<'de> Deserialize<'de> for MessageContent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ContentVisitor;
+
+        impl<'de> Visitor<'de> for ContentVisitor {
+            type Value = MessageContent;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or an array of content blocks")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<MessageContent, E> {
+                Ok(MessageContent::Text(text.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, text: String) -> Result<MessageContent, E> {
+                Ok(MessageContent::Text(text))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MessageContent, A::Error> {
+                let mut blocks = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(block) = seq.next_element()? {
+                    blocks.push(block);
+                }
+                Ok(MessageContent::Blocks(blocks))
+            }
+        }
+
+        deserializer.deserialize_any(ContentVisitor)
+    }
+}
+
 /// Content block types
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[serde(tag = "type", try_from = "RawContentBlock")]
 pub enum ContentBlock {
     #[serde(rename = "text")]
     Text {
@@ -73,11 +129,12 @@ pub enum ContentBlock {
     Image {
         source: ImageSource,
     },
+    /// `input` is forwarded as the tool call's arguments without being parsed.
     #[serde(rename = "tool_use")]
     ToolUse {
         id: String,
         name: String,
-        input: Value,
+        input: Box<RawValue>,
     },
     #[serde(rename = "tool_result")]
     ToolResult {
@@ -92,6 +149,63 @@ pub enum ContentBlock {
     },
 }
 
+/// Every field of every content block. Being a plain struct, it reads `input` straight into
+/// a `RawValue`, which an internally tagged enum cannot.
+#[derive(Deserialize)]
+struct RawContentBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    text: Option<String>,
+    cache_control: Option<Value>,
+    source: Option<ImageSource>,
+    id: Option<String>,
+    name: Option<String>,
+    input: Option<Box<RawValue>>,
+    tool_use_id: Option<String>,
+    content: Option<String>,
+    is_error: Option<bool>,
+    thinking: Option<String>,
+}
+
+/// `value`, or serde's usual error for a missing field.
+fn required<T>(value: Option<T>, field: &str) -> Result<T, String> {
+    value.ok_or_else(|| format!("missing field `{field}`"))
+}
+
+impl TryFrom<RawContentBlock> for ContentBlock {
+    type Error = String;
+
+    fn try_from(raw: RawContentBlock) -> Result<Self, String> {
+        Ok(match raw.block_type.as_str() {
+            "text" => ContentBlock::Text {
+                text: required(raw.text, "text")?,
+                cache_control: raw.cache_control,
+            },
+            "image" => ContentBlock::Image {
+                source: required(raw.source, "source")?,
+            },
+            "tool_use" => ContentBlock::ToolUse {
+                id: required(raw.id, "id")?,
+                name: required(raw.name, "name")?,
+                input: required(raw.input, "input")?,
+            },
+            "tool_result" => ContentBlock::ToolResult {
+                tool_use_id: required(raw.tool_use_id, "tool_use_id")?,
+                content: required(raw.content, "content")?,
+                is_error: raw.is_error,
+            },
+            "thinking" => ContentBlock::Thinking {
+                thinking: required(raw.thinking, "thinking")?,
+            },
+            other => {
+                return Err(format!(
+                    "unknown variant `{other}`, expected one of `text`, `image`, `tool_use`, `tool_result`, `thinking`"
+                ))
+            }
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageSource {
     #[serde(rename = "type")]
@@ -126,7 +240,7 @@ pub struct AnthropicResponse {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(untagged, try_from = "RawResponseContent")]
 pub enum ResponseContent {
     Text {
         #[serde(rename = "type")]
@@ -138,7 +252,8 @@ pub enum ResponseContent {
         content_type: String,
         id: String,
         name: String,
-        input: Value,
+        /// The upstream's tool call arguments, checked to be JSON but not parsed into a tree.
+        input: Box<RawValue>,
     },
     Thinking {
         #[serde(rename = "type")]
@@ -147,6 +262,42 @@ pub enum ResponseContent {
     },
 }
 
+/// Every field of every response content block, for the same reason as [`RawContentBlock`].
+#[derive(Deserialize)]
+struct RawResponseContent {
+    #[serde(rename = "type")]
+    content_type: String,
+    text: Option<String>,
+    id: Option<String>,
+    name: Option<String>,
+    input: Option<Box<RawValue>>,
+    thinking: Option<String>,
+}
+
+impl TryFrom<RawResponseContent> for ResponseContent {
+    type Error = String;
+
+    fn try_from(raw: RawResponseContent) -> Result<Self, String> {
+        Ok(match raw.content_type.as_str() {
+            "text" => ResponseContent::Text {
+                text: required(raw.text, "text")?,
+                content_type: raw.content_type,
+            },
+            "tool_use" => ResponseContent::ToolUse {
+                id: required(raw.id, "id")?,
+                name: required(raw.name, "name")?,
+                input: required(raw.input, "input")?,
+                content_type: raw.content_type,
+            },
+            "thinking" => ResponseContent::Thinking {
+                thinking: required(raw.thinking, "thinking")?,
+                content_type: raw.content_type,
+            },
+            other => return Err(format!("unknown content type `{other}`")),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
     pub input_tokens: u32,
@@ -154,6 +305,7 @@ pub struct Usage {
 }
 
 /// Streaming event types
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum StreamEvent {
@@ -199,6 +351,7 @@ pub enum ContentBlockStart {
     Thinking { thinking: String },
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Delta {
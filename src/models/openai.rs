@@ -20,6 +20,8 @@ pub struct OpenAIRequest {
     pub tools: Option<Vec<Tool>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,6 +59,8 @@ pub struct ImageUrl {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolCall {
+    /// Empty when the upstream left it out.
+    #[serde(default)]
     pub id: String,
     #[serde(rename = "type")]
     pub call_type: String,
@@ -155,6 +159,8 @@ pub struct Delta {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeltaToolCall {
+    /// 0 when the upstream leaves it out.
+    #[serde(default)]
     pub index: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<String>,
@@ -0,0 +1,199 @@
+//! Optional content moderation pre-check: the latest user turn is POSTed to an
+//! OpenAI-compatible moderation endpoint (OpenAI `/v1/moderations` or a local classifier
+//! speaking the same schema) before the request goes upstream.
+
+use crate::error::{ProxyError, ProxyResult};
+use crate::metrics;
+use crate::models::anthropic::{
+    AnthropicRequest, AnthropicResponse, ContentBlock, MessageContent, ResponseContent, Usage,
+};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Response header reporting `blocked` or `flagged` moderation outcomes.
+pub const MODERATION_HEADER: &str = "x-proxy-moderation";
+
+const MODERATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Text of the refusal returned for blocked requests.
+const REFUSAL_TEXT: &str = "I can't help with that request.";
+
+/// What happens to a request the endpoint flags (MODERATION_ACTION).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    /// Answer with a refusal instead of calling upstream.
+    Block,
+    /// Forward it, but log and tag the response with `x-proxy-moderation: flagged`.
+    Flag,
+}
+
+/// MODERATION_* settings; enabled when MODERATION_URL is set.
+#[derive(Debug, Clone)]
+pub struct ModerationSettings {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub model: Option<String>,
+    /// Flag when any category score reaches this; unset trusts the endpoint's `flagged`.
+    pub threshold: Option<f64>,
+    pub action: ModerationAction,
+    /// Reject requests when the endpoint is unreachable instead of letting them through.
+    pub fail_closed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    #[serde(default)]
+    flagged: bool,
+    #[serde(default)]
+    categories: HashMap<String, bool>,
+    #[serde(default)]
+    category_scores: HashMap<String, f64>,
+}
+
+/// Outcome of a moderation check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Passed,
+    /// Flagged categories (sorted); the action decides whether the request proceeds.
+    Flagged(Vec<String>),
+}
+
+pub struct Moderator {
+    settings: ModerationSettings,
+    client: Client,
+}
+
+impl Moderator {
+    pub fn new(settings: ModerationSettings, client: Client) -> Self {
+        Self { settings, client }
+    }
+
+    pub fn action(&self) -> ModerationAction {
+        self.settings.action
+    }
+
+    /// Classifies `input`. Endpoint failures pass the request unless MODERATION_FAIL_CLOSED.
+    pub async fn check(&self, input: &str) -> ProxyResult<Verdict> {
+        if input.trim().is_empty() {
+            return Ok(Verdict::Passed);
+        }
+        let verdict = match self.classify(input).await {
+            Ok(verdict) => verdict,
+            Err(e) if self.settings.fail_closed => {
+                metrics::increment("proxy_moderation_checks_total", &[("result", "error")], 1);
+                tracing::error!("Moderation check failed, rejecting request: {:#}", e);
+                return Err(ProxyError::Upstream("Content moderation is unavailable".to_string()));
+            }
+            Err(e) => {
+                metrics::increment("proxy_moderation_checks_total", &[("result", "error")], 1);
+                tracing::warn!("Moderation check failed, allowing request: {:#}", e);
+                return Ok(Verdict::Passed);
+            }
+        };
+        let result = match (&verdict, self.settings.action) {
+            (Verdict::Passed, _) => "passed",
+            (Verdict::Flagged(_), ModerationAction::Block) => "blocked",
+            (Verdict::Flagged(_), ModerationAction::Flag) => "flagged",
+        };
+        metrics::increment("proxy_moderation_checks_total", &[("result", result)], 1);
+        Ok(verdict)
+    }
+
+    async fn classify(&self, input: &str) -> anyhow::Result<Verdict> {
+        let mut body = json!({ "input": input });
+        if let Some(model) = &self.settings.model {
+            body["model"] = json!(model);
+        }
+        let mut request = self
+            .client
+            .post(&self.settings.url)
+            .timeout(MODERATION_TIMEOUT)
+            .json(&body);
+        if let Some(key) = &self.settings.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response: ModerationResponse = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())?
+            .json()
+            .await?;
+        let Some(result) = response.results.into_iter().next() else {
+            anyhow::bail!("moderation response has no results");
+        };
+        let mut categories: Vec<String> = match self.settings.threshold {
+            Some(threshold) => result
+                .category_scores
+                .into_iter()
+                .filter(|(_, score)| *score >= threshold)
+                .map(|(category, _)| category)
+                .collect(),
+            None if result.flagged => result
+                .categories
+                .into_iter()
+                .filter(|(_, hit)| *hit)
+                .map(|(category, _)| category)
+                .collect(),
+            None => Vec::new(),
+        };
+        if categories.is_empty() && result.flagged && self.settings.threshold.is_none() {
+            categories.push("flagged".to_string());
+        }
+        categories.sort();
+        Ok(if categories.is_empty() {
+            Verdict::Passed
+        } else {
+            Verdict::Flagged(categories)
+        })
+    }
+}
+
+/// Text the user wrote in the latest user turn (tool results are not moderated).
+pub fn latest_user_text(req: &AnthropicRequest) -> String {
+    let Some(message) = req.messages.iter().rev().find(|m| m.role == "user") else {
+        return String::new();
+    };
+    match &message.content {
+        MessageContent::Text(text) => text.clone(),
+        MessageContent::Blocks(blocks) => blocks
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Anthropic-style refusal for a blocked request.
+pub fn refusal(model: &str) -> AnthropicResponse {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    AnthropicResponse {
+        id: format!("msg_moderation_{nanos:x}"),
+        response_type: "message".to_string(),
+        role: "assistant".to_string(),
+        content: vec![ResponseContent::Text {
+            content_type: "text".to_string(),
+            text: REFUSAL_TEXT.to_string(),
+        }],
+        model: model.to_string(),
+        stop_reason: Some("refusal".to_string()),
+        stop_sequence: None,
+        usage: Usage {
+            input_tokens: 0,
+            output_tokens: 0,
+        },
+    }
+}
@@ -0,0 +1,334 @@
+//! `anthropic-proxy monitor`: follows a running proxy's `/admin/tap` and shows live requests,
+//! request and token rates, and recent errors in the terminal (`monitor` feature).
+
+pub use imp::run;
+
+#[cfg(feature = "monitor")]
+mod imp {
+    use crate::tap::TapEvent;
+    use futures::StreamExt;
+    use memchr::memmem;
+    use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::style::{Color, Modifier, Style, Stylize};
+    use ratatui::text::{Line, Span};
+    use ratatui::widgets::{Block, List, ListItem, Paragraph, Row, Sparkline, Table};
+    use ratatui::Frame;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Requests kept in the live table.
+    const RECENT: usize = 200;
+    /// Errors kept in the error feed.
+    const ERRORS: usize = 50;
+    /// Seconds of history behind the rates and the throughput sparkline.
+    const HISTORY_SECS: u64 = 60;
+    /// Seconds the request and token rates average over.
+    const RATE_SECS: u64 = 10;
+    const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+    #[derive(Default)]
+    struct State {
+        connected: bool,
+        status: String,
+        recent: VecDeque<TapEvent>,
+        errors: VecDeque<TapEvent>,
+        requests: u64,
+        failed: u64,
+        input_tokens: u64,
+        output_tokens: u64,
+        cost_usd: f64,
+        /// Requests the tap dropped because the monitor fell behind.
+        missed: u64,
+        /// Completion time and output tokens of recent requests.
+        history: VecDeque<(Instant, u64)>,
+    }
+
+    impl State {
+        fn push(&mut self, event: TapEvent) {
+            let now = Instant::now();
+            let output = u64::from(event.output_tokens.unwrap_or(0));
+            self.requests += 1;
+            self.input_tokens += u64::from(event.input_tokens.unwrap_or(0));
+            self.output_tokens += output;
+            self.cost_usd += event.cost_usd.unwrap_or(0.0);
+            self.history.push_back((now, output));
+            while self
+                .history
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at).as_secs() >= HISTORY_SECS)
+            {
+                self.history.pop_front();
+            }
+            if event.status >= 400 {
+                self.failed += 1;
+                self.errors.push_front(event.clone());
+                self.errors.truncate(ERRORS);
+            }
+            self.recent.push_front(event);
+            self.recent.truncate(RECENT);
+        }
+
+        /// Requests and output tokens per second over the last RATE_SECS.
+        fn rates(&self) -> (f64, f64) {
+            let recent = self.history.iter().filter(|(at, _)| at.elapsed().as_secs() < RATE_SECS);
+            let (count, tokens) = recent.fold((0u64, 0u64), |(n, t), (_, out)| (n + 1, t + out));
+            (count as f64 / RATE_SECS as f64, tokens as f64 / RATE_SECS as f64)
+        }
+
+        /// Output tokens per second, one bucket per second, oldest first.
+        fn throughput(&self) -> Vec<u64> {
+            let mut buckets = vec![0; HISTORY_SECS as usize];
+            for (at, tokens) in &self.history {
+                let age = at.elapsed().as_secs();
+                if age < HISTORY_SECS {
+                    buckets[(HISTORY_SECS - 1 - age) as usize] += tokens;
+                }
+            }
+            buckets
+        }
+
+        fn clear(&mut self) {
+            *self = State {
+                connected: self.connected,
+                status: std::mem::take(&mut self.status),
+                ..State::default()
+            };
+        }
+    }
+
+    pub async fn run(url: String, admin_token: String) -> anyhow::Result<()> {
+        let url = url.trim_end_matches('/').to_string();
+        let state = Arc::new(Mutex::new(State {
+            status: format!("connecting to {url}"),
+            ..State::default()
+        }));
+        let follower = tokio::spawn(follow(url.clone(), admin_token, Arc::clone(&state)));
+
+        let ui_state = Arc::clone(&state);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut terminal = ratatui::init();
+            let result = ui(&mut terminal, &url, &ui_state);
+            ratatui::restore();
+            result
+        })
+        .await?;
+        follower.abort();
+        result
+    }
+
+    fn ui(terminal: &mut ratatui::DefaultTerminal, url: &str, state: &Mutex<State>) -> anyhow::Result<()> {
+        loop {
+            {
+                let state = state.lock().unwrap_or_else(|e| e.into_inner());
+                terminal.draw(|frame| draw(frame, url, &state))?;
+            }
+            if !event::poll(Duration::from_millis(250))? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                KeyCode::Char('c') => state.lock().unwrap_or_else(|e| e.into_inner()).clear(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Keeps a tap subscription open, reconnecting after failures.
+    async fn follow(url: String, admin_token: String, state: Arc<Mutex<State>>) {
+        let client = reqwest::Client::new();
+        loop {
+            let outcome = subscribe(&client, &url, &admin_token, &state).await;
+            {
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                state.connected = false;
+                state.status = match outcome {
+                    Ok(()) => "tap closed by the proxy; reconnecting".to_string(),
+                    Err(e) => format!("{e:#}; reconnecting"),
+                };
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn subscribe(client: &reqwest::Client, url: &str, admin_token: &str, state: &Mutex<State>) -> anyhow::Result<()> {
+        let response = client
+            .get(format!("{url}/admin/tap"))
+            .bearer_auth(admin_token)
+            .send()
+            .await?
+            .error_for_status()?;
+        {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            state.connected = true;
+            state.status = format!("connected to {url}");
+        }
+
+        let mut body = response.bytes_stream();
+        // Raw bytes until an event is complete: a chunk may end inside a multi-byte character.
+        let mut buffer = Vec::new();
+        while let Some(chunk) = body.next().await {
+            buffer.extend_from_slice(&chunk?);
+            while let Some(pos) = memmem::find(&buffer, b"\n\n") {
+                let block: Vec<u8> = buffer.drain(..pos + 2).collect();
+                let block = String::from_utf8_lossy(&block);
+                let (mut name, mut data) = ("", String::new());
+                for line in block.lines() {
+                    if let Some(value) = line.strip_prefix("event:") {
+                        name = value.trim();
+                    } else if let Some(value) = line.strip_prefix("data:") {
+                        data.push_str(value.trim_start());
+                    }
+                }
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                match name {
+                    "request" => match serde_json::from_str::<TapEvent>(&data) {
+                        // The monitor's own subscription shows up when it reconnects.
+                        Ok(event) if event.path == "/admin/tap" => {}
+                        Ok(event) => state.push(event),
+                        Err(e) => state.status = format!("unreadable tap event: {e}"),
+                    },
+                    "lagged" => state.missed += data.trim().parse::<u64>().unwrap_or(0),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn draw(frame: &mut Frame, url: &str, state: &State) {
+        let [header, sparkline, table, errors, footer] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Length(6),
+            Constraint::Min(6),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let (req_rate, token_rate) = state.rates();
+        let status_style = if state.connected {
+            Style::new().fg(Color::Green)
+        } else {
+            Style::new().fg(Color::Red)
+        };
+        let mut totals = vec![
+            Span::raw(format!("requests {}  ", state.requests)),
+            Span::styled(format!("errors {}  ", state.failed), error_style(state.failed > 0)),
+            Span::raw(format!("{req_rate:.1} req/s  {token_rate:.0} out tok/s  ")),
+            Span::raw(format!("tokens {} in / {} out", state.input_tokens, state.output_tokens)),
+        ];
+        if state.cost_usd > 0.0 {
+            totals.push(Span::raw(format!("  ${:.4}", state.cost_usd)));
+        }
+        if state.missed > 0 {
+            totals.push(Span::styled(format!("  missed {}", state.missed), Style::new().fg(Color::Yellow)));
+        }
+        frame.render_widget(
+            Paragraph::new(vec![Line::styled(state.status.clone(), status_style), Line::from(totals)])
+                .block(Block::bordered().title(format!(" anthropic-proxy monitor · {url} "))),
+            header,
+        );
+
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(" output tokens/s, last {HISTORY_SECS}s ")))
+                .data(state.throughput())
+                .style(Style::new().fg(Color::Cyan)),
+            sparkline,
+        );
+
+        let rows = state.recent.iter().map(|e| {
+            let model = match (&e.incoming_model, &e.routed_model) {
+                (Some(incoming), Some(routed)) if incoming != routed => format!("{incoming} → {routed}"),
+                (Some(model), _) | (None, Some(model)) => model.clone(),
+                (None, None) => e.path.clone(),
+            };
+            let tokens = |n: Option<u32>| n.map_or("-".to_string(), |n| n.to_string());
+            let millis = |ms: Option<f64>| ms.map_or("-".to_string(), |ms| format!("{ms:.0}ms"));
+            Row::new(vec![
+                time_of(&e.timestamp).to_string(),
+                e.status.to_string(),
+                e.client.clone().unwrap_or_else(|| "-".to_string()),
+                model,
+                match e.stream {
+                    Some(true) => "stream",
+                    Some(false) => "complete",
+                    None => "-",
+                }
+                .to_string(),
+                tokens(e.input_tokens),
+                tokens(e.output_tokens),
+                millis(e.ttfb_ms),
+                millis(Some(e.duration_ms)),
+                e.stop_reason.clone().unwrap_or_else(|| "-".to_string()),
+            ])
+            .style(error_style(e.status >= 400))
+        });
+        let widths = [
+            Constraint::Length(8),
+            Constraint::Length(6),
+            Constraint::Length(14),
+            Constraint::Min(20),
+            Constraint::Length(8),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(8),
+            Constraint::Length(9),
+            Constraint::Length(13),
+        ];
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(
+                    Row::new(["time", "status", "client", "model", "mode", "in", "out", "ttfb", "duration", "stop"])
+                        .add_modifier(Modifier::BOLD),
+                )
+                .block(Block::bordered().title(" requests ")),
+            table,
+        );
+
+        let items = state.errors.iter().map(|e| {
+            ListItem::new(format!(
+                "{} {} {} {}",
+                time_of(&e.timestamp),
+                e.status,
+                e.incoming_model.as_deref().unwrap_or(&e.path),
+                e.error.as_deref().unwrap_or("")
+            ))
+        });
+        frame.render_widget(
+            List::new(items)
+                .style(Style::new().fg(Color::Red))
+                .block(Block::bordered().title(" errors ")),
+            errors,
+        );
+
+        frame.render_widget(Line::from(" q quit · c clear").dim(), footer);
+    }
+
+    fn error_style(error: bool) -> Style {
+        if error {
+            Style::new().fg(Color::Red)
+        } else {
+            Style::new()
+        }
+    }
+
+    /// `HH:MM:SS` of an RFC 3339 timestamp.
+    fn time_of(timestamp: &str) -> &str {
+        timestamp.get(11..19).unwrap_or(timestamp)
+    }
+}
+
+#[cfg(not(feature = "monitor"))]
+mod imp {
+    pub async fn run(_url: String, _admin_token: String) -> anyhow::Result<()> {
+        anyhow::bail!("The monitor needs terminal UI support (rebuild with --features monitor)")
+    }
+}
@@ -0,0 +1,92 @@
+//! `anthropic-proxy transform`: offline conversion of one payload, read from a file or stdin.
+//! `a2o` turns an Anthropic Messages request into the OpenAI chat completions request the
+//! proxy would send upstream, applying the loaded configuration's model routing and PII
+//! scrubbing. `o2a` turns an OpenAI response (JSON, or a captured SSE event stream) into
+//! what the client would receive. No server or upstream is involved.
+
+use crate::config::Config;
+use crate::models::anthropic::AnthropicRequest;
+use crate::models::openai::OpenAIResponse;
+use crate::pii::Scrubber;
+use crate::proxy;
+use crate::quota::Admission;
+use crate::translate;
+use anyhow::Context;
+use bytes::Bytes;
+use clap::ValueEnum;
+use futures::StreamExt;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// Which way `transform` converts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Direction {
+    /// Anthropic request to OpenAI request
+    #[default]
+    A2o,
+    /// OpenAI response (JSON or SSE) to Anthropic response
+    O2a,
+}
+
+pub fn run(config: &Config, direction: Direction, input: Option<PathBuf>) -> anyhow::Result<()> {
+    let raw = match input {
+        Some(path) => std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?,
+        None => {
+            let mut raw = String::new();
+            std::io::stdin().read_to_string(&mut raw).context("Failed to read stdin")?;
+            raw
+        }
+    };
+    match direction {
+        Direction::A2o => {
+            let mut req: AnthropicRequest =
+                serde_json::from_str(&raw).context("Input is not an Anthropic Messages request")?;
+            if let Some(ref pii) = config.pii {
+                Scrubber::new(pii).scrub(&mut req);
+            }
+            let (openai_req, _) = translate::anthropic_to_openai(req, config)?;
+            println!("{}", serde_json::to_string_pretty(&openai_req)?);
+        }
+        Direction::O2a if is_event_stream(&raw) => {
+            // Each chunk goes through the stream translator exactly as it would from upstream.
+            let chunks = vec![Ok::<_, reqwest::Error>(Bytes::from(raw))];
+            let chunks = futures::stream::iter(chunks);
+            let events = proxy::create_sse_stream(
+                chunks,
+                Admission::anonymous(),
+                Vec::new(),
+                Vec::new(),
+                None,
+                config.stream_tool_input,
+                None,
+                None,
+            );
+            let mut stdout = std::io::stdout().lock();
+            futures::executor::block_on(async {
+                futures::pin_mut!(events);
+                while let Some(event) = events.next().await {
+                    stdout.write_all(&event?)?;
+                }
+                anyhow::Ok(())
+            })?;
+        }
+        Direction::O2a => {
+            let resp: OpenAIResponse =
+                serde_json::from_str(&raw).context("Input is not an OpenAI chat completions response")?;
+            let anthropic_resp = translate::openai_to_anthropic(resp)?;
+            println!("{}", serde_json::to_string_pretty(&anthropic_resp)?);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `raw` is an SSE stream: its first non-blank line, after any byte order mark, is a
+/// comment or one of the fields `data`, `event`, `id` and `retry`.
+fn is_event_stream(raw: &str) -> bool {
+    let raw = raw.strip_prefix('\u{feff}').unwrap_or(raw);
+    let Some(line) = raw.split(['\r', '\n']).find(|line| !line.trim().is_empty()) else {
+        return false;
+    };
+    let field = line.split(':').next().unwrap_or(line);
+    matches!(field, "" | "data" | "event" | "id" | "retry")
+}
@@ -0,0 +1,280 @@
+//! Optional PII scrubbing: emails, phone numbers and API keys in outgoing prompts are replaced
+//! with placeholders such as `[EMAIL_1]` before the request leaves the proxy. With PII_RESTORE
+//! the placeholders the model echoes back are swapped for the original values again.
+
+use crate::metrics;
+use crate::models::anthropic::{
+    AnthropicRequest, AnthropicResponse, ContentBlock, MessageContent, ResponseContent, SystemPrompt,
+};
+use crate::stream::DeltaFilter;
+use regex::{Captures, Regex};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Longest placeholder a stream holds back while waiting for its closing `]`.
+const MAX_PLACEHOLDER_LEN: usize = 16;
+
+/// A category of personal data the scrubber recognises.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiiKind {
+    Email,
+    Phone,
+    /// API keys and access tokens in well-known formats.
+    Key,
+}
+
+impl PiiKind {
+    const ALL: [PiiKind; 3] = [PiiKind::Email, PiiKind::Phone, PiiKind::Key];
+
+    fn label(self) -> &'static str {
+        match self {
+            PiiKind::Email => "EMAIL",
+            PiiKind::Phone => "PHONE",
+            PiiKind::Key => "KEY",
+        }
+    }
+
+    // None of the patterns match quotes or backslashes, so originals can be put back
+    // into JSON string fragments (tool arguments) without escaping.
+    fn pattern(self) -> &'static str {
+        match self {
+            PiiKind::Email => r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}\b",
+            PiiKind::Phone => {
+                r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{1,4}\)[\s.-]?|\b\d{2,4}[\s.-])\d{3,4}[\s.-]?\d{3,4}\b|\+\d{8,15}\b"
+            }
+            PiiKind::Key => concat!(
+                r"\b(?:sk-[A-Za-z0-9_-]{16,}|sk_(?:live|test)_[A-Za-z0-9]{16,}|xox[abpr]-[A-Za-z0-9-]{10,}",
+                r"|gh[pousr]_[A-Za-z0-9]{20,}|github_pat_[A-Za-z0-9_]{20,}|(?:AKIA|ASIA)[0-9A-Z]{16}",
+                r"|AIza[0-9A-Za-z_-]{35})"
+            ),
+        }
+    }
+}
+
+/// PII_SCRUB / PII_RESTORE settings.
+#[derive(Debug, Clone)]
+pub struct PiiSettings {
+    pub kinds: Vec<PiiKind>,
+    /// Put original values back into responses.
+    pub restore: bool,
+}
+
+impl PiiSettings {
+    /// Parses PII_SCRUB: `all`/`true`, or a comma-separated subset of `email,phone,key`.
+    pub fn parse(raw: &str, restore: bool) -> anyhow::Result<Option<Self>> {
+        let raw = raw.trim().to_ascii_lowercase();
+        let kinds = match raw.as_str() {
+            "" | "false" | "0" | "no" | "off" => return Ok(None),
+            "all" | "true" | "1" | "yes" | "on" => PiiKind::ALL.to_vec(),
+            list => list
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|kind| match kind {
+                    "email" => Ok(PiiKind::Email),
+                    "phone" => Ok(PiiKind::Phone),
+                    "key" | "keys" => Ok(PiiKind::Key),
+                    other => anyhow::bail!("PII_SCRUB entries must be email, phone or key, got '{other}'"),
+                })
+                .collect::<anyhow::Result<_>>()?,
+        };
+        Ok(Some(Self { kinds, restore }))
+    }
+}
+
+/// Compiled scrubbing patterns, shared by all requests.
+pub struct Scrubber {
+    patterns: Vec<(PiiKind, Regex)>,
+    restore: bool,
+}
+
+impl Scrubber {
+    pub fn new(settings: &PiiSettings) -> Self {
+        let patterns = settings
+            .kinds
+            .iter()
+            .map(|&kind| (kind, Regex::new(kind.pattern()).expect("valid PII pattern")))
+            .collect();
+        Self {
+            patterns,
+            restore: settings.restore,
+        }
+    }
+
+    /// Masks PII in the system prompt, message text, tool results and tool inputs of `req`.
+    /// Returns the mapping needed to restore responses when PII_RESTORE is on and anything
+    /// was masked.
+    pub fn scrub(&self, req: &mut AnthropicRequest) -> Option<Redactions> {
+        let mut redactions = Redactions::default();
+        match &mut req.system {
+            Some(SystemPrompt::Single(text)) => self.mask(text, &mut redactions),
+            Some(SystemPrompt::Multiple(parts)) => {
+                for part in parts {
+                    self.mask(&mut part.text, &mut redactions);
+                }
+            }
+            None => {}
+        }
+        for message in &mut req.messages {
+            match &mut message.content {
+                MessageContent::Text(text) => self.mask(text, &mut redactions),
+                MessageContent::Blocks(blocks) => {
+                    for block in blocks {
+                        match block {
+                            ContentBlock::Text { text, .. } => self.mask(text, &mut redactions),
+                            ContentBlock::ToolResult { content, .. } => self.mask(content, &mut redactions),
+                            ContentBlock::ToolUse { input, .. } => self.mask_raw(input, &mut redactions),
+                            ContentBlock::Image { .. } | ContentBlock::Thinking { .. } => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        for kind in PiiKind::ALL {
+            let count = redactions.counts[kind as usize];
+            if count > 0 {
+                let kind = kind.label().to_ascii_lowercase();
+                metrics::increment("proxy_pii_redactions_total", &[("kind", &kind)], count as u64);
+            }
+        }
+        if !redactions.originals.is_empty() {
+            tracing::debug!("Masked {} PII value(s) in request", redactions.originals.len());
+        }
+        Some(redactions).filter(|r| self.restore && !r.originals.is_empty())
+    }
+
+    fn mask(&self, text: &mut String, redactions: &mut Redactions) {
+        for (kind, pattern) in &self.patterns {
+            if let Cow::Owned(masked) = pattern.replace_all(text, |caps: &Captures| {
+                redactions.placeholder(*kind, &caps[0])
+            }) {
+                *text = masked;
+            }
+        }
+    }
+
+    /// Masks the strings in a tool input, which arrives unparsed.
+    fn mask_raw(&self, input: &mut Box<RawValue>, redactions: &mut Redactions) {
+        let Ok(mut value) = serde_json::from_str::<Value>(input.get()) else { return };
+        self.mask_value(&mut value, redactions);
+        if let Ok(masked) = serde_json::value::to_raw_value(&value) {
+            *input = masked;
+        }
+    }
+
+    fn mask_value(&self, value: &mut Value, redactions: &mut Redactions) {
+        match value {
+            Value::String(text) => self.mask(text, redactions),
+            Value::Array(items) => items.iter_mut().for_each(|v| self.mask_value(v, redactions)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.mask_value(v, redactions)),
+            _ => {}
+        }
+    }
+}
+
+/// Placeholders issued for one request. The same value always gets the same placeholder,
+/// so identical prompts scrub identically (and share response cache entries).
+#[derive(Debug, Default)]
+pub struct Redactions {
+    originals: HashMap<String, String>,
+    placeholders: HashMap<String, String>,
+    counts: [usize; 3],
+}
+
+impl Redactions {
+    fn placeholder(&mut self, kind: PiiKind, original: &str) -> String {
+        if let Some(placeholder) = self.placeholders.get(original) {
+            return placeholder.clone();
+        }
+        let count = &mut self.counts[kind as usize];
+        *count += 1;
+        let placeholder = format!("[{}_{}]", kind.label(), count);
+        self.placeholders.insert(original.to_string(), placeholder.clone());
+        self.originals.insert(placeholder.clone(), original.to_string());
+        placeholder
+    }
+
+    /// Replaces known placeholders in `text` with their original values.
+    pub fn restore<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        placeholder_pattern().replace_all(text, |caps: &Captures| {
+            self.originals.get(&caps[0]).cloned().unwrap_or_else(|| caps[0].to_string())
+        })
+    }
+
+    /// Restores placeholders in the text, thinking and tool inputs of a full response.
+    pub fn restore_response(&self, mut resp: AnthropicResponse) -> AnthropicResponse {
+        for block in &mut resp.content {
+            match block {
+                ResponseContent::Text { text, .. } => *text = self.restore(text).into_owned(),
+                ResponseContent::Thinking { thinking, .. } => *thinking = self.restore(thinking).into_owned(),
+                ResponseContent::ToolUse { input, .. } => self.restore_raw(input),
+            }
+        }
+        resp
+    }
+
+    fn restore_raw(&self, input: &mut Box<RawValue>) {
+        let Ok(mut value) = serde_json::from_str::<Value>(input.get()) else { return };
+        self.restore_value(&mut value);
+        if let Ok(restored) = serde_json::value::to_raw_value(&value) {
+            *input = restored;
+        }
+    }
+
+    fn restore_value(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => *text = self.restore(text).into_owned(),
+            Value::Array(items) => items.iter_mut().for_each(|v| self.restore_value(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.restore_value(v)),
+            _ => {}
+        }
+    }
+
+    /// Restorer for streamed deltas, where a placeholder may be split across chunks.
+    pub fn into_stream(self) -> StreamRestorer {
+        StreamRestorer {
+            redactions: self,
+            pending: String::new(),
+        }
+    }
+}
+
+/// Restores placeholders in a stream of deltas for one content block at a time.
+pub struct StreamRestorer {
+    redactions: Redactions,
+    pending: String,
+}
+
+impl DeltaFilter for StreamRestorer {
+    /// Returns the restored text that is ready to send, holding back a trailing fragment
+    /// that could still become a placeholder.
+    fn push(&mut self, delta: &str) -> String {
+        self.pending.push_str(delta);
+        let split = self
+            .pending
+            .rfind('[')
+            .filter(|&start| {
+                let tail = &self.pending[start + 1..];
+                tail.len() < MAX_PLACEHOLDER_LEN
+                    && tail.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+            })
+            .unwrap_or(self.pending.len());
+        let ready: String = self.pending.drain(..split).collect();
+        self.redactions.restore(&ready).into_owned()
+    }
+
+    /// Releases whatever is held back; call when the content block ends.
+    fn flush(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.redactions.restore(&rest).into_owned()
+    }
+}
+
+fn placeholder_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"\[(?:EMAIL|PHONE|KEY)_\d+\]").expect("valid placeholder pattern"))
+}
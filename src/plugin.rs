@@ -0,0 +1,199 @@
+//! Plugins: request and response hooks for code built on the server. Implement [`ProxyPlugin`],
+//! register it with [`Plugins::with`] and start the server with [`crate::run`]:
+//!
+//! ```no_run
+//! use anthropic_proxy::models::anthropic::AnthropicRequest;
+//! use anthropic_proxy::plugin::{BoxFuture, Plugins, ProxyPlugin, RequestContext};
+//! use anthropic_proxy::ProxyError;
+//!
+//! struct MaxTokensCap(u32);
+//!
+//! impl ProxyPlugin for MaxTokensCap {
+//!     fn name(&self) -> &str {
+//!         "max-tokens-cap"
+//!     }
+//!
+//!     fn on_request<'a>(
+//!         &'a self,
+//!         _ctx: &'a RequestContext,
+//!         req: &'a mut AnthropicRequest,
+//!     ) -> BoxFuture<'a, Result<(), ProxyError>> {
+//!         Box::pin(async move {
+//!             req.max_tokens = req.max_tokens.min(self.0);
+//!             Ok(())
+//!         })
+//!     }
+//! }
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     anthropic_proxy::run(Plugins::new().with(MaxTokensCap(4096)))
+//! }
+//! ```
+//!
+//! Hooks run in registration order, after the built-in client authentication and request
+//! limits; only `on_error` also sees requests those rejected. A hook returning an error ends the
+//! request with that error.
+
+use crate::error::ProxyResult;
+use crate::models::{anthropic, openai};
+use axum::{extract::Request, http::HeaderMap, http::StatusCode, middleware::Next, response::Response, Extension};
+use bytes::Bytes;
+use std::sync::{Arc, OnceLock};
+
+pub use futures::future::BoxFuture;
+
+/// Hooks into the `/v1/messages` path. Every hook has a default that does nothing.
+pub trait ProxyPlugin: Send + Sync {
+    /// Shown in the startup log.
+    fn name(&self) -> &str;
+
+    /// The client's request, before PII scrubbing, moderation and translation.
+    fn on_request<'a>(
+        &'a self,
+        _ctx: &'a RequestContext,
+        _req: &'a mut anthropic::AnthropicRequest,
+    ) -> BoxFuture<'a, ProxyResult<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// The translated request, after model routing and before the cache lookup, so changes
+    /// are part of the cache key.
+    fn on_upstream_request<'a>(
+        &'a self,
+        _ctx: &'a RequestContext,
+        _req: &'a mut openai::OpenAIRequest,
+    ) -> BoxFuture<'a, ProxyResult<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// A non-streaming upstream response, before it is translated. Cached and replayed
+    /// responses do not pass through here.
+    fn on_upstream_response<'a>(
+        &'a self,
+        _ctx: &'a RequestContext,
+        _resp: &'a mut openai::OpenAIResponse,
+    ) -> BoxFuture<'a, ProxyResult<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// One translated SSE event (`event:` and `data:` lines) of a live stream, on its way to
+    /// the client; `None` drops it.
+    fn on_stream_event<'a>(&'a self, _ctx: &'a RequestContext, event: Bytes) -> BoxFuture<'a, Option<Bytes>> {
+        Box::pin(async move { Some(event) })
+    }
+
+    /// An error response, from the proxy or from a hook.
+    fn on_error<'a>(&'a self, _ctx: &'a RequestContext, _error: &'a ErrorInfo) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// The request a hook runs for.
+pub struct RequestContext {
+    /// Request headers as received, credentials included.
+    pub headers: HeaderMap,
+    client: OnceLock<String>,
+}
+
+impl RequestContext {
+    /// Name of the authenticated client key, if any (set once the request body is read).
+    pub fn client(&self) -> Option<&str> {
+        self.client.get().map(String::as_str)
+    }
+
+    pub(crate) fn set_client(&self, name: &str) {
+        let _ = self.client.set(name.to_string());
+    }
+}
+
+/// An error response, as the client receives it.
+#[derive(Debug, Clone)]
+pub struct ErrorInfo {
+    pub status: StatusCode,
+    /// Anthropic error `type`, e.g. `rate_limit_error`.
+    pub error_type: &'static str,
+    pub message: String,
+}
+
+/// Registered plugins, in the order their hooks run.
+#[derive(Default, Clone)]
+pub struct Plugins(Vec<Arc<dyn ProxyPlugin>>);
+
+impl Plugins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, plugin: impl ProxyPlugin + 'static) -> Self {
+        self.0.push(Arc::new(plugin));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|p| p.name()).collect()
+    }
+
+    pub(crate) async fn on_request(&self, ctx: &RequestContext, req: &mut anthropic::AnthropicRequest) -> ProxyResult<()> {
+        for plugin in &self.0 {
+            plugin.on_request(ctx, req).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn on_upstream_request(&self, ctx: &RequestContext, req: &mut openai::OpenAIRequest) -> ProxyResult<()> {
+        for plugin in &self.0 {
+            plugin.on_upstream_request(ctx, req).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn on_upstream_response(
+        &self,
+        ctx: &RequestContext,
+        resp: &mut openai::OpenAIResponse,
+    ) -> ProxyResult<()> {
+        for plugin in &self.0 {
+            plugin.on_upstream_response(ctx, resp).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn on_stream_event(&self, ctx: &RequestContext, mut event: Bytes) -> Option<Bytes> {
+        for plugin in &self.0 {
+            event = plugin.on_stream_event(ctx, event).await?;
+        }
+        Some(event)
+    }
+}
+
+/// Hooks of one request: the plugins and the context they share.
+#[derive(Clone)]
+pub(crate) struct Hooks {
+    pub plugins: Arc<Plugins>,
+    pub ctx: Arc<RequestContext>,
+}
+
+/// Middleware on the API routes (only mounted with plugins registered): creates the request's
+/// [`RequestContext`] for the handler and runs `on_error` for error responses. Mounted outside
+/// client authentication so `on_error` sees its rejections; the other hooks run in the handler.
+pub(crate) async fn run_hooks(Extension(plugins): Extension<Arc<Plugins>>, mut request: Request, next: Next) -> Response {
+    let ctx = Arc::new(RequestContext {
+        headers: request.headers().clone(),
+        client: OnceLock::new(),
+    });
+    request.extensions_mut().insert(Hooks {
+        plugins: Arc::clone(&plugins),
+        ctx: Arc::clone(&ctx),
+    });
+    let response = next.run(request).await;
+    if let Some(error) = response.extensions().get::<ErrorInfo>() {
+        for plugin in &plugins.0 {
+            plugin.on_error(&ctx, error).await;
+        }
+    }
+    response
+}
@@ -0,0 +1,147 @@
+//! Built-in presets (PRESET): an upstream, Claude tier mappings and model quirks for common
+//! setups, so a first configuration needs one setting. A preset only fills gaps: an explicit
+//! UPSTREAM_BASE_URL, routes, `[models]`, REASONING_MODEL / COMPLETION_MODEL and
+//! `[model_params]` all take precedence over it.
+
+use crate::configfile::{ModelParams, Param};
+use crate::translate;
+use serde_json::json;
+
+/// Request adjustments for upstream models matching `pattern`, as a `[model_params]` entry.
+#[derive(Debug)]
+struct Quirk {
+    pattern: &'static str,
+    max_tokens: Option<u32>,
+    /// Ask for token usage on streams (`stream_options.include_usage`).
+    include_usage: bool,
+    strip: &'static [Param],
+}
+
+#[derive(Debug)]
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub base_url: &'static str,
+    /// Variable holding the API key, read when UPSTREAM_API_KEY and OPENROUTER_API_KEY are unset.
+    pub api_key_env: Option<&'static str>,
+    /// Upstream model for each Claude tier.
+    pub haiku: &'static str,
+    pub sonnet: &'static str,
+    pub opus: &'static str,
+    /// Upstream model for requests with extended thinking, whatever their tier.
+    pub reasoning: Option<&'static str>,
+    quirks: &'static [Quirk],
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "openrouter-default",
+        description: "Claude models through OpenRouter",
+        base_url: "https://openrouter.ai/api",
+        api_key_env: None,
+        haiku: "anthropic/claude-3.5-haiku",
+        sonnet: "anthropic/claude-sonnet-4",
+        opus: "anthropic/claude-opus-4",
+        reasoning: None,
+        quirks: &[Quirk {
+            pattern: "*",
+            max_tokens: None,
+            include_usage: true,
+            strip: &[],
+        }],
+    },
+    Preset {
+        name: "ollama-local",
+        description: "open models on a local Ollama server",
+        base_url: "http://localhost:11434",
+        api_key_env: None,
+        haiku: "llama3.2:3b",
+        sonnet: "qwen2.5-coder:14b",
+        opus: "qwen2.5-coder:32b",
+        reasoning: Some("deepseek-r1:14b"),
+        // Ollama's OpenAI endpoint ignores tool_choice at best and rejects it in older versions.
+        quirks: &[Quirk {
+            pattern: "*",
+            max_tokens: None,
+            include_usage: true,
+            strip: &[Param::ToolChoice],
+        }],
+    },
+    Preset {
+        name: "deepseek",
+        description: "DeepSeek's API, with deepseek-reasoner for extended thinking",
+        base_url: "https://api.deepseek.com",
+        api_key_env: Some("DEEPSEEK_API_KEY"),
+        haiku: "deepseek-chat",
+        sonnet: "deepseek-chat",
+        opus: "deepseek-chat",
+        reasoning: Some("deepseek-reasoner"),
+        quirks: &[
+            Quirk {
+                pattern: "deepseek-chat",
+                max_tokens: Some(8192),
+                include_usage: true,
+                strip: &[],
+            },
+            // The reasoner ignores sampling parameters; sending them only invites errors.
+            Quirk {
+                pattern: "deepseek-reasoner",
+                max_tokens: Some(65536),
+                include_usage: true,
+                strip: &[Param::Temperature, Param::TopP],
+            },
+        ],
+    },
+];
+
+/// The preset called `name`, or an error listing the available ones.
+pub fn find(name: &str) -> anyhow::Result<&'static Preset> {
+    let name = name.trim();
+    PRESETS.iter().find(|p| p.name.eq_ignore_ascii_case(name)).ok_or_else(|| {
+        let names: Vec<&str> = PRESETS.iter().map(|p| p.name).collect();
+        anyhow::anyhow!("Unknown PRESET '{name}'; available: {}", names.join(", "))
+    })
+}
+
+impl Preset {
+    /// Upstream model for an incoming Claude model name; `None` for other names.
+    pub fn model(&self, incoming: &str, thinking: bool) -> Option<&'static str> {
+        if thinking {
+            if let Some(model) = self.reasoning {
+                return Some(model);
+            }
+        }
+        match translate::model_tier(incoming)? {
+            "haiku" => Some(self.haiku),
+            "sonnet" => Some(self.sonnet),
+            _ => Some(self.opus),
+        }
+    }
+
+    /// Every upstream model the preset sends requests to.
+    pub fn models(&self) -> impl Iterator<Item = &'static str> {
+        [self.haiku, self.sonnet, self.opus].into_iter().chain(self.reasoning)
+    }
+
+    /// The quirks as `[model_params]` entries.
+    pub fn model_params(&self) -> Vec<ModelParams> {
+        self.quirks
+            .iter()
+            .map(|quirk| ModelParams {
+                pattern: quirk.pattern.to_string(),
+                max_tokens: quirk.max_tokens,
+                temperature: None,
+                stream_options: quirk.include_usage.then(|| json!({ "include_usage": true })),
+                strip: quirk.strip.to_vec(),
+                context_window: None,
+                truncate: None,
+                keep_recent: None,
+                summarize: false,
+                max_stop_sequences: None,
+                supports_tools: None,
+                supports_vision: None,
+                supports_reasoning: None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,158 @@
+//! Cost estimation: per-model prices in USD per million tokens, from MODEL_PRICES_PATH and
+//! optionally kept current from OpenRouter's model list (PRICE_SYNC). Configured prices win
+//! over synced ones.
+
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Response header carrying the estimated cost of a non-streaming request.
+pub const COST_HEADER: &str = "x-proxy-cost-usd";
+
+/// OpenRouter's public model list, used for `PRICE_SYNC=openrouter`.
+pub const OPENROUTER_MODELS_URL: &str = "https://openrouter.ai/api/v1/models";
+
+/// Default seconds between price syncs.
+pub const DEFAULT_PRICE_SYNC_INTERVAL_SECS: u64 = 86_400;
+
+/// Price of one model, in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, input_tokens: u32, output_tokens: u32) -> f64 {
+        (f64::from(input_tokens) * self.input + f64::from(output_tokens) * self.output) / 1_000_000.0
+    }
+}
+
+/// MODEL_PRICES_PATH / PRICE_SYNC / PRICE_SYNC_INTERVAL.
+#[derive(Debug, Clone, Default)]
+pub struct PricingSettings {
+    pub prices: HashMap<String, ModelPrice>,
+    /// Model list in OpenRouter's format to fetch prices from.
+    pub sync_url: Option<String>,
+    pub sync_interval_secs: u64,
+}
+
+impl PricingSettings {
+    /// Reads a JSON object mapping model names to `{"input": .., "output": ..}`.
+    pub fn read_prices(path: &Path) -> anyhow::Result<HashMap<String, ModelPrice>> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read MODEL_PRICES_PATH {}: {e}", path.display()))?;
+        serde_json::from_str(&raw)
+            .map_err(|e| anyhow::anyhow!("Invalid price table in {}: {e}", path.display()))
+    }
+
+    /// Parses PRICE_SYNC: `openrouter`, or the URL of a model list in OpenRouter's format.
+    pub fn parse_sync(raw: &str) -> anyhow::Result<Option<String>> {
+        match raw.trim() {
+            "" | "off" | "none" | "false" => Ok(None),
+            "openrouter" => Ok(Some(OPENROUTER_MODELS_URL.to_string())),
+            url if url.starts_with("http://") || url.starts_with("https://") => Ok(Some(url.to_string())),
+            other => anyhow::bail!("PRICE_SYNC must be openrouter or a URL, got '{other}'"),
+        }
+    }
+}
+
+/// Prices shared by all requests.
+#[derive(Debug, Default)]
+pub struct PriceTable {
+    configured: HashMap<String, ModelPrice>,
+    synced: RwLock<HashMap<String, ModelPrice>>,
+}
+
+impl PriceTable {
+    pub fn new(settings: &PricingSettings) -> Self {
+        Self {
+            configured: settings.prices.clone(),
+            synced: RwLock::default(),
+        }
+    }
+
+    /// Price for an upstream model name. Synced prices are keyed both by OpenRouter id
+    /// (`openai/gpt-4.1`) and by the bare name (`gpt-4.1`).
+    pub fn price(&self, model: &str) -> Option<ModelPrice> {
+        if let Some(price) = self.configured.get(model) {
+            return Some(*price);
+        }
+        self.synced.read().unwrap_or_else(|e| e.into_inner()).get(model).copied()
+    }
+
+    /// Fetches prices now and then every `interval_secs`, in the background.
+    pub fn spawn_sync(self: &Arc<Self>, client: Client, url: String, interval_secs: u64) {
+        let table = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(60)));
+            loop {
+                interval.tick().await;
+                match table.sync(&client, &url).await {
+                    Ok(count) => tracing::info!("Synced prices for {} model(s) from {}", count, url),
+                    Err(e) => tracing::warn!("Price sync from {} failed: {:#}", url, e),
+                }
+            }
+        });
+    }
+
+    async fn sync(&self, client: &Client, url: &str) -> anyhow::Result<usize> {
+        let list: ModelList = client
+            .get(url)
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut prices = HashMap::new();
+        let mut count = 0;
+        for model in list.data {
+            let per_million = |per_token: &str| per_token.trim().parse::<f64>().ok().map(|p| p * 1_000_000.0);
+            let (Some(input), Some(output)) = (per_million(&model.pricing.prompt), per_million(&model.pricing.completion))
+            else {
+                continue;
+            };
+            if input < 0.0 || output < 0.0 {
+                // OpenRouter marks variable-priced routers with -1.
+                continue;
+            }
+            let price = ModelPrice { input, output };
+            if let Some((_, bare)) = model.id.split_once('/') {
+                prices.entry(bare.to_string()).or_insert(price);
+            }
+            prices.insert(model.id, price);
+            count += 1;
+        }
+        anyhow::ensure!(count > 0, "model list has no prices");
+        *self.synced.write().unwrap_or_else(|e| e.into_inner()) = prices;
+        Ok(count)
+    }
+}
+
+/// Formats a cost for headers and logs.
+pub fn format_cost(cost: f64) -> String {
+    format!("{cost:.6}")
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ListedModel>,
+}
+
+#[derive(Deserialize)]
+struct ListedModel {
+    id: String,
+    pricing: ListedPricing,
+}
+
+/// Per-token prices as decimal strings.
+#[derive(Deserialize)]
+struct ListedPricing {
+    prompt: String,
+    completion: String,
+}
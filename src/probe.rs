@@ -0,0 +1,304 @@
+//! `anthropic-proxy probe`: calls the configured upstream the way the proxy would and reports
+//! what it finds: whether `/v1/models` answers and the key is accepted, the latency of a tiny
+//! completion with and without streaming, and which optional parameters the backend rejects.
+//! Exits non-zero when the proxy could not serve requests.
+
+use crate::check::Diagnostics;
+use crate::config::{Config, Upstream};
+use crate::keypool::PooledKey;
+use crate::models::openai::{self, Function, Message, MessageContent, OpenAIRequest, StreamChunk, Tool};
+use crate::translate;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+const PROBE_PROMPT: &str = "Reply with the single word OK.";
+const PROBE_MAX_TOKENS: u32 = 16;
+
+/// Runs the probe and prints the report; returns whether the upstream is usable.
+pub async fn run(config: &Config) -> bool {
+    let mut d = Diagnostics::default();
+    let upstream = &config.upstream;
+    let key = upstream.keys.next();
+    let auth = key.as_deref().map(PooledKey::header_value);
+    if upstream.is_mock() {
+        d.ok("Upstream is the built-in mock; there is nothing to probe");
+        return d.report("Upstream");
+    }
+    d.ok(format!("Upstream {} ({} key(s))", upstream.base_url, upstream.keys.len()));
+    if key.is_none() {
+        if config.secrets.upstream_api_key.is_some() {
+            d.warn("The upstream key comes from a secret manager, which probe does not fetch");
+        } else {
+            d.warn("No upstream API key: requests are sent unauthenticated");
+        }
+    }
+
+    let listed = list_models(upstream, auth, &mut d).await;
+    let Some(model) = probe_model(config, &listed) else {
+        d.error("No model to probe: set COMPLETION_MODEL or pass --model");
+        return d.report("Upstream");
+    };
+    if !listed.is_empty() && !listed.contains(&model) {
+        d.warn(format!("Model {model} is not in the upstream's model list"));
+    }
+
+    if !complete(upstream, auth, &model, &mut d).await || !stream(upstream, auth, &model, &mut d).await {
+        return d.report("Upstream");
+    }
+    parameters(upstream, auth, &model, &mut d).await;
+    d.report("Upstream")
+}
+
+/// The model requests would most likely be sent as.
+fn probe_model(config: &Config, listed: &[String]) -> Option<String> {
+    config
+        .completion_model
+        .clone()
+        .or_else(|| config.reasoning_model.clone())
+        .or_else(|| config.routes.iter().find(|r| r.upstream.is_none()).and_then(|r| r.model.clone()))
+        .or_else(|| listed.first().cloned())
+}
+
+/// Adds the key and the upstream's static headers, as the proxy does.
+fn request(upstream: &Upstream, builder: RequestBuilder, auth: Option<&str>) -> RequestBuilder {
+    let builder = builder.timeout(PROBE_TIMEOUT);
+    let builder = match auth {
+        Some(h) => builder.header("Authorization", h),
+        None => builder,
+    };
+    builder.headers(upstream.headers.clone())
+}
+
+/// Upstream error text, shortened for one report line.
+async fn failure(response: Response) -> String {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|v| v.pointer("/error/message").and_then(Value::as_str).map(str::to_string))
+        .unwrap_or(body);
+    let message: String = message.trim().chars().take(200).collect();
+    if message.is_empty() {
+        status.to_string()
+    } else {
+        format!("{status}: {message}")
+    }
+}
+
+/// A transport error with its causes (connection refused, certificate problems, ...).
+fn describe(e: reqwest::Error) -> String {
+    format!("{:#}", anyhow::Error::from(e.without_url()))
+}
+
+fn auth_hint(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => " (check the upstream API key)",
+        StatusCode::NOT_FOUND => " (check UPSTREAM_BASE_URL and UPSTREAM_PATH)",
+        _ => "",
+    }
+}
+
+/// GET /v1/models (next to the chat completions path); returns the model ids it lists.
+async fn list_models(upstream: &Upstream, auth: Option<&str>, d: &mut Diagnostics) -> Vec<String> {
+    let Some(url) = upstream.models_url() else {
+        d.warn(format!("No model list next to upstream path {}: not checked", upstream.path));
+        return Vec::new();
+    };
+    let started = Instant::now();
+    let response = match request(upstream, upstream.client.get(&url), auth).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            d.error(format!("GET {url}: {}", describe(e)));
+            return Vec::new();
+        }
+    };
+    let elapsed = started.elapsed();
+    let status = response.status();
+    if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        d.error(format!("GET {url}: {}{}", failure(response).await, auth_hint(status)));
+        return Vec::new();
+    }
+    if !status.is_success() {
+        d.warn(format!("GET {url}: {} (some backends do not list models)", failure(response).await));
+        return Vec::new();
+    }
+    let ids: Vec<String> = match response.json::<Value>().await {
+        Ok(body) => body
+            .get("data")
+            .and_then(Value::as_array)
+            .map(|models| {
+                models
+                    .iter()
+                    .filter_map(|m| m.get("id").and_then(Value::as_str).map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Err(e) => {
+            d.warn(format!("GET {url}: unexpected body: {}", describe(e)));
+            return Vec::new();
+        }
+    };
+    d.ok(format!("GET {url}: {} model(s) in {}ms", ids.len(), elapsed.as_millis()));
+    ids
+}
+
+fn completion_request(model: &str, stream: bool) -> OpenAIRequest {
+    OpenAIRequest {
+        model: model.to_string(),
+        messages: vec![Message {
+            role: "user".to_string(),
+            content: Some(MessageContent::Text(PROBE_PROMPT.to_string())),
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        }],
+        max_tokens: Some(PROBE_MAX_TOKENS),
+        temperature: None,
+        top_p: None,
+        stop: None,
+        stream: stream.then_some(true),
+        tools: None,
+        tool_choice: None,
+        stream_options: None,
+    }
+}
+
+async fn post(upstream: &Upstream, auth: Option<&str>, body: &OpenAIRequest) -> reqwest::Result<Response> {
+    request(upstream, upstream.client.post(&*upstream.chat_completions_url(&body.model)).json(body), auth)
+        .send()
+        .await
+}
+
+/// Non-streaming completion, checked to convert to an Anthropic response.
+async fn complete(upstream: &Upstream, auth: Option<&str>, model: &str, d: &mut Diagnostics) -> bool {
+    let started = Instant::now();
+    let response = match post(upstream, auth, &completion_request(model, false)).await {
+        Ok(response) => response,
+        Err(e) => {
+            d.error(format!("Completion with {model}: {}", describe(e)));
+            return false;
+        }
+    };
+    let status = response.status();
+    if !status.is_success() {
+        d.error(format!("Completion with {model}: {}{}", failure(response).await, auth_hint(status)));
+        return false;
+    }
+    let converted = match response.json::<openai::OpenAIResponse>().await {
+        Ok(body) => translate::openai_to_anthropic(body).map_err(|e| e.to_string()),
+        Err(e) => Err(format!("not a chat completions response: {}", describe(e))),
+    };
+    match converted {
+        Ok(resp) => {
+            d.ok(format!(
+                "Completion with {model}: {}ms, {} input / {} output tokens",
+                started.elapsed().as_millis(),
+                resp.usage.input_tokens,
+                resp.usage.output_tokens
+            ));
+            true
+        }
+        Err(e) => {
+            d.error(format!("Completion with {model}: {e}"));
+            false
+        }
+    }
+}
+
+/// Streaming completion: time to first event, total time, and whether every event parses.
+async fn stream(upstream: &Upstream, auth: Option<&str>, model: &str, d: &mut Diagnostics) -> bool {
+    let started = Instant::now();
+    let mut response = match post(upstream, auth, &completion_request(model, true)).await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            d.error(format!("Streaming with {model}: {}", failure(response).await));
+            return false;
+        }
+        Err(e) => {
+            d.error(format!("Streaming with {model}: {}", describe(e)));
+            return false;
+        }
+    };
+    let mut first_chunk = None;
+    let mut body = Vec::new();
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                first_chunk.get_or_insert_with(|| started.elapsed());
+                body.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                d.error(format!("Streaming with {model}: {}", describe(e)));
+                return false;
+            }
+        }
+    }
+    let body = String::from_utf8_lossy(&body);
+    let (mut events, mut invalid, mut done) = (0, 0, false);
+    for data in body.lines().filter_map(|line| line.strip_prefix("data:")).map(str::trim) {
+        if data == "[DONE]" {
+            done = true;
+        } else if serde_json::from_str::<StreamChunk>(data).is_ok() {
+            events += 1;
+        } else {
+            invalid += 1;
+        }
+    }
+    if events == 0 {
+        d.error(format!("Streaming with {model}: no chat completion chunks in the response"));
+        return false;
+    }
+    d.ok(format!(
+        "Streaming with {model}: first chunk in {}ms, {events} chunk(s) in {}ms",
+        first_chunk.unwrap_or_default().as_millis(),
+        started.elapsed().as_millis()
+    ));
+    if invalid > 0 {
+        d.warn(format!("Streaming with {model}: {invalid} event(s) are not chat completion chunks and would be skipped"));
+    }
+    if !done {
+        d.warn(format!("Streaming with {model}: the stream did not end with [DONE]"));
+    }
+    true
+}
+
+/// Adds one optional parameter to a probe request.
+type SetParameter = fn(&mut OpenAIRequest, &Tool);
+
+/// Sends each optional parameter the proxy forwards on its own.
+async fn parameters(upstream: &Upstream, auth: Option<&str>, model: &str, d: &mut Diagnostics) {
+    let tool = Tool {
+        tool_type: "function".to_string(),
+        function: Function {
+            name: "get_time".to_string(),
+            description: Some("Returns the current time".to_string()),
+            parameters: json!({ "type": "object", "properties": {} }),
+        },
+    };
+    let probes: [(&str, SetParameter); 5] = [
+        ("temperature", |r, _| r.temperature = Some(0.5)),
+        ("top_p", |r, _| r.top_p = Some(0.9)),
+        ("stop", |r, _| r.stop = Some(vec!["\n\n".to_string()])),
+        ("tools", |r, t| r.tools = Some(vec![t.clone()])),
+        ("tool_choice", |r, t| {
+            r.tools = Some(vec![t.clone()]);
+            r.tool_choice = Some(json!("auto"));
+        }),
+    ];
+    let mut accepted = Vec::new();
+    for (name, set) in probes {
+        let mut body = completion_request(model, false);
+        set(&mut body, &tool);
+        match post(upstream, auth, &body).await {
+            Ok(response) if response.status().is_success() => accepted.push(name),
+            Ok(response) => d.warn(format!("Parameter {name} is rejected: {}", failure(response).await)),
+            Err(e) => d.warn(format!("Parameter {name}: {}", describe(e))),
+        }
+    }
+    if !accepted.is_empty() {
+        d.ok(format!("Parameters accepted: {}", accepted.join(", ")));
+    }
+}
@@ -1,38 +1,69 @@
 //! HTTP handler and streaming: accept Anthropic requests, call upstream, return Anthropic responses.
 
-use crate::config::Config;
+use crate::access::ClientIp;
+use crate::accesslog;
+use crate::alerts::{self, Alert};
+use crate::auth::ClientIdentity;
+use crate::capabilities;
+use crate::capture::{self, Capture, CaptureDir, Tee, CAPTURE_HEADER};
+use crate::chaos::Faults;
+use crate::coalesce::CoalesceSettings;
+use crate::conversations::{self, ConversationStore};
+use crate::cache::{CacheKey, CacheMode, ResponseCache, CACHE_CONTROL_HEADER, CACHE_KEY_HEADER};
+use crate::config::{wildcard_match, Config, Upstream};
 use crate::error::{ProxyError, ProxyResult};
+use crate::events::{self, EventKind};
+use crate::http3;
+use crate::keypool::PooledKey;
+use crate::latency::{self, TranslationTimer, UpstreamTimer};
+use crate::metrics;
+use crate::mockupstream;
 use crate::models::{anthropic, openai};
+use crate::moderation::{self, ModerationAction, Moderator, Verdict, MODERATION_HEADER};
+use crate::pii::{Redactions, Scrubber};
+use crate::plugin::Hooks;
+use crate::pricing::{self, ModelPrice, PriceTable, COST_HEADER};
+use crate::quota::{Admission, QuotaTracker};
+use crate::ratelimit::RateLimiter;
+use crate::redact;
+use crate::replay::{self, Exchange, Recording, Traffic, REPLAY_HEADER};
+use crate::rewrite::Rewriter;
+use crate::sysprompt::TemplateVars;
+use crate::stops;
+use crate::telemetry::{InSpan, TraceContext};
+use crate::tokens::TokenCounter;
 use crate::transform;
+use crate::transport::StreamGuard;
+use crate::translate;
+use crate::truncate::{self, TRUNCATED_HEADER};
+use crate::usagedb::{UsageDb, UsageQuery};
+use crate::warmup;
+use crate::stream::{self, DeltaFilter, Output, StreamTranslator, ToolInputMode};
 use axum::{
     body::Body,
+    extract::{Path, Query},
     http::{HeaderMap, HeaderValue},
     response::{IntoResponse, Response},
     Extension, Json,
 };
 use bytes::Bytes;
+use chrono::Utc;
 use futures::stream::{Stream, StreamExt};
 use reqwest::Client;
 use serde_json::json;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::sync::OnceLock;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
 
 const UPSTREAM_TIMEOUT_SECS: u64 = 300;
 
-/// Fixed SSE payload for message_stop (avoids per-stream allocation).
-const SSE_MESSAGE_STOP: &[u8] = b"event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n";
-
 /// SSE headers built once for streaming responses.
 static SSE_HEADERS: OnceLock<HeaderMap> = OnceLock::new();
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum BlockType {
-    Thinking,
-    Text,
-    ToolUse,
-}
-
 fn sse_header_map() -> &'static HeaderMap {
     SSE_HEADERS.get_or_init(|| {
         let mut h = HeaderMap::new();
@@ -44,35 +75,398 @@ fn sse_header_map() -> &'static HeaderMap {
 }
 
 /// Entrypoint: parse Anthropic request, transform to OpenAI, call upstream, transform response.
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_handler(
     Extension(config): Extension<Arc<Config>>,
-    Extension(client): Extension<Client>,
-    Json(req): Json<anthropic::AnthropicRequest>,
+    Extension(cache): Extension<Arc<ResponseCache>>,
+    Extension(quotas): Extension<Arc<QuotaTracker>>,
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    Extension(moderator): Extension<Option<Arc<Moderator>>>,
+    Extension(scrubber): Extension<Option<Arc<Scrubber>>>,
+    Extension(prices): Extension<Arc<PriceTable>>,
+    Extension(captures): Extension<Option<Arc<CaptureDir>>>,
+    Extension(traffic): Extension<Option<Arc<Traffic>>>,
+    Extension(counter): Extension<Arc<TokenCounter>>,
+    Extension(conversations): Extension<Option<Arc<ConversationStore>>>,
+    identity: Option<Extension<Arc<ClientIdentity>>>,
+    client_ip: Option<Extension<ClientIp>>,
+    hooks: Option<Extension<Hooks>>,
+    headers: HeaderMap,
+    Json(mut req): Json<anthropic::AnthropicRequest>,
 ) -> ProxyResult<Response> {
+    let received = Instant::now();
     let is_streaming = req.stream.unwrap_or(false);
+    let identity = identity.map(|Extension(id)| id);
+    let hooks = hooks.map(|Extension(hooks)| hooks);
+    let trace = TraceContext::from_headers(&headers);
+    let forwarded = forwarded_headers(&config.forward_headers, &headers);
+    let chaos = config.chaos.as_ref().map(|c| c.pick(&headers)).transpose()?.unwrap_or_default();
+    if let Some(id) = identity.as_deref() {
+        accesslog::with_current(|entry| entry.set_client(&id.name));
+        if let Some(hooks) = &hooks {
+            hooks.ctx.set_client(&id.name);
+        }
+    }
+    let capture = captures.as_deref().and_then(|c| c.start(&headers));
+    if let Some(capture) = &capture {
+        capture.write_json("anthropic-request.json", &req);
+    }
+    let turn = conversations.as_ref().and_then(|store| {
+        let client = identity.as_deref().map(|id| id.name.as_str());
+        store.start(&headers, client, &req.model, is_streaming, &req)
+    });
+    // Keyed on the request as received, so replays still match after transformation changes.
+    let traffic_key = traffic.as_ref().and_then(|_| replay::request_key(&req));
+    let recorded_request = match (traffic.as_deref(), &traffic_key) {
+        (Some(Traffic::Record(_)), Some(_)) => serde_json::to_value(&req).ok(),
+        _ => None,
+    };
+    let cache_mode = CacheMode::from_header(
+        headers
+            .get(CACHE_CONTROL_HEADER)
+            .and_then(|v| v.to_str().ok()),
+    );
     tracing::debug!("Received request model={} streaming={}", req.model, is_streaming);
+    let verbose = config.verbose || config.verbose_sampling.is_some_and(|s| s.sampled(&headers));
 
-    if config.verbose {
-        let _ = tracing::trace!(
+    if verbose {
+        tracing::trace!(
+            target: redact::PAYLOAD_TARGET,
             "Incoming Anthropic request: {}",
-            serde_json::to_string_pretty(&req).unwrap_or_default()
+            redact::to_log_json(&req, config.log_content)
         );
     }
 
-    let openai_req = transform::anthropic_to_openai(req, &config)?;
+    config.limits.check(&req)?;
+    if let Some(hooks) = &hooks {
+        hooks.plugins.on_request(&hooks.ctx, &mut req).await?;
+    }
+    let (model, upstream_model) = (req.model.clone(), translate::upstream_model(&config, &req));
+    let vars = TemplateVars {
+        client_name: identity.as_deref().map_or("anonymous", |id| id.name.as_str()),
+        model: &model,
+        upstream_model: &upstream_model,
+        custom: &config.prompt_vars,
+        now: Utc::now(),
+    };
+    let rewrite = config.rewrite.render(&vars);
+    rewrite.rewrite_request(&mut req);
+    let redactions = scrubber.as_deref().and_then(|s| s.scrub(&mut req));
+    accesslog::with_current(|entry| entry.capture_payload(|| redact::to_log_value(&req, config.log_content)));
+    let system_prompt = match identity.as_deref() {
+        Some(id) => Cow::Owned(id.system_prompt.or(&config.system_prompt)),
+        None => Cow::Borrowed(&config.system_prompt),
+    };
+    system_prompt.apply(&mut req, &vars);
+    let incoming_model = req.model.clone();
+    let moderation_input = moderator.as_ref().map(|_| moderation::latest_user_text(&req));
+    let mut prompt_tokens = config.limits.context_window_guard.then(|| counter.count_request(&req));
+    let truncated = match prompt_tokens {
+        Some(tokens) => truncate::fit(&config, &counter, &mut req, tokens).await,
+        None => None,
+    };
+    if truncated.is_some() {
+        prompt_tokens = Some(counter.count_request(&req));
+    }
+    let input_estimate = (is_streaming && config.stream_input_estimate)
+        .then(|| prompt_tokens.unwrap_or_else(|| counter.count_request(&req)));
+    let thinking = transform::has_thinking_enabled(&req.extra);
+    let started = Instant::now();
+    let (mut openai_req, stop_sequences) =
+        tracing::info_span!("transform").in_scope(|| translate::anthropic_to_openai(req, &config))?;
+    latency::observe_translation("request", is_streaming, started.elapsed());
+    if let Some(capture) = &capture {
+        capture.write_json("openai-request.json", &openai_req);
+    }
+    let span = Span::current();
+    span.record("stream", is_streaming);
+    span.record("proxy.incoming_model", incoming_model.as_str());
+    span.record("gen_ai.request.model", openai_req.model.as_str());
+    if let Some(id) = identity.as_deref() {
+        span.record("client", id.name.as_str());
+    }
+
+    if let Some(id) = identity.as_deref() {
+        if !id.allows_model(&incoming_model, &openai_req.model) {
+            tracing::warn!(
+                "Client '{}' denied model {} (routed to {})",
+                id.name,
+                incoming_model,
+                openai_req.model
+            );
+            return Err(ProxyError::Permission(format!(
+                "This API key is not allowed to use model '{incoming_model}'"
+            )));
+        }
+    }
+    capabilities::check(&config.capabilities(&openai_req.model), &openai_req, thinking)?;
+    if let Some(tokens) = prompt_tokens {
+        config.limits.check_context_window(&config, &openai_req.model, tokens)?;
+    }
+    let permit = limiter
+        .check(
+            client_ip.map(|Extension(ClientIp(ip))| ip),
+            identity.as_deref().map(|id| id.name.as_str()),
+        )
+        .await?;
+    let admission = match identity.as_deref() {
+        Some(id) => quotas.admit(id, is_streaming)?,
+        None => Admission::anonymous(),
+    };
+    let admission = match permit {
+        Some(permit) => admission.with_rate_limit(permit),
+        None => admission,
+    };
+    let mut quota_headers = admission.headers.clone();
+    if let Some(truncated) = truncated {
+        quota_headers.insert(TRUNCATED_HEADER, truncated.header_value());
+    }
+
+    if let (Some(moderator), Some(input)) = (moderator.as_deref(), moderation_input) {
+        if let Verdict::Flagged(categories) = moderator.check(&input).await? {
+            let client = identity.as_deref().map_or("anonymous", |id| id.name.as_str());
+            match moderator.action() {
+                ModerationAction::Block => {
+                    tracing::warn!("Moderation blocked request from '{}': {}", client, categories.join(","));
+                    let refusal = moderation::refusal(&incoming_model);
+                    record_outcome(&refusal);
+                    let mut response = if is_streaming {
+                        buffered_stream_response(&refusal)
+                    } else {
+                        Json(refusal).into_response()
+                    };
+                    response.headers_mut().extend(quota_headers);
+                    response
+                        .headers_mut()
+                        .insert(MODERATION_HEADER, HeaderValue::from_static("blocked"));
+                    return Ok(conversations::finish(turn, response));
+                }
+                ModerationAction::Flag => {
+                    tracing::warn!("Moderation flagged request from '{}': {}", client, categories.join(","));
+                    quota_headers.insert(MODERATION_HEADER, HeaderValue::from_static("flagged"));
+                }
+            }
+        }
+    }
+
+    if let Some(hooks) = &hooks {
+        hooks.plugins.on_upstream_request(&hooks.ctx, &mut openai_req).await?;
+    }
 
-    if config.verbose {
-        let _ = tracing::trace!(
+    if verbose {
+        tracing::trace!(
+            target: redact::PAYLOAD_TARGET,
             "Transformed OpenAI request: {}",
-            serde_json::to_string_pretty(&openai_req).unwrap_or_default()
+            redact::to_log_json(&openai_req, config.log_content)
         );
     }
 
-    if is_streaming {
-        handle_streaming(config, client, openai_req).await
+    let upstream = identity
+        .as_deref()
+        .and_then(|id| id.upstream.clone())
+        .or_else(|| config.route(&incoming_model).and_then(|r| r.upstream.clone()))
+        .unwrap_or_else(|| Arc::clone(&config.upstream));
+    accesslog::with_current(|entry| {
+        entry.set_route(&incoming_model, &openai_req.model, &upstream.base_url, is_streaming)
+    });
+
+    let cache_key = if cache.enabled() && cache_mode != CacheMode::Bypass {
+        ResponseCache::key_for(&upstream, &openai_req)
+    } else {
+        None
+    };
+
+    if let Some(key) = cache_key.as_ref().filter(|_| cache_mode.reads()) {
+        let cached = cache.get(key);
+        let result = if cached.is_some() { "hit" } else { "miss" };
+        metrics::increment("proxy_cache_lookups_total", &[("result", result)], 1);
+        if let Some(cached) = cached {
+            tracing::debug!("Cache hit model={}", openai_req.model);
+            record_outcome(&cached);
+            let cached = match client_response(&cached, redactions.as_ref(), &stop_sequences, &rewrite) {
+                Some(resp) => Arc::new(resp),
+                None => cached,
+            };
+            if let Some(capture) = &capture {
+                capture.write_json("anthropic-response.json", cached.as_ref());
+            }
+            let response = if is_streaming {
+                buffered_stream_response(&cached)
+            } else {
+                Json(cached.as_ref()).into_response()
+            };
+            let mut response = with_cache_status(response, "hit", cache_key.as_ref());
+            response.headers_mut().extend(quota_headers);
+            with_capture_id(&mut response, capture.as_ref());
+            latency::observe("proxy_client_ttfb_seconds", is_streaming, received.elapsed());
+            return Ok(conversations::finish(turn, response));
+        }
+    }
+    if cache_mode == CacheMode::Only {
+        return Err(ProxyError::CacheMiss(
+            "No cached response for this request (x-proxy-cache: only)".to_string(),
+        ));
+    }
+
+    let source = match (traffic.as_deref(), traffic_key.as_deref()) {
+        (Some(Traffic::Replay(replayer)), Some(key)) => match replayer.next(key) {
+            Some(exchange) => Source::Replay(exchange),
+            None => {
+                return Err(ProxyError::ReplayMiss(format!(
+                    "No recorded exchange for this request (key {key})"
+                )))
+            }
+        },
+        (Some(Traffic::Record(recorder)), Some(key)) => Source::Live {
+            upstream: &upstream,
+            trace: &trace,
+            forwarded: &forwarded,
+            chaos,
+            recording: recorded_request.map(|request| recorder.start(key.to_string(), request, &openai_req, is_streaming)),
+        },
+        _ => Source::Live {
+            upstream: &upstream,
+            trace: &trace,
+            forwarded: &forwarded,
+            chaos,
+            recording: None,
+        },
+    };
+    let replay_key = match &source {
+        Source::Replay(exchange) => HeaderValue::from_str(&exchange.key).ok(),
+        Source::Live { .. } => None,
+    };
+
+    let (response, status) = if is_streaming {
+        let price = prices.price(&openai_req.model);
+        let mut filters: Vec<Box<dyn DeltaFilter>> = Vec::new();
+        if let Some(redactions) = redactions {
+            filters.push(Box::new(redactions.into_stream()));
+        }
+        if let Some(rewrite) = rewrite.stream_filter() {
+            filters.push(Box::new(rewrite));
+        }
+        let response = handle_streaming(
+            source,
+            openai_req,
+            admission,
+            filters,
+            stop_sequences,
+            input_estimate,
+            config.stream_tool_input,
+            price,
+            received,
+            capture.as_ref(),
+            config.stream_coalesce,
+            hooks,
+        )
+        .await?;
+        (response, "bypass")
     } else {
-        handle_non_streaming(config, client, openai_req).await
+        let store = cache_key.filter(|_| cache_mode.writes()).map(|k| (cache.as_ref(), k));
+        let status = if store.is_some() { "miss" } else { "bypass" };
+        let price = prices.price(&openai_req.model);
+        let response = handle_non_streaming(
+            config,
+            verbose,
+            source,
+            openai_req,
+            store,
+            admission,
+            redactions.as_ref(),
+            &stop_sequences,
+            &rewrite,
+            price,
+            capture.as_ref(),
+            hooks.as_ref(),
+        )
+        .await?;
+        latency::observe("proxy_client_ttfb_seconds", false, received.elapsed());
+        (response, status)
+    };
+    let mut response = with_cache_status(response, status, cache_key.as_ref());
+    response.headers_mut().extend(quota_headers);
+    with_capture_id(&mut response, capture.as_ref());
+    if let Some(key) = replay_key {
+        response.headers_mut().insert(REPLAY_HEADER, key);
     }
+    Ok(conversations::finish(turn, response))
+}
+
+/// Tells the client which capture files belong to its request.
+fn with_capture_id(response: &mut Response, capture: Option<&Capture>) {
+    if let Some(value) = capture.and_then(|c| HeaderValue::from_str(c.id()).ok()) {
+        response.headers_mut().insert(CAPTURE_HEADER, value);
+    }
+}
+
+/// Tags a response with `x-proxy-cache: hit|miss|bypass` and the cache key, if any.
+fn with_cache_status(mut response: Response, status: &'static str, key: Option<&CacheKey>) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(CACHE_CONTROL_HEADER, HeaderValue::from_static(status));
+    if let Some(value) = key.and_then(|k| HeaderValue::from_str(&hex::encode(k)).ok()) {
+        headers.insert(CACHE_KEY_HEADER, value);
+    }
+    response
+}
+
+/// `/v1/messages/count_tokens`: local estimate, no upstream call.
+pub async fn count_tokens_handler(
+    Extension(counter): Extension<Arc<TokenCounter>>,
+    Json(req): Json<anthropic::CountTokensRequest>,
+) -> Json<anthropic::CountTokensResponse> {
+    let input_tokens = counter.count_tokens_request(&req);
+    tracing::debug!("count_tokens model={} input_tokens={}", req.model, input_tokens);
+    Json(anthropic::CountTokensResponse { input_tokens })
+}
+
+/// `/usage`: quota counters for the calling client, or for every client when auth is off.
+/// With USAGE_DB, also a `history` of stored usage filtered by `from`/`to` and grouped by
+/// `group_by` (day, model or client).
+pub async fn usage_handler(
+    Extension(quotas): Extension<Arc<QuotaTracker>>,
+    Extension(usage_db): Extension<Option<Arc<UsageDb>>>,
+    identity: Option<Extension<Arc<ClientIdentity>>>,
+    Query(mut query): Query<UsageQuery>,
+) -> ProxyResult<Response> {
+    let mut report = match &identity {
+        Some(Extension(id)) => serde_json::to_value(quotas.report(id))?,
+        None => json!({ "clients": quotas.report_all() }),
+    };
+    if let Some(db) = usage_db {
+        if let Some(Extension(id)) = &identity {
+            query.client = Some(id.name.clone());
+        }
+        let history = tokio::task::spawn_blocking(move || db.summarize(&query))
+            .await
+            .map_err(|e| ProxyError::Internal(e.to_string()))?
+            .map_err(|e| ProxyError::Internal(format!("Usage query failed: {e}")))?;
+        report["history"] = json!(history);
+    }
+    Ok(Json(report).into_response())
+}
+
+/// `/conversations/{id}`: the exchanges stored for a session, oldest first. Authenticated
+/// clients see only their own.
+pub async fn conversation_handler(
+    Extension(conversations): Extension<Option<Arc<ConversationStore>>>,
+    identity: Option<Extension<Arc<ClientIdentity>>>,
+    Path(id): Path<String>,
+) -> ProxyResult<Response> {
+    let Some(store) = conversations else {
+        return Err(ProxyError::NotFound(
+            "Conversation history is not enabled (set CONVERSATIONS_DB)".to_string(),
+        ));
+    };
+    let client = identity.as_ref().map(|Extension(id)| id.name.as_str());
+    let exchanges = store
+        .load(&id, client)
+        .await
+        .map_err(|e| ProxyError::Internal(format!("Conversation query failed: {e:#}")))?;
+    if exchanges.is_empty() {
+        return Err(ProxyError::NotFound(format!("No conversation with id '{id}'")));
+    }
+    Ok(Json(json!({ "id": id, "exchanges": exchanges })).into_response())
 }
 
 /// Build POST request to upstream chat completions with optional auth and timeout.
@@ -80,20 +474,28 @@ fn build_upstream_request(
     client: &Client,
     url: &str,
     auth_header: Option<&str>,
+    forwarded: &HeaderMap,
+    headers: &HeaderMap,
     body: &openai::OpenAIRequest,
 ) -> reqwest::RequestBuilder {
     let mut builder = client
         .post(url)
         .json(body)
         .timeout(Duration::from_secs(UPSTREAM_TIMEOUT_SECS));
+    if !forwarded.is_empty() {
+        builder = builder.headers(forwarded.clone());
+    }
     if let Some(h) = auth_header {
         builder = builder.header("Authorization", h);
     }
+    if !headers.is_empty() {
+        builder = builder.headers(headers.clone());
+    }
     builder
 }
 
 /// Ensure response is success; otherwise read body and return `ProxyError::Upstream`.
-async fn require_success(mut response: reqwest::Response) -> ProxyResult<reqwest::Response> {
+async fn require_success(response: reqwest::Response) -> ProxyResult<reqwest::Response> {
     if response.status().is_success() {
         return Ok(response);
     }
@@ -102,256 +504,562 @@ async fn require_success(mut response: reqwest::Response) -> ProxyResult<reqwest
         .text()
         .await
         .unwrap_or_else(|_| "Unknown error".to_string());
-    tracing::error!("Upstream error ({}): {}", status, body);
+    tracing::error!("Upstream error ({}): {}", status, redact::mask_credentials(&body));
     Err(ProxyError::Upstream(format!("Upstream returned {status}: {body}")))
 }
 
+/// Longest excerpt of an unusable upstream body quoted in the error.
+const BODY_SNIPPET_CHARS: usize = 200;
+
+/// The error for an upstream body that is not a chat completion, quoting its start.
+fn invalid_response(body: &[u8], error: &serde_json::Error) -> ProxyError {
+    let text = String::from_utf8_lossy(body);
+    let text = text.trim();
+    if text.is_empty() {
+        tracing::error!("Upstream returned an empty response body");
+        return ProxyError::Upstream("Invalid upstream response: empty body".to_string());
+    }
+    let mut snippet: String = text.chars().take(BODY_SNIPPET_CHARS).collect();
+    if snippet.len() < text.len() {
+        snippet.push_str("...");
+    }
+    let snippet = redact::mask_credentials(&snippet);
+    tracing::error!("Invalid upstream response ({}): {}", error, snippet);
+    ProxyError::Upstream(format!("Invalid upstream response: {error}; body: {snippet}"))
+}
+
+/// `resp` as the client gets it: cut at stop sequences the upstream was not given, scrubbed PII
+/// restored and rewrite rules applied. `None` when that changes nothing.
+fn client_response(
+    resp: &anthropic::AnthropicResponse,
+    redactions: Option<&Redactions>,
+    stop_sequences: &[String],
+    rewrite: &Rewriter,
+) -> Option<anthropic::AnthropicResponse> {
+    if redactions.is_none() && stop_sequences.is_empty() && !rewrite.rewrites_responses() {
+        return None;
+    }
+    let mut resp = resp.clone();
+    if stops::enforce(&mut resp, stop_sequences) {
+        accesslog::with_current(|entry| entry.set_stop_reason("stop_sequence"));
+    }
+    if let Some(redactions) = redactions {
+        resp = redactions.restore_response(resp);
+    }
+    rewrite.rewrite_response(&mut resp);
+    Some(resp)
+}
+
+/// Notes a complete response's stop reason and usage in the access log.
+fn record_outcome(resp: &anthropic::AnthropicResponse) {
+    accesslog::with_current(|entry| {
+        if let Some(reason) = &resp.stop_reason {
+            entry.set_stop_reason(reason);
+        }
+        entry.set_usage(resp.usage.input_tokens, resp.usage.output_tokens);
+    });
+}
+
+/// Headers that never pass through FORWARD_HEADERS: credentials, and those describing the
+/// client's own connection and body.
+const NEVER_FORWARDED: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "x-api-key",
+    "cookie",
+    "host",
+    "connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+    "content-type",
+    "content-encoding",
+    "accept-encoding",
+];
+
+/// The client's headers matching a FORWARD_HEADERS pattern, to repeat on the upstream call.
+fn forwarded_headers(patterns: &[String], headers: &HeaderMap) -> HeaderMap {
+    if patterns.is_empty() {
+        return HeaderMap::new();
+    }
+    headers
+        .iter()
+        .filter(|(name, _)| !NEVER_FORWARDED.contains(&name.as_str()))
+        .filter(|(name, _)| patterns.iter().any(|p| wildcard_match(p, name.as_str())))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Sends `openai_req` with the next pooled key, reporting the outcome back to the pool.
+pub(crate) async fn send_upstream(
+    upstream: &Upstream,
+    openai_req: &openai::OpenAIRequest,
+    trace: &TraceContext,
+    forwarded: &HeaderMap,
+    chaos: Faults,
+) -> ProxyResult<reqwest::Response> {
+    let key = upstream.keys.next();
+    let sent = Instant::now();
+    warmup::touch();
+    let url = upstream.chat_completions_url(&openai_req.model);
+    let send = async {
+        if upstream.is_mock() {
+            return Ok(mockupstream::respond(openai_req).await);
+        }
+        http3::send(upstream, &url, || {
+            trace.apply(build_upstream_request(
+                &upstream.client,
+                &url,
+                key.as_deref().map(PooledKey::header_value),
+                forwarded,
+                &upstream.headers,
+                openai_req,
+            ))
+        })
+        .await
+    };
+    let response = chaos.apply(openai_req.stream.unwrap_or(false), send).await?;
+    if let Some(key) = &key {
+        if upstream.keys.report(key, &response) {
+            let exhausted = upstream.keys.exhausted();
+            if upstream.keys.len() > 1 {
+                events::emit(
+                    EventKind::UpstreamFailover,
+                    json!({
+                        "upstream": upstream.base_url,
+                        "key": key.id,
+                        "status": response.status().as_u16(),
+                        "keys": upstream.keys.len(),
+                        "exhausted": exhausted,
+                    }),
+                );
+            }
+            if exhausted {
+                alerts::raise(Alert::upstream_unavailable(
+                    &upstream.base_url,
+                    response.status().as_u16(),
+                    upstream.keys.len(),
+                ));
+            }
+        }
+    }
+    latency::observe("proxy_upstream_ttfb_seconds", openai_req.stream.unwrap_or(false), sent.elapsed());
+    Span::current().record("http.response.status_code", response.status().as_u16());
+    require_success(response).await
+}
+
+/// Where a request's upstream response comes from.
+enum Source<'a> {
+    /// The upstream itself, recording the exchange under TRAFFIC_RECORD.
+    Live {
+        upstream: &'a Upstream,
+        trace: &'a TraceContext,
+        /// Client headers allowed through by FORWARD_HEADERS.
+        forwarded: &'a HeaderMap,
+        /// Faults to inject (CHAOS_RATE, `x-proxy-chaos`).
+        chaos: Faults,
+        recording: Option<Recording>,
+    },
+    /// A recorded exchange (TRAFFIC_REPLAY).
+    Replay(Arc<Exchange>),
+}
+
+/// Client span around one upstream chat completions call.
+fn upstream_span(upstream: &Upstream, openai_req: &openai::OpenAIRequest) -> Span {
+    tracing::info_span!(
+        "upstream",
+        otel.kind = "client",
+        server.address = %upstream.base_url,
+        gen_ai.request.model = %openai_req.model,
+        http.response.status_code = Empty,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_non_streaming(
     config: Arc<Config>,
-    client: Client,
+    verbose: bool,
+    source: Source<'_>,
     openai_req: openai::OpenAIRequest,
+    store: Option<(&ResponseCache, CacheKey)>,
+    admission: Admission,
+    redactions: Option<&Redactions>,
+    stop_sequences: &[String],
+    rewrite: &Rewriter,
+    price: Option<ModelPrice>,
+    capture: Option<&Capture>,
+    hooks: Option<&Hooks>,
 ) -> ProxyResult<Response> {
-    let url = config.chat_completions_url();
-    tracing::debug!("Non-streaming request to {} model={}", url, openai_req.model);
-
-    let response = build_upstream_request(
-        &client,
-        url,
-        config.auth_header_value.as_deref(),
-        &openai_req,
-    )
-    .send()
-    .await?;
-
-    let response = require_success(response).await?;
-    let openai_resp: openai::OpenAIResponse = response.json().await?;
+    let (body, parsed) = match &source {
+        Source::Live { upstream, trace, forwarded, chaos, recording } => {
+            let url = upstream.chat_completions_url(&openai_req.model);
+            tracing::debug!("Non-streaming request to {} model={}", url, openai_req.model);
+            // A 200 with an empty or cut-off body is usually a transient upstream fault, so it is
+            // retried once (with the next pooled key) before the client sees an error.
+            let mut retried = false;
+            let (body, parsed) = loop {
+                let body = async {
+                    let sent = Instant::now();
+                    let response = send_upstream(upstream, &openai_req, trace, forwarded, *chaos).await?;
+                    let _stream = StreamGuard::new(response.version());
+                    let body = response.bytes().await?;
+                    latency::observe("proxy_upstream_duration_seconds", false, sent.elapsed());
+                    ProxyResult::Ok(body)
+                }
+                .instrument(upstream_span(upstream, &openai_req))
+                .await?;
+                let parsed = serde_json::from_slice::<openai::OpenAIResponse>(&body);
+                if parsed.is_ok() || retried {
+                    break (body, parsed);
+                }
+                let reason = if body.trim_ascii().is_empty() { "empty" } else { "invalid" };
+                tracing::warn!("Upstream returned an {} response body, retrying once", reason);
+                metrics::increment("proxy_upstream_retries_total", &[("reason", reason)], 1);
+                retried = true;
+            };
+            if let Some(recording) = recording {
+                recording.set_upstream(&body);
+            }
+            (body, parsed)
+        }
+        Source::Replay(exchange) => {
+            tracing::debug!("Replaying non-streaming exchange {} model={}", exchange.key, openai_req.model);
+            let body = Bytes::from(exchange.upstream.clone());
+            let parsed = serde_json::from_slice(&body);
+            (body, parsed)
+        }
+    };
+    if let Some(capture) = capture {
+        capture.write("upstream-response.json", &body);
+    }
+    let mut openai_resp: openai::OpenAIResponse = parsed.map_err(|e| invalid_response(&body, &e))?;
+    if let (Some(hooks), Source::Live { .. }) = (hooks, &source) {
+        hooks.plugins.on_upstream_response(&hooks.ctx, &mut openai_resp).await?;
+    }
+    let span = Span::current();
+    span.record("gen_ai.usage.input_tokens", openai_resp.usage.prompt_tokens);
+    span.record("gen_ai.usage.output_tokens", openai_resp.usage.completion_tokens);
+    if let Some(reason) = openai_resp.choices.first().and_then(|c| c.finish_reason.as_deref()) {
+        span.record("gen_ai.response.finish_reasons", reason);
+    }
 
-    if config.verbose {
-        let _ = tracing::trace!(
+    if verbose {
+        tracing::trace!(
+            target: redact::PAYLOAD_TARGET,
             "OpenAI response: {}",
-            serde_json::to_string_pretty(&openai_resp).unwrap_or_default()
+            redact::to_log_json(&openai_resp, config.log_content)
         );
     }
 
-    let anthropic_resp = transform::openai_to_anthropic(openai_resp)?;
+    admission.record_tokens(u64::from(openai_resp.usage.total_tokens));
+
+    let translating = Instant::now();
+    let anthropic_resp = translate::openai_to_anthropic(openai_resp)?;
+    record_outcome(&anthropic_resp);
+    let cost = price.map(|p| p.cost(anthropic_resp.usage.input_tokens, anthropic_resp.usage.output_tokens));
+    if let Some(cost) = cost {
+        admission.record_cost(cost);
+        accesslog::with_current(|entry| entry.set_cost(cost));
+    }
 
-    if config.verbose {
-        let _ = tracing::trace!(
+    if verbose {
+        tracing::trace!(
+            target: redact::PAYLOAD_TARGET,
             "Anthropic response: {}",
-            serde_json::to_string_pretty(&anthropic_resp).unwrap_or_default()
+            redact::to_log_json(&anthropic_resp, config.log_content)
         );
     }
 
-    Ok(Json(anthropic_resp).into_response())
+    // The cache keeps the response as generated; what the client gets is derived per request.
+    let restored = client_response(&anthropic_resp, redactions, stop_sequences, rewrite);
+    let sent = restored.as_ref().unwrap_or(&anthropic_resp);
+    if let Some(capture) = capture {
+        capture.write_json("anthropic-response.json", sent);
+    }
+    match &source {
+        Source::Live { recording: Some(recording), .. } => recording.set_response(&serde_json::to_vec(sent)?),
+        Source::Replay(exchange) => exchange.verify(&serde_json::to_vec(sent)?),
+        Source::Live { recording: None, .. } => {}
+    }
+    let mut response = Json(sent).into_response();
+    if let Some(value) = cost.and_then(|cost| HeaderValue::from_str(&pricing::format_cost(cost)).ok()) {
+        response.headers_mut().insert(COST_HEADER, value);
+    }
+    latency::observe_translation("response", false, translating.elapsed());
+    if let Some((cache, key)) = store {
+        cache.insert(key, &openai_req.model, anthropic_resp);
+    }
+    Ok(response)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_streaming(
-    config: Arc<Config>,
-    client: Client,
+    source: Source<'_>,
     openai_req: openai::OpenAIRequest,
+    admission: Admission,
+    filters: Vec<Box<dyn DeltaFilter>>,
+    stop_sequences: Vec<String>,
+    input_tokens: Option<u32>,
+    tool_input: ToolInputMode,
+    price: Option<ModelPrice>,
+    received: Instant,
+    capture: Option<&Capture>,
+    coalesce: Option<CoalesceSettings>,
+    hooks: Option<Hooks>,
 ) -> ProxyResult<Response> {
-    let url = config.chat_completions_url();
-    tracing::debug!("Streaming request to {} model={}", url, openai_req.model);
-
-    let response = build_upstream_request(
-        &client,
-        url,
-        config.auth_header_value.as_deref(),
-        &openai_req,
-    )
-    .send()
-    .await?;
-
-    let response = require_success(response).await?;
-    let stream = response.bytes_stream();
-    let sse_stream = create_sse_stream(stream);
+    let stream = match &source {
+        Source::Live { upstream, trace, forwarded, chaos, recording } => {
+            let url = upstream.chat_completions_url(&openai_req.model);
+            tracing::debug!("Streaming request to {} model={}", url, openai_req.model);
+            let sent = Instant::now();
+            let response = send_upstream(upstream, &openai_req, trace, forwarded, *chaos)
+                .instrument(upstream_span(upstream, &openai_req))
+                .await?;
+            let guard = StreamGuard::new(response.version());
+            let stream = match recording.clone() {
+                Some(recording) => Tee::new(response.bytes_stream(), move |body, finished| {
+                    if finished {
+                        recording.set_upstream(&body);
+                    }
+                }),
+                None => Tee::passthrough(response.bytes_stream()),
+            };
+            // The closure owns `guard`, so the stream stays counted until the body is dropped.
+            UpstreamTimer::new(stream.inspect(move |_| { let _ = &guard; }), sent).boxed()
+        }
+        Source::Replay(exchange) => {
+            tracing::debug!("Replaying streaming exchange {} model={}", exchange.key, openai_req.model);
+            futures::stream::iter(exchange.upstream_chunks().into_iter().map(Ok::<_, reqwest::Error>)).boxed()
+        }
+    };
+    let stream = capture::tee(capture, "upstream-stream.sse", stream);
+    // Replayed chunks arrive all at once; merging them would not match the recording.
+    let coalesce = coalesce.filter(|_| matches!(source, Source::Live { .. }));
+    let sse_stream = create_sse_stream(
+        stream,
+        admission,
+        filters,
+        stop_sequences,
+        input_tokens,
+        tool_input,
+        price,
+        coalesce,
+    );
+    let sse_stream = match hooks.filter(|_| matches!(source, Source::Live { .. })) {
+        Some(hooks) => sse_stream
+            .filter_map(move |event| {
+                let hooks = hooks.clone();
+                async move {
+                    match event {
+                        Ok(event) => hooks.plugins.on_stream_event(&hooks.ctx, event).await.map(Ok),
+                        Err(e) => Some(Err(e)),
+                    }
+                }
+            })
+            .left_stream(),
+        None => sse_stream.right_stream(),
+    };
+    let span = tracing::info_span!(
+        "stream_translation",
+        gen_ai.response.finish_reasons = Empty,
+        gen_ai.usage.input_tokens = Empty,
+        gen_ai.usage.output_tokens = Empty,
+    );
+    let sse_stream = capture::tee(capture, "anthropic-stream.sse", sse_stream);
+    let sse_stream = match source {
+        Source::Live { recording: Some(recording), .. } => Tee::new(sse_stream, move |body, finished| {
+            if finished {
+                recording.set_response(&body);
+            }
+        }),
+        Source::Replay(exchange) => Tee::new(sse_stream, move |body, finished| {
+            if finished {
+                exchange.verify(&body);
+            }
+        }),
+        Source::Live { recording: None, .. } => Tee::passthrough(sse_stream),
+    };
+    let sse_stream = InSpan::new(TranslationTimer::new(sse_stream, received), span);
 
     Ok((sse_header_map().clone(), Body::from_stream(sse_stream)).into_response())
 }
 
-#[inline]
-fn sse_event(event: &str, data: &str) -> Bytes {
-    Bytes::from(format!("event: {event}\ndata: {data}\n\n"))
+/// Replays a complete response (cached, or a moderation refusal) as an Anthropic SSE stream.
+fn buffered_stream_response(resp: &anthropic::AnthropicResponse) -> Response {
+    let events = stream::response_events(resp);
+    let stream = futures::stream::iter(events.into_iter().map(Ok::<_, std::io::Error>));
+    (sse_header_map().clone(), Body::from_stream(stream)).into_response()
 }
 
-fn create_sse_stream(
+/// Translates the upstream OpenAI SSE stream into Anthropic events. `admission` is held for
+/// the lifetime of the stream (keeping its concurrent-stream slot) and receives token usage;
+/// `filters` rewrite the deltas (restoring scrubbed PII, applying rewrite rules);
+/// `stop_sequences` end the output where they appear, for those the upstream was not given;
+/// `input_tokens` is the prompt estimate reported in `message_start`; `tool_input` says what
+/// to do with tool calls whose input is not valid JSON; `price` turns the final usage into a
+/// cost estimate charged to the client's budget; `coalesce` merges text and thinking deltas.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn create_sse_stream(
     stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+    admission: Admission,
+    filters: Vec<Box<dyn DeltaFilter>>,
+    stop_sequences: Vec<String>,
+    input_tokens: Option<u32>,
+    tool_input: ToolInputMode,
+    price: Option<ModelPrice>,
+    coalesce: Option<CoalesceSettings>,
 ) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
-    async_stream::stream! {
-        let mut buffer = String::new();
-        let mut message_id = None;
-        let mut current_model = None;
-        let mut content_index = 0;
-        let mut tool_call_id = None;
-        let mut has_sent_message_start = false;
-        let mut current_block_type: Option<BlockType> = None;
+    let mut stats = StreamStats::default();
+    let access = accesslog::current();
+    let mut translator = StreamTranslator::new()
+        .with_stop_sequences(stop_sequences)
+        .with_tool_input(tool_input);
+    if let Some(tokens) = input_tokens {
+        translator = translator.with_input_tokens(tokens);
+    }
+    if let Some(settings) = coalesce {
+        translator = translator.with_coalescing(settings);
+    }
+    for filter in filters {
+        translator = translator.with_filter(filter);
+    }
+    let events = async_stream::stream! {
+        let mut out = Vec::new();
 
         tokio::pin!(stream);
 
-        while let Some(chunk) = stream.next().await {
-            match chunk {
-                Ok(bytes) => {
-                    buffer.push_str(&String::from_utf8_lossy(&bytes));
-
-                    while let Some(pos) = buffer.find("\n\n") {
-                        let line = buffer[..pos].to_string();
-                        buffer.drain(..=pos + 1);
-
-                        if line.trim().is_empty() {
-                            continue;
-                        }
-
-                        for l in line.lines() {
-                            let Some(data) = l.strip_prefix("data: ") else { continue };
-                            if data.trim() == "[DONE]" {
-                                yield Ok(Bytes::from_static(SSE_MESSAGE_STOP));
-                                continue;
-                            }
-
-                            let Ok(chunk) = serde_json::from_str::<openai::StreamChunk>(data) else { continue };
-                            if message_id.is_none() {
-                                message_id = Some(chunk.id.clone());
-                            }
-                            if current_model.is_none() {
-                                current_model = Some(chunk.model.clone());
-                            }
-
-                            let Some(choice) = chunk.choices.first() else { continue };
-
-                            if !has_sent_message_start {
-                                let msg = anthropic::StreamEvent::MessageStart {
-                                    message: anthropic::MessageStartData {
-                                        id: message_id.clone().unwrap_or_default(),
-                                        message_type: "message".to_string(),
-                                        role: "assistant".to_string(),
-                                        model: current_model.clone().unwrap_or_default(),
-                                        usage: anthropic::Usage {
-                                            input_tokens: 0,
-                                            output_tokens: 0,
-                                        },
-                                    },
-                                };
-                                let data = serde_json::to_string(&msg).unwrap_or_default();
-                                yield Ok(sse_event("message_start", &data));
-                                has_sent_message_start = true;
-                            }
-
-                            if let Some(reasoning) = &choice.delta.reasoning {
-                                if current_block_type.is_none() {
-                                    let event = json!({
-                                        "type": "content_block_start",
-                                        "index": content_index,
-                                        "content_block": { "type": "thinking", "thinking": "" }
-                                    });
-                                    let data = serde_json::to_string(&event).unwrap_or_default();
-                                    yield Ok(sse_event("content_block_start", &data));
-                                    current_block_type = Some(BlockType::Thinking);
-                                }
-                                let event = json!({
-                                    "type": "content_block_delta",
-                                    "index": content_index,
-                                    "delta": { "type": "thinking_delta", "thinking": reasoning }
-                                });
-                                let data = serde_json::to_string(&event).unwrap_or_default();
-                                yield Ok(sse_event("content_block_delta", &data));
-                            }
-
-                            if let Some(content) = &choice.delta.content {
-                                if !content.is_empty() {
-                                    if current_block_type != Some(BlockType::Text) {
-                                        if current_block_type.is_some() {
-                                            let event = json!({"type": "content_block_stop", "index": content_index});
-                                            let data = serde_json::to_string(&event).unwrap_or_default();
-                                            yield Ok(sse_event("content_block_stop", &data));
-                                            content_index += 1;
-                                        }
-                                        let event = json!({
-                                            "type": "content_block_start",
-                                            "index": content_index,
-                                            "content_block": { "type": "text", "text": "" }
-                                        });
-                                        let data = serde_json::to_string(&event).unwrap_or_default();
-                                        yield Ok(sse_event("content_block_start", &data));
-                                        current_block_type = Some(BlockType::Text);
-                                    }
-                                    let event = json!({
-                                        "type": "content_block_delta",
-                                        "index": content_index,
-                                        "delta": { "type": "text_delta", "text": content }
-                                    });
-                                    let data = serde_json::to_string(&event).unwrap_or_default();
-                                    yield Ok(sse_event("content_block_delta", &data));
-                                }
-                            }
-
-                            if let Some(tool_calls) = &choice.delta.tool_calls {
-                                for tool_call in tool_calls {
-                                    if let Some(id) = &tool_call.id {
-                                        if current_block_type.is_some() {
-                                            let event = json!({"type": "content_block_stop", "index": content_index});
-                                            let data = serde_json::to_string(&event).unwrap_or_default();
-                                            yield Ok(sse_event("content_block_stop", &data));
-                                            content_index += 1;
-                                        }
-                                        tool_call_id = Some(id.clone());
-                                    }
-                                    if let Some(function) = &tool_call.function {
-                                        if let Some(name) = &function.name {
-                                            let event = json!({
-                                                "type": "content_block_start",
-                                                "index": content_index,
-                                                "content_block": {
-                                                    "type": "tool_use",
-                                                    "id": tool_call_id.clone().unwrap_or_default(),
-                                                    "name": name
-                                                }
-                                            });
-                                            let data = serde_json::to_string(&event).unwrap_or_default();
-                                            yield Ok(sse_event("content_block_start", &data));
-                                            current_block_type = Some(BlockType::ToolUse);
-                                        }
-                                        if let Some(args) = &function.arguments {
-                                            let event = json!({
-                                                "type": "content_block_delta",
-                                                "index": content_index,
-                                                "delta": { "type": "input_json_delta", "partial_json": args }
-                                            });
-                                            let data = serde_json::to_string(&event).unwrap_or_default();
-                                            yield Ok(sse_event("content_block_delta", &data));
-                                        }
-                                    }
-                                }
-                            }
-
-                            if let Some(finish_reason) = &choice.finish_reason {
-                                if current_block_type.is_some() {
-                                    let event = json!({"type": "content_block_stop", "index": content_index});
-                                    let data = serde_json::to_string(&event).unwrap_or_default();
-                                    yield Ok(sse_event("content_block_stop", &data));
-                                }
-                                let stop_reason = transform::map_stop_reason(Some(finish_reason));
-                                let event = json!({
-                                    "type": "message_delta",
-                                    "delta": { "stop_reason": stop_reason, "stop_sequence": serde_json::Value::Null },
-                                    "usage": chunk.usage.as_ref().map(|u| json!({ "output_tokens": u.completion_tokens }))
-                                });
-                                let data = serde_json::to_string(&event).unwrap_or_default();
-                                yield Ok(sse_event("message_delta", &data));
+        loop {
+            let next = match translator.flush_deadline() {
+                Some(deadline) => match tokio::time::timeout_at(deadline.into(), stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        translator.flush(&mut out);
+                        for event in out.drain(..) {
+                            if let Output::Event(event) = event {
+                                yield Ok(event);
                             }
                         }
+                        continue;
                     }
+                },
+                None => stream.next().await,
+            };
+            let ended = match next {
+                // The end of the stream may complete a last event, so its outputs are handled below.
+                None => {
+                    translator.finish(&mut out);
+                    true
                 }
-                Err(e) => {
+                Some(Ok(bytes)) => {
+                    translator.push(&bytes, &mut out);
+                    // An upstream that never pauses would otherwise hold deltas past the window.
+                    if translator.flush_deadline().is_some_and(|deadline| deadline <= Instant::now()) {
+                        translator.flush(&mut out);
+                    }
+                    false
+                }
+                Some(Err(e)) => {
                     tracing::error!("Stream error: {}", e);
-                    let error_event = json!({
-                        "type": "error",
-                        "error": { "type": "stream_error", "message": format!("Stream error: {e}") }
-                    });
-                    let data = serde_json::to_string(&error_event).unwrap_or_default();
-                    yield Ok(sse_event("error", &data));
-                    break;
+                    translator.error(&format!("Stream error: {e}"), &mut out);
+                    true
+                }
+            };
+            for output in out.drain(..) {
+                match output {
+                    Output::Event(event) => yield Ok(event),
+                    Output::Usage(usage) => {
+                        admission.record_tokens(u64::from(usage.total_tokens));
+                        let span = Span::current();
+                        span.record("gen_ai.usage.input_tokens", usage.prompt_tokens);
+                        span.record("gen_ai.usage.output_tokens", usage.completion_tokens);
+                        let cost = price.map(|p| p.cost(usage.prompt_tokens, usage.completion_tokens));
+                        if let Some(cost) = cost {
+                            admission.record_cost(cost);
+                        }
+                        if let Some(access) = &access {
+                            access.set_usage(usage.prompt_tokens, usage.completion_tokens);
+                            if let Some(cost) = cost {
+                                access.set_cost(cost);
+                            }
+                        }
+                    }
+                    Output::Finish { finish_reason, stop_reason } => {
+                        Span::current().record("gen_ai.response.finish_reasons", finish_reason.as_str());
+                        if let (Some(access), Some(reason)) = (&access, &stop_reason) {
+                            access.set_stop_reason(reason);
+                        }
+                    }
+                    Output::Malformed { bytes, error } => {
+                        tracing::warn!("Dropped unparseable upstream stream chunk ({} bytes): {}", bytes, error);
+                        metrics::increment("proxy_stream_parse_errors_total", &[], 1);
+                    }
+                    Output::InvalidToolInput { name, action } => {
+                        tracing::warn!("Tool call '{}' streamed invalid JSON input ({})", name, action);
+                        metrics::increment("proxy_invalid_tool_inputs_total", &[("action", action)], 1);
+                    }
                 }
             }
+            if ended {
+                break;
+            }
+        }
+    };
+    events.map(move |event| {
+        if let Ok(bytes) = &event {
+            stats.record(bytes);
+        }
+        event
+    })
+}
+
+/// Per-stream event counts, published to the metrics registry when the stream ends
+/// (including when the client disconnects) rather than on every event.
+#[derive(Default)]
+struct StreamStats {
+    events: BTreeMap<&'static str, u64>,
+    tool_calls: usize,
+}
+
+impl StreamStats {
+    const EVENTS: [&'static str; 7] = [
+        "message_start",
+        "content_block_start",
+        "content_block_delta",
+        "content_block_stop",
+        "message_delta",
+        "message_stop",
+        "error",
+    ];
+
+    fn record(&mut self, event: &[u8]) {
+        let name = event
+            .strip_prefix(b"event: ")
+            .and_then(|rest| rest.split(|&b| b == b'\n').next())
+            .and_then(|name| Self::EVENTS.into_iter().find(|e| e.as_bytes() == name))
+            .unwrap_or("other");
+        *self.events.entry(name).or_default() += 1;
+        // Block starts are built by json!, so the block type serializes without spaces.
+        if name == "content_block_start" && contains(event, br#""type":"tool_use""#) {
+            self.tool_calls += 1;
         }
     }
 }
+
+impl Drop for StreamStats {
+    fn drop(&mut self) {
+        for (event, count) in &self.events {
+            metrics::increment("proxy_stream_events_total", &[("event", event)], *count);
+        }
+        if self.events.contains_key("message_start") {
+            let blocks = self.events.get("content_block_start").copied().unwrap_or_default();
+            translate::record_response_shape(blocks as usize, self.tool_calls, true);
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
@@ -0,0 +1,112 @@
+//! Python module `anthropic_proxy` (`pyo3` feature): the converters and the stream translator
+//! for Python eval harnesses and gateways. JSON is passed as `str`, stream data as `bytes`.
+
+use crate::models::{anthropic, openai};
+use crate::stream::{self, Output};
+use crate::transform;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+/// Anthropic Messages request JSON -> OpenAI chat completions request JSON. The model name and
+/// parameters are kept as they are. Raises ValueError on invalid input.
+#[pyfunction]
+fn anthropic_to_openai(request: &str) -> PyResult<String> {
+    let req: anthropic::AnthropicRequest = serde_json::from_str(request).map_err(value_error)?;
+    serde_json::to_string(&transform::anthropic_to_openai(req, &mut ())).map_err(value_error)
+}
+
+/// OpenAI chat completions response JSON -> Anthropic Messages response JSON. Raises
+/// ValueError on invalid input.
+#[pyfunction]
+fn openai_to_anthropic(response: &str) -> PyResult<String> {
+    let resp: openai::OpenAIResponse = serde_json::from_str(response).map_err(value_error)?;
+    let resp = transform::openai_to_anthropic(resp, &mut ()).map_err(value_error)?;
+    serde_json::to_string(&resp).map_err(value_error)
+}
+
+fn value_error(e: impl std::fmt::Display) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+/// Translates one OpenAI stream into Anthropic SSE events. `push` takes upstream bytes as they
+/// arrive and returns the complete events; `usage` and `finish_reason` hold what the upstream
+/// reported so far.
+#[pyclass(name = "StreamTranslator", unsendable)]
+struct PyStreamTranslator {
+    inner: stream::StreamTranslator,
+    out: Vec<Output>,
+    /// Prompt and completion tokens.
+    usage: Option<(u32, u32)>,
+    finish_reason: Option<String>,
+}
+
+#[pymethods]
+impl PyStreamTranslator {
+    #[new]
+    fn new() -> Self {
+        Self {
+            inner: stream::StreamTranslator::new(),
+            out: Vec::new(),
+            usage: None,
+            finish_reason: None,
+        }
+    }
+
+    fn push<'py>(&mut self, py: Python<'py>, data: &[u8]) -> Vec<Bound<'py, PyBytes>> {
+        self.inner.push(data, &mut self.out);
+        self.events(py)
+    }
+
+    /// Events ending the stream with an Anthropic `error` event.
+    fn error<'py>(&mut self, py: Python<'py>, message: &str) -> Vec<Bound<'py, PyBytes>> {
+        self.inner.error(message, &mut self.out);
+        self.events(py)
+    }
+
+    /// Call when the upstream stream has ended.
+    fn finish<'py>(&mut self, py: Python<'py>) -> Vec<Bound<'py, PyBytes>> {
+        self.inner.finish(&mut self.out);
+        self.events(py)
+    }
+
+    /// `(prompt_tokens, completion_tokens)`, once the upstream reported usage.
+    #[getter]
+    fn usage(&self) -> Option<(u32, u32)> {
+        self.usage
+    }
+
+    /// The upstream's finish reason, once it sent one.
+    #[getter]
+    fn finish_reason(&self) -> Option<String> {
+        self.finish_reason.clone()
+    }
+}
+
+impl PyStreamTranslator {
+    fn events<'py>(&mut self, py: Python<'py>) -> Vec<Bound<'py, PyBytes>> {
+        let mut events = Vec::with_capacity(self.out.len());
+        for output in self.out.drain(..) {
+            match output {
+                Output::Event(event) => events.push(PyBytes::new(py, &event)),
+                Output::Usage(usage) => self.usage = Some((usage.prompt_tokens, usage.completion_tokens)),
+                Output::Finish { finish_reason, .. } => self.finish_reason = Some(finish_reason),
+                Output::Malformed { bytes, error } => {
+                    tracing::warn!("Dropped unparseable upstream stream chunk ({} bytes): {}", bytes, error);
+                }
+                Output::InvalidToolInput { name, action } => {
+                    tracing::warn!("Tool call '{}' has invalid JSON input ({})", name, action);
+                }
+            }
+        }
+        events
+    }
+}
+
+#[pymodule]
+fn anthropic_proxy(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(anthropic_to_openai, m)?)?;
+    m.add_function(wrap_pyfunction!(openai_to_anthropic, m)?)?;
+    m.add_class::<PyStreamTranslator>()?;
+    Ok(())
+}
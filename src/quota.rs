@@ -0,0 +1,514 @@
+//! Per-client quotas: requests per minute, tokens per day, concurrent streams and daily /
+//! monthly spend budgets (estimated from model prices).
+
+use crate::alerts::{self, Alert};
+use crate::auth::ClientIdentity;
+use crate::error::{ProxyError, ProxyResult};
+use crate::events;
+use crate::ratelimit::RateLimitPermit;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Share of a budget spent after which responses carry `x-proxy-budget-warning`.
+const BUDGET_WARNING_FRACTION: f64 = 0.8;
+
+/// Limits for one client; `None` means unlimited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_per_day: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrent_streams: Option<u32>,
+    /// Estimated spend allowed per budget day, in USD.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_budget_usd: Option<f64>,
+    /// Estimated spend allowed per budget month, in USD.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_budget_usd: Option<f64>,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self == &Quota::default()
+    }
+}
+
+/// When spend budgets reset (BUDGET_RESET_HOUR, BUDGET_RESET_DAY): daily budgets at `hour`
+/// UTC, monthly budgets at `hour` UTC on `day_of_month`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetSchedule {
+    pub hour: u32,
+    /// 1–28, so every month has the day.
+    pub day_of_month: u32,
+}
+
+impl Default for BudgetSchedule {
+    fn default() -> Self {
+        Self { hour: 0, day_of_month: 1 }
+    }
+}
+
+impl BudgetSchedule {
+    /// First day of the daily and of the monthly budget period containing `now`.
+    fn periods(&self, now: DateTime<Utc>) -> (NaiveDate, NaiveDate) {
+        let day = (now - chrono::Duration::hours(i64::from(self.hour))).date_naive();
+        let month = day - Days::new(u64::from(self.day_of_month - 1));
+        let month = month.with_day(1).unwrap_or(month) + Days::new(u64::from(self.day_of_month - 1));
+        (day, month)
+    }
+
+    fn at_reset_hour(&self, date: NaiveDate) -> DateTime<Utc> {
+        date.and_hms_opt(self.hour, 0, 0).unwrap_or_default().and_utc()
+    }
+
+    fn day_reset(&self, day: NaiveDate) -> DateTime<Utc> {
+        self.at_reset_hour(day + Days::new(1))
+    }
+
+    fn month_reset(&self, month: NaiveDate) -> DateTime<Utc> {
+        self.at_reset_hour(month + Months::new(1))
+    }
+}
+
+/// Live counters for one client, as reported on `/usage`.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyUsage {
+    pub requests_total: u64,
+    pub tokens_total: u64,
+    pub requests_this_minute: u32,
+    pub tokens_today: u64,
+    pub active_streams: u32,
+    #[serde(serialize_with = "serialize_usd")]
+    pub spend_today_usd: f64,
+    #[serde(serialize_with = "serialize_usd")]
+    pub spend_this_month_usd: f64,
+    #[serde(skip)]
+    minute_started: Instant,
+    #[serde(skip)]
+    day: NaiveDate,
+    #[serde(skip)]
+    budget_day: NaiveDate,
+    #[serde(skip)]
+    budget_month: NaiveDate,
+}
+
+impl KeyUsage {
+    fn new(schedule: &BudgetSchedule) -> Self {
+        let (budget_day, budget_month) = schedule.periods(Utc::now());
+        Self {
+            requests_total: 0,
+            tokens_total: 0,
+            requests_this_minute: 0,
+            tokens_today: 0,
+            active_streams: 0,
+            spend_today_usd: 0.0,
+            spend_this_month_usd: 0.0,
+            minute_started: Instant::now(),
+            day: Utc::now().date_naive(),
+            budget_day,
+            budget_month,
+        }
+    }
+
+    /// Rolls the minute, day and budget windows forward.
+    fn roll(&mut self, schedule: &BudgetSchedule) {
+        if self.minute_started.elapsed() >= MINUTE {
+            self.minute_started = Instant::now();
+            self.requests_this_minute = 0;
+        }
+        let today = Utc::now().date_naive();
+        if self.day != today {
+            self.day = today;
+            self.tokens_today = 0;
+        }
+        let (budget_day, budget_month) = schedule.periods(Utc::now());
+        if self.budget_day != budget_day {
+            self.budget_day = budget_day;
+            self.spend_today_usd = 0.0;
+        }
+        if self.budget_month != budget_month {
+            self.budget_month = budget_month;
+            self.spend_this_month_usd = 0.0;
+        }
+    }
+
+    fn minute_reset_secs(&self) -> u64 {
+        MINUTE.saturating_sub(self.minute_started.elapsed()).as_secs().max(1)
+    }
+}
+
+/// Seconds until the next UTC midnight, when daily token quotas reset.
+fn day_reset_secs() -> u64 {
+    let now = Utc::now();
+    let midnight = (now.date_naive() + chrono::Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .map(|t| t.and_utc());
+    midnight
+        .map(|m| (m - now).num_seconds().max(1) as u64)
+        .unwrap_or(86_400)
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub client: String,
+    pub quota: Quota,
+    #[serde(flatten)]
+    pub usage: KeyUsage,
+}
+
+/// Tracks usage per client name and enforces each client's effective quota.
+pub struct QuotaTracker {
+    defaults: Quota,
+    schedule: BudgetSchedule,
+    usage: Mutex<HashMap<String, KeyUsage>>,
+}
+
+impl QuotaTracker {
+    pub fn new(defaults: Quota, schedule: BudgetSchedule) -> Self {
+        Self {
+            defaults,
+            schedule,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The client's own quota, or the configured default.
+    pub fn quota_for<'a>(&'a self, identity: &'a ClientIdentity) -> &'a Quota {
+        identity.quota.as_ref().unwrap_or(&self.defaults)
+    }
+
+    /// Admits a request or fails with a `rate_limit_error`. The returned [`Admission`]
+    /// holds a stream slot (if streaming) until dropped.
+    pub fn admit(
+        self: &Arc<Self>,
+        identity: &ClientIdentity,
+        streaming: bool,
+    ) -> ProxyResult<Admission> {
+        let quota = self.quota_for(identity).clone();
+        let mut all = self.lock();
+        let usage = all
+            .entry(identity.name.clone())
+            .or_insert_with(|| KeyUsage::new(&self.schedule));
+        usage.roll(&self.schedule);
+
+        if let Some(limit) = quota.requests_per_minute {
+            if usage.requests_this_minute >= limit {
+                return Err(rejection(
+                    format!("Request rate limit of {limit} per minute exceeded"),
+                    usage.minute_reset_secs(),
+                    &quota,
+                    usage,
+                    &self.schedule,
+                ));
+            }
+        }
+        if let Some(limit) = quota.tokens_per_day {
+            if usage.tokens_today >= limit {
+                return Err(rejection(
+                    format!("Daily token quota of {limit} exhausted"),
+                    day_reset_secs(),
+                    &quota,
+                    usage,
+                    &self.schedule,
+                ));
+            }
+        }
+        if let Some(budget) = quota.daily_budget_usd {
+            if usage.spend_today_usd >= budget {
+                let resets = self.schedule.day_reset(usage.budget_day);
+                alerts::raise(Alert::budget_exhausted(&identity.name, "daily", budget, resets));
+                return Err(budget_rejection(
+                    format!("Daily budget of ${budget} exhausted"),
+                    resets,
+                    &quota,
+                    usage,
+                    &self.schedule,
+                ));
+            }
+        }
+        if let Some(budget) = quota.monthly_budget_usd {
+            if usage.spend_this_month_usd >= budget {
+                let resets = self.schedule.month_reset(usage.budget_month);
+                alerts::raise(Alert::budget_exhausted(&identity.name, "monthly", budget, resets));
+                return Err(budget_rejection(
+                    format!("Monthly budget of ${budget} exhausted"),
+                    resets,
+                    &quota,
+                    usage,
+                    &self.schedule,
+                ));
+            }
+        }
+        if streaming {
+            if let Some(limit) = quota.concurrent_streams {
+                if usage.active_streams >= limit {
+                    return Err(rejection(
+                        format!("Concurrent stream limit of {limit} reached"),
+                        1,
+                        &quota,
+                        usage,
+                        &self.schedule,
+                    ));
+                }
+            }
+            usage.active_streams += 1;
+        }
+
+        usage.requests_this_minute += 1;
+        usage.requests_total += 1;
+        let headers = rate_limit_headers(&quota, usage, &self.schedule);
+        drop(all);
+
+        Ok(Admission {
+            tracker: Some(Arc::clone(self)),
+            client: identity.name.clone(),
+            quota,
+            holds_stream: streaming,
+            rate_limit: None,
+            headers,
+        })
+    }
+
+    fn record_tokens(&self, client: &str, tokens: u64) {
+        if let Some(usage) = self.lock().get_mut(client) {
+            usage.roll(&self.schedule);
+            usage.tokens_today += tokens;
+            usage.tokens_total += tokens;
+        }
+    }
+
+    fn record_cost(&self, client: &str, quota: &Quota, cost_usd: f64) {
+        if let Some(usage) = self.lock().get_mut(client) {
+            usage.roll(&self.schedule);
+            let (day_before, month_before) = (usage.spend_today_usd, usage.spend_this_month_usd);
+            usage.spend_today_usd += cost_usd;
+            usage.spend_this_month_usd += cost_usd;
+            if let Some(budget) = quota.daily_budget_usd {
+                let resets = self.schedule.day_reset(usage.budget_day);
+                events::budget_spent(client, "daily", budget, day_before, usage.spend_today_usd, resets);
+            }
+            if let Some(budget) = quota.monthly_budget_usd {
+                let resets = self.schedule.month_reset(usage.budget_month);
+                events::budget_spent(client, "monthly", budget, month_before, usage.spend_this_month_usd, resets);
+            }
+        }
+    }
+
+    fn release_stream(&self, client: &str) {
+        if let Some(usage) = self.lock().get_mut(client) {
+            usage.active_streams = usage.active_streams.saturating_sub(1);
+        }
+    }
+
+    /// Usage for one client, if it has made any requests.
+    pub fn report(&self, identity: &ClientIdentity) -> UsageReport {
+        let mut all = self.lock();
+        let usage = all
+            .entry(identity.name.clone())
+            .or_insert_with(|| KeyUsage::new(&self.schedule));
+        usage.roll(&self.schedule);
+        UsageReport {
+            client: identity.name.clone(),
+            quota: self.quota_for(identity).clone(),
+            usage: usage.clone(),
+        }
+    }
+
+    /// Usage for every client seen so far (quotas shown are the defaults).
+    pub fn report_all(&self) -> Vec<UsageReport> {
+        let mut all = self.lock();
+        let mut reports: Vec<UsageReport> = all
+            .iter_mut()
+            .map(|(client, usage)| {
+                usage.roll(&self.schedule);
+                UsageReport {
+                    client: client.clone(),
+                    quota: self.defaults.clone(),
+                    usage: usage.clone(),
+                }
+            })
+            .collect();
+        reports.sort_by(|a, b| a.client.cmp(&b.client));
+        reports
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, KeyUsage>> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An admitted request. Records token usage against the client (and its rate-limit buckets)
+/// and releases its concurrent-stream slot when dropped.
+pub struct Admission {
+    tracker: Option<Arc<QuotaTracker>>,
+    client: String,
+    /// The client's effective quota, for budget threshold events.
+    quota: Quota,
+    holds_stream: bool,
+    rate_limit: Option<RateLimitPermit>,
+    /// `anthropic-ratelimit-*` headers to attach to the response.
+    pub headers: HeaderMap,
+}
+
+impl Admission {
+    /// A request without a client identity; only rate limits apply to it.
+    pub fn anonymous() -> Self {
+        Self {
+            tracker: None,
+            client: String::new(),
+            quota: Quota::default(),
+            holds_stream: false,
+            rate_limit: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Also charges token usage to `permit`; its headers replace the quota's, since
+    /// per-minute limits are the ones clients hit first.
+    pub fn with_rate_limit(mut self, permit: RateLimitPermit) -> Self {
+        self.headers.extend(permit.headers.clone());
+        self.rate_limit = Some(permit);
+        self
+    }
+
+    pub fn record_tokens(&self, tokens: u64) {
+        if let Some(tracker) = &self.tracker {
+            tracker.record_tokens(&self.client, tokens);
+        }
+        if let Some(permit) = &self.rate_limit {
+            permit.record_tokens(tokens);
+        }
+    }
+
+    /// Charges an estimated cost against the client's spend budgets.
+    pub fn record_cost(&self, cost_usd: f64) {
+        if let Some(tracker) = &self.tracker {
+            tracker.record_cost(&self.client, &self.quota, cost_usd);
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let (true, Some(tracker)) = (self.holds_stream, &self.tracker) {
+            tracker.release_stream(&self.client);
+        }
+    }
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &'static str, value: impl ToString) {
+    if let Ok(value) = HeaderValue::from_str(&value.to_string()) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
+
+/// Anthropic-compatible rate-limit headers for the limits that are configured, plus
+/// `x-proxy-budget-*` headers for spend budgets.
+fn rate_limit_headers(quota: &Quota, usage: &KeyUsage, schedule: &BudgetSchedule) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(limit) = quota.requests_per_minute {
+        insert_header(&mut headers, "anthropic-ratelimit-requests-limit", limit);
+        insert_header(
+            &mut headers,
+            "anthropic-ratelimit-requests-remaining",
+            limit.saturating_sub(usage.requests_this_minute),
+        );
+        insert_header(
+            &mut headers,
+            "anthropic-ratelimit-requests-reset",
+            reset_timestamp(usage.minute_reset_secs()),
+        );
+    }
+    if let Some(limit) = quota.tokens_per_day {
+        insert_header(&mut headers, "anthropic-ratelimit-tokens-limit", limit);
+        insert_header(
+            &mut headers,
+            "anthropic-ratelimit-tokens-remaining",
+            limit.saturating_sub(usage.tokens_today),
+        );
+        insert_header(
+            &mut headers,
+            "anthropic-ratelimit-tokens-reset",
+            reset_timestamp(day_reset_secs()),
+        );
+    }
+
+    let mut warnings = Vec::new();
+    let budgets = [
+        ("daily", quota.daily_budget_usd, usage.spend_today_usd, schedule.day_reset(usage.budget_day)),
+        ("monthly", quota.monthly_budget_usd, usage.spend_this_month_usd, schedule.month_reset(usage.budget_month)),
+    ];
+    for (period, budget, spent, resets) in budgets {
+        let Some(budget) = budget else { continue };
+        let remaining = (budget - spent).max(0.0);
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(format!("x-proxy-budget-{period}-remaining-usd")),
+            HeaderValue::from_str(&format!("{remaining:.6}")),
+        ) {
+            headers.insert(name, value);
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(format!("x-proxy-budget-{period}-reset")),
+            HeaderValue::from_str(&resets.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        ) {
+            headers.insert(name, value);
+        }
+        if spent >= budget * BUDGET_WARNING_FRACTION {
+            warnings.push(period);
+        }
+    }
+    if !warnings.is_empty() {
+        insert_header(&mut headers, "x-proxy-budget-warning", warnings.join(","));
+    }
+    headers
+}
+
+/// Spend rounded to micro-dollars, hiding floating-point noise on `/usage`.
+fn serialize_usd<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64((value * 1_000_000.0).round() / 1_000_000.0)
+}
+
+/// RFC 3339 timestamp `secs` from now, the format Anthropic uses for `*-reset` headers.
+fn reset_timestamp(secs: u64) -> String {
+    (Utc::now() + chrono::Duration::seconds(secs as i64))
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn rejection(
+    message: String,
+    retry_after_secs: u64,
+    quota: &Quota,
+    usage: &KeyUsage,
+    schedule: &BudgetSchedule,
+) -> ProxyError {
+    tracing::warn!("Quota rejection: {}", message);
+    let mut headers = rate_limit_headers(quota, usage, schedule);
+    insert_header(&mut headers, "retry-after", retry_after_secs);
+    ProxyError::RateLimited { message, headers }
+}
+
+/// Rejection once a spend budget is exhausted; retried requests fail until `resets`.
+fn budget_rejection(
+    message: String,
+    resets: DateTime<Utc>,
+    quota: &Quota,
+    usage: &KeyUsage,
+    schedule: &BudgetSchedule,
+) -> ProxyError {
+    tracing::warn!("Budget rejection: {}", message);
+    let mut headers = rate_limit_headers(quota, usage, schedule);
+    insert_header(&mut headers, "retry-after", (resets - Utc::now()).num_seconds().max(1));
+    let resets = resets.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    ProxyError::BudgetExceeded {
+        message: format!("{message}; resets at {resets}"),
+        headers,
+    }
+}
@@ -0,0 +1,615 @@
+//! Token-bucket rate limits on request and token throughput, with separate buckets per client
+//! key and per source IP, optionally shared between replicas through Redis (`redis` feature).
+
+use crate::error::{ProxyError, ProxyResult};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Bucket count above which full (idle) buckets are dropped.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// How long to limit locally after a Redis failure before trying Redis again.
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Sustained rates for one kind of subject; `None` or 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<u32>,
+    /// Requests that may arrive back-to-back; defaults to `requests_per_minute`.
+    pub burst: Option<u32>,
+    /// Upstream tokens (prompt + completion) per minute, charged once a response completes.
+    pub tokens_per_minute: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.unwrap_or(0) == 0 && self.tokens_per_minute.unwrap_or(0) == 0
+    }
+}
+
+/// RATE_LIMIT_* settings.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitSettings {
+    pub per_ip: RateLimit,
+    pub per_key: RateLimit,
+    /// Share buckets across replicas through this Redis server (`redis` feature).
+    pub redis_url: Option<String>,
+    /// Prefix for the Redis bucket keys.
+    pub redis_prefix: String,
+}
+
+impl RateLimitSettings {
+    pub fn is_enabled(&self) -> bool {
+        !self.per_ip.is_unlimited() || !self.per_key.is_unlimited()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Ip(IpAddr),
+    Key(String),
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "address {ip}"),
+            Self::Key(name) => write!(f, "client '{name}'"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Requests,
+    Tokens,
+}
+
+impl Kind {
+    fn unit(self) -> &'static str {
+        match self {
+            Self::Requests => "requests",
+            Self::Tokens => "tokens",
+        }
+    }
+
+    /// Level a bucket must hold to admit a request. Token buckets only need to be out of debt.
+    fn needed(self) -> f64 {
+        match self {
+            Self::Requests => 1.0,
+            Self::Tokens => f64::MIN_POSITIVE,
+        }
+    }
+
+    /// Amount taken when a request is admitted; tokens are charged after the response.
+    fn taken(self) -> f64 {
+        match self {
+            Self::Requests => 1.0,
+            Self::Tokens => 0.0,
+        }
+    }
+}
+
+/// One bucket a request has to pass.
+#[derive(Debug, Clone)]
+struct Spec {
+    subject: Subject,
+    kind: Kind,
+    /// Refill rate per minute.
+    rate: f64,
+    capacity: f64,
+}
+
+impl Spec {
+    fn per_sec(&self) -> f64 {
+        self.rate / 60.0
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    level: f64,
+    capacity: f64,
+    per_sec: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64, per_minute: f64) -> Self {
+        Self {
+            level: capacity,
+            capacity,
+            per_sec: per_minute / 60.0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.level = (self.level + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Seconds until the bucket holds `level`.
+    fn secs_until(&self, level: f64) -> u64 {
+        ((level - self.level) / self.per_sec).max(0.0).ceil() as u64
+    }
+
+    fn is_full(&self) -> bool {
+        self.level >= self.capacity
+    }
+}
+
+/// Bucket levels after an admission attempt: every bucket when admitted, otherwise the index
+/// of the first bucket that refused.
+type Outcome = Result<Vec<Bucket>, (usize, Bucket)>;
+
+struct State {
+    buckets: HashMap<(Subject, Kind), Bucket>,
+    prune_at: usize,
+}
+
+/// Token buckets for every configured subject, kept in Redis when REDIS_URL is set so that
+/// replicas share them, and in memory otherwise (or while Redis is unreachable).
+pub struct RateLimiter {
+    /// Replaced by [`RateLimiter::update`] on config reload; the Redis connection is not.
+    settings: RwLock<RateLimitSettings>,
+    state: Mutex<State>,
+    shared: Option<shared::SharedBuckets>,
+    /// While Redis is failing: when to try it again. Requests until then are limited locally.
+    redis_retry_at: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> anyhow::Result<Self> {
+        let shared = match &settings.redis_url {
+            Some(url) if settings.is_enabled() => {
+                Some(shared::SharedBuckets::connect(url, &settings.redis_prefix)?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            settings: RwLock::new(settings),
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                prune_at: PRUNE_THRESHOLD,
+            }),
+            shared,
+            redis_retry_at: Mutex::new(None),
+        })
+    }
+
+    /// Applies new per-IP and per-key rates; existing buckets keep their level.
+    pub fn update(&self, per_ip: RateLimit, per_key: RateLimit) {
+        let mut settings = self.settings.write().unwrap_or_else(|e| e.into_inner());
+        settings.per_ip = per_ip;
+        settings.per_key = per_key;
+    }
+
+    fn specs(&self, ip: Option<IpAddr>, client: Option<&str>) -> Vec<Spec> {
+        let (per_ip, per_key) = {
+            let settings = self.settings.read().unwrap_or_else(|e| e.into_inner());
+            (settings.per_ip, settings.per_key)
+        };
+        let subjects = ip
+            .map(|ip| (Subject::Ip(ip), per_ip))
+            .into_iter()
+            .chain(client.map(|name| (Subject::Key(name.to_string()), per_key)));
+        let mut specs = Vec::new();
+        for (subject, limit) in subjects {
+            // A zero rate would never refill (and divides by zero), so it gets no bucket.
+            if let Some(rpm) = limit.requests_per_minute.filter(|&n| n > 0) {
+                specs.push(Spec {
+                    subject: subject.clone(),
+                    kind: Kind::Requests,
+                    rate: f64::from(rpm),
+                    capacity: f64::from(limit.burst.unwrap_or(rpm).max(1)),
+                });
+            }
+            if let Some(tpm) = limit.tokens_per_minute.filter(|&n| n > 0) {
+                specs.push(Spec {
+                    subject,
+                    kind: Kind::Tokens,
+                    rate: tpm as f64,
+                    capacity: tpm as f64,
+                });
+            }
+        }
+        specs
+    }
+
+    /// Takes one request from the caller's buckets, or fails with a `rate_limit_error` without
+    /// consuming anything. Token buckets admit while they are not in debt.
+    pub async fn check(
+        self: &Arc<Self>,
+        ip: Option<IpAddr>,
+        client: Option<&str>,
+    ) -> ProxyResult<Option<RateLimitPermit>> {
+        let specs = self.specs(ip, client);
+        if specs.is_empty() {
+            return Ok(None);
+        }
+
+        let outcome = match self.shared.as_ref().filter(|_| self.redis_due()) {
+            Some(shared) => match shared.take(&specs).await {
+                Ok(outcome) => {
+                    self.recovered();
+                    outcome
+                }
+                Err(e) => {
+                    self.degrade(&e);
+                    self.take_local(&specs)
+                }
+            },
+            None => self.take_local(&specs),
+        };
+
+        let mut headers = HeaderMap::new();
+        let buckets = match outcome {
+            Ok(buckets) => buckets,
+            Err((index, bucket)) => {
+                let spec = &specs[index];
+                let retry_after = bucket.secs_until(spec.kind.needed()).max(1);
+                let message = format!(
+                    "Rate limit of {} {} per minute exceeded for {}",
+                    spec.rate,
+                    spec.kind.unit(),
+                    spec.subject
+                );
+                tracing::warn!("Rate limit rejection: {}", message);
+                set_headers(&mut headers, spec.kind, spec.rate, &bucket);
+                insert_header(&mut headers, "retry-after", retry_after);
+                return Err(ProxyError::RateLimited { message, headers });
+            }
+        };
+        for (spec, bucket) in specs.iter().zip(&buckets) {
+            set_headers(&mut headers, spec.kind, spec.rate, bucket);
+        }
+        Ok(Some(RateLimitPermit {
+            limiter: Arc::clone(self),
+            tokens: specs.into_iter().filter(|s| s.kind == Kind::Tokens).collect(),
+            headers,
+        }))
+    }
+
+    fn take_local(&self, specs: &[Spec]) -> Outcome {
+        let now = Instant::now();
+        let mut state = self.lock();
+        state.prune(now);
+        for (index, spec) in specs.iter().enumerate() {
+            let bucket = state
+                .buckets
+                .entry((spec.subject.clone(), spec.kind))
+                .or_insert_with(|| Bucket::new(spec.capacity, spec.rate));
+            // Rates may have changed since the bucket was created.
+            bucket.capacity = spec.capacity;
+            bucket.per_sec = spec.per_sec();
+            bucket.refill(now);
+            if bucket.level < spec.kind.needed() {
+                return Err((index, bucket.clone()));
+            }
+        }
+        Ok(specs
+            .iter()
+            .filter_map(|spec| {
+                let bucket = state.buckets.get_mut(&(spec.subject.clone(), spec.kind))?;
+                bucket.level -= spec.kind.taken();
+                Some(bucket.clone())
+            })
+            .collect())
+    }
+
+    fn charge_local(&self, specs: &[Spec], tokens: u64) {
+        let now = Instant::now();
+        let mut state = self.lock();
+        for spec in specs {
+            if let Some(bucket) = state.buckets.get_mut(&(spec.subject.clone(), spec.kind)) {
+                bucket.refill(now);
+                bucket.level -= tokens as f64;
+            }
+        }
+    }
+
+    fn charge(self: &Arc<Self>, specs: Vec<Spec>, tokens: u64) {
+        if self.shared.is_none() || !self.redis_due() {
+            self.charge_local(&specs, tokens);
+            return;
+        }
+        let limiter = Arc::clone(self);
+        tokio::spawn(async move {
+            let Some(shared) = &limiter.shared else { return };
+            if let Err(e) = shared.charge(&specs, tokens).await {
+                limiter.degrade(&e);
+                limiter.charge_local(&specs, tokens);
+            }
+        });
+    }
+
+    /// Whether Redis should be used, i.e. it is healthy or the retry interval has passed.
+    fn redis_due(&self) -> bool {
+        self.retry_lock()
+            .is_none_or(|retry_at| Instant::now() >= retry_at)
+    }
+
+    fn degrade(&self, error: &anyhow::Error) {
+        let mut retry_at = self.retry_lock();
+        if retry_at.is_none() {
+            tracing::warn!("Redis rate limiting unavailable, limiting locally: {:#}", error);
+        }
+        *retry_at = Some(Instant::now() + REDIS_RETRY_INTERVAL);
+    }
+
+    fn recovered(&self) {
+        if self.retry_lock().take().is_some() {
+            tracing::info!("Redis rate limiting restored");
+        }
+    }
+
+    fn retry_lock(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.redis_retry_at.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    /// Drops buckets that have refilled completely; they behave exactly like new ones.
+    fn prune(&mut self, now: Instant) {
+        if self.buckets.len() < self.prune_at {
+            return;
+        }
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            !bucket.is_full()
+        });
+        self.prune_at = (self.buckets.len() * 2).max(PRUNE_THRESHOLD);
+    }
+}
+
+/// A request admitted by the rate limiter; charges upstream token usage to its buckets.
+pub struct RateLimitPermit {
+    limiter: Arc<RateLimiter>,
+    tokens: Vec<Spec>,
+    /// `anthropic-ratelimit-*` headers for the tightest bucket of each kind.
+    pub headers: HeaderMap,
+}
+
+impl RateLimitPermit {
+    pub fn record_tokens(&self, tokens: u64) {
+        if !self.tokens.is_empty() {
+            self.limiter.charge(self.tokens.clone(), tokens);
+        }
+    }
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &'static str, value: impl ToString) {
+    if let Ok(value) = HeaderValue::from_str(&value.to_string()) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
+
+/// Sets the limit/remaining/reset headers for `kind` unless a tighter bucket already did.
+fn set_headers(headers: &mut HeaderMap, kind: Kind, rate: f64, bucket: &Bucket) {
+    let (limit, remaining, reset) = match kind {
+        Kind::Requests => (
+            "anthropic-ratelimit-requests-limit",
+            "anthropic-ratelimit-requests-remaining",
+            "anthropic-ratelimit-requests-reset",
+        ),
+        Kind::Tokens => (
+            "anthropic-ratelimit-tokens-limit",
+            "anthropic-ratelimit-tokens-remaining",
+            "anthropic-ratelimit-tokens-reset",
+        ),
+    };
+    let left = bucket.level.max(0.0).floor() as u64;
+    let tighter = headers
+        .get(remaining)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|current| current <= left);
+    if tighter {
+        return;
+    }
+    insert_header(headers, limit, rate as u64);
+    insert_header(headers, remaining, left);
+    let reset_at = Utc::now() + chrono::Duration::seconds(bucket.secs_until(bucket.capacity) as i64);
+    insert_header(
+        headers,
+        reset,
+        reset_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    );
+}
+
+#[cfg(feature = "redis")]
+mod shared {
+    use super::{Bucket, Outcome, Spec, Subject};
+    use anyhow::Context;
+    use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+    use redis::Script;
+    use std::time::{Duration, Instant};
+
+    /// Redis calls slower than this fall back to local limiting for the request.
+    const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+
+    /// Refills and checks every bucket, then takes from all of them, in one atomic step. Buckets
+    /// are hashes of `level` and `updated` (Redis server time), expiring once they would be
+    /// full again. ARGV holds `capacity, per_sec, needed, taken` per key.
+    const TAKE: &str = r#"
+local t = redis.call('TIME')
+local now = tonumber(t[1]) + tonumber(t[2]) / 1e6
+local levels = {}
+for i, key in ipairs(KEYS) do
+  local capacity = tonumber(ARGV[i * 4 - 3])
+  local per_sec = tonumber(ARGV[i * 4 - 2])
+  local state = redis.call('HMGET', key, 'level', 'updated')
+  local level = tonumber(state[1]) or capacity
+  local updated = tonumber(state[2]) or now
+  level = math.min(capacity, level + math.max(0, now - updated) * per_sec)
+  if level < tonumber(ARGV[i * 4 - 1]) then
+    return {0, i, tostring(level)}
+  end
+  levels[i] = level
+end
+local result = {1}
+for i, key in ipairs(KEYS) do
+  local level = levels[i] - tonumber(ARGV[i * 4])
+  redis.call('HSET', key, 'level', tostring(level), 'updated', tostring(now))
+  redis.call('EXPIRE', key, math.ceil((tonumber(ARGV[i * 4 - 3]) - level) / tonumber(ARGV[i * 4 - 2])) + 1)
+  result[i + 1] = tostring(level)
+end
+return result
+"#;
+
+    /// Refills each token bucket and subtracts ARGV[1] tokens from it.
+    const CHARGE: &str = r#"
+local t = redis.call('TIME')
+local now = tonumber(t[1]) + tonumber(t[2]) / 1e6
+local tokens = tonumber(ARGV[1])
+for i, key in ipairs(KEYS) do
+  local capacity = tonumber(ARGV[i * 2])
+  local per_sec = tonumber(ARGV[i * 2 + 1])
+  local state = redis.call('HMGET', key, 'level', 'updated')
+  local level = tonumber(state[1]) or capacity
+  local updated = tonumber(state[2]) or now
+  level = math.min(capacity, level + math.max(0, now - updated) * per_sec) - tokens
+  redis.call('HSET', key, 'level', tostring(level), 'updated', tostring(now))
+  redis.call('EXPIRE', key, math.ceil((capacity - level) / per_sec) + 1)
+end
+return 1
+"#;
+
+    pub struct SharedBuckets {
+        connection: ConnectionManager,
+        prefix: String,
+        take: Script,
+        charge: Script,
+    }
+
+    impl SharedBuckets {
+        /// Connects lazily, so an unreachable Redis at startup only means local limiting.
+        pub fn connect(url: &str, prefix: &str) -> anyhow::Result<Self> {
+            let client = redis::Client::open(url).context("Invalid REDIS_URL")?;
+            let config = ConnectionManagerConfig::new()
+                .set_connection_timeout(Some(REDIS_TIMEOUT))
+                .set_response_timeout(Some(REDIS_TIMEOUT))
+                .set_number_of_retries(1);
+            Ok(Self {
+                connection: ConnectionManager::new_lazy_with_config(client, config)
+                    .context("Failed to set up the Redis connection")?,
+                prefix: prefix.to_string(),
+                take: Script::new(TAKE),
+                charge: Script::new(CHARGE),
+            })
+        }
+
+        fn key(&self, spec: &Spec) -> String {
+            let subject = match &spec.subject {
+                Subject::Ip(ip) => format!("ip:{ip}"),
+                Subject::Key(name) => format!("key:{name}"),
+            };
+            format!("{}{}:{}", self.prefix, subject, spec.kind.unit())
+        }
+
+        pub async fn take(&self, specs: &[Spec]) -> anyhow::Result<Outcome> {
+            let mut invocation = self.take.prepare_invoke();
+            for spec in specs {
+                invocation
+                    .key(self.key(spec))
+                    .arg(spec.capacity)
+                    .arg(spec.per_sec())
+                    .arg(spec.kind.needed())
+                    .arg(spec.kind.taken());
+            }
+            let mut connection = self.connection.clone();
+            let reply: Vec<redis::Value> =
+                tokio::time::timeout(REDIS_TIMEOUT, invocation.invoke_async(&mut connection))
+                    .await
+                    .context("Redis timed out")??;
+            let number = |value: Option<&redis::Value>| -> anyhow::Result<f64> {
+                let value = value.context("Short reply from Redis")?;
+                Ok(redis::from_redis_value_ref::<String>(value)?.parse()?)
+            };
+            let bucket = |spec: &Spec, level: f64| Bucket {
+                level,
+                capacity: spec.capacity,
+                per_sec: spec.per_sec(),
+                updated: Instant::now(),
+            };
+            if number(reply.first())? == 0.0 {
+                let index = (number(reply.get(1))? as usize).saturating_sub(1);
+                let spec = specs.get(index).context("Bad bucket index from Redis")?;
+                return Ok(Err((index, bucket(spec, number(reply.get(2))?))));
+            }
+            specs
+                .iter()
+                .zip(reply.iter().skip(1))
+                .map(|(spec, level)| Ok(bucket(spec, number(Some(level))?)))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .map(Ok)
+        }
+
+        pub async fn charge(&self, specs: &[Spec], tokens: u64) -> anyhow::Result<()> {
+            let mut invocation = self.charge.prepare_invoke();
+            invocation.arg(tokens);
+            for spec in specs {
+                invocation
+                    .key(self.key(spec))
+                    .arg(spec.capacity)
+                    .arg(spec.per_sec());
+            }
+            let mut connection = self.connection.clone();
+            let _: i64 =
+                tokio::time::timeout(REDIS_TIMEOUT, invocation.invoke_async(&mut connection))
+                    .await
+                    .context("Redis timed out")??;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+mod shared {
+    use super::{Outcome, Spec};
+
+    pub struct SharedBuckets;
+
+    impl SharedBuckets {
+        pub fn connect(_url: &str, _prefix: &str) -> anyhow::Result<Self> {
+            anyhow::bail!("REDIS_URL is set but this build lacks Redis support (rebuild with --features redis)")
+        }
+
+        pub async fn take(&self, _specs: &[Spec]) -> anyhow::Result<Outcome> {
+            anyhow::bail!("Redis support is not compiled in")
+        }
+
+        pub async fn charge(&self, _specs: &[Spec], _tokens: u64) -> anyhow::Result<()> {
+            anyhow::bail!("Redis support is not compiled in")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_rates_are_unlimited() {
+        let zero = RateLimit {
+            requests_per_minute: Some(0),
+            burst: Some(5),
+            tokens_per_minute: Some(0),
+        };
+        assert!(zero.is_unlimited());
+        let limiter = RateLimiter::new(RateLimitSettings::default()).unwrap();
+        limiter.update(zero, zero);
+        assert!(limiter.specs(Some(IpAddr::from([127, 0, 0, 1])), Some("ci")).is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+//! Log redaction: credentials are always masked in logged payloads, and message content is
+//! kept, truncated, hashed or dropped according to LOG_CONTENT. Without VERBOSE, payloads
+//! can still be logged for a sample of requests (VERBOSE_SAMPLE_RATE, VERBOSE_DEBUG_HEADER).
+
+use axum::http::HeaderMap;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Default LOG_CONTENT_MAX_CHARS for `truncate`.
+pub const DEFAULT_TRUNCATE_CHARS: usize = 200;
+
+/// Request header that asks for one request's payloads to be logged.
+pub const DEBUG_HEADER: &str = "x-proxy-debug";
+
+/// Target of payload log events, so sampled requests can log them without the rest of TRACE.
+pub const PAYLOAD_TARGET: &str = "anthropic_proxy::payload";
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Prefixes of well-known credential formats masked wherever they appear in logged text.
+const CREDENTIAL_PREFIXES: &[&str] = &["Bearer ", "sk-", "sk_", "xoxb-", "xoxp-", "ghp_", "github_pat_", "AKIA"];
+
+/// Shortest run after a credential prefix that is treated as a secret.
+const MIN_CREDENTIAL_CHARS: usize = 12;
+
+/// Object keys whose string values carry message content.
+const CONTENT_KEYS: &[&str] = &[
+    "content",
+    "text",
+    "thinking",
+    "system",
+    "data",
+    "arguments",
+    "partial_json",
+];
+
+/// Object keys (tool inputs) whose every nested string is content.
+const OPAQUE_CONTENT_KEYS: &[&str] = &["input"];
+
+/// How message content appears in verbose logs (LOG_CONTENT).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContentLogging {
+    /// Logged as-is (credentials are still masked).
+    #[default]
+    Full,
+    /// The first N characters, plus the length of the rest.
+    Truncate(usize),
+    /// A short SHA-256 digest and the length, enough to correlate identical prompts.
+    Hash,
+    /// Only the length.
+    Omit,
+}
+
+impl ContentLogging {
+    pub fn parse(raw: &str, truncate_chars: usize) -> anyhow::Result<Self> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "" | "full" => Ok(Self::Full),
+            "truncate" => Ok(Self::Truncate(truncate_chars)),
+            "hash" => Ok(Self::Hash),
+            "none" | "omit" => Ok(Self::Omit),
+            other => anyhow::bail!("LOG_CONTENT must be full, truncate, hash or none, got '{other}'"),
+        }
+    }
+
+    fn apply(self, text: &str) -> String {
+        let chars = text.chars().count();
+        match self {
+            Self::Full => mask_credentials(text).into_owned(),
+            Self::Truncate(max) if chars <= max => mask_credentials(text).into_owned(),
+            Self::Truncate(max) => {
+                let head: String = text.chars().take(max).collect();
+                format!("{}…[+{} chars]", mask_credentials(&head), chars - max)
+            }
+            Self::Hash => {
+                let digest = hex::encode(Sha256::digest(text.as_bytes()));
+                format!("[sha256:{} {} chars]", &digest[..12], chars)
+            }
+            Self::Omit => format!("[{chars} chars]"),
+        }
+    }
+}
+
+/// Payload logging for some requests while VERBOSE is off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerboseSampling {
+    /// Fraction of requests logged, spread evenly over the request stream.
+    pub rate: f64,
+    /// Also log requests carrying `x-proxy-debug: true`.
+    pub debug_header: bool,
+}
+
+/// Requests seen by [`VerboseSampling::sampled`].
+static SAMPLE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+impl VerboseSampling {
+    /// Whether this request's payloads are logged.
+    pub fn sampled(&self, headers: &HeaderMap) -> bool {
+        let requested = self.debug_header
+            && headers
+                .get(DEBUG_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "true" | "1" | "yes"));
+        if requested {
+            return true;
+        }
+        if self.rate <= 0.0 {
+            return false;
+        }
+        // Every request where the running count of `rate`-sized shares crosses an integer.
+        let n = SAMPLE_SEQ.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+}
+
+/// Pretty JSON of `value` for logs, with credentials masked and content per `mode`.
+pub fn to_log_json<T: Serialize>(value: &T, mode: ContentLogging) -> String {
+    serde_json::to_string_pretty(&to_log_value(value, mode)).unwrap_or_default()
+}
+
+/// `value` as JSON with credentials masked and content per `mode`, for dumps.
+pub fn to_log_value<T: Serialize>(value: &T, mode: ContentLogging) -> Value {
+    let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+    redact_value(&mut value, mode, false);
+    value
+}
+
+fn redact_value(value: &mut Value, mode: ContentLogging, in_content: bool) {
+    match value {
+        Value::String(text) if in_content => *text = mode.apply(text),
+        Value::String(text) => {
+            if let Cow::Owned(masked) = mask_credentials(text) {
+                *text = masked;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                redact_value(item, mode, in_content);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                if is_credential_key(key) && !item.is_null() {
+                    *item = Value::String(REDACTED.to_string());
+                } else if item.is_string() && CONTENT_KEYS.contains(&key.as_str()) {
+                    redact_value(item, mode, true);
+                } else {
+                    let opaque = in_content || OPAQUE_CONTENT_KEYS.contains(&key.as_str());
+                    redact_value(item, mode, opaque);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_credential_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase().replace('-', "_");
+    matches!(
+        key.as_str(),
+        "api_key" | "apikey" | "x_api_key" | "authorization" | "proxy_authorization" | "token"
+            | "access_token" | "refresh_token" | "id_token" | "secret" | "password" | "signature"
+    ) || key.ends_with("_api_key")
+        || key.ends_with("_secret")
+        || key.ends_with("_password")
+}
+
+/// Masks anything that looks like an API key or bearer token in free text, keeping the
+/// prefix so the credential type stays recognisable.
+pub fn mask_credentials(text: &str) -> Cow<'_, str> {
+    let is_secret_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | '+' | '=');
+    let mut out = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let at_boundary = text[..i].chars().next_back().is_none_or(|c| !is_secret_char(c));
+        let prefix = CREDENTIAL_PREFIXES
+            .iter()
+            .find(|p| at_boundary && rest.starts_with(**p));
+        if let Some(prefix) = prefix {
+            let secret_len = rest[prefix.len()..]
+                .find(|c: char| !is_secret_char(c))
+                .unwrap_or(rest.len() - prefix.len());
+            if secret_len >= MIN_CREDENTIAL_CHARS {
+                out.push_str(&text[copied..i + prefix.len()]);
+                out.push_str("***");
+                i += prefix.len() + secret_len;
+                copied = i;
+                continue;
+            }
+        }
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    out.push_str(&text[copied..]);
+    Cow::Owned(out)
+}
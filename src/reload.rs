@@ -0,0 +1,231 @@
+//! Live reload of the TOML config file: while serving, the file is watched and a changed
+//! version is validated by building a complete configuration from it. Model maps, routes,
+//! model parameters, rewrite rules, named upstreams, model overrides, forwarded headers,
+//! stream coalescing, rate limits and the default upstream's address, path, keys, headers and
+//! warm-up then apply to new requests; requests in flight finish with the configuration they
+//! started with. Other settings are reported as needing a restart. An invalid file is refused
+//! and the running configuration kept.
+
+use crate::config::{env_keys, Config, Upstream};
+use crate::configfile::Change;
+use crate::ratelimit::RateLimiter;
+use axum::{extract::Request, middleware::Next, response::Response, Extension};
+use notify::{RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Quiet time after a change before the file is read, so that the several writes (or write
+/// and rename) of one save are reloaded once.
+const CONFIG_SETTLE: Duration = Duration::from_millis(250);
+
+/// Plain settings applied without a restart, besides `[models]`, `[[routes]]`,
+/// `[model_params]`, `[[rewrite]]` and `[upstreams.*]`.
+const RELOADABLE: &[&str] = &[
+    env_keys::REASONING_MODEL,
+    env_keys::COMPLETION_MODEL,
+    env_keys::RATE_LIMIT_IP_RPM,
+    env_keys::RATE_LIMIT_IP_BURST,
+    env_keys::RATE_LIMIT_IP_TPM,
+    env_keys::RATE_LIMIT_KEY_RPM,
+    env_keys::RATE_LIMIT_KEY_BURST,
+    env_keys::RATE_LIMIT_KEY_TPM,
+    env_keys::UPSTREAM_BASE_URL,
+    env_keys::UPSTREAM_API_KEY,
+    env_keys::UPSTREAM_API_KEYS,
+    env_keys::UPSTREAM_CA_BUNDLE,
+    env_keys::UPSTREAM_CLIENT_CERT,
+    env_keys::UPSTREAM_CLIENT_KEY,
+    env_keys::UPSTREAM_PROXY,
+    env_keys::UPSTREAM_HEADERS,
+    env_keys::UPSTREAM_PATH,
+    env_keys::UPSTREAM_HTTP_VERSION,
+    env_keys::UPSTREAM_POOL_MAX_IDLE,
+    env_keys::UPSTREAM_POOL_IDLE_TIMEOUT_SECS,
+    env_keys::UPSTREAM_TCP_KEEPALIVE_SECS,
+    env_keys::UPSTREAM_TCP_KEEPALIVE_INTERVAL_SECS,
+    env_keys::UPSTREAM_TCP_KEEPALIVE_RETRIES,
+    env_keys::UPSTREAM_TCP_NODELAY,
+    env_keys::UPSTREAM_DNS_CACHE_TTL_SECS,
+    env_keys::UPSTREAM_RESOLVE,
+    env_keys::UPSTREAM_WARM_CONNECTIONS,
+    env_keys::UPSTREAM_WARM_INTERVAL_SECS,
+    env_keys::UPSTREAM_HTTP2_KEEPALIVE_SECS,
+    env_keys::FORWARD_HEADERS,
+    env_keys::STREAM_COALESCE_MS,
+    env_keys::STREAM_COALESCE_BYTES,
+];
+
+/// The configuration new requests see.
+pub struct LiveConfig {
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            current: RwLock::new(config),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn replace(&self, config: Config) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+}
+
+/// Middleware giving each request the configuration current when it arrived, as an
+/// `Extension<Arc<Config>>`.
+pub async fn current_config(
+    Extension(live): Extension<Arc<LiveConfig>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    request.extensions_mut().insert(live.current());
+    next.run(request).await
+}
+
+/// Watches the config file and applies changes until the process exits.
+pub fn watch(live: Arc<LiveConfig>, limiter: Arc<RateLimiter>) -> anyhow::Result<()> {
+    let Some(path) = live.current().config_file.as_ref().map(|f| f.path.clone()) else {
+        return Ok(());
+    };
+    // Editors often save by renaming a new file over the old one, which a watch on the file
+    // itself would not survive, so its directory is watched.
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().map(ToOwned::to_owned);
+    let (changed, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            // Reading the file is an event too.
+            Ok(event) if event.kind.is_access() => {}
+            Ok(event) => {
+                if event.paths.iter().any(|p| p.file_name() == name.as_deref()) {
+                    let _ = changed.send(());
+                }
+            }
+            Err(e) => tracing::warn!("Config file watch error: {}", e),
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while changes.recv().await.is_some() {
+            tokio::time::sleep(CONFIG_SETTLE).await;
+            while changes.try_recv().is_ok() {}
+            // Reading happens on a blocking thread: the file may name key files, CA bundles
+            // and price tables that are read as well.
+            let (live, limiter) = (Arc::clone(&live), Arc::clone(&limiter));
+            let _ = tokio::task::spawn_blocking(move || reload(&live, &limiter)).await;
+        }
+    });
+    Ok(())
+}
+
+fn reload(live: &LiveConfig, limiter: &RateLimiter) {
+    let running = live.current();
+    let Some(previous) = running.config_file.as_deref() else {
+        return;
+    };
+    let loaded = match running.reload() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            tracing::error!(
+                "Config file {} is invalid, keeping the running configuration: {:#}",
+                previous.path.display(),
+                e
+            );
+            return;
+        }
+    };
+    let Some(file) = loaded.config_file.as_deref() else {
+        return;
+    };
+    let changes = file.diff(previous);
+    if changes.is_empty() {
+        tracing::debug!("Config file {} touched without changes", file.path.display());
+        return;
+    }
+    let mut restart = false;
+    for Change { setting, description } in &changes {
+        if is_reloadable(setting) {
+            tracing::info!("Config change: {}", description);
+        } else {
+            restart = true;
+            tracing::warn!("Config change: {} (applies after a restart)", description);
+        }
+    }
+
+    let mut next = (*running).clone();
+    next.routes = loaded.routes.clone();
+    next.model_params = loaded.model_params.clone();
+    next.rewrite = Arc::clone(&loaded.rewrite);
+    next.forward_headers = loaded.forward_headers.clone();
+    next.stream_coalesce = loaded.stream_coalesce;
+    next.warmup = loaded.warmup;
+    next.reasoning_model = loaded.reasoning_model.clone();
+    next.completion_model = loaded.completion_model.clone();
+    next.rate_limits.per_ip = loaded.rate_limits.per_ip;
+    next.rate_limits.per_key = loaded.rate_limits.per_key;
+    limiter.update(loaded.rate_limits.per_ip, loaded.rate_limits.per_key);
+    // A new preset could point the default upstream elsewhere without its key, so the upstream
+    // waits for the restart the preset needs.
+    if !changes.iter().any(|c| c.setting == env_keys::PRESET) {
+        next.upstream = reload_upstream(&running, &loaded);
+    }
+    next.config_file = loaded.config_file.clone();
+    live.replace(next);
+    tracing::info!(
+        "Reloaded config from {} ({} change(s){})",
+        file.path.display(),
+        changes.len(),
+        if restart { ", some need a restart" } else { "" }
+    );
+}
+
+fn is_reloadable(setting: &str) -> bool {
+    matches!(setting, "models" | "routes" | "model_params" | "rewrite")
+        || setting.starts_with("upstreams.")
+        || RELOADABLE.contains(&setting)
+}
+
+/// The default upstream with `loaded`'s address, path, transport and headers. Its key pool is
+/// kept, so cooldowns and keys added by secret managers or the admin API survive; keys listed
+/// in the file are swapped for the new ones.
+fn reload_upstream(running: &Config, loaded: &Config) -> Arc<Upstream> {
+    let keys = Arc::clone(&running.upstream.keys);
+    if let (Some(old), Some(new)) = (running.config_file.as_deref(), loaded.config_file.as_deref()) {
+        let file_keys = |file: &crate::configfile::ConfigFile| {
+            [env_keys::UPSTREAM_API_KEY, env_keys::UPSTREAM_API_KEYS]
+                .iter()
+                .filter_map(|name| file.applied_setting(name))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let (old_keys, new_keys) = (file_keys(old), file_keys(new));
+        if old_keys != new_keys {
+            keys.swap_keys(&old_keys, &new_keys);
+        }
+    }
+    let (old, new) = (&running.upstream, &loaded.upstream);
+    if old.base_url == new.base_url
+        && old.path == new.path
+        && old.transport == new.transport
+        && old.headers == new.headers
+    {
+        return Arc::clone(old);
+    }
+    let upstream = Upstream::new(&new.base_url, keys, new.transport.clone(), new.client.clone())
+        .and_then(|upstream| upstream.with_headers(new.headers.clone()).with_path(&new.path));
+    match upstream {
+        Ok(upstream) => Arc::new(upstream),
+        Err(e) => {
+            tracing::error!("Keeping upstream {}: {:#}", old.base_url, e);
+            Arc::clone(old)
+        }
+    }
+}
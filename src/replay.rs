@@ -0,0 +1,247 @@
+//! Traffic record and replay. With TRAFFIC_RECORD every `/v1/messages` exchange that reached
+//! the upstream is appended to a JSON Lines archive: the Anthropic request as received, the
+//! OpenAI request sent, the raw upstream response or SSE stream, and what the client got back.
+//! With TRAFFIC_REPLAY the proxy answers from such an archive instead of calling the upstream,
+//! running the recorded upstream responses through the current translation and reporting where
+//! the result differs from the recording, so transformation changes can be regression-tested
+//! offline against real traffic.
+
+use crate::cache;
+use crate::metrics;
+use crate::models::anthropic::AnthropicRequest;
+use bytes::Bytes;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Response header carrying the archive key of a replayed request.
+pub const REPLAY_HEADER: &str = "x-proxy-replay";
+
+/// TRAFFIC_RECORD / TRAFFIC_REPLAY; the two are mutually exclusive.
+#[derive(Debug, Clone)]
+pub enum TrafficMode {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// One archived exchange, a line of the archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    /// Matches replayed requests; see [`request_key`].
+    pub key: String,
+    pub recorded_at: String,
+    pub stream: bool,
+    /// The Anthropic request as received.
+    pub request: Value,
+    /// The OpenAI request sent upstream.
+    pub openai_request: Value,
+    /// The raw upstream body: a JSON response, or the SSE stream text.
+    pub upstream: String,
+    /// The Anthropic response or SSE stream returned to the client.
+    pub response: String,
+}
+
+/// Archive key of a request: SHA-256 of its JSON without `metadata`, which carries per-session
+/// ids. `stream` stays in, so streaming and non-streaming recordings never stand in for
+/// each other.
+pub fn request_key(req: &AnthropicRequest) -> Option<String> {
+    let mut value = serde_json::to_value(req).ok()?;
+    if let Some(obj) = value.as_object_mut() {
+        obj.remove("metadata");
+    }
+    cache::content_hash(&value).map(hex::encode)
+}
+
+/// Recording or replaying, as configured.
+pub enum Traffic {
+    Record(Recorder),
+    Replay(Replayer),
+}
+
+impl Traffic {
+    pub fn open(mode: &TrafficMode) -> anyhow::Result<Self> {
+        match mode {
+            TrafficMode::Record(path) => Recorder::open(path).map(Traffic::Record),
+            TrafficMode::Replay(path) => Replayer::load(path).map(Traffic::Replay),
+        }
+    }
+}
+
+/// Appends exchanges to the archive.
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Cannot open TRAFFIC_RECORD {}: {e}", path.display()))?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Starts recording a request; the exchange is written once both the upstream body and the
+    /// client response are complete and every handle has been dropped.
+    pub fn start<T: Serialize>(&self, key: String, request: Value, openai_request: &T, stream: bool) -> Recording {
+        Recording(Arc::new(Pending {
+            file: Arc::clone(&self.file),
+            key,
+            stream,
+            request,
+            openai_request: serde_json::to_value(openai_request).unwrap_or(Value::Null),
+            upstream: Mutex::new(None),
+            response: Mutex::new(None),
+        }))
+    }
+}
+
+/// A request being recorded; cloned into the streams that fill it in.
+#[derive(Clone)]
+pub struct Recording(Arc<Pending>);
+
+struct Pending {
+    file: Arc<Mutex<File>>,
+    key: String,
+    stream: bool,
+    request: Value,
+    openai_request: Value,
+    upstream: Mutex<Option<String>>,
+    response: Mutex<Option<String>>,
+}
+
+impl Recording {
+    pub fn set_upstream(&self, body: &[u8]) {
+        *self.0.upstream.lock().unwrap_or_else(|e| e.into_inner()) = Some(String::from_utf8_lossy(body).into_owned());
+    }
+
+    pub fn set_response(&self, body: &[u8]) {
+        *self.0.response.lock().unwrap_or_else(|e| e.into_inner()) = Some(String::from_utf8_lossy(body).into_owned());
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let upstream = self.upstream.get_mut().unwrap_or_else(|e| e.into_inner()).take();
+        let response = self.response.get_mut().unwrap_or_else(|e| e.into_inner()).take();
+        // Failed or cut-short exchanges would replay as something the upstream never sent.
+        let (Some(upstream), Some(response)) = (upstream, response) else {
+            return;
+        };
+        let exchange = Exchange {
+            key: std::mem::take(&mut self.key),
+            recorded_at: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            stream: self.stream,
+            request: self.request.take(),
+            openai_request: self.openai_request.take(),
+            upstream,
+            response,
+        };
+        let mut line = match serde_json::to_vec(&exchange) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Failed to serialize recorded exchange {}: {}", exchange.key, e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&line) {
+            tracing::error!("Failed to record exchange {}: {}", exchange.key, e);
+            return;
+        }
+        metrics::increment("proxy_traffic_recorded_total", &[], 1);
+    }
+}
+
+/// Serves exchanges from an archive. Requests recorded more than once replay their
+/// recordings in order, wrapping around.
+pub struct Replayer {
+    exchanges: HashMap<String, (Vec<Arc<Exchange>>, AtomicUsize)>,
+}
+
+impl Replayer {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .map_err(|e| anyhow::anyhow!("Cannot open TRAFFIC_REPLAY {}: {e}", path.display()))?;
+        let mut exchanges: HashMap<String, (Vec<Arc<Exchange>>, AtomicUsize)> = HashMap::new();
+        let mut count = 0;
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange: Exchange = serde_json::from_str(&line)
+                .map_err(|e| anyhow::anyhow!("Invalid exchange on line {} of {}: {e}", n + 1, path.display()))?;
+            exchanges
+                .entry(exchange.key.clone())
+                .or_default()
+                .0
+                .push(Arc::new(exchange));
+            count += 1;
+        }
+        tracing::info!(
+            "Traffic replay: loaded {} exchange(s) for {} request(s) from {}",
+            count,
+            exchanges.len(),
+            path.display()
+        );
+        Ok(Self { exchanges })
+    }
+
+    /// The next recording for `key`, if any.
+    pub fn next(&self, key: &str) -> Option<Arc<Exchange>> {
+        let found = self.exchanges.get(key).map(|(recorded, next)| {
+            let i = next.fetch_add(1, Ordering::Relaxed) % recorded.len();
+            Arc::clone(&recorded[i])
+        });
+        let result = if found.is_some() { "hit" } else { "miss" };
+        metrics::increment("proxy_replay_lookups_total", &[("result", result)], 1);
+        found
+    }
+}
+
+impl Exchange {
+    /// The recorded upstream stream, one chunk per SSE event.
+    pub fn upstream_chunks(&self) -> Vec<Bytes> {
+        self.upstream
+            .split_inclusive("\n\n")
+            .map(|event| Bytes::copy_from_slice(event.as_bytes()))
+            .collect()
+    }
+
+    /// Compares a replayed client response with the recorded one.
+    pub fn verify(&self, produced: &[u8]) {
+        let result = if produced == self.response.as_bytes() {
+            "match"
+        } else {
+            tracing::warn!(
+                "Replay of {} differs from the recording ({} bytes recorded, {} produced){}",
+                self.key,
+                self.response.len(),
+                produced.len(),
+                first_difference(self.response.as_bytes(), produced)
+                    .map(|at| format!(", first difference at byte {at}"))
+                    .unwrap_or_default()
+            );
+            "mismatch"
+        };
+        metrics::increment("proxy_replay_responses_total", &[("result", result)], 1);
+    }
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
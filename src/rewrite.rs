@@ -0,0 +1,254 @@
+//! Content rewrite rules: `[[rewrite]]` entries in the config file replace regex matches in
+//! outgoing message content (system prompt, message text, tool results and tool inputs)
+//! and/or in the model's output (text and thinking), e.g. to mask internal hostnames or to
+//! normalize terminology. Each rule can be limited to the `system`, `user` or `assistant` role;
+//! the model's output is `assistant` content. Replacements may use the system prompt's
+//! template variables (`{{date}}`, `{{client_name}}`, `{{model}}`, ...), filled in per request.
+//!
+//! Streamed output is rewritten a line at a time, so a match must not span a line break there
+//! and text reaches the client in whole lines while response rules are configured.
+
+use crate::metrics;
+use crate::models::anthropic::{
+    AnthropicRequest, AnthropicResponse, ContentBlock, MessageContent, ResponseContent, SystemPrompt,
+};
+use crate::stream::DeltaFilter;
+use crate::sysprompt::TemplateVars;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Longest line a stream holds back before releasing it up to its last whitespace.
+const MAX_HELD_LINE: usize = 1024;
+
+/// Which way a rule applies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Content sent to the model.
+    Request,
+    /// The model's output.
+    Response,
+    #[default]
+    Both,
+}
+
+/// Message role a rule is limited to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn of(message_role: &str) -> Option<Self> {
+        match message_role {
+            "user" => Some(Role::User),
+            "assistant" => Some(Role::Assistant),
+            "system" => Some(Role::System),
+            _ => None,
+        }
+    }
+}
+
+/// One compiled `[[rewrite]]` entry.
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    /// For logs and the `rule` metric label.
+    pub name: String,
+    pub pattern: Regex,
+    /// May refer to capture groups as `$1` or `${name}`.
+    pub replacement: String,
+    pub direction: Direction,
+    /// Roles the rule applies to; every role when empty.
+    pub roles: Vec<Role>,
+}
+
+impl RewriteRule {
+    fn applies(&self, direction: Direction, role: Role) -> bool {
+        (self.direction == Direction::Both || self.direction == direction)
+            && (self.roles.is_empty() || self.roles.contains(&role))
+    }
+}
+
+/// The configured rules, in file order.
+#[derive(Debug, Default)]
+pub struct Rewriter {
+    rules: Vec<RewriteRule>,
+}
+
+impl Rewriter {
+    pub fn new(rules: Vec<RewriteRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[RewriteRule] {
+        &self.rules
+    }
+
+    /// These rules with the template variables in their replacements filled in from `vars`.
+    /// Values are inserted literally, so a `$` in one is not read as a capture group.
+    pub fn render(self: &Arc<Self>, vars: &TemplateVars) -> Arc<Self> {
+        if !self.rules.iter().any(|rule| rule.replacement.contains("{{")) {
+            return Arc::clone(self);
+        }
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| RewriteRule {
+                replacement: vars
+                    .render_with(&rule.replacement, |value| Cow::Owned(value.replace('$', "$$")))
+                    .into_owned(),
+                ..rule.clone()
+            })
+            .collect();
+        Arc::new(Self::new(rules))
+    }
+
+    /// Whether any rule rewrites the model's output.
+    pub fn rewrites_responses(&self) -> bool {
+        self.rules.iter().any(|rule| rule.applies(Direction::Response, Role::Assistant))
+    }
+
+    /// Rewrites the system prompt, message text, tool results and tool inputs of `req`.
+    pub fn rewrite_request(&self, req: &mut AnthropicRequest) {
+        if self.is_empty() {
+            return;
+        }
+        match &mut req.system {
+            Some(SystemPrompt::Single(text)) => self.apply(Direction::Request, Role::System, text),
+            Some(SystemPrompt::Multiple(parts)) => {
+                for part in parts {
+                    self.apply(Direction::Request, Role::System, &mut part.text);
+                }
+            }
+            None => {}
+        }
+        for message in &mut req.messages {
+            let Some(role) = Role::of(&message.role) else { continue };
+            match &mut message.content {
+                MessageContent::Text(text) => self.apply(Direction::Request, role, text),
+                MessageContent::Blocks(blocks) => {
+                    for block in blocks {
+                        match block {
+                            ContentBlock::Text { text, .. } => self.apply(Direction::Request, role, text),
+                            ContentBlock::ToolResult { content, .. } => self.apply(Direction::Request, role, content),
+                            ContentBlock::ToolUse { input, .. } => self.apply_raw(role, input),
+                            ContentBlock::Image { .. } | ContentBlock::Thinking { .. } => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rewrites the text and thinking of a full response.
+    pub fn rewrite_response(&self, resp: &mut AnthropicResponse) {
+        for block in &mut resp.content {
+            match block {
+                ResponseContent::Text { text, .. } => self.apply(Direction::Response, Role::Assistant, text),
+                ResponseContent::Thinking { thinking, .. } => {
+                    self.apply(Direction::Response, Role::Assistant, thinking)
+                }
+                ResponseContent::ToolUse { .. } => {}
+            }
+        }
+    }
+
+    /// Filter rewriting streamed output, when any rule applies to it.
+    pub fn stream_filter(self: &Arc<Self>) -> Option<RewriteFilter> {
+        self.rewrites_responses().then(|| RewriteFilter {
+            rewriter: Arc::clone(self),
+            pending: String::new(),
+        })
+    }
+
+    fn apply(&self, direction: Direction, role: Role, text: &mut String) {
+        for rule in self.rules.iter().filter(|rule| rule.applies(direction, role)) {
+            let mut count = 0u64;
+            let rewritten = rule.pattern.replace_all(text, |caps: &Captures| {
+                count += 1;
+                let mut replaced = String::new();
+                caps.expand(&rule.replacement, &mut replaced);
+                replaced
+            });
+            if let Cow::Owned(rewritten) = rewritten {
+                *text = rewritten;
+            }
+            if count > 0 {
+                let direction = if direction == Direction::Request { "request" } else { "response" };
+                metrics::increment("proxy_rewrites_total", &[("rule", &rule.name), ("direction", direction)], count);
+            }
+        }
+    }
+
+    /// Rewrites the strings in a tool input, which arrives unparsed.
+    fn apply_raw(&self, role: Role, input: &mut Box<RawValue>) {
+        let Ok(mut value) = serde_json::from_str::<Value>(input.get()) else { return };
+        self.apply_value(role, &mut value);
+        if let Ok(rewritten) = serde_json::value::to_raw_value(&value) {
+            *input = rewritten;
+        }
+    }
+
+    fn apply_value(&self, role: Role, value: &mut Value) {
+        match value {
+            Value::String(text) => self.apply(Direction::Request, role, text),
+            Value::Array(items) => items.iter_mut().for_each(|v| self.apply_value(role, v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.apply_value(role, v)),
+            _ => {}
+        }
+    }
+}
+
+/// Rewrites streamed text and thinking deltas a line at a time.
+pub struct RewriteFilter {
+    rewriter: Arc<Rewriter>,
+    pending: String,
+}
+
+impl RewriteFilter {
+    fn rewrite(&self, mut text: String) -> String {
+        self.rewriter.apply(Direction::Response, Role::Assistant, &mut text);
+        text
+    }
+}
+
+impl DeltaFilter for RewriteFilter {
+    /// Returns the rewritten text up to the last line break, holding back the partial line
+    /// after it unless that grows past [`MAX_HELD_LINE`].
+    fn push(&mut self, delta: &str) -> String {
+        self.pending.push_str(delta);
+        let split = match self.pending.rfind('\n') {
+            Some(end) => end + 1,
+            None if self.pending.len() > MAX_HELD_LINE => {
+                let space = self.pending.char_indices().rev().find(|(_, c)| c.is_whitespace());
+                space.map_or(self.pending.len(), |(i, c)| i + c.len_utf8())
+            }
+            None => 0,
+        };
+        let ready: String = self.pending.drain(..split).collect();
+        if ready.is_empty() {
+            return ready;
+        }
+        self.rewrite(ready)
+    }
+
+    fn flush(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.rewrite(rest)
+    }
+
+    fn tool_input(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,336 @@
+//! The proxy as an [`axum::Router`], for mounting inside another axum application (sharing a
+//! port with other services) instead of running the `anthropic-proxy` binary.
+//!
+//! ```no_run
+//! use anthropic_proxy::{Config, RouterBuilder};
+//! use axum::{extract::Request, middleware::{self, Next}, response::Response, routing::get};
+//!
+//! async fn audit(request: Request, next: Next) -> Response {
+//!     tracing::info!("model request from {:?}", request.headers().get("x-team"));
+//!     next.run(request).await
+//! }
+//!
+//! # async fn serve() -> anyhow::Result<()> {
+//! let proxy = RouterBuilder::new(Config::from_env_with_path(None)?)
+//!     .api_layer(middleware::from_fn(audit))
+//!     .build()
+//!     .await?;
+//! let app = axum::Router::new()
+//!     .route("/", get(|| async { "my service" }))
+//!     .nest("/anthropic", proxy);
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+//! axum::serve(listener, app).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Building the router starts the proxy's background tasks (price sync, key file and config
+//! watching, connection warm-up) on the current Tokio runtime. Logging is left to the host
+//! application: the proxy logs through `tracing` and installs no subscriber of its own.
+
+use crate::{
+    access, accesslog, admin, alerts, auth, capture, conversations, events, jwt, limits, metrics, mockupstream, moderation, pii,
+    plugin, pricing, proxy, quota, ratelimit, reload, replay, script, secrets, signing, slowlog, statsd, tap, telemetry,
+    usagedb, usageevents, warmup, wasmplugin,
+};
+use crate::cache::ResponseCache;
+use crate::config::Config;
+use crate::plugin::Plugins;
+use crate::tokens::TokenCounter;
+use crate::transport::Transport;
+use axum::{
+    extract::{DefaultBodyLimit, Request},
+    http::{header, HeaderName},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post, Route},
+    Extension, Router,
+};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tower::{Layer, Service};
+use tower_http::{sensitive_headers::SetSensitiveRequestHeadersLayer, trace::TraceLayer};
+
+/// A layer hook, applied to a router while it is built.
+type RouterLayer = Box<dyn FnOnce(Router) -> Router + Send>;
+
+/// Builds the proxy's router from a [`Config`].
+pub struct RouterBuilder {
+    config: Config,
+    plugins: Plugins,
+    api_layers: Vec<RouterLayer>,
+    layers: Vec<RouterLayer>,
+}
+
+/// The proxy's router for `config`, with no plugins or extra layers.
+pub async fn router(config: Config) -> anyhow::Result<Router> {
+    RouterBuilder::new(config).build().await
+}
+
+impl RouterBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            plugins: Plugins::new(),
+            api_layers: Vec::new(),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Registers `plugins`; WASM_PLUGINS and SCRIPT_PATH are added after them.
+    pub fn plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    /// Wraps the model API routes (`/v1/messages`, `/v1/messages/count_tokens`, `/usage` and
+    /// `/conversations/{id}`) in `layer`, which sees only requests that passed client
+    /// authentication, signing and body limits. Layers added later wrap earlier ones.
+    pub fn api_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.api_layers.push(Box::new(move |router| router.route_layer(layer)));
+        self
+    }
+
+    /// Wraps every proxy route, `/health`, `/metrics` and the admin API included, in `layer`.
+    /// It runs after the IP filter and inside the access log, so requests it rejects are still
+    /// logged. Layers added later wrap earlier ones.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |router| router.layer(layer)));
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<Router> {
+        Ok(self.build_with_config().await?.0)
+    }
+
+    /// The router and the config it serves, for the listeners.
+    pub(crate) async fn build_with_config(self) -> anyhow::Result<(Router, Arc<Config>)> {
+        let Self {
+            config,
+            plugins,
+            api_layers,
+            layers,
+        } = self;
+        if let Some(ref settings) = config.statsd {
+            metrics::export_to_statsd(statsd::StatsdSink::connect(settings.clone())?);
+        }
+        mockupstream::install(config.mock_upstream.clone());
+        for path in &config.upstream_key_files {
+            Arc::clone(&config.upstream.keys).watch_file(path.clone());
+        }
+
+        let client = Transport::default().client()?;
+
+        let jwt_verifier = match config.jwt.clone() {
+            Some(settings) => Some(jwt::JwtVerifier::connect(settings, client.clone()).await?),
+            None => None,
+        };
+
+        let moderator = config
+            .moderation
+            .clone()
+            .map(|settings| Arc::new(moderation::Moderator::new(settings, client.clone())));
+
+        let scrubber = config.pii.as_ref().map(|settings| Arc::new(pii::Scrubber::new(settings)));
+
+        let captures = config
+            .capture_dir
+            .clone()
+            .map(|dir| capture::CaptureDir::new(dir).map(Arc::new))
+            .transpose()?;
+
+        let traffic = config
+            .traffic
+            .as_ref()
+            .map(|mode| replay::Traffic::open(mode).map(Arc::new))
+            .transpose()?;
+
+        let prices = Arc::new(pricing::PriceTable::new(&config.pricing));
+        if let Some(url) = config.pricing.sync_url.clone() {
+            prices.spawn_sync(client.clone(), url, config.pricing.sync_interval_secs);
+        }
+
+        let access_logger = config
+            .access_log
+            .as_ref()
+            .map(|settings| accesslog::AccessLogger::open(settings, &config.logging.rotation))
+            .transpose()?;
+        let usage_db = config
+            .usage_db
+            .as_ref()
+            .map(|settings| usagedb::UsageDb::open(settings).map(Arc::new))
+            .transpose()?;
+        let conversations = match &config.conversations {
+            Some(settings) => Some(Arc::new(conversations::ConversationStore::open(settings).await?)),
+            None => None,
+        };
+        let usage_events = config
+            .usage_events
+            .clone()
+            .map(usageevents::UsagePublisher::start)
+            .transpose()?;
+        let slow_log = config.slow_log.clone().map(slowlog::SlowLog::new).transpose()?;
+        let tap = config.admin_token.is_some().then(tap::Tap::default);
+        let alerter = config.alerts.clone().map(|settings| {
+            let alerter = Arc::new(alerts::Alerter::new(settings, client.clone()));
+            alerts::install(Arc::clone(&alerter));
+            alerter
+        });
+        let event_sink = config.events.clone().map(|settings| {
+            let sink = Arc::new(events::EventSink::new(settings, client.clone()));
+            events::install(Arc::clone(&sink));
+            sink
+        });
+        let request_sinks = (access_logger.is_some()
+            || usage_db.is_some()
+            || usage_events.is_some()
+            || slow_log.is_some()
+            || tap.is_some()
+            || alerter.is_some()
+            || event_sink.is_some())
+        .then(|| {
+            Arc::new(accesslog::RequestSinks {
+                access_log: access_logger,
+                usage_db: usage_db.clone(),
+                usage_events,
+                slow_log,
+                tap,
+                alerts: alerter,
+                events: event_sink,
+            })
+        });
+
+        let quotas = Arc::new(quota::QuotaTracker::new(
+            config.default_quota.clone(),
+            config.budget_schedule,
+        ));
+        let rate_limiter = Arc::new(ratelimit::RateLimiter::new(config.rate_limits.clone())?);
+        let config = Arc::new(config);
+        secrets::load(&config, client.clone()).await?;
+        let live_config = Arc::new(reload::LiveConfig::new(Arc::clone(&config)));
+        warmup::spawn(Arc::clone(&live_config));
+        if let Some(file) = config.config_file.as_ref().filter(|_| config.config_watch) {
+            match reload::watch(Arc::clone(&live_config), Arc::clone(&rate_limiter)) {
+                Ok(()) => tracing::info!("Config reload: watching {}", file.path.display()),
+                Err(e) => tracing::warn!("Config reload disabled, cannot watch {}: {:#}", file.path.display(), e),
+            }
+        }
+        let token_counter = Arc::new(TokenCounter::new(config.token_cache_size));
+        let response_cache = Arc::new(ResponseCache::new(
+            std::time::Duration::from_secs(config.response_cache_ttl_secs),
+            config.response_cache_size,
+        ));
+        if config.statsd.is_some() {
+            let (cache, counter) = (Arc::clone(&response_cache), Arc::clone(&token_counter));
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(statsd::GAUGE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    admin::refresh_gauges(&cache, &counter);
+                }
+            });
+        }
+
+        let mut api_routes = Router::new()
+            .route("/v1/messages", post(proxy::proxy_handler))
+            .route("/v1/messages/count_tokens", post(proxy::count_tokens_handler))
+            .route("/usage", get(proxy::usage_handler))
+            .route("/conversations/:id", get(proxy::conversation_handler));
+        for layer in api_layers {
+            api_routes = layer(api_routes);
+        }
+        let mut api_routes = api_routes
+            .route_layer(middleware::from_fn(signing::require_signature))
+            .route_layer(middleware::from_fn(limits::limit_body))
+            .route_layer(DefaultBodyLimit::max(config.limits.max_body_bytes))
+            .route_layer(middleware::from_fn(auth::require_client_key));
+        if config.otel.is_some() {
+            api_routes = api_routes.route_layer(middleware::from_fn(telemetry::trace_request));
+        }
+        let plugins = wasmplugin::register(&config.wasm_plugins, plugins)?;
+        let plugins = script::register(config.script.as_deref(), plugins)?;
+        if !plugins.is_empty() {
+            tracing::info!("Plugins: {}", plugins.names().join(", "));
+            // Outside authentication, so `on_error` also sees rejected requests.
+            api_routes = api_routes
+                .route_layer(middleware::from_fn(plugin::run_hooks))
+                .layer(Extension(Arc::new(plugins)));
+        }
+
+        let mut app = Router::new()
+            .merge(api_routes)
+            .route("/health", get(health_handler))
+            .route("/metrics", get(admin::metrics_handler));
+
+        if config.admin_token.is_some() {
+            let admin_routes = Router::new()
+                .route("/admin/cache", get(admin::cache_stats).delete(admin::cache_flush))
+                .route("/admin/cache/invalidate", post(admin::cache_invalidate))
+                .route(
+                    "/admin/upstream-keys",
+                    get(admin::upstream_keys).post(admin::upstream_key_add),
+                )
+                .route("/admin/upstream-keys/:id", delete(admin::upstream_key_remove))
+                .route("/admin/tap", get(admin::tap))
+                .route_layer(middleware::from_fn(admin::require_admin));
+            app = app.merge(admin_routes);
+            tracing::info!("Admin API: enabled");
+        }
+
+        for layer in layers {
+            app = layer(app);
+        }
+        let mut app = app.layer(middleware::from_fn(access::filter_ip));
+        if let Some(sinks) = request_sinks {
+            app = app
+                .layer(middleware::from_fn(accesslog::log_request))
+                .layer(Extension(sinks));
+        }
+        let mut app = app
+            .layer(middleware::from_fn(reload::current_config))
+            .layer(Extension(live_config))
+            .layer(Extension(token_counter))
+            .layer(Extension(response_cache))
+            .layer(Extension(jwt_verifier))
+            .layer(Extension(moderator))
+            .layer(Extension(scrubber))
+            .layer(Extension(usage_db))
+            .layer(Extension(conversations))
+            .layer(Extension(prices))
+            .layer(Extension(captures))
+            .layer(Extension(traffic))
+            .layer(Extension(quotas))
+            .layer(Extension(rate_limiter))
+            .layer(Extension(client))
+            .layer(TraceLayer::new_for_http())
+            .layer(SetSensitiveRequestHeadersLayer::new([
+                header::AUTHORIZATION,
+                header::PROXY_AUTHORIZATION,
+                header::COOKIE,
+                HeaderName::from_static("x-api-key"),
+            ]));
+        if let Some(ref cors) = config.cors {
+            app = app.layer(cors.layer());
+        }
+
+        Ok((app, config))
+    }
+}
+
+async fn health_handler() -> &'static str {
+    "OK"
+}
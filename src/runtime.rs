@@ -0,0 +1,63 @@
+//! The server's tokio runtime (RUNTIME_FLAVOR, RUNTIME_WORKER_THREADS,
+//! RUNTIME_MAX_BLOCKING_THREADS). By default tokio starts one worker per CPU, sized for the
+//! whole machine; containers with a CPU quota and tiny edge boxes may want fewer.
+
+use tokio::runtime::{Builder, Runtime, RuntimeFlavor};
+
+/// Which scheduler serves requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Flavor {
+    /// Work-stealing pool of worker threads.
+    #[default]
+    MultiThread,
+    /// Everything on the main thread; the smallest footprint, but one busy request delays
+    /// the rest.
+    CurrentThread,
+}
+
+impl Flavor {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "multi-thread" | "multi_thread" => Ok(Self::MultiThread),
+            "current-thread" | "current_thread" => Ok(Self::CurrentThread),
+            other => anyhow::bail!("expected multi-thread or current-thread, got '{other}'"),
+        }
+    }
+}
+
+/// RUNTIME_FLAVOR / RUNTIME_WORKER_THREADS / RUNTIME_MAX_BLOCKING_THREADS; unset fields keep
+/// tokio's defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RuntimeSettings {
+    pub flavor: Flavor,
+    /// Worker threads of the multi-thread runtime (tokio: one per CPU).
+    pub worker_threads: Option<usize>,
+    /// Cap on threads for blocking work such as file I/O and SQLite (tokio: 512).
+    pub max_blocking_threads: Option<usize>,
+}
+
+impl RuntimeSettings {
+    pub fn build(&self) -> std::io::Result<Runtime> {
+        let mut builder = match self.flavor {
+            Flavor::MultiThread => Builder::new_multi_thread(),
+            Flavor::CurrentThread => Builder::new_current_thread(),
+        };
+        builder.enable_all();
+        if let (Flavor::MultiThread, Some(threads)) = (self.flavor, self.worker_threads) {
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(threads);
+        }
+        builder.build()
+    }
+}
+
+/// Runs blocking `f` without stalling other tasks: on a worker thread it hands that thread's
+/// tasks to the others first; the current-thread runtime has no others, so `f` just runs.
+pub fn block_in_place<R>(f: impl FnOnce() -> R) -> R {
+    match tokio::runtime::Handle::try_current().map(|h| h.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
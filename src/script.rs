@@ -0,0 +1,155 @@
+//! Request scripts (SCRIPT_PATH, `scripting` feature): a Rhai script whose functions run on each
+//! `/v1/messages` request, for customization that doesn't warrant a plugin.
+//!
+//! `rewrite_request(req)` gets the Anthropic request as an object map and returns the changed
+//! request, or nothing to keep it. `route(req)` returns the model name to route the request as
+//! (before `[[routes]]` and model selection apply), or nothing to keep the requested one. A
+//! `throw` in either rejects the request with the thrown message. Scripts cannot import modules
+//! or call `eval`, and each call is cut off after a fixed number of operations.
+
+pub use imp::register;
+
+#[cfg(feature = "scripting")]
+mod imp {
+    use crate::error::{ProxyError, ProxyResult};
+    use crate::models::anthropic::AnthropicRequest;
+    use crate::plugin::{BoxFuture, Plugins, ProxyPlugin, RequestContext};
+    use anyhow::Context;
+    use rhai::module_resolvers::DummyModuleResolver;
+    use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// Operations one function call may run before it is stopped.
+    const MAX_OPERATIONS: u64 = 1_000_000;
+    const MAX_CALL_LEVELS: usize = 32;
+    /// Longest string a script may build.
+    const MAX_STRING_SIZE: usize = 16 << 20;
+
+    struct Script {
+        name: String,
+        engine: Engine,
+        ast: AST,
+        rewrite: bool,
+        route: bool,
+    }
+
+    /// The loaded script, as a plugin.
+    pub struct ScriptPlugin(Arc<Script>);
+
+    /// Compiles the script at `path`, if any, and adds it to `plugins`.
+    pub fn register(path: Option<&Path>, plugins: Plugins) -> anyhow::Result<Plugins> {
+        let Some(path) = path else { return Ok(plugins) };
+        let script = Script::load(path).with_context(|| format!("Script {}", path.display()))?;
+        Ok(plugins.with(ScriptPlugin(Arc::new(script))))
+    }
+
+    impl Script {
+        fn load(path: &Path) -> anyhow::Result<Self> {
+            let mut engine = Engine::new();
+            engine
+                .set_module_resolver(DummyModuleResolver::new())
+                .disable_symbol("eval")
+                .set_max_operations(MAX_OPERATIONS)
+                .set_max_call_levels(MAX_CALL_LEVELS)
+                .set_max_string_size(MAX_STRING_SIZE)
+                .on_print(|text| tracing::info!(target: "script", "{}", text))
+                .on_debug(|text, _, pos| tracing::debug!(target: "script", "{} {}", pos, text));
+            let ast = engine.compile_file(path.into()).map_err(|e| anyhow::anyhow!("{e}"))?;
+            let defines = |name: &str| ast.iter_functions().any(|f| f.name == name && f.params.len() == 1);
+            let (rewrite, route) = (defines("rewrite_request"), defines("route"));
+            anyhow::ensure!(rewrite || route, "the script defines neither rewrite_request(req) nor route(req)");
+            let name = path
+                .file_name()
+                .map_or_else(|| path.display().to_string(), |s| s.to_string_lossy().into_owned());
+            Ok(Self {
+                name,
+                engine,
+                ast,
+                rewrite,
+                route,
+            })
+        }
+
+        /// Calls `function` with `req`; `None` when it returned nothing.
+        fn call(&self, function: &str, req: Dynamic) -> Result<Option<Dynamic>, Box<EvalAltResult>> {
+            let options = CallFnOptions::new().eval_ast(false);
+            let result: Dynamic = self
+                .engine
+                .call_fn_with_options(options, &mut Scope::new(), &self.ast, function, (req,))?;
+            Ok(Some(result).filter(|r| !r.is_unit()))
+        }
+
+        fn on_request(&self, req: &mut AnthropicRequest) -> ProxyResult<()> {
+            if self.rewrite {
+                let rewritten = self
+                    .call("rewrite_request", self.to_dynamic(req)?)
+                    .map_err(|e| self.error("rewrite_request", *e))?;
+                if let Some(rewritten) = rewritten {
+                    *req = rhai::serde::from_dynamic(&rewritten).map_err(|e| {
+                        ProxyError::Internal(format!("Script {} returned an invalid request: {e}", self.name))
+                    })?;
+                }
+            }
+            if self.route {
+                if let Some(model) = self.call("route", self.to_dynamic(req)?).map_err(|e| self.error("route", *e))? {
+                    req.model = model.into_string().map_err(|kind| {
+                        ProxyError::Internal(format!("Script {} route returned {kind}, not a model name", self.name))
+                    })?;
+                }
+            }
+            Ok(())
+        }
+
+        fn to_dynamic(&self, req: &AnthropicRequest) -> ProxyResult<Dynamic> {
+            rhai::serde::to_dynamic(req).map_err(|e| ProxyError::Internal(format!("Script {}: {e}", self.name)))
+        }
+
+        /// A `throw` rejects the request; anything else is the script's fault.
+        fn error(&self, function: &str, error: EvalAltResult) -> ProxyError {
+            match error {
+                EvalAltResult::ErrorRuntime(message, _) if !message.is_unit() => {
+                    ProxyError::Permission(message.to_string())
+                }
+                error => {
+                    tracing::error!("Script {} {} failed: {}", self.name, function, error);
+                    ProxyError::Internal(format!("Script {} failed", self.name))
+                }
+            }
+        }
+    }
+
+    impl ProxyPlugin for ScriptPlugin {
+        fn name(&self) -> &str {
+            &self.0.name
+        }
+
+        fn on_request<'a>(
+            &'a self,
+            _ctx: &'a RequestContext,
+            req: &'a mut AnthropicRequest,
+        ) -> BoxFuture<'a, ProxyResult<()>> {
+            Box::pin(async move {
+                let script = Arc::clone(&self.0);
+                let mut owned = req.clone();
+                *req = tokio::task::spawn_blocking(move || script.on_request(&mut owned).map(|()| owned))
+                    .await
+                    .map_err(|e| ProxyError::Internal(format!("Script {} panicked: {e}", self.0.name)))??;
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod imp {
+    use crate::plugin::Plugins;
+    use std::path::Path;
+
+    pub fn register(path: Option<&Path>, plugins: Plugins) -> anyhow::Result<Plugins> {
+        if path.is_some() {
+            anyhow::bail!("SCRIPT_PATH is set but this build lacks scripting support (rebuild with --features scripting)");
+        }
+        Ok(plugins)
+    }
+}
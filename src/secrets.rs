@@ -0,0 +1,293 @@
+//! External secret managers: fetch the upstream API key and client keys from HashiCorp Vault
+//! (`vault` feature) or AWS Secrets Manager (`aws-secrets` feature) at startup and on a
+//! refresh interval.
+
+use crate::config::Config;
+use anyhow::{Context, Result};
+use reqwest::Client;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a secret lives, parsed from `vault://<mount>/<path>#<field>` or
+/// `aws-sm://<secret-id>[#<json field>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    /// KV v2 secret; `field` defaults to `value`.
+    Vault {
+        mount: String,
+        path: String,
+        field: String,
+    },
+    /// Secret string, or one field of it when the string is a JSON object.
+    AwsSecretsManager {
+        secret_id: String,
+        field: Option<String>,
+    },
+}
+
+impl SecretSource {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let (location, field) = match spec.rsplit_once('#') {
+            Some((location, field)) if !field.is_empty() => (location, Some(field.to_string())),
+            _ => (spec.trim_end_matches('#'), None),
+        };
+        if let Some(rest) = location.strip_prefix("vault://") {
+            let (mount, path) = rest
+                .split_once('/')
+                .filter(|(m, p)| !m.is_empty() && !p.is_empty())
+                .with_context(|| format!("Vault secret '{spec}' must look like vault://<mount>/<path>"))?;
+            return Ok(Self::Vault {
+                mount: mount.to_string(),
+                path: path.trim_end_matches('/').to_string(),
+                field: field.unwrap_or_else(|| "value".to_string()),
+            });
+        }
+        if let Some(secret_id) = location.strip_prefix("aws-sm://").filter(|id| !id.is_empty()) {
+            return Ok(Self::AwsSecretsManager {
+                secret_id: secret_id.to_string(),
+                field,
+            });
+        }
+        anyhow::bail!("Unsupported secret reference '{spec}' (expected vault:// or aws-sm://)")
+    }
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vault { mount, path, field } => write!(f, "vault://{mount}/{path}#{field}"),
+            Self::AwsSecretsManager { secret_id, field: Some(field) } => {
+                write!(f, "aws-sm://{secret_id}#{field}")
+            }
+            Self::AwsSecretsManager { secret_id, field: None } => write!(f, "aws-sm://{secret_id}"),
+        }
+    }
+}
+
+/// Vault connection from VAULT_ADDR / VAULT_TOKEN / VAULT_NAMESPACE.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "vault"), allow(dead_code))]
+pub struct VaultSettings {
+    pub addr: String,
+    pub token: String,
+    pub namespace: Option<String>,
+}
+
+/// Secret-manager references from *_SECRET environment variables.
+#[derive(Debug, Clone, Default)]
+pub struct SecretSettings {
+    pub upstream_api_key: Option<SecretSource>,
+    pub client_keys: Option<SecretSource>,
+    pub vault: Option<VaultSettings>,
+    /// Seconds between re-fetches; 0 fetches once at startup.
+    pub refresh_secs: u64,
+}
+
+impl SecretSettings {
+    pub fn is_empty(&self) -> bool {
+        self.upstream_api_key.is_none() && self.client_keys.is_none()
+    }
+}
+
+struct SecretStore {
+    client: Client,
+    vault: Option<VaultSettings>,
+    #[cfg(feature = "aws-secrets")]
+    aws: tokio::sync::OnceCell<aws_sdk_secretsmanager::Client>,
+}
+
+impl SecretStore {
+    async fn fetch(&self, source: &SecretSource) -> Result<String> {
+        let value = match source {
+            SecretSource::Vault { mount, path, field } => {
+                let settings = self
+                    .vault
+                    .as_ref()
+                    .context("VAULT_ADDR and VAULT_TOKEN must be set to read Vault secrets")?;
+                vault::fetch(&self.client, settings, mount, path, field).await?
+            }
+            SecretSource::AwsSecretsManager { secret_id, field } => {
+                #[cfg(feature = "aws-secrets")]
+                let raw = {
+                    let client = self
+                        .aws
+                        .get_or_init(|| async {
+                            aws_sdk_secretsmanager::Client::new(&aws_config::load_from_env().await)
+                        })
+                        .await;
+                    aws::fetch(client, secret_id).await?
+                };
+                #[cfg(not(feature = "aws-secrets"))]
+                let raw = aws::fetch(secret_id).await?;
+                match field {
+                    Some(field) => json_field(&raw, field)
+                        .with_context(|| format!("Secret {source} has no string field '{field}'"))?,
+                    None => raw,
+                }
+            }
+        };
+        Ok(value.trim().to_string())
+    }
+}
+
+fn json_field(raw: &str, field: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(raw)
+        .ok()?
+        .get(field)?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Loads the configured secrets into the upstream key pool and client keys, then keeps them
+/// fresh in the background. Startup fails if the first fetch does; later failures keep the
+/// previous values.
+pub async fn load(config: &Arc<Config>, client: Client) -> Result<()> {
+    let settings = &config.secrets;
+    if settings.is_empty() {
+        return Ok(());
+    }
+    let store = SecretStore {
+        client,
+        vault: settings.vault.clone(),
+        #[cfg(feature = "aws-secrets")]
+        aws: tokio::sync::OnceCell::new(),
+    };
+
+    let mut upstream_key = String::new();
+    if let Some(source) = &settings.upstream_api_key {
+        upstream_key = store.fetch(source).await?;
+        anyhow::ensure!(!upstream_key.is_empty(), "Secret {source} is empty");
+        config.upstream.keys.swap_keys("", &upstream_key);
+        tracing::info!("Upstream API key loaded from {}", source);
+    }
+    let mut client_keys = String::new();
+    if let Some(source) = &settings.client_keys {
+        client_keys = store.fetch(source).await?;
+        let count = config
+            .client_keys
+            .set_managed(&client_keys, &config.upstream)
+            .with_context(|| format!("Invalid client keys in {source}"))?;
+        tracing::info!("Client auth: {} key(s) loaded from {}", count, source);
+    }
+
+    if settings.refresh_secs == 0 {
+        return Ok(());
+    }
+    let config = Arc::clone(config);
+    tokio::spawn(async move {
+        let settings = &config.secrets;
+        let mut interval = tokio::time::interval(Duration::from_secs(settings.refresh_secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Some(source) = &settings.upstream_api_key {
+                match store.fetch(source).await {
+                    Ok(key) if key.is_empty() => tracing::warn!("Secret {} is empty; keeping current key", source),
+                    Ok(key) if key != upstream_key => {
+                        config.upstream.keys.swap_keys(&upstream_key, &key);
+                        tracing::info!("Upstream API key rotated from {}", source);
+                        upstream_key = key;
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Refreshing {} failed: {:#}", source, e),
+                }
+            }
+            if let Some(source) = &settings.client_keys {
+                match store.fetch(source).await {
+                    Ok(raw) if raw != client_keys => {
+                        match config.client_keys.set_managed(&raw, &config.upstream) {
+                            Ok(count) => {
+                                tracing::info!("Client keys reloaded from {} ({} keys)", source, count);
+                                client_keys = raw;
+                            }
+                            Err(e) => tracing::warn!("Ignoring client keys from {}: {:#}", source, e),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Refreshing {} failed: {:#}", source, e),
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(feature = "vault")]
+mod vault {
+    use super::VaultSettings;
+    use anyhow::Context;
+    use reqwest::Client;
+
+    /// Reads one field of a KV v2 secret: `GET /v1/<mount>/data/<path>`.
+    pub async fn fetch(
+        client: &Client,
+        settings: &VaultSettings,
+        mount: &str,
+        path: &str,
+        field: &str,
+    ) -> anyhow::Result<String> {
+        let url = format!("{}/v1/{}/data/{}", settings.addr.trim_end_matches('/'), mount, path);
+        let mut request = client.get(&url).header("X-Vault-Token", &settings.token);
+        if let Some(namespace) = &settings.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let body: serde_json::Value = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Vault request for {mount}/{path} failed"))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid Vault response for {mount}/{path}"))?;
+        body.pointer("/data/data")
+            .and_then(|data| data.get(field))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .with_context(|| format!("Vault secret {mount}/{path} has no string field '{field}'"))
+    }
+}
+
+#[cfg(not(feature = "vault"))]
+mod vault {
+    use super::VaultSettings;
+    use reqwest::Client;
+
+    pub async fn fetch(
+        _client: &Client,
+        _settings: &VaultSettings,
+        _mount: &str,
+        _path: &str,
+        _field: &str,
+    ) -> anyhow::Result<String> {
+        anyhow::bail!("A vault:// secret is configured but this build lacks Vault support (rebuild with --features vault)")
+    }
+}
+
+#[cfg(feature = "aws-secrets")]
+mod aws {
+    use anyhow::Context;
+
+    pub async fn fetch(
+        client: &aws_sdk_secretsmanager::Client,
+        secret_id: &str,
+    ) -> anyhow::Result<String> {
+        client
+            .get_secret_value()
+            .secret_id(secret_id)
+            .send()
+            .await
+            .with_context(|| format!("AWS Secrets Manager request for {secret_id} failed"))?
+            .secret_string()
+            .map(str::to_string)
+            .with_context(|| format!("AWS secret {secret_id} has no SecretString"))
+    }
+}
+
+#[cfg(not(feature = "aws-secrets"))]
+mod aws {
+    pub async fn fetch(_secret_id: &str) -> anyhow::Result<String> {
+        anyhow::bail!("An aws-sm:// secret is configured but this build lacks AWS support (rebuild with --features aws-secrets)")
+    }
+}
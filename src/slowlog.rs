@@ -0,0 +1,110 @@
+//! Slow-request log: requests whose total duration or time to first byte exceed
+//! SLOW_REQUEST_SECS / SLOW_TTFB_SECS are logged at WARN with a one-line summary, and with
+//! SLOW_REQUEST_DUMP_DIR their sanitized request is written to a JSON file for later study.
+
+use crate::accesslog::{Details, Record};
+use crate::metrics;
+use serde_json::json;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// SLOW_REQUEST_SECS / SLOW_TTFB_SECS / SLOW_REQUEST_DUMP_DIR.
+#[derive(Debug, Clone)]
+pub struct SlowLogSettings {
+    pub duration: Option<Duration>,
+    pub ttfb: Option<Duration>,
+    /// Directory for payload dumps; created at startup if missing.
+    pub dump_dir: Option<PathBuf>,
+}
+
+pub struct SlowLog {
+    settings: SlowLogSettings,
+    /// Disambiguates dumps written within the same millisecond.
+    dumps: AtomicU64,
+}
+
+impl SlowLog {
+    pub fn new(settings: SlowLogSettings) -> anyhow::Result<Self> {
+        if let Some(dir) = &settings.dump_dir {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| anyhow::anyhow!("Cannot create SLOW_REQUEST_DUMP_DIR {}: {e}", dir.display()))?;
+        }
+        Ok(Self {
+            settings,
+            dumps: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether request payloads should be kept for dumping.
+    pub fn dumps_payloads(&self) -> bool {
+        self.settings.dump_dir.is_some()
+    }
+
+    /// Logs (and dumps) a finished request if it crossed a threshold.
+    pub fn check(&self, record: &Record, details: &Details) {
+        let slow_ttfb = matches!((self.settings.ttfb, record.ttfb), (Some(limit), Some(ttfb)) if ttfb >= limit);
+        let slow_total = self.settings.duration.is_some_and(|limit| record.duration >= limit);
+        if !slow_ttfb && !slow_total {
+            return;
+        }
+        let reason = if slow_ttfb { "ttfb" } else { "duration" };
+        metrics::increment("proxy_slow_requests_total", &[("reason", reason)], 1);
+
+        let dump = self.dump(record, details, reason);
+        let secs = |d: Duration| format!("{:.2}s", d.as_secs_f64());
+        let or_dash = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        tracing::warn!(
+            "Slow request ({}): {} {} {} client={} model={}->{} upstream={} stream={} ttfb={} duration={} tokens={}/{} stop={}{}",
+            reason,
+            record.method,
+            record.path,
+            record.status,
+            or_dash(&details.client),
+            or_dash(&details.incoming_model),
+            or_dash(&details.routed_model),
+            or_dash(&details.upstream),
+            details.stream.map_or("-".to_string(), |s| s.to_string()),
+            record.ttfb.map_or("-".to_string(), secs),
+            secs(record.duration),
+            details.input_tokens.map_or("-".to_string(), |n| n.to_string()),
+            details.output_tokens.map_or("-".to_string(), |n| n.to_string()),
+            or_dash(&details.stop_reason),
+            dump.map(|path| format!(" dump={}", path.display())).unwrap_or_default(),
+        );
+    }
+
+    fn dump(&self, record: &Record, details: &Details, reason: &str) -> Option<PathBuf> {
+        let dir = self.settings.dump_dir.as_ref()?;
+        let seq = self.dumps.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("slow-{}-{seq}.json", record.received.format("%Y%m%dT%H%M%S%.3fZ")));
+        let body = json!({
+            "reason": reason,
+            "timestamp": record.received.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "method": record.method,
+            "path": record.path,
+            "status": record.status,
+            "client": details.client,
+            "incoming_model": details.incoming_model,
+            "routed_model": details.routed_model,
+            "upstream": details.upstream,
+            "stream": details.stream,
+            "stop_reason": details.stop_reason,
+            "input_tokens": details.input_tokens,
+            "output_tokens": details.output_tokens,
+            "ttfb_ms": record.ttfb.map(|d| d.as_millis() as u64),
+            "duration_ms": record.duration.as_millis() as u64,
+            "request": details.payload,
+        });
+        let written = serde_json::to_vec_pretty(&body)
+            .map_err(std::io::Error::other)
+            .and_then(|bytes| std::fs::write(&path, bytes));
+        match written {
+            Ok(()) => Some(path),
+            Err(e) => {
+                tracing::error!("Failed to write slow request dump {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! StatsD / DogStatsD export: with STATSD_ADDR set, every counter increment, gauge update and
+//! histogram observation recorded in [`crate::metrics`] is also sent over UDP. DogStatsD
+//! lines carry labels and STATSD_TAGS as `|#key:value` tags; plain StatsD has no tags, so
+//! label values are appended to the metric name instead.
+
+use std::fmt::Write;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// How often gauges that are otherwise sampled per scrape are sent.
+pub const GAUGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest datagram sent; fits a 1500-byte MTU after IP and UDP headers.
+const MAX_PACKET_BYTES: usize = 1432;
+
+/// Line flavour (STATSD_FORMAT).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StatsdFormat {
+    #[default]
+    DogStatsd,
+    Statsd,
+}
+
+/// STATSD_ADDR / STATSD_FORMAT / STATSD_PREFIX / STATSD_TAGS.
+#[derive(Debug, Clone)]
+pub struct StatsdSettings {
+    /// `host:port`, resolved once at startup.
+    pub addr: String,
+    pub format: StatsdFormat,
+    /// Prepended to every metric name, e.g. `anthropic_proxy.`.
+    pub prefix: String,
+    /// `key:value` tags added to every DogStatsD line.
+    pub tags: Vec<String>,
+}
+
+impl StatsdSettings {
+    pub fn parse(addr: &str, format: &str, prefix: &str, tags: &str) -> anyhow::Result<Option<Self>> {
+        let addr = addr.trim();
+        if addr.is_empty() {
+            return Ok(None);
+        }
+        let format = match format.trim().to_ascii_lowercase().as_str() {
+            "" | "dogstatsd" | "datadog" => StatsdFormat::DogStatsd,
+            "statsd" => StatsdFormat::Statsd,
+            other => anyhow::bail!("STATSD_FORMAT must be dogstatsd or statsd, got '{other}'"),
+        };
+        let tags: Vec<String> = tags
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(sanitize)
+            .collect();
+        anyhow::ensure!(
+            tags.is_empty() || format == StatsdFormat::DogStatsd,
+            "STATSD_TAGS needs STATSD_FORMAT=dogstatsd; plain StatsD has no tags"
+        );
+        Ok(Some(Self {
+            addr: addr.to_string(),
+            format,
+            prefix: prefix.trim().to_string(),
+            tags,
+        }))
+    }
+}
+
+/// Metric types as StatsD spells them.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// Formats lines and hands them to a sender thread, which packs them into datagrams.
+pub struct StatsdSink {
+    settings: StatsdSettings,
+    lines: mpsc::Sender<String>,
+}
+
+impl StatsdSink {
+    pub fn connect(settings: StatsdSettings) -> anyhow::Result<Self> {
+        let addr = settings
+            .addr
+            .to_socket_addrs()
+            .map_err(|e| anyhow::anyhow!("Cannot resolve STATSD_ADDR {}: {e}", settings.addr))?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("STATSD_ADDR {} has no addresses", settings.addr))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(addr)?;
+        let (lines, rx) = mpsc::channel::<String>();
+        std::thread::Builder::new()
+            .name("statsd".to_string())
+            .spawn(move || {
+                let mut warned = false;
+                while let Ok(first) = rx.recv() {
+                    let mut packet = first;
+                    for line in rx.try_iter() {
+                        if packet.len() + 1 + line.len() > MAX_PACKET_BYTES {
+                            send(&socket, &packet, &mut warned);
+                            packet = line;
+                        } else {
+                            packet.push('\n');
+                            packet.push_str(&line);
+                        }
+                    }
+                    send(&socket, &packet, &mut warned);
+                }
+            })?;
+        Ok(Self { settings, lines })
+    }
+
+    pub fn record(&self, name: &str, pairs: &[(&'static str, &str)], kind: Kind, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        let mut line = String::with_capacity(64);
+        line.push_str(&self.settings.prefix);
+        line.push_str(name);
+        if self.settings.format == StatsdFormat::Statsd {
+            for (_, v) in pairs {
+                line.push('.');
+                line.push_str(&sanitize(v).replace(['.', ':'], "_"));
+            }
+        }
+        let kind = match kind {
+            Kind::Counter => "c",
+            Kind::Gauge => "g",
+            Kind::Histogram => "h",
+        };
+        let _ = write!(line, ":{value}|{kind}");
+        if self.settings.format == StatsdFormat::DogStatsd && (!pairs.is_empty() || !self.settings.tags.is_empty()) {
+            line.push_str("|#");
+            let labels = pairs.iter().map(|(k, v)| format!("{k}:{}", sanitize(v)));
+            let tags: Vec<String> = labels.chain(self.settings.tags.iter().cloned()).collect();
+            line.push_str(&tags.join(","));
+        }
+        let _ = self.lines.send(line);
+    }
+}
+
+fn send(socket: &UdpSocket, packet: &str, warned: &mut bool) {
+    if let Err(e) = socket.send(packet.as_bytes()) {
+        // Usually nothing listening yet; say so once rather than per packet.
+        if !*warned {
+            tracing::warn!("Failed to send StatsD metrics: {}", e);
+            *warned = true;
+        }
+    }
+}
+
+/// Replaces characters that delimit StatsD lines, values and tags.
+fn sanitize(raw: &str) -> String {
+    raw.chars()
+        .map(|c| match c {
+            '|' | '#' | ',' | '@' | '\n' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
@@ -0,0 +1,71 @@
+//! Stop sequences beyond the upstream's limit: OpenAI-compatible APIs accept at most four
+//! (MAX_STOP_SEQUENCES, or `[model_params] max_stop_sequences` per model), while Anthropic
+//! clients may send more. The list is normalized (empty and duplicate sequences dropped, as are
+//! sequences containing another one, which would always stop first), the shortest sequences are
+//! sent upstream and the rest are enforced here: output is cut where one of them appears and the
+//! response ends with `stop_reason: "stop_sequence"`. A model whose `[model_params]` strip `stop`
+//! gets none, and the proxy enforces them all.
+
+use crate::metrics;
+use crate::models::anthropic::{AnthropicResponse, ResponseContent};
+use crate::stream::find_stop_sequence;
+
+/// OpenAI's limit on `stop`.
+pub const DEFAULT_MAX_STOP_SEQUENCES: usize = 4;
+
+/// Normalizes `sequences` and keeps at most `limit` of them (no limit when 0). Returns the
+/// sequences left for the proxy to enforce.
+pub fn split(sequences: &mut Option<Vec<String>>, limit: usize) -> Vec<String> {
+    let Some(list) = sequences.as_mut() else {
+        return Vec::new();
+    };
+    let mut normalized: Vec<String> = Vec::with_capacity(list.len());
+    for sequence in list.drain(..) {
+        if !sequence.is_empty() && !normalized.contains(&sequence) {
+            normalized.push(sequence);
+        }
+    }
+    let redundant = |s: &String| normalized.iter().any(|other| other != s && s.contains(other.as_str()));
+    let mut kept: Vec<String> = normalized.iter().filter(|s| !redundant(s)).cloned().collect();
+    if limit == 0 || kept.len() <= limit {
+        *sequences = Some(kept).filter(|k| !k.is_empty());
+        return Vec::new();
+    }
+    // Shorter sequences are likelier to occur, so they go upstream.
+    kept.sort_by_key(|s| s.len());
+    let enforced = kept.split_off(limit);
+    tracing::debug!("Sending {} stop sequence(s) upstream, enforcing {} in the proxy", kept.len(), enforced.len());
+    metrics::increment("proxy_stop_sequences_enforced_total", &[], enforced.len() as u64);
+    *sequences = Some(kept);
+    enforced
+}
+
+/// Normalizes `sequences` and takes all of them for the proxy to enforce, for models whose
+/// `[model_params]` strip `stop`.
+pub fn take_all(sequences: &mut Option<Vec<String>>) -> Vec<String> {
+    split(sequences, 0);
+    let enforced = sequences.take().unwrap_or_default();
+    if !enforced.is_empty() {
+        tracing::debug!("Enforcing {} stop sequence(s) in the proxy, none sent upstream", enforced.len());
+        metrics::increment("proxy_stop_sequences_enforced_total", &[], enforced.len() as u64);
+    }
+    enforced
+}
+
+/// Cuts a complete response at the first of `sequences` in its text, dropping the blocks after
+/// it. Returns whether the response was cut.
+pub fn enforce(resp: &mut AnthropicResponse, sequences: &[String]) -> bool {
+    if sequences.is_empty() {
+        return false;
+    }
+    for (index, block) in resp.content.iter_mut().enumerate() {
+        let ResponseContent::Text { text, .. } = block else { continue };
+        let Some((at, sequence)) = find_stop_sequence(text, sequences) else { continue };
+        text.truncate(at);
+        resp.content.truncate(index + 1);
+        resp.stop_reason = Some("stop_sequence".to_string());
+        resp.stop_sequence = Some(sequence.to_string());
+        return true;
+    }
+    false
+}
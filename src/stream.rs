@@ -0,0 +1,832 @@
+//! Streaming translation: an OpenAI chat completions event stream in, Anthropic Messages SSE
+//! events out. [`StreamTranslator`] does no I/O: feed it upstream bytes as they arrive and
+//! write out the events it returns.
+
+use crate::coalesce::Coalescer;
+use crate::models::{anthropic, openai};
+use crate::transform;
+use bytes::{BufMut, Bytes, BytesMut};
+use memchr::{memchr, memchr2};
+use serde_json::json;
+use std::borrow::Cow;
+use std::time::Instant;
+
+/// Fixed SSE payload for message_stop (avoids per-stream allocation).
+pub const SSE_MESSAGE_STOP: &[u8] = b"event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BlockType {
+    Thinking,
+    Text,
+    ToolUse,
+}
+
+/// Rewrites delta text on its way to the client, e.g. to put redacted values back. It may hold
+/// back the end of a delta (a value split across deltas) until more text or the block's end.
+pub trait DeltaFilter: Send {
+    /// The text to send for `delta`.
+    fn push(&mut self, delta: &str) -> String;
+
+    /// Everything still held back; called when a block ends.
+    fn flush(&mut self) -> String;
+
+    /// Whether tool call arguments, which arrive as raw JSON fragments, pass through as well.
+    fn tool_input(&self) -> bool {
+        true
+    }
+}
+
+/// What [`StreamTranslator`] made of its input.
+#[derive(Debug)]
+pub enum Output {
+    /// An Anthropic SSE event (`event:` and `data:` lines), ready to write to the client.
+    Event(Bytes),
+    /// Token usage reported by the upstream.
+    Usage(openai::Usage),
+    /// The upstream's finish reason and the Anthropic stop reason sent for it.
+    Finish {
+        finish_reason: String,
+        stop_reason: Option<String>,
+    },
+    /// An upstream event whose data is not a valid chunk; it was skipped.
+    Malformed { bytes: usize, error: String },
+    /// A tool call's input was not a JSON object when its block ended. `action` is `passed`
+    /// (sent as it was), `repaired` (completed, e.g. after the upstream cut it short) or
+    /// `rejected` (the stream ended with an error event).
+    InvalidToolInput { name: String, action: &'static str },
+}
+
+/// What [`StreamTranslator`] does with tool call input that is not valid JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ToolInputMode {
+    /// Send it on as it is.
+    #[default]
+    Pass,
+    /// Complete it where that makes it valid (closing strings, arrays and objects cut short),
+    /// else end the stream with an error.
+    Repair,
+    /// End the stream with an `error` event.
+    Error,
+}
+
+/// A tool call as its fragments arrive. Only the first fragment of a call is guaranteed its
+/// `index`; `id` and `name` may come with it, later or never, and some backends repeat them.
+struct ToolCallState {
+    /// The call's `index` in the upstream chunks.
+    index: usize,
+    id: String,
+    /// `id` came from the upstream rather than [`transform::tool_use_id`].
+    upstream_id: bool,
+    /// Set once the name arrives, when its `content_block_start` is sent.
+    name: String,
+    started: bool,
+    /// Arguments that arrived before the name, sent once the block starts.
+    pending_arguments: String,
+    /// Arguments sent so far, checked when the block ends.
+    input: String,
+}
+
+/// UTF-8 byte order mark, which may start an event stream.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Translates one upstream stream. Upstream bytes may be split anywhere, multi-byte characters
+/// included; events are produced as soon as they are complete. The stream is read as the SSE
+/// spec says: lines end in LF, CRLF or CR, `data` fields may span several lines, and comments,
+/// `id` and `retry` fields are skipped.
+pub struct StreamTranslator {
+    /// Raw bytes until a line is complete.
+    buffer: BytesMut,
+    /// Whether a leading byte order mark was looked for.
+    bom_checked: bool,
+    /// `data` lines of the event being read, each followed by a line feed.
+    event_data: Vec<u8>,
+    /// `event` field of the event being read.
+    event_type: Vec<u8>,
+    sse: SseWriter,
+    filters: Vec<Box<dyn DeltaFilter>>,
+    message_id: Option<String>,
+    current_model: Option<String>,
+    content_index: usize,
+    /// The upstream tool call being translated.
+    tool_call: Option<ToolCallState>,
+    has_sent_message_start: bool,
+    /// `usage.input_tokens` of `message_start`.
+    input_tokens: u32,
+    tool_input: ToolInputMode,
+    current_block_type: Option<BlockType>,
+    /// Sequences that end the output when they appear in text.
+    stop_sequences: Vec<String>,
+    /// Text held back while it could be the start of a stop sequence.
+    held: String,
+    /// A stop sequence ended the output; later upstream content is dropped.
+    stopped: bool,
+}
+
+impl Default for StreamTranslator {
+    fn default() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            bom_checked: false,
+            event_data: Vec::new(),
+            event_type: Vec::new(),
+            sse: SseWriter::default(),
+            filters: Vec::new(),
+            message_id: None,
+            current_model: None,
+            content_index: 0,
+            tool_call: None,
+            has_sent_message_start: false,
+            input_tokens: 0,
+            tool_input: ToolInputMode::Pass,
+            current_block_type: None,
+            stop_sequences: Vec::new(),
+            held: String::new(),
+            stopped: false,
+        }
+    }
+}
+
+impl StreamTranslator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merges text and thinking deltas arriving within `settings.window`; callers must
+    /// [`flush`](Self::flush) at [`flush_deadline`](Self::flush_deadline) if no input comes.
+    /// Not on wasm32-unknown-unknown, where `Instant::now` panics.
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub fn with_coalescing(mut self, settings: crate::coalesce::CoalesceSettings) -> Self {
+        self.sse.coalescer = Some(Coalescer::new(settings));
+        self
+    }
+
+    /// Passes every delta's text through `filter` before it is sent, after any filters added
+    /// earlier.
+    pub fn with_filter(mut self, filter: Box<dyn DeltaFilter>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Ends the output when text contains one of `sequences`, for stop sequences the upstream
+    /// was not given: the text before the sequence is sent, then a `stop_sequence` stop. Text
+    /// that could be the start of one is held back until it can't.
+    pub fn with_stop_sequences(mut self, sequences: Vec<String>) -> Self {
+        self.stop_sequences = sequences;
+        self
+    }
+
+    /// Reports `tokens` as the prompt's `input_tokens` in `message_start`, e.g. a local
+    /// estimate: OpenAI-compatible upstreams only report usage at the end of the stream.
+    pub fn with_input_tokens(mut self, tokens: u32) -> Self {
+        self.input_tokens = tokens;
+        self
+    }
+
+    /// What to do with a tool call whose input is not a JSON object once its block ends.
+    pub fn with_tool_input(mut self, mode: ToolInputMode) -> Self {
+        self.tool_input = mode;
+        self
+    }
+
+    /// Translates the complete upstream events in `bytes` (and in earlier input still
+    /// buffered) into `out`.
+    pub fn push(&mut self, bytes: &[u8], out: &mut Vec<Output>) {
+        self.buffer.extend_from_slice(bytes);
+        if !self.bom_checked {
+            if UTF8_BOM.starts_with(&self.buffer) && self.buffer.len() < UTF8_BOM.len() {
+                return;
+            }
+            if self.buffer.starts_with(UTF8_BOM) {
+                let _ = self.buffer.split_to(UTF8_BOM.len());
+            }
+            self.bom_checked = true;
+        }
+        while let Some(end) = memchr2(b'\n', b'\r', &self.buffer) {
+            let terminator = match (self.buffer[end], self.buffer.get(end + 1)) {
+                (b'\r', Some(b'\n')) => 2,
+                // A CR at the end of the input may be the first half of a CRLF.
+                (b'\r', None) => break,
+                _ => 1,
+            };
+            let line = self.buffer.split_to(end + terminator);
+            self.line(&line[..end], out);
+        }
+    }
+
+    /// One line of the upstream stream: a field of the event being read, a comment, or the
+    /// blank line ending the event.
+    fn line(&mut self, line: &[u8], out: &mut Vec<Output>) {
+        if line.is_empty() {
+            self.dispatch(out);
+            return;
+        }
+        let (field, value) = match memchr(b':', line) {
+            Some(0) => return,
+            Some(colon) => {
+                let value = &line[colon + 1..];
+                (&line[..colon], value.strip_prefix(b" ").unwrap_or(value))
+            }
+            None => (line, &[][..]),
+        };
+        match field {
+            b"data" => {
+                self.event_data.extend_from_slice(value);
+                self.event_data.push(b'\n');
+            }
+            b"event" => {
+                self.event_type.clear();
+                self.event_type.extend_from_slice(value);
+            }
+            // Nothing to resume: `id` and `retry` only matter for reconnecting.
+            _ => {}
+        }
+    }
+
+    /// The event read so far, now that a blank line ended it. An `error` event ends the
+    /// stream with its message; any other is a chunk, whatever its type.
+    fn dispatch(&mut self, out: &mut Vec<Output>) {
+        let mut data = std::mem::take(&mut self.event_data);
+        data.pop();
+        if !data.is_empty() && !self.stopped && self.event_type == b"error" {
+            let message = serde_json::from_slice::<serde_json::Value>(&data)
+                .ok()
+                .and_then(|error| {
+                    let message = error.pointer("/error/message").or_else(|| error.get("message"))?;
+                    message.as_str().map(str::to_string)
+                })
+                .unwrap_or_else(|| String::from_utf8_lossy(&data).into_owned());
+            self.error(&format!("Upstream error: {message}"), out);
+            self.stopped = true;
+        } else if !data.is_empty() {
+            self.data(&mut data, out);
+        }
+        data.clear();
+        self.event_data = data;
+        self.event_type.clear();
+    }
+
+    /// When a held-back delta is due, if coalescing and one is held.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.sse.flush_deadline()
+    }
+
+    /// Sends a held-back delta now.
+    pub fn flush(&mut self, out: &mut Vec<Output>) {
+        out.extend(self.sse.flush().map(Output::Event));
+    }
+
+    /// Ends the stream with an Anthropic `error` event, after anything held back.
+    pub fn error(&mut self, message: &str, out: &mut Vec<Output>) {
+        self.release_held(out);
+        self.flush(out);
+        let error_event = json!({
+            "type": "error",
+            "error": { "type": "stream_error", "message": message }
+        });
+        out.push(Output::Event(self.sse.event("error", &error_event)));
+    }
+
+    /// The upstream stream ended: completes a last line ended by a lone CR and sends anything
+    /// held back. An event missing its closing blank line is dropped, as the SSE spec says.
+    pub fn finish(&mut self, out: &mut Vec<Output>) {
+        if self.buffer.last() == Some(&b'\r') {
+            let line = self.buffer.split();
+            self.line(&line[..line.len() - 1], out);
+        }
+        self.release_held(out);
+        self.flush(out);
+    }
+
+    /// Delta text after the filters.
+    fn filtered<'a>(filters: &mut [Box<dyn DeltaFilter>], block: BlockType, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for filter in filters.iter_mut().filter(|f| block != BlockType::ToolUse || f.tool_input()) {
+            text = Cow::Owned(filter.push(&text));
+        }
+        text
+    }
+
+    /// Delta text the filters still hold back at the end of a block.
+    fn flush_filters(filters: &mut [Box<dyn DeltaFilter>], block: BlockType) -> String {
+        // What one filter releases still goes through the filters after it.
+        let mut rest = String::new();
+        for filter in filters.iter_mut().filter(|f| block != BlockType::ToolUse || f.tool_input()) {
+            if !rest.is_empty() {
+                rest = filter.push(&rest);
+            }
+            rest.push_str(&filter.flush());
+        }
+        rest
+    }
+
+    /// Ends the open block. A tool call's input is checked first, which may end the stream
+    /// with an error instead (`stopped` is then set).
+    fn close_block(&mut self, block: BlockType, out: &mut Vec<Output>) {
+        self.release_held(out);
+        let mut rest = Self::flush_filters(&mut self.filters, block);
+        if block == BlockType::ToolUse {
+            match self.check_tool_input(&rest, out) {
+                Some(completion) => rest.push_str(&completion),
+                None => return,
+            }
+        }
+        let events = self.sse.close_block(self.content_index, block, &rest);
+        out.extend(events.into_iter().map(Output::Event));
+    }
+
+    /// Checks that the input of the tool call being closed, `rest` included, is a JSON object.
+    /// Returns the text completing a repaired input (empty if nothing needed repair), or None
+    /// when the stream ended with an error instead.
+    fn check_tool_input(&mut self, rest: &str, out: &mut Vec<Output>) -> Option<String> {
+        let call = self.tool_call.as_mut().filter(|call| call.started)?;
+        call.input.push_str(rest);
+        if call.input.trim().is_empty() || transform::is_json_object(&call.input) {
+            return Some(String::new());
+        }
+        let name = call.name.clone();
+        let completion = match self.tool_input {
+            ToolInputMode::Pass => Some(String::new()),
+            ToolInputMode::Repair => json_completion(&call.input),
+            ToolInputMode::Error => None,
+        };
+        let action = match (&completion, self.tool_input) {
+            (Some(_), ToolInputMode::Pass) => "passed",
+            (Some(_), _) => "repaired",
+            (None, _) => "rejected",
+        };
+        out.push(Output::InvalidToolInput { name: name.clone(), action });
+        if completion.is_none() {
+            self.error(&format!("Tool call '{name}' has invalid JSON input"), out);
+            self.stopped = true;
+        }
+        completion
+    }
+
+    /// Text deltas as far as they can't be part of a stop sequence, and the sequence if one
+    /// was found; the text then ends before it.
+    fn scan_stop<'a>(&mut self, text: &'a str) -> (Cow<'a, str>, Option<String>) {
+        if self.stop_sequences.is_empty() {
+            return (Cow::Borrowed(text), None);
+        }
+        self.held.push_str(text);
+        if let Some((at, sequence)) = find_stop_sequence(&self.held, &self.stop_sequences) {
+            let sequence = sequence.to_string();
+            let mut ready = std::mem::take(&mut self.held);
+            ready.truncate(at);
+            return (Cow::Owned(ready), Some(sequence));
+        }
+        let keep = self
+            .stop_sequences
+            .iter()
+            .filter_map(|s| {
+                (1..s.len())
+                    .rev()
+                    .find(|&n| s.is_char_boundary(n) && self.held.ends_with(&s[..n]))
+            })
+            .max()
+            .unwrap_or(0);
+        let ready = self.held.drain(..self.held.len() - keep).collect();
+        (Cow::Owned(ready), None)
+    }
+
+    /// Sends text held back by [`scan_stop`](Self::scan_stop), which can no longer become a
+    /// stop sequence.
+    fn release_held(&mut self, out: &mut Vec<Output>) {
+        if self.held.is_empty() {
+            return;
+        }
+        let held = std::mem::take(&mut self.held);
+        let held = Self::filtered(&mut self.filters, BlockType::Text, &held);
+        if !held.is_empty() {
+            out.extend(self.sse.delta(self.content_index, BlockType::Text, &held).map(Output::Event));
+        }
+    }
+
+    /// Ends the message at `sequence`, as the upstream would have.
+    fn stop_at(&mut self, sequence: String, out: &mut Vec<Output>) {
+        if let Some(block) = self.current_block_type.take() {
+            self.close_block(block, out);
+        }
+        let stop_reason = Some("stop_sequence".to_string());
+        let event = json!({
+            "type": "message_delta",
+            "delta": { "stop_reason": stop_reason, "stop_sequence": sequence },
+        });
+        out.push(Output::Finish {
+            finish_reason: "stop".to_string(),
+            stop_reason,
+        });
+        out.push(Output::Event(self.sse.event("message_delta", &event)));
+        out.push(Output::Event(Bytes::from_static(SSE_MESSAGE_STOP)));
+        self.stopped = true;
+    }
+
+    /// One fragment of a tool call. Fragments are told apart by `index`, or by a new upstream
+    /// `id` for backends that reuse one index; a call without an id gets a synthesized one.
+    fn tool_call_delta(&mut self, delta: &openai::DeltaToolCall, out: &mut Vec<Output>) {
+        let id = delta.id.as_deref().filter(|id| !id.is_empty());
+        let function = delta.function.as_ref();
+        let name = function.and_then(|f| f.name.as_deref()).filter(|name| !name.is_empty());
+        let arguments = function.and_then(|f| f.arguments.as_deref()).unwrap_or_default();
+
+        let same_call = self.tool_call.as_ref().is_some_and(|call| {
+            call.index == delta.index
+                && (!call.started || self.current_block_type == Some(BlockType::ToolUse))
+                && !(call.upstream_id && id.is_some_and(|id| id != call.id))
+        });
+        if !same_call {
+            if let Some(call) = self.tool_call.as_ref().filter(|call| !call.started) {
+                tracing::warn!("Dropped upstream tool call {} that never got a name", call.index);
+            }
+            // The previous call is complete: its block is closed (and its input checked) while
+            // `tool_call` still describes it, and the next block gets the next index.
+            if self.current_block_type == Some(BlockType::ToolUse) {
+                self.close_block(BlockType::ToolUse, out);
+                if self.stopped {
+                    return;
+                }
+                self.current_block_type = None;
+                self.content_index += 1;
+            }
+            self.tool_call = Some(ToolCallState {
+                index: delta.index,
+                id: id.map_or_else(
+                    || transform::tool_use_id(self.message_id.as_deref().unwrap_or_default(), delta.index),
+                    str::to_string,
+                ),
+                upstream_id: id.is_some(),
+                name: String::new(),
+                started: false,
+                pending_arguments: String::new(),
+                input: String::new(),
+            });
+        }
+        let Some(call) = self.tool_call.as_mut() else { return };
+
+        let arguments = if call.started {
+            Cow::Borrowed(arguments)
+        } else {
+            if let Some(id) = id.filter(|_| !call.upstream_id) {
+                call.id = id.to_string();
+                call.upstream_id = true;
+            }
+            call.pending_arguments.push_str(arguments);
+            let Some(name) = name else { return };
+            call.started = true;
+            call.name = name.to_string();
+            let (tool_use_id, arguments) = (call.id.clone(), std::mem::take(&mut call.pending_arguments));
+            if let Some(block) = self.current_block_type {
+                self.close_block(block, out);
+                if self.stopped {
+                    return;
+                }
+                self.content_index += 1;
+            }
+            self.flush(out);
+            let event = json!({
+                "type": "content_block_start",
+                "index": self.content_index,
+                "content_block": { "type": "tool_use", "id": tool_use_id, "name": name }
+            });
+            out.push(Output::Event(self.sse.event("content_block_start", &event)));
+            self.current_block_type = Some(BlockType::ToolUse);
+            Cow::Owned(arguments)
+        };
+        let arguments = Self::filtered(&mut self.filters, BlockType::ToolUse, &arguments);
+        if let Some(call) = self.tool_call.as_mut() {
+            call.input.push_str(&arguments);
+        }
+        if !arguments.is_empty() {
+            out.push(Output::Event(self.sse.block_delta(self.content_index, BlockType::ToolUse, &arguments)));
+        }
+    }
+
+    /// One `data:` line of the upstream stream.
+    fn data(&mut self, data: &mut [u8], out: &mut Vec<Output>) {
+        if data.trim_ascii() == b"[DONE]" {
+            self.flush(out);
+            if !self.stopped {
+                out.push(Output::Event(Bytes::from_static(SSE_MESSAGE_STOP)));
+            }
+            return;
+        }
+
+        let chunk = match parse_chunk(data) {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                out.push(Output::Malformed { bytes: data.len(), error });
+                return;
+            }
+        };
+        if self.message_id.is_none() {
+            self.message_id = Some(chunk.id.clone());
+        }
+        if self.current_model.is_none() {
+            self.current_model = Some(chunk.model.clone());
+        }
+        if let Some(usage) = &chunk.usage {
+            out.push(Output::Usage(usage.clone()));
+        }
+        if self.stopped {
+            return;
+        }
+
+        let Some(choice) = chunk.choices.first() else { return };
+
+        if !self.has_sent_message_start {
+            let msg = anthropic::StreamEvent::MessageStart {
+                message: anthropic::MessageStartData {
+                    id: self.message_id.clone().unwrap_or_default(),
+                    message_type: "message".to_string(),
+                    role: "assistant".to_string(),
+                    model: self.current_model.clone().unwrap_or_default(),
+                    usage: anthropic::Usage {
+                        input_tokens: self.input_tokens,
+                        output_tokens: 0,
+                    },
+                },
+            };
+            out.push(Output::Event(self.sse.event("message_start", &msg)));
+            self.has_sent_message_start = true;
+        }
+
+        if let Some(reasoning) = &choice.delta.reasoning {
+            if self.current_block_type.is_none() {
+                let event = json!({
+                    "type": "content_block_start",
+                    "index": self.content_index,
+                    "content_block": { "type": "thinking", "thinking": "" }
+                });
+                out.push(Output::Event(self.sse.event("content_block_start", &event)));
+                self.current_block_type = Some(BlockType::Thinking);
+            }
+            let reasoning = Self::filtered(&mut self.filters, BlockType::Thinking, reasoning);
+            if !reasoning.is_empty() {
+                out.extend(
+                    self.sse
+                        .delta(self.content_index, BlockType::Thinking, &reasoning)
+                        .map(Output::Event),
+                );
+            }
+        }
+
+        if let Some(content) = &choice.delta.content {
+            if !content.is_empty() {
+                if self.current_block_type != Some(BlockType::Text) {
+                    if let Some(block) = self.current_block_type {
+                        self.close_block(block, out);
+                        if self.stopped {
+                            return;
+                        }
+                        self.content_index += 1;
+                    }
+                    let event = json!({
+                        "type": "content_block_start",
+                        "index": self.content_index,
+                        "content_block": { "type": "text", "text": "" }
+                    });
+                    out.push(Output::Event(self.sse.event("content_block_start", &event)));
+                    self.current_block_type = Some(BlockType::Text);
+                }
+                let (content, stop) = self.scan_stop(content);
+                let content = Self::filtered(&mut self.filters, BlockType::Text, &content);
+                if !content.is_empty() {
+                    out.extend(
+                        self.sse
+                            .delta(self.content_index, BlockType::Text, &content)
+                            .map(Output::Event),
+                    );
+                }
+                if let Some(sequence) = stop {
+                    self.stop_at(sequence, out);
+                    return;
+                }
+            }
+        }
+
+        if let Some(tool_calls) = &choice.delta.tool_calls {
+            for tool_call in tool_calls {
+                self.tool_call_delta(tool_call, out);
+                if self.stopped {
+                    return;
+                }
+            }
+        }
+
+        if let Some(finish_reason) = &choice.finish_reason {
+            if let Some(block) = self.current_block_type {
+                self.close_block(block, out);
+                if self.stopped {
+                    return;
+                }
+            }
+            let stop_reason = transform::map_stop_reason(Some(finish_reason));
+            let event = json!({
+                "type": "message_delta",
+                "delta": { "stop_reason": stop_reason, "stop_sequence": serde_json::Value::Null },
+                "usage": chunk.usage.as_ref().map(|u| json!({ "output_tokens": u.completion_tokens }))
+            });
+            out.push(Output::Finish {
+                finish_reason: finish_reason.clone(),
+                stop_reason,
+            });
+            out.push(Output::Event(self.sse.event("message_delta", &event)));
+        }
+    }
+}
+
+/// Text that, appended to `input`, makes it a JSON object: closes an unterminated string,
+/// finishes a cut-off literal or a key without a value, and closes open arrays and objects.
+/// None if appending cannot repair it.
+fn json_completion(input: &str) -> Option<String> {
+    let mut closers = Vec::new();
+    let (mut in_string, mut escaped) = (false, false);
+    for c in input.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' if closers.pop() != Some(c) => return None,
+            _ => {}
+        }
+    }
+    let mut string_end = String::new();
+    if in_string {
+        if escaped {
+            string_end.push('\\');
+        }
+        string_end.push('"');
+    }
+    let closers: String = closers.iter().rev().collect();
+    let word = input
+        .trim_end()
+        .rsplit(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-')
+        .next()
+        .unwrap_or_default();
+    let literal = ["true", "false", "null"]
+        .into_iter()
+        .find(|literal| !word.is_empty() && literal.starts_with(word))
+        .map_or("", |literal| &literal[word.len()..]);
+    ["", literal, "0", "null", ":null"].into_iter().find_map(|value| {
+        let completion = format!("{string_end}{value}{closers}");
+        transform::is_json_object(&format!("{input}{completion}")).then_some(completion)
+    })
+}
+
+/// Where the first of `sequences` to appear in `text` starts, and which one it is.
+pub(crate) fn find_stop_sequence<'a>(text: &str, sequences: &'a [String]) -> Option<(usize, &'a str)> {
+    sequences
+        .iter()
+        .filter_map(|s| text.find(s.as_str()).map(|at| (at, s.as_str())))
+        .min_by_key(|&(at, _)| at)
+}
+
+/// Parses one upstream stream chunk in place with simd-json.
+#[cfg(feature = "simd-json")]
+fn parse_chunk(data: &mut [u8]) -> Result<openai::StreamChunk, String> {
+    simd_json::serde::from_slice(data).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn parse_chunk(data: &mut [u8]) -> Result<openai::StreamChunk, String> {
+    serde_json::from_slice(data).map_err(|e| e.to_string())
+}
+
+/// A complete response as the Anthropic SSE events a stream would have carried, e.g. to answer
+/// a streaming request from a cache.
+pub fn response_events(resp: &anthropic::AnthropicResponse) -> Vec<Bytes> {
+    let mut sse = SseWriter::default();
+    let mut events = Vec::with_capacity(resp.content.len() * 3 + 3);
+    let start = anthropic::StreamEvent::MessageStart {
+        message: anthropic::MessageStartData {
+            id: resp.id.clone(),
+            message_type: "message".to_string(),
+            role: "assistant".to_string(),
+            model: resp.model.clone(),
+            usage: anthropic::Usage {
+                input_tokens: resp.usage.input_tokens,
+                output_tokens: 0,
+            },
+        },
+    };
+    events.push(sse.event("message_start", &start));
+
+    for (index, block) in resp.content.iter().enumerate() {
+        let (content_block, delta) = match block {
+            anthropic::ResponseContent::Text { text, .. } => (
+                json!({ "type": "text", "text": "" }),
+                json!({ "type": "text_delta", "text": text }),
+            ),
+            anthropic::ResponseContent::ToolUse { id, name, input, .. } => (
+                json!({ "type": "tool_use", "id": id, "name": name, "input": {} }),
+                json!({ "type": "input_json_delta", "partial_json": input.get() }),
+            ),
+            anthropic::ResponseContent::Thinking { thinking, .. } => (
+                json!({ "type": "thinking", "thinking": "" }),
+                json!({ "type": "thinking_delta", "thinking": thinking }),
+            ),
+        };
+        let event = json!({ "type": "content_block_start", "index": index, "content_block": content_block });
+        events.push(sse.event("content_block_start", &event));
+        let event = json!({ "type": "content_block_delta", "index": index, "delta": delta });
+        events.push(sse.event("content_block_delta", &event));
+        let event = json!({ "type": "content_block_stop", "index": index });
+        events.push(sse.event("content_block_stop", &event));
+    }
+
+    let event = json!({
+        "type": "message_delta",
+        "delta": { "stop_reason": resp.stop_reason, "stop_sequence": resp.stop_sequence },
+        "usage": { "output_tokens": resp.usage.output_tokens }
+    });
+    events.push(sse.event("message_delta", &event));
+    events.push(Bytes::from_static(SSE_MESSAGE_STOP));
+    events
+}
+
+/// Writes SSE events into one buffer per stream: the event name and the JSON data are
+/// serialized in place, then split off as `Bytes`. Once the events already handed out are
+/// dropped, the buffer reuses its allocation instead of allocating per event. With
+/// coalescing, text and thinking deltas are held back and merged; `flush` sends them.
+struct SseWriter {
+    buf: BytesMut,
+    coalescer: Option<Coalescer<(usize, BlockType)>>,
+}
+
+impl Default for SseWriter {
+    fn default() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(1024),
+            coalescer: None,
+        }
+    }
+}
+
+impl SseWriter {
+    fn event(&mut self, event: &str, data: &impl serde::Serialize) -> Bytes {
+        self.buf.extend_from_slice(b"event: ");
+        self.buf.extend_from_slice(event.as_bytes());
+        self.buf.extend_from_slice(b"\ndata: ");
+        let start = self.buf.len();
+        if serde_json::to_writer((&mut self.buf).writer(), data).is_err() {
+            self.buf.truncate(start);
+        }
+        self.buf.extend_from_slice(b"\n\n");
+        self.buf.split().freeze()
+    }
+
+    /// `content_block_delta` event carrying `text` for a block of the given type.
+    fn block_delta(&mut self, index: usize, block: BlockType, text: &str) -> Bytes {
+        let delta = match block {
+            BlockType::Thinking => json!({ "type": "thinking_delta", "thinking": text }),
+            BlockType::Text => json!({ "type": "text_delta", "text": text }),
+            BlockType::ToolUse => json!({ "type": "input_json_delta", "partial_json": text }),
+        };
+        let event = json!({ "type": "content_block_delta", "index": index, "delta": delta });
+        self.event("content_block_delta", &event)
+    }
+
+    /// Like `block_delta`, but text and thinking are held back while coalescing; returns the
+    /// event due now, if any.
+    fn delta(&mut self, index: usize, block: BlockType, text: &str) -> Option<Bytes> {
+        match self.coalescer.as_mut() {
+            Some(coalescer) if block != BlockType::ToolUse => coalescer
+                .push((index, block), text)
+                .map(|((index, block), text)| self.block_delta(index, block, &text)),
+            _ => Some(self.block_delta(index, block, text)),
+        }
+    }
+
+    /// The held-back delta, which must go out before any other event.
+    fn flush(&mut self) -> Option<Bytes> {
+        let ((index, block), text) = self.coalescer.as_mut()?.take()?;
+        Some(self.block_delta(index, block, &text))
+    }
+
+    /// When the held-back delta is due even if upstream sends nothing more.
+    fn flush_deadline(&self) -> Option<Instant> {
+        self.coalescer.as_ref()?.deadline()
+    }
+
+    /// Events ending the open block: `rest` (delta text the filters held back) and any
+    /// delta still held by the coalescer, then the stop.
+    fn close_block(&mut self, index: usize, block: BlockType, rest: &str) -> Vec<Bytes> {
+        let mut events = Vec::with_capacity(2);
+        if !rest.is_empty() {
+            events.extend(self.delta(index, block, rest));
+        }
+        events.extend(self.flush());
+        let event = json!({"type": "content_block_stop", "index": index});
+        events.push(self.event("content_block_stop", &event));
+        events
+    }
+}
@@ -0,0 +1,172 @@
+//! Summaries of the history truncation drops (`[model_params."<pattern>"] summarize = true`):
+//! the evicted turns are sent as a transcript to SUMMARY_MODEL on the default upstream, and its
+//! summary is appended to the system prompt, so a long session keeps its gist on a model with a
+//! small context window. Summaries are cached by the evicted messages, so a client resending
+//! the same history pays for one summary.
+
+use crate::cache::{content_hash, CacheKey};
+use crate::chaos::Faults;
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::metrics;
+use crate::models::anthropic::{AnthropicRequest, ContentBlock, Message, MessageContent, SystemMessage, SystemPrompt};
+use crate::models::openai;
+use crate::proxy;
+use crate::telemetry::TraceContext;
+use axum::http::HeaderMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub const DEFAULT_SUMMARY_MAX_TOKENS: u32 = 1024;
+/// Summaries kept for resent histories.
+const CACHE_CAPACITY: usize = 256;
+/// Tool results and inputs are cut to this many characters in the transcript.
+const TOOL_CHARS: usize = 2000;
+const INSTRUCTIONS: &str = "You summarize the earlier part of a conversation between a user and an AI assistant, \
+which no longer fits the assistant's context window. Write a concise summary the assistant can continue from: \
+the user's goals and requests, decisions made, files, commands and tool results that matter, and work still \
+outstanding. Reply with the summary only.";
+
+/// SUMMARY_MODEL / SUMMARY_MAX_TOKENS.
+#[derive(Debug, Clone)]
+pub struct SummarySettings {
+    /// Model on the default upstream that writes summaries; a small, cheap one will do.
+    pub model: String,
+    /// Longest summary; truncation leaves this much of the context window free for it.
+    pub max_tokens: u32,
+}
+
+static CACHE: Mutex<VecDeque<(CacheKey, Arc<str>)>> = Mutex::new(VecDeque::new());
+
+/// Summarizes the `evicted` messages with the configured summary model.
+pub async fn summarize(config: &Config, settings: &SummarySettings, evicted: &[Message]) -> ProxyResult<Arc<str>> {
+    let key = content_hash(evicted);
+    if let Some(summary) = key.and_then(cached) {
+        metrics::increment("proxy_summaries_total", &[("result", "cached")], 1);
+        return Ok(summary);
+    }
+
+    let request = openai::OpenAIRequest {
+        model: settings.model.clone(),
+        messages: vec![text_message("system", INSTRUCTIONS), text_message("user", &transcript(evicted))],
+        max_tokens: Some(settings.max_tokens),
+        temperature: None,
+        top_p: None,
+        stop: None,
+        stream: None,
+        tools: None,
+        tool_choice: None,
+        stream_options: None,
+    };
+    let started = Instant::now();
+    let result = async {
+        let response = proxy::send_upstream(
+            &config.upstream,
+            &request,
+            &TraceContext::default(),
+            &HeaderMap::new(),
+            Faults::default(),
+        )
+        .await?;
+        let response: openai::OpenAIResponse = response.json().await?;
+        response
+            .choices
+            .into_iter()
+            .find_map(|choice| choice.message.content.filter(|text| !text.trim().is_empty()))
+            .ok_or_else(|| ProxyError::Upstream("Summary model returned no text".to_string()))
+    }
+    .await;
+    let summary: Arc<str> = match result {
+        Ok(text) => Arc::from(text.trim()),
+        Err(e) => {
+            metrics::increment("proxy_summaries_total", &[("result", "error")], 1);
+            return Err(e);
+        }
+    };
+    tracing::debug!(
+        "Summarized {} message(s) with {} in {}ms",
+        evicted.len(),
+        settings.model,
+        started.elapsed().as_millis()
+    );
+    metrics::increment("proxy_summaries_total", &[("result", "created")], 1);
+    if let Some(key) = key {
+        let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        cache.push_back((key, Arc::clone(&summary)));
+        if cache.len() > CACHE_CAPACITY {
+            cache.pop_front();
+        }
+    }
+    Ok(summary)
+}
+
+/// Appends `summary` to `req`'s system prompt as a note on the earlier conversation.
+pub fn inject(req: &mut AnthropicRequest, summary: &str) {
+    let note = format!(
+        "<conversation_summary>\nEarlier messages of this conversation were removed to fit the context window. \
+Summary of them:\n{summary}\n</conversation_summary>"
+    );
+    match &mut req.system {
+        Some(SystemPrompt::Single(text)) => {
+            text.push_str("\n\n");
+            text.push_str(&note);
+        }
+        Some(SystemPrompt::Multiple(parts)) => parts.push(SystemMessage {
+            message_type: "text".to_string(),
+            text: note,
+            cache_control: None,
+        }),
+        None => req.system = Some(SystemPrompt::Single(note)),
+    }
+}
+
+fn cached(key: CacheKey) -> Option<Arc<str>> {
+    let cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.iter().find(|(k, _)| *k == key).map(|(_, summary)| Arc::clone(summary))
+}
+
+fn text_message(role: &str, text: &str) -> openai::Message {
+    openai::Message {
+        role: role.to_string(),
+        content: Some(openai::MessageContent::Text(text.to_string())),
+        tool_calls: None,
+        tool_call_id: None,
+        name: None,
+    }
+}
+
+/// `messages` as plain text, one `role: content` paragraph each.
+fn transcript(messages: &[Message]) -> String {
+    let mut out = String::new();
+    for message in messages {
+        let content = match &message.content {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Blocks(blocks) => blocks
+                .iter()
+                .filter_map(|block| match block {
+                    ContentBlock::Text { text, .. } => Some(text.clone()),
+                    ContentBlock::ToolUse { name, input, .. } => {
+                        Some(format!("[called tool {name}: {}]", clip(input.get())))
+                    }
+                    ContentBlock::ToolResult { content, is_error, .. } => {
+                        let label = if *is_error == Some(true) { "tool error" } else { "tool result" };
+                        Some(format!("[{label}: {}]", clip(content)))
+                    }
+                    ContentBlock::Image { .. } => Some("[image]".to_string()),
+                    ContentBlock::Thinking { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        out.push_str(&format!("{}: {}\n\n", message.role, content));
+    }
+    out
+}
+
+fn clip(text: &str) -> String {
+    match text.char_indices().nth(TOOL_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
@@ -0,0 +1,143 @@
+//! Operator text added to every system prompt: SYSTEM_PROMPT_PREFIX before whatever the client
+//! sends and SYSTEM_PROMPT_SUFFIX after it, for organization policies or tool-usage instructions
+//! enforced at the proxy. A client key's `system_prompt_prefix` / `system_prompt_suffix`
+//! replace the global ones for its requests.
+//!
+//! The text is a template rendered for each request: `{{date}}`, `{{datetime}}` (UTC),
+//! `{{client_name}}`, `{{model}}` (as requested), `{{upstream_model}}` (as routed), and a
+//! `{{name}}` for every PROMPT_VARS_<NAME> variable (`[prompt_vars] name = "..."` in a TOML
+//! file). Unknown names are left as they are. `[[rewrite]]` replacements are rendered the
+//! same way.
+
+use crate::models::anthropic::{AnthropicRequest, SystemMessage, SystemPrompt};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+
+/// Prefix of the environment variables that define custom template variables.
+pub const PROMPT_VARS_PREFIX: &str = "PROMPT_VARS_";
+
+/// The custom template variables, by lower-cased name without PROMPT_VARS_.
+pub fn prompt_vars() -> BTreeMap<String, String> {
+    env::vars()
+        .filter_map(|(name, value)| {
+            let var = name.strip_prefix(PROMPT_VARS_PREFIX)?;
+            (!var.is_empty()).then(|| (var.to_ascii_lowercase(), value))
+        })
+        .collect()
+}
+
+/// What a template can refer to for one request.
+pub struct TemplateVars<'a> {
+    pub client_name: &'a str,
+    pub model: &'a str,
+    pub upstream_model: &'a str,
+    pub custom: &'a BTreeMap<String, String>,
+    pub now: DateTime<Utc>,
+}
+
+impl TemplateVars<'_> {
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        Some(match name {
+            "date" => Cow::Owned(self.now.format("%Y-%m-%d").to_string()),
+            "datetime" => Cow::Owned(self.now.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            "client_name" => Cow::Borrowed(self.client_name),
+            "model" => Cow::Borrowed(self.model),
+            "upstream_model" => Cow::Borrowed(self.upstream_model),
+            other => Cow::Borrowed(self.custom.get(other)?.as_str()),
+        })
+    }
+
+    /// `text` with its `{{name}}` placeholders filled in.
+    pub fn render<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        self.render_with(text, |value| Cow::Borrowed(value))
+    }
+
+    /// Like [`render`](Self::render), passing each value through `escape` first, for templates
+    /// in which some characters of a value would otherwise mean something.
+    pub fn render_with<'t>(&self, text: &'t str, escape: impl Fn(&str) -> Cow<'_, str>) -> Cow<'t, str> {
+        if !text.contains("{{") {
+            return Cow::Borrowed(text);
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            let end = start + 2 + len + 2;
+            out.push_str(&rest[..start]);
+            match self.get(rest[start + 2..end - 2].trim()) {
+                Some(value) => out.push_str(&escape(&value)),
+                None => out.push_str(&rest[start..end]),
+            }
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        Cow::Owned(out)
+    }
+}
+
+/// SYSTEM_PROMPT_PREFIX / SYSTEM_PROMPT_SUFFIX, or a client key's own.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemPromptEdits {
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+}
+
+impl SystemPromptEdits {
+    pub fn is_empty(&self) -> bool {
+        self.prefix.is_none() && self.suffix.is_none()
+    }
+
+    /// These edits, with `fallback`'s prefix or suffix where they set none.
+    pub fn or(&self, fallback: &SystemPromptEdits) -> SystemPromptEdits {
+        SystemPromptEdits {
+            prefix: self.prefix.clone().or_else(|| fallback.prefix.clone()),
+            suffix: self.suffix.clone().or_else(|| fallback.suffix.clone()),
+        }
+    }
+
+    /// Adds the prefix and suffix, rendered with `vars`, to `req`'s system prompt, creating one
+    /// if it has none. Block prompts get them as blocks of their own, leaving the client's
+    /// `cache_control` in place.
+    pub fn apply(&self, req: &mut AnthropicRequest, vars: &TemplateVars) {
+        if self.is_empty() {
+            return;
+        }
+        let prefix = self.prefix.as_deref().map(|text| vars.render(text));
+        let suffix = self.suffix.as_deref().map(|text| vars.render(text));
+        match &mut req.system {
+            Some(SystemPrompt::Multiple(parts)) => {
+                if let Some(prefix) = prefix {
+                    parts.insert(0, text_block(&prefix));
+                }
+                if let Some(suffix) = suffix {
+                    parts.push(text_block(&suffix));
+                }
+            }
+            system => {
+                let client = match system.take() {
+                    Some(SystemPrompt::Single(text)) => Some(text),
+                    _ => None,
+                };
+                let text = [prefix.as_deref(), client.as_deref(), suffix.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .filter(|part| !part.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+                *system = Some(SystemPrompt::Single(text));
+            }
+        }
+    }
+}
+
+fn text_block(text: &str) -> SystemMessage {
+    SystemMessage {
+        message_type: "text".to_string(),
+        text: text.to_string(),
+        cache_control: None,
+    }
+}
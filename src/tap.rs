@@ -0,0 +1,75 @@
+//! Live request tap: with the admin API enabled, every finished request is broadcast to
+//! subscribers of `/admin/tap` (server-sent events), which `anthropic-proxy monitor` renders.
+//! Nothing is buffered for absent subscribers, and slow ones skip events rather than
+//! holding up requests.
+
+use crate::accesslog::{Details, Record};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events a subscriber may fall behind by before it starts missing them.
+const TAP_CAPACITY: usize = 1024;
+
+/// One finished request, as sent on the tap.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TapEvent {
+    pub timestamp: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub client: Option<String>,
+    pub incoming_model: Option<String>,
+    pub routed_model: Option<String>,
+    pub stream: Option<bool>,
+    pub stop_reason: Option<String>,
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+    pub cost_usd: Option<f64>,
+    pub ttfb_ms: Option<f64>,
+    pub duration_ms: f64,
+    /// `type: message` of the error returned to the client, if any.
+    pub error: Option<String>,
+}
+
+pub struct Tap {
+    events: broadcast::Sender<Arc<TapEvent>>,
+}
+
+impl Default for Tap {
+    fn default() -> Self {
+        Self {
+            events: broadcast::channel(TAP_CAPACITY).0,
+        }
+    }
+}
+
+impl Tap {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<TapEvent>> {
+        self.events.subscribe()
+    }
+
+    pub fn publish(&self, record: &Record, details: &Details) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let millis = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+        let _ = self.events.send(Arc::new(TapEvent {
+            timestamp: record.received.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            method: record.method.clone(),
+            path: record.path.clone(),
+            status: record.status,
+            client: details.client.clone(),
+            incoming_model: details.incoming_model.clone(),
+            routed_model: details.routed_model.clone(),
+            stream: details.stream,
+            stop_reason: details.stop_reason.clone(),
+            input_tokens: details.input_tokens,
+            output_tokens: details.output_tokens,
+            cost_usd: details.cost_usd,
+            ttfb_ms: record.ttfb.map(millis),
+            duration_ms: millis(record.duration),
+            error: details.error.clone(),
+        }));
+    }
+}
@@ -0,0 +1,230 @@
+//! OpenTelemetry tracing: a span per `/v1/messages` request, with child spans for the
+//! transform, upstream call and stream translation, exported over OTLP/HTTP when
+//! OTEL_EXPORTER_OTLP_ENDPOINT is set (`otel` feature). W3C `traceparent`/`tracestate`
+//! headers are continued from the client and forwarded upstream.
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use tracing_subscriber::{Layer, Registry};
+
+/// Default OTEL_SERVICE_NAME.
+pub const DEFAULT_SERVICE_NAME: &str = "anthropic-proxy";
+
+/// OTLP export settings. The exporter itself reads the standard OTEL_EXPORTER_OTLP_*
+/// variables (endpoint, headers, timeout).
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub struct OtelSettings {
+    pub endpoint: String,
+    pub service_name: String,
+}
+
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+pub use imp::otlp_layer;
+
+const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+
+/// The client's W3C trace context, forwarded on the upstream request.
+#[derive(Debug, Clone, Default)]
+pub struct TraceContext {
+    traceparent: Option<HeaderValue>,
+    tracestate: Option<HeaderValue>,
+}
+
+impl TraceContext {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let traceparent = headers.get(TRACEPARENT).filter(|v| is_valid_traceparent(v)).cloned();
+        Self {
+            tracestate: traceparent.as_ref().and_then(|_| headers.get(TRACESTATE).cloned()),
+            traceparent,
+        }
+    }
+
+    /// Adds trace headers to an upstream request: the current span's context when traces
+    /// are exported (so the provider's spans nest under ours), else the client's unchanged.
+    pub fn apply(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let mut headers = imp::current_context_headers();
+        if !headers.contains_key(TRACEPARENT) {
+            headers.extend(
+                [(TRACEPARENT, &self.traceparent), (TRACESTATE, &self.tracestate)]
+                    .into_iter()
+                    .filter_map(|(name, value)| Some((name, value.clone()?))),
+            );
+        }
+        builder.headers(headers)
+    }
+}
+
+/// `version-traceid-parentid-flags`, lowercase hex, with non-zero ids (W3C Trace Context).
+fn is_valid_traceparent(value: &HeaderValue) -> bool {
+    let Ok(value) = value.to_str() else { return false };
+    let parts: Vec<&str> = value.split('-').collect();
+    let hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    let nonzero = |s: &str| s.bytes().any(|b| b != b'0');
+    match parts.as_slice() {
+        [version, trace_id, parent_id, flags, ..] => {
+            hex(version, 2)
+                && *version != "ff"
+                && (*version != "00" || parts.len() == 4)
+                && hex(trace_id, 32)
+                && nonzero(trace_id)
+                && hex(parent_id, 16)
+                && nonzero(parent_id)
+                && hex(flags, 2)
+        }
+        _ => false,
+    }
+}
+
+/// Wraps each request in a server span recording the route, status and (from the handler)
+/// model and token usage.
+pub async fn trace_request(req: Request, next: Next) -> Response {
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), req.uri().path()),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        http.request.method = %req.method(),
+        url.path = %req.uri().path(),
+        http.response.status_code = Empty,
+        client = Empty,
+        stream = Empty,
+        proxy.incoming_model = Empty,
+        gen_ai.request.model = Empty,
+        gen_ai.response.finish_reasons = Empty,
+        gen_ai.usage.input_tokens = Empty,
+        gen_ai.usage.output_tokens = Empty,
+    );
+    imp::set_remote_parent(&span, req.headers());
+    let response = next.run(req).instrument(span.clone()).await;
+    let status = response.status();
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    response
+}
+
+/// A stream polled inside `span`, so its events and usage attach to that span.
+pub struct InSpan<S> {
+    inner: Pin<Box<S>>,
+    span: Span,
+}
+
+impl<S> InSpan<S> {
+    pub fn new(inner: S, span: Span) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            span,
+        }
+    }
+}
+
+impl<S: Stream> Stream for InSpan<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        let _entered = this.span.enter();
+        this.inner.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(feature = "otel")]
+mod imp {
+    use super::{BoxedLayer, OtelSettings};
+    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+    use opentelemetry::propagation::{Extractor, Injector};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::SpanExporter;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::Layer;
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|v| v.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(HeaderName::as_str).collect()
+        }
+    }
+
+    struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+                self.0.insert(name, value);
+            }
+        }
+    }
+
+    /// Makes `span` a child of the client's `traceparent`, if any.
+    pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+        let parent = opentelemetry::global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+        let _ = span.set_parent(parent);
+    }
+
+    /// `traceparent`/`tracestate` for the current span; empty when it is not exported.
+    pub fn current_context_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let context = Span::current().context();
+        opentelemetry::global::get_text_map_propagator(|p| p.inject_context(&context, &mut HeaderInjector(&mut headers)));
+        headers
+    }
+
+    /// Builds the OTLP exporter and the tracing layer feeding it. The provider is also
+    /// installed globally, which keeps its batch processor alive for the process lifetime.
+    pub fn otlp_layer(settings: &OtelSettings) -> anyhow::Result<BoxedLayer> {
+        let exporter = SpanExporter::builder().with_http().build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(settings.service_name.clone())
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        opentelemetry::global::set_tracer_provider(provider);
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        Ok(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod imp {
+    use super::{BoxedLayer, OtelSettings};
+    use axum::http::HeaderMap;
+    use tracing::Span;
+
+    pub fn set_remote_parent(_span: &Span, _headers: &HeaderMap) {}
+
+    pub fn current_context_headers() -> HeaderMap {
+        HeaderMap::new()
+    }
+
+    pub fn otlp_layer(_settings: &OtelSettings) -> anyhow::Result<BoxedLayer> {
+        anyhow::bail!(
+            "OTEL_EXPORTER_OTLP_ENDPOINT is set but this build lacks OpenTelemetry support (rebuild with --features otel)"
+        )
+    }
+}
@@ -0,0 +1,287 @@
+//! Native HTTPS: rustls termination for the listener with certificate hot reload, optional
+//! client certificates (mTLS), or automatic Let's Encrypt certificates (`acme` feature).
+
+use crate::listeners::{SocketAcceptor, SocketSettings};
+use anyhow::Context;
+use axum::Router;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tower_http::add_extension::AddExtension;
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// PEM certificate chain and private key from TLS_CERT / TLS_KEY.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle that client certificates must chain to (TLS_CLIENT_CA); enables mTLS.
+    pub client_ca: Option<PathBuf>,
+    /// Accept connections without a client certificate (TLS_CLIENT_AUTH=optional).
+    pub client_auth_optional: bool,
+}
+
+/// A verified client certificate, attached to every request on its connection.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    /// Full subject DN, e.g. `CN=build-agent,O=Example`.
+    pub subject: String,
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let subject = cert.subject();
+        let common_name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        Some(Self {
+            subject: subject.to_string(),
+            common_name,
+        })
+    }
+
+    /// Common name when present, otherwise the full subject.
+    pub fn name(&self) -> &str {
+        self.common_name.as_deref().unwrap_or(&self.subject)
+    }
+}
+
+/// ACME challenge used to prove control of the domains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    /// Answered on the HTTPS listener itself (must be reachable on port 443).
+    TlsAlpn01,
+    /// Answered by a plain-HTTP listener on `http_port` (must be reachable on port 80).
+    Http01,
+}
+
+/// Let's Encrypt settings from ACME_* environment variables.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+pub struct AcmeSettings {
+    pub domains: Vec<String>,
+    /// Contact e-mail addresses for expiry notices.
+    pub contacts: Vec<String>,
+    /// Where the account key and certificates are cached across restarts.
+    pub cache_dir: PathBuf,
+    /// Use the Let's Encrypt staging directory (untrusted certificates, higher limits).
+    pub staging: bool,
+    pub challenge: AcmeChallenge,
+    pub http_port: u16,
+}
+
+pub use acme::serve as serve_acme;
+
+fn server_config(settings: &TlsSettings) -> anyhow::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(&settings.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificate {}", settings.cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&settings.key)
+        .with_context(|| format!("Failed to read TLS key {}", settings.key.display()))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &settings.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("Failed to read TLS_CLIENT_CA {}", path.display()))?
+            {
+                roots.add(cert?).context("Invalid certificate in TLS_CLIENT_CA")?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if settings.client_auth_optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key do not match")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Loads the certificate, key and client CA as [`serve`] does, for `anthropic-proxy check`.
+pub fn validate(settings: &TlsSettings) -> anyhow::Result<()> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    server_config(settings).map(|_| ())
+}
+
+/// Serves `app` over HTTPS. Certificate, key and client CA are re-read when they change, so
+/// renewals apply to new connections without a restart.
+pub async fn serve(
+    settings: &TlsSettings,
+    addr: SocketAddr,
+    sockets: &SocketSettings,
+    app: Router,
+) -> anyhow::Result<()> {
+    // Use ring (the provider reqwest already links) even if other providers are compiled in.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let config = RustlsConfig::from_config(Arc::new(server_config(settings)?));
+    tokio::spawn(watch(config.clone(), settings.clone()));
+    let acceptor = ClientCertAcceptor {
+        inner: RustlsAcceptor::new(config).acceptor(SocketAcceptor::new(*sockets)),
+    };
+    axum_server::from_tcp(sockets.bind(addr)?)?
+        .acceptor(acceptor)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+/// Completes the TLS handshake and exposes the peer's certificate (if any) to handlers as an
+/// `Option<ClientCertificate>` extension.
+#[derive(Clone)]
+struct ClientCertAcceptor {
+    inner: RustlsAcceptor<SocketAcceptor>,
+}
+
+impl<S> Accept<TcpStream, S> for ClientCertAcceptor
+where
+    S: Send + 'static,
+{
+    type Stream = TlsStream<TcpStream>;
+    type Service = AddExtension<S, Option<ClientCertificate>>;
+    type Future = BoxFuture<'static, std::io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|der| ClientCertificate::from_der(der));
+            Ok((stream, AddExtension::new(service, certificate)))
+        })
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+async fn watch(config: RustlsConfig, settings: TlsSettings) {
+    let stamp = |s: &TlsSettings| {
+        (
+            modified(&s.cert),
+            modified(&s.key),
+            s.client_ca.as_deref().and_then(modified),
+        )
+    };
+    let mut last = stamp(&settings);
+    let mut interval = tokio::time::interval(RELOAD_POLL_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = stamp(&settings);
+        if current == last {
+            continue;
+        }
+        // Keep the old certificate if the new files are incomplete (e.g. mid-renewal); the
+        // next poll retries because `last` is left unchanged.
+        match server_config(&settings) {
+            Ok(server_config) => {
+                config.reload_from_config(Arc::new(server_config));
+                tracing::info!("TLS certificate reloaded from {}", settings.cert.display());
+                last = current;
+            }
+            Err(e) => tracing::warn!("TLS certificate reload failed, keeping previous: {:#}", e),
+        }
+    }
+}
+
+#[cfg(feature = "acme")]
+mod acme {
+    use super::{AcmeChallenge, AcmeSettings};
+    use crate::listeners::{SocketAcceptor, SocketSettings};
+    use axum::Router;
+    use futures::StreamExt;
+    use rustls_acme::{caches::DirCache, AcmeConfig, UseChallenge};
+    use std::net::SocketAddr;
+
+    /// Serves `app` over HTTPS with certificates obtained and renewed automatically.
+    pub async fn serve(
+        settings: &AcmeSettings,
+        addr: SocketAddr,
+        sockets: &SocketSettings,
+        app: Router,
+    ) -> anyhow::Result<()> {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let mut state = AcmeConfig::new(&settings.domains)
+            .contact(settings.contacts.iter().map(|e| format!("mailto:{e}")))
+            .cache(DirCache::new(settings.cache_dir.clone()))
+            .directory_lets_encrypt(!settings.staging)
+            .challenge_type(match settings.challenge {
+                AcmeChallenge::TlsAlpn01 => UseChallenge::TlsAlpn01,
+                AcmeChallenge::Http01 => UseChallenge::Http01,
+            })
+            .state();
+        let acceptor = state.axum_acceptor(state.default_rustls_config());
+        let challenge_service = (settings.challenge == AcmeChallenge::Http01)
+            .then(|| state.http01_challenge_tower_service());
+
+        tokio::spawn(async move {
+            while let Some(event) = state.next().await {
+                match event {
+                    Ok(event) => tracing::info!("ACME: {:?}", event),
+                    Err(e) => tracing::error!("ACME: {:?}", e),
+                }
+            }
+        });
+
+        let https = axum_server::from_tcp(sockets.bind(addr)?)?
+            .acceptor(SocketAcceptor::new(*sockets).wrap(acceptor))
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+        match challenge_service {
+            Some(service) => {
+                let http_addr = SocketAddr::new(addr.ip(), settings.http_port);
+                tracing::info!("ACME HTTP-01 challenges on {}", http_addr);
+                let challenges = Router::new()
+                    .route_service("/.well-known/acme-challenge/:token", service);
+                let http = axum_server::bind(http_addr).serve(challenges.into_make_service());
+                tokio::try_join!(https, http)?;
+            }
+            None => https.await?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "acme"))]
+mod acme {
+    use super::AcmeSettings;
+    use crate::listeners::SocketSettings;
+    use axum::Router;
+    use std::net::SocketAddr;
+
+    pub async fn serve(
+        _settings: &AcmeSettings,
+        _addr: SocketAddr,
+        _sockets: &SocketSettings,
+        _app: Router,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("ACME_DOMAINS is set but this build lacks ACME support (rebuild with --features acme)")
+    }
+}
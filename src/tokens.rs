@@ -0,0 +1,280 @@
+//! Local token estimation for `count_tokens` and preflight checks, with a content-hash cache.
+
+use crate::cache::CacheStats;
+use crate::models::anthropic;
+use rustc_hash::FxHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Default number of cached segment counts when TOKEN_CACHE_SIZE is not set.
+pub const DEFAULT_TOKEN_CACHE_SIZE: usize = 4096;
+
+/// Flat estimate for an image block (Anthropic bills roughly 1.6k tokens for a ~1MP image).
+const IMAGE_TOKENS: u32 = 1600;
+
+/// Per-message framing overhead (role markers, separators).
+const MESSAGE_OVERHEAD: u32 = 4;
+
+/// Token estimator that caches counts per segment (system prompt, tools, each message).
+///
+/// Claude Code resends the same system prompt, tool list and conversation prefix on every
+/// turn, so caching per segment means only newly appended messages are ever re-counted.
+pub struct TokenCounter {
+    capacity: usize,
+    cache: Mutex<SegmentCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// FxHash of what a segment's estimate reads. Hashing the borrowed text is cheaper than the
+/// estimate itself; a collision only costs an estimate.
+type SegmentKey = u64;
+
+#[derive(Default)]
+struct SegmentCache {
+    entries: HashMap<SegmentKey, u32>,
+    order: VecDeque<SegmentKey>,
+}
+
+/// A part of a request that is counted, and cached, on its own.
+trait Segment {
+    /// Hashed ahead of the content: the same text costs a different number of tokens as a
+    /// system prompt, a message or a tool.
+    const KIND: u8;
+    /// Feeds everything [`estimate`](Self::estimate) reads to `hasher`.
+    fn hash_content(&self, hasher: &mut FxHasher);
+    fn estimate(&self) -> u32;
+}
+
+impl TokenCounter {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            cache: Mutex::new(SegmentCache::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Estimates input tokens for a `count_tokens` request body.
+    pub fn count_tokens_request(&self, req: &anthropic::CountTokensRequest) -> u32 {
+        self.count_parts(req.system.as_ref(), &req.messages, req.tools.as_deref())
+    }
+
+    /// Estimates the prompt tokens of a `/v1/messages` request.
+    pub fn count_request(&self, req: &anthropic::AnthropicRequest) -> u32 {
+        self.count_parts(req.system.as_ref(), &req.messages, req.tools.as_deref())
+    }
+
+    /// Estimates the tokens of one message, as counted in a request.
+    pub fn count_message(&self, msg: &anthropic::Message) -> u32 {
+        self.cached(msg)
+    }
+
+    fn count_parts(
+        &self,
+        system: Option<&anthropic::SystemPrompt>,
+        messages: &[anthropic::Message],
+        tools: Option<&[anthropic::Tool]>,
+    ) -> u32 {
+        let mut total = 0u32;
+        if let Some(system) = system {
+            total = total.saturating_add(self.cached(system));
+        }
+        if let Some(tools) = tools {
+            for tool in tools {
+                total = total.saturating_add(self.cached(tool));
+            }
+        }
+        for msg in messages {
+            total = total.saturating_add(self.cached(msg));
+        }
+        total
+    }
+
+    /// Returns the cached count for a segment, computing and storing it on a miss.
+    fn cached<S: Segment>(&self, segment: &S) -> u32 {
+        if self.capacity == 0 {
+            return segment.estimate();
+        }
+        let mut hasher = FxHasher::default();
+        hasher.write_u8(S::KIND);
+        segment.hash_content(&mut hasher);
+        let key = hasher.finish();
+
+        if let Some(&count) = self.lock().entries.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return count;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let count = segment.estimate();
+        let mut cache = self.lock();
+        if cache.entries.insert(key, count).is_none() {
+            cache.order.push_back(key);
+            while cache.order.len() > self.capacity {
+                if let Some(evicted) = cache.order.pop_front() {
+                    cache.entries.remove(&evicted);
+                }
+            }
+        }
+        count
+    }
+
+    /// Drops every cached count; returns how many were removed.
+    pub fn clear(&self) -> usize {
+        let mut cache = self.lock();
+        let n = cache.entries.len();
+        *cache = SegmentCache::default();
+        n
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.lock().entries.len();
+        // Key stored twice plus the count, ignoring map overhead.
+        let memory_bytes = entries * (2 * std::mem::size_of::<SegmentKey>() + 4);
+        CacheStats::new(
+            entries,
+            self.capacity,
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            memory_bytes,
+        )
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SegmentCache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Rough BPE-free estimate: ~4 ASCII bytes per token, one token per non-ASCII char (CJK etc.).
+pub fn estimate_text(text: &str) -> u32 {
+    let mut ascii = 0u32;
+    let mut other = 0u32;
+    for c in text.chars() {
+        if c.is_ascii() {
+            ascii += 1;
+        } else {
+            other += 1;
+        }
+    }
+    ascii.div_ceil(4) + other
+}
+
+impl Segment for anthropic::SystemPrompt {
+    const KIND: u8 = 0;
+
+    fn hash_content(&self, hasher: &mut FxHasher) {
+        match self {
+            anthropic::SystemPrompt::Single(text) => text.hash(hasher),
+            anthropic::SystemPrompt::Multiple(parts) => parts.iter().for_each(|p| p.text.hash(hasher)),
+        }
+    }
+
+    fn estimate(&self) -> u32 {
+        match self {
+            anthropic::SystemPrompt::Single(text) => estimate_text(text),
+            anthropic::SystemPrompt::Multiple(parts) => parts.iter().map(|p| estimate_text(&p.text)).sum(),
+        }
+    }
+}
+
+impl Segment for anthropic::Message {
+    const KIND: u8 = 1;
+
+    fn hash_content(&self, hasher: &mut FxHasher) {
+        match &self.content {
+            anthropic::MessageContent::Text(text) => text.hash(hasher),
+            anthropic::MessageContent::Blocks(blocks) => {
+                for block in blocks {
+                    std::mem::discriminant(block).hash(hasher);
+                    match block {
+                        anthropic::ContentBlock::Text { text, .. } => text.hash(hasher),
+                        anthropic::ContentBlock::Image { .. } => {}
+                        anthropic::ContentBlock::ToolUse { name, input, .. } => {
+                            name.hash(hasher);
+                            input.get().hash(hasher);
+                        }
+                        anthropic::ContentBlock::ToolResult { content, .. } => content.hash(hasher),
+                        anthropic::ContentBlock::Thinking { thinking } => thinking.hash(hasher),
+                    }
+                }
+            }
+        }
+    }
+
+    fn estimate(&self) -> u32 {
+        let content = match &self.content {
+            anthropic::MessageContent::Text(text) => estimate_text(text),
+            anthropic::MessageContent::Blocks(blocks) => blocks.iter().map(estimate_block).sum(),
+        };
+        content + MESSAGE_OVERHEAD
+    }
+}
+
+impl Segment for anthropic::Tool {
+    const KIND: u8 = 2;
+
+    fn hash_content(&self, hasher: &mut FxHasher) {
+        self.name.hash(hasher);
+        self.description.hash(hasher);
+        self.input_schema.hash(hasher);
+    }
+
+    fn estimate(&self) -> u32 {
+        estimate_text(&self.name)
+            + self.description.as_deref().map(estimate_text).unwrap_or(0)
+            + estimate_text(&self.input_schema.to_string())
+    }
+}
+
+fn estimate_block(block: &anthropic::ContentBlock) -> u32 {
+    match block {
+        anthropic::ContentBlock::Text { text, .. } => estimate_text(text),
+        anthropic::ContentBlock::Image { .. } => IMAGE_TOKENS,
+        anthropic::ContentBlock::ToolUse { name, input, .. } => {
+            estimate_text(name) + estimate_text(input.get())
+        }
+        anthropic::ContentBlock::ToolResult { content, .. } => estimate_text(content),
+        anthropic::ContentBlock::Thinking { thinking } => estimate_text(thinking),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_text(text: &str) -> anthropic::Message {
+        anthropic::Message {
+            role: "user".to_string(),
+            content: anthropic::MessageContent::Text(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn segment_kinds_are_cached_apart() {
+        let counter = TokenCounter::new(16);
+        let text = "The same words, counted twice.";
+        let system = counter.count_parts(Some(&anthropic::SystemPrompt::Single(text.to_string())), &[], None);
+        let message = counter.count_message(&user_text(text));
+        assert_eq!(message, system + MESSAGE_OVERHEAD);
+        // And the other way round.
+        let counter = TokenCounter::new(16);
+        let message = counter.count_message(&user_text(text));
+        let system = counter.count_parts(Some(&anthropic::SystemPrompt::Single(text.to_string())), &[], None);
+        assert_eq!(message, system + MESSAGE_OVERHEAD);
+        assert_eq!(counter.stats().entries, 2);
+    }
+
+    #[test]
+    fn repeated_segments_hit_the_cache() {
+        let counter = TokenCounter::new(16);
+        let first = counter.count_message(&user_text("hello there"));
+        let second = counter.count_message(&user_text("hello there"));
+        assert_eq!(first, second);
+        let stats = counter.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+}
@@ -1,28 +1,35 @@
 //! Request/response translation between Anthropic Messages API and OpenAI chat completions.
+//!
+//! The conversions keep the request's model name and parameters as they are; model routing and
+//! per-model parameter rules are the caller's business (the proxy applies its config on top).
 
-use crate::config::Config;
-use crate::error::{ProxyError, ProxyResult};
 use crate::models::{anthropic, openai};
-use serde_json::{json, Value};
+use serde_json::value::RawValue;
+use serde_json::Value;
 
-/// Picks the model name: reasoning vs completion from config or request.
-fn select_model(config: &Config, req: &anthropic::AnthropicRequest, has_thinking: bool) -> String {
-    let fallback = || req.model.clone();
-    if has_thinking {
-        config
-            .reasoning_model
-            .clone()
-            .unwrap_or_else(fallback)
-    } else {
-        config
-            .completion_model
-            .clone()
-            .unwrap_or_else(fallback)
-    }
+/// A response that cannot be translated.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("No choices in response")]
+    NoChoices,
 }
 
+/// Told about content a conversion had to change or leave out, e.g. to count it. Both
+/// methods do nothing by default; `()` observes nothing.
+pub trait Observer {
+    /// Content the other API has no place for: `thinking_block` and `batch_tool` in requests,
+    /// `tool_arguments` (not a JSON object, replaced by `{}`) in responses.
+    fn dropped(&mut self, _item: &'static str) {}
+
+    /// A tool schema field removed because OpenAI-compatible backends reject it
+    /// (`format_uri`).
+    fn schema_rewritten(&mut self, _rule: &'static str) {}
+}
+
+impl Observer for () {}
+
 /// Returns true if the request has extended thinking enabled (e.g. thinking.type == "enabled").
-fn has_thinking_enabled(extra: &Value) -> bool {
+pub fn has_thinking_enabled(extra: &Value) -> bool {
     extra
         .get("thinking")
         .and_then(|v| v.as_object())
@@ -30,15 +37,10 @@ fn has_thinking_enabled(extra: &Value) -> bool {
         .unwrap_or(false)
 }
 
-/// Converts an Anthropic request into an OpenAI chat completions request.
-pub fn anthropic_to_openai(
-    req: anthropic::AnthropicRequest,
-    config: &Config,
-) -> ProxyResult<openai::OpenAIRequest> {
-    let has_thinking = has_thinking_enabled(&req.extra);
-    let model = select_model(config, &req, has_thinking);
-
-    let mut openai_messages = Vec::new();
+/// Converts an Anthropic request into an OpenAI chat completions request. The request is
+/// consumed: message text, tool inputs and images are moved into the result, not copied.
+pub fn anthropic_to_openai(req: anthropic::AnthropicRequest, observer: &mut impl Observer) -> openai::OpenAIRequest {
+    let mut openai_messages = Vec::with_capacity(req.messages.len() + 1);
 
     if let Some(system) = req.system {
         match system {
@@ -59,39 +61,32 @@ pub fn anthropic_to_openai(
     }
 
     for msg in req.messages {
-        openai_messages.extend(convert_message(msg)?);
+        convert_message(msg, &mut openai_messages, observer);
     }
 
     let tools = req.tools.and_then(|tools| {
-        let filtered: Vec<_> = tools
-            .into_iter()
-            .filter(|t| t.tool_type.as_deref() != Some("BatchTool"))
-            .collect();
-        if filtered.is_empty() {
-            None
-        } else {
-            Some(
-                filtered
-                    .into_iter()
-                    .map(|t| {
-                        let mut parameters = t.input_schema;
-                        clean_schema(&mut parameters);
-                        openai::Tool {
-                            tool_type: "function".to_string(),
-                            function: openai::Function {
-                                name: t.name,
-                                description: t.description,
-                                parameters,
-                            },
-                        }
-                    })
-                    .collect(),
-            )
+        let mut converted = Vec::with_capacity(tools.len());
+        for t in tools {
+            if t.tool_type.as_deref() == Some("BatchTool") {
+                observer.dropped("batch_tool");
+                continue;
+            }
+            let mut parameters = t.input_schema;
+            clean_schema(&mut parameters, observer);
+            converted.push(openai::Tool {
+                tool_type: "function".to_string(),
+                function: openai::Function {
+                    name: t.name,
+                    description: t.description,
+                    parameters,
+                },
+            });
         }
+        Some(converted).filter(|tools| !tools.is_empty())
     });
 
-    Ok(openai::OpenAIRequest {
-        model,
+    openai::OpenAIRequest {
+        model: req.model,
         messages: openai_messages,
         max_tokens: Some(req.max_tokens),
         temperature: req.temperature,
@@ -100,7 +95,8 @@ pub fn anthropic_to_openai(
         stream: req.stream,
         tools,
         tool_choice: None,
-    })
+        stream_options: None,
+    }
 }
 
 fn openai_message(
@@ -118,14 +114,12 @@ fn openai_message(
     }
 }
 
-/// Converts one Anthropic message into one or more OpenAI messages.
-fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>> {
-    let mut result = Vec::new();
-
+/// Converts one Anthropic message into one or more OpenAI messages, appended to `result`.
+fn convert_message(msg: anthropic::Message, result: &mut Vec<openai::Message>, observer: &mut impl Observer) {
     match msg.content {
         anthropic::MessageContent::Text(text) => {
             result.push(openai_message(
-                &msg.role,
+                msg.role,
                 Some(openai::MessageContent::Text(text)),
                 None,
                 None,
@@ -141,17 +135,26 @@ fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>>
                         current_content_parts.push(openai::ContentPart::Text { text });
                     }
                     anthropic::ContentBlock::Image { source } => {
-                        let data_url = format!("data:{};base64,{}", source.media_type, source.data);
+                        // Images are the largest blocks: size the URL once instead of growing it.
+                        let mut data_url = String::with_capacity(
+                            "data:;base64,".len() + source.media_type.len() + source.data.len(),
+                        );
+                        data_url.push_str("data:");
+                        data_url.push_str(&source.media_type);
+                        data_url.push_str(";base64,");
+                        data_url.push_str(&source.data);
                         current_content_parts.push(openai::ContentPart::ImageUrl {
                             image_url: openai::ImageUrl { url: data_url },
                         });
                     }
                     anthropic::ContentBlock::ToolUse { id, name, input } => {
-                        let args = serde_json::to_string(&input).map_err(ProxyError::from)?;
                         tool_calls.push(openai::ToolCall {
                             id,
                             call_type: "function".to_string(),
-                            function: openai::FunctionCall { name, arguments: args },
+                            function: openai::FunctionCall {
+                                name,
+                                arguments: Box::<str>::from(input).into_string(),
+                            },
                         });
                     }
                     anthropic::ContentBlock::ToolResult {
@@ -166,86 +169,97 @@ fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>>
                             Some(tool_use_id),
                         ));
                     }
-                    anthropic::ContentBlock::Thinking { .. } => {}
+                    anthropic::ContentBlock::Thinking { .. } => observer.dropped("thinking_block"),
                 }
             }
 
             if !current_content_parts.is_empty() || !tool_calls.is_empty() {
-                let content = if current_content_parts.is_empty() {
-                    None
-                } else if current_content_parts.len() == 1 {
-                    match &current_content_parts[0] {
-                        openai::ContentPart::Text { text } => {
-                            Some(openai::MessageContent::Text(text.clone()))
-                        }
-                        _ => Some(openai::MessageContent::Parts(current_content_parts)),
-                    }
-                } else {
-                    Some(openai::MessageContent::Parts(current_content_parts))
+                // A lone text part is sent as plain string content.
+                let content = match <[_; 1]>::try_from(current_content_parts) {
+                    Ok([openai::ContentPart::Text { text }]) => Some(openai::MessageContent::Text(text)),
+                    Ok(part) => Some(openai::MessageContent::Parts(part.into())),
+                    Err(parts) if parts.is_empty() => None,
+                    Err(parts) => Some(openai::MessageContent::Parts(parts)),
                 };
 
-                result.push(openai_message(&msg.role, content, Some(tool_calls).filter(|t| !t.is_empty()), None));
+                result.push(openai_message(msg.role, content, Some(tool_calls).filter(|t| !t.is_empty()), None));
             }
         }
     }
-
-    Ok(result)
 }
 
 /// Removes JSON schema fields that some OpenAI-compatible backends reject (e.g. "format": "uri").
-fn clean_schema(schema: &mut Value) {
+fn clean_schema(schema: &mut Value, observer: &mut impl Observer) {
     if let Some(obj) = schema.as_object_mut() {
         if obj.get("format").and_then(|v| v.as_str()) == Some("uri") {
             obj.remove("format");
+            observer.schema_rewritten("format_uri");
         }
         if let Some(properties) = obj.get_mut("properties").and_then(|v| v.as_object_mut()) {
             for (_, value) in properties.iter_mut() {
-                clean_schema(value);
+                clean_schema(value, observer);
             }
         }
         if let Some(items) = obj.get_mut("items") {
-            clean_schema(items);
+            clean_schema(items, observer);
         }
     }
 }
 
+/// A `toolu_`-style id for a tool call the upstream sent without one, derived from the
+/// response's id and the call's position so every fragment of the call gets the same id.
+pub fn tool_use_id(message_id: &str, index: usize) -> String {
+    // FNV-1a: unlike `DefaultHasher`, the same on every Rust release, so recorded and cached
+    // responses keep their ids.
+    let hash = message_id
+        .bytes()
+        .chain((index as u64).to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash: u64, b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+    format!("toolu_{hash:016x}{index:02x}")
+}
+
+/// Whether `input` parses as a JSON object, as tool inputs must be.
+pub(crate) fn is_json_object(input: &str) -> bool {
+    serde_json::from_str::<serde_json::Map<String, Value>>(input).is_ok()
+}
+
 /// Converts an OpenAI chat completions response into Anthropic message format.
 pub fn openai_to_anthropic(
     resp: openai::OpenAIResponse,
-) -> ProxyResult<anthropic::AnthropicResponse> {
-    let choice = resp
-        .choices
-        .first()
-        .ok_or_else(|| ProxyError::Transform("No choices in response".to_string()))?;
+    observer: &mut impl Observer,
+) -> Result<anthropic::AnthropicResponse, Error> {
+    let choice = resp.choices.into_iter().next().ok_or(Error::NoChoices)?;
+    let tool_calls = choice.message.tool_calls.unwrap_or_default();
 
-    let mut content = Vec::new();
+    let mut content = Vec::with_capacity(1 + tool_calls.len());
 
-    if let Some(text) = &choice.message.content {
-        if !text.is_empty() {
-            content.push(anthropic::ResponseContent::Text {
-                content_type: "text".to_string(),
-                text: text.clone(),
-            });
-        }
+    if let Some(text) = choice.message.content.filter(|text| !text.is_empty()) {
+        content.push(anthropic::ResponseContent::Text {
+            content_type: "text".to_string(),
+            text,
+        });
     }
 
-    if let Some(tool_calls) = &choice.message.tool_calls {
-        for tool_call in tool_calls {
-            let input: Value = serde_json::from_str(&tool_call.function.arguments)
-                .unwrap_or_else(|_| json!({}));
-            content.push(anthropic::ResponseContent::ToolUse {
-                content_type: "tool_use".to_string(),
-                id: tool_call.id.clone(),
-                name: tool_call.function.name.clone(),
-                input,
-            });
-        }
+    for (index, tool_call) in tool_calls.into_iter().enumerate() {
+        let openai::FunctionCall { name, arguments } = tool_call.function;
+        let input = if is_json_object(&arguments) {
+            RawValue::from_string(arguments).expect("checked JSON object")
+        } else {
+            tracing::warn!("Tool call '{}' has arguments that are not a JSON object", name);
+            observer.dropped("tool_arguments");
+            RawValue::from_string("{}".to_string()).expect("valid empty object")
+        };
+        content.push(anthropic::ResponseContent::ToolUse {
+            content_type: "tool_use".to_string(),
+            id: Some(tool_call.id)
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(|| tool_use_id(&resp.id, index)),
+            name,
+            input,
+        });
     }
 
-    let stop_reason = choice
-        .finish_reason
-        .as_ref()
-        .and_then(|r| map_stop_reason(Some(r)));
+    let stop_reason = map_stop_reason(choice.finish_reason.as_deref());
 
     Ok(anthropic::AnthropicResponse {
         id: resp.id,
@@ -271,3 +285,50 @@ pub fn map_stop_reason(finish_reason: Option<&str>) -> Option<String> {
         _ => "end_turn",
     }.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthesized_tool_use_ids_are_fixed() {
+        // A changed id breaks replays recorded with the old one.
+        assert_eq!(tool_use_id("chatcmpl-1", 0), "toolu_db2a069ef7b8cad100");
+        assert_ne!(tool_use_id("chatcmpl-1", 1), tool_use_id("chatcmpl-1", 0));
+    }
+
+    #[test]
+    fn tool_arguments_must_be_objects() {
+        let resp: openai::OpenAIResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "m",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {"id": "a", "type": "function", "function": {"name": "f", "arguments": "{\"x\":1}"}},
+                        {"id": "b", "type": "function", "function": {"name": "f", "arguments": "[1]"}},
+                        {"id": "c", "type": "function", "function": {"name": "f", "arguments": "\"x\""}}
+                    ]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+        }))
+        .unwrap();
+        let inputs: Vec<_> = openai_to_anthropic(resp, &mut ())
+            .unwrap()
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                anthropic::ResponseContent::ToolUse { input, .. } => Some(input.get().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inputs, [r#"{"x":1}"#, "{}", "{}"]);
+    }
+}
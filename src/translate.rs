@@ -0,0 +1,140 @@
+//! The proxy's side of translation: the library's conversions (`anthropic_proxy::transform`)
+//! plus model routing, `[model_params]` and the translation metrics.
+
+use crate::config::Config;
+use crate::configfile::{ModelParams, Param};
+use crate::error::ProxyResult;
+use crate::metrics;
+use crate::models::{anthropic, openai};
+use crate::stops;
+use crate::transform::{self, Observer};
+
+/// Bucket bounds for `proxy_response_blocks`.
+const BLOCK_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 5.0, 8.0, 13.0];
+
+/// Counts what the conversions drop or rewrite.
+struct Metrics;
+
+impl Observer for Metrics {
+    fn dropped(&mut self, item: &'static str) {
+        metrics::increment("proxy_transform_dropped_total", &[("item", item)], 1);
+    }
+
+    fn schema_rewritten(&mut self, rule: &'static str) {
+        metrics::increment("proxy_schema_rewrites_total", &[("rule", rule)], 1);
+    }
+}
+
+/// Records how many content blocks and tool calls a translated response carried.
+pub fn record_response_shape(blocks: usize, tool_calls: usize, streaming: bool) {
+    let mode = if streaming { "stream" } else { "complete" };
+    metrics::observe("proxy_response_blocks", &[("mode", mode)], BLOCK_BUCKETS, blocks as f64);
+    if tool_calls > 0 {
+        metrics::increment("proxy_tool_calls_total", &[("mode", mode)], tool_calls as u64);
+    }
+}
+
+/// Picks the model name: a config file route, else reasoning vs completion from config, else
+/// the preset's mapping, else the request's (moved, not copied).
+fn select_model(config: &Config, requested: String, has_thinking: bool) -> String {
+    if let Some(model) = config.route(&requested).and_then(|r| r.model.clone()) {
+        return model;
+    }
+    let configured = if has_thinking {
+        &config.reasoning_model
+    } else {
+        &config.completion_model
+    };
+    configured
+        .clone()
+        .or_else(|| {
+            config
+                .preset
+                .and_then(|preset| preset.model(&requested, has_thinking))
+                .map(str::to_string)
+        })
+        .unwrap_or(requested)
+}
+
+/// The upstream model `req` is routed to.
+pub fn upstream_model(config: &Config, req: &anthropic::AnthropicRequest) -> String {
+    select_model(config, req.model.clone(), transform::has_thinking_enabled(&req.extra))
+}
+
+/// Claude model family of an incoming model name ("haiku", "sonnet", "opus"), if recognizable.
+pub fn model_tier(model: &str) -> Option<&'static str> {
+    let lower = model.to_ascii_lowercase();
+    ["haiku", "sonnet", "opus"]
+        .into_iter()
+        .find(|tier| lower.contains(tier))
+}
+
+/// Converts an Anthropic request into an OpenAI chat completions request for the routed
+/// upstream model, with that model's `[model_params]` applied. Also returns the stop sequences
+/// the upstream is not sent, for the proxy to enforce.
+pub fn anthropic_to_openai(
+    req: anthropic::AnthropicRequest,
+    config: &Config,
+) -> ProxyResult<(openai::OpenAIRequest, Vec<String>)> {
+    let has_thinking = transform::has_thinking_enabled(&req.extra);
+    let mut openai_req = transform::anthropic_to_openai(req, &mut Metrics);
+    openai_req.model = select_model(config, std::mem::take(&mut openai_req.model), has_thinking);
+    clamp_max_tokens(config, &mut openai_req);
+    let params = config.model_params(&openai_req.model);
+    // Split before `strip` could drop the client's sequences unenforced.
+    let enforced = if params.is_some_and(|p| p.strip.contains(&Param::Stop)) {
+        stops::take_all(&mut openai_req.stop)
+    } else {
+        stops::split(&mut openai_req.stop, config.max_stop_sequences(&openai_req.model))
+    };
+    if let Some(params) = params {
+        apply_model_params(params, &mut openai_req);
+    }
+    Ok((openai_req, enforced))
+}
+
+/// Lowers `max_tokens` to the upstream model's output limit, which clients such as Claude Code
+/// routinely exceed and many upstreams reject outright.
+fn clamp_max_tokens(config: &Config, req: &mut openai::OpenAIRequest) {
+    if let (Some(limit), Some(requested)) = (config.capabilities(&req.model).max_output_tokens, req.max_tokens) {
+        if requested > limit {
+            tracing::info!("max_tokens {} clamped to {} for {}", requested, limit, req.model);
+            metrics::increment("proxy_max_tokens_clamped_total", &[("model", &req.model)], 1);
+            req.max_tokens = Some(limit);
+        }
+    }
+}
+
+/// Applies the config file's `[model_params]` for the upstream model: defaults, forced
+/// `stream_options`, then stripped parameters.
+fn apply_model_params(params: &ModelParams, req: &mut openai::OpenAIRequest) {
+    if req.temperature.is_none() {
+        req.temperature = params.temperature;
+    }
+    if req.stream == Some(true) && params.stream_options.is_some() {
+        req.stream_options = params.stream_options.clone();
+    }
+    for param in &params.strip {
+        match param {
+            Param::MaxTokens => req.max_tokens = None,
+            Param::Temperature => req.temperature = None,
+            Param::TopP => req.top_p = None,
+            Param::Stop => req.stop = None,
+            Param::Tools => req.tools = None,
+            Param::ToolChoice => req.tool_choice = None,
+            Param::StreamOptions => req.stream_options = None,
+        }
+    }
+}
+
+/// Converts an OpenAI chat completions response into Anthropic message format.
+pub fn openai_to_anthropic(resp: openai::OpenAIResponse) -> ProxyResult<anthropic::AnthropicResponse> {
+    let resp = transform::openai_to_anthropic(resp, &mut Metrics)?;
+    let tool_calls = resp
+        .content
+        .iter()
+        .filter(|c| matches!(c, anthropic::ResponseContent::ToolUse { .. }))
+        .count();
+    record_response_shape(resp.content.len(), tool_calls, false);
+    Ok(resp)
+}
@@ -0,0 +1,288 @@
+//! HTTP client construction for upstream connections: timeouts, HTTP version, connection pool
+//! tuning, private CAs, client certificates and egress proxies.
+
+use crate::dns::{CachingResolver, HostOverrides};
+use crate::latency::ConnectTimer;
+use crate::metrics;
+use anyhow::Context;
+use reqwest::{Certificate, Client, ClientBuilder, Identity, Proxy, Url, Version};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_POOL_MAX_IDLE: usize = 10;
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Proxy value that disables proxying, including HTTPS_PROXY / ALL_PROXY from the environment.
+const NO_PROXY: &str = "none";
+
+/// HTTP version spoken to an upstream (UPSTREAM_HTTP_VERSION).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    /// HTTP/2 when TLS negotiates it (ALPN), else HTTP/1.1.
+    #[default]
+    Auto,
+    Http1,
+    /// HTTP/2 only, with prior knowledge on plaintext connections (h2c), so many requests
+    /// share one connection.
+    Http2,
+    /// HTTP/3 over QUIC once the upstream advertises it, else as `Auto` (`http3` feature).
+    Http3,
+}
+
+impl HttpVersion {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "http1" | "http1.1" => Ok(Self::Http1),
+            "http2" | "h2" | "h2c" => Ok(Self::Http2),
+            "http3" | "h3" => Ok(Self::Http3),
+            other => anyhow::bail!("expected auto, http1, http2 or http3, got '{other}'"),
+        }
+    }
+}
+
+/// Connection reuse and socket options: UPSTREAM_POOL_MAX_IDLE, UPSTREAM_POOL_IDLE_TIMEOUT_SECS,
+/// UPSTREAM_TCP_NODELAY, UPSTREAM_TCP_KEEPALIVE_*, UPSTREAM_HTTP2_KEEPALIVE_SECS and
+/// UPSTREAM_DNS_CACHE_TTL_SECS. Shared by every upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSettings {
+    /// Idle connections kept per upstream host; with one upstream, effectively the pool size.
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept; `None` keeps it until the upstream closes it.
+    pub idle_timeout: Option<Duration>,
+    /// Disables Nagle's algorithm on upstream connections.
+    pub tcp_nodelay: bool,
+    /// Idle time before TCP keepalive probes start, so middleboxes don't drop idle pooled
+    /// connections.
+    pub tcp_keepalive: Option<Duration>,
+    /// Time between unanswered keepalive probes.
+    pub tcp_keepalive_interval: Option<Duration>,
+    /// Unanswered keepalive probes before the connection is dropped.
+    pub tcp_keepalive_retries: Option<u32>,
+    /// HTTP/2 PING interval, sent on idle connections too.
+    pub http2_keepalive: Option<Duration>,
+    /// How long resolved upstream addresses are reused; `None` asks the system resolver for
+    /// every new connection.
+    pub dns_cache_ttl: Option<Duration>,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: DEFAULT_POOL_MAX_IDLE,
+            idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            tcp_keepalive_interval: None,
+            tcp_keepalive_retries: None,
+            http2_keepalive: None,
+            dns_cache_ttl: None,
+        }
+    }
+}
+
+/// TLS, proxy and pool settings for connections to one upstream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transport {
+    /// PEM bundle of extra root CAs, trusted alongside the built-in roots.
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM client certificate chain presented to the upstream (mTLS).
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key for `client_cert`.
+    pub client_key: Option<PathBuf>,
+    /// Egress proxy URL (`http://`, `https://`, `socks5://` or `socks5h://`, credentials in the
+    /// userinfo) or `none`. Unset falls back to HTTPS_PROXY / ALL_PROXY / NO_PROXY.
+    pub proxy: Option<String>,
+    /// Unset means [`HttpVersion::Auto`].
+    pub http_version: Option<HttpVersion>,
+    /// Host names pinned to addresses, bypassing DNS.
+    pub resolve: HostOverrides,
+    /// Always the default upstream's: there is one set of pool settings.
+    pub pool: PoolSettings,
+}
+
+impl Transport {
+    #[inline]
+    pub fn is_default(&self) -> bool {
+        self == &Transport::default()
+    }
+
+    /// Fills unset fields from `fallback`. The client certificate and key are taken as a pair;
+    /// host overrides are merged, this transport's winning for a host listed in both; the pool
+    /// settings always come from `fallback`.
+    pub fn or(self, fallback: &Transport) -> Transport {
+        let (client_cert, client_key) = if self.client_cert.is_some() || self.client_key.is_some() {
+            (self.client_cert, self.client_key)
+        } else {
+            (fallback.client_cert.clone(), fallback.client_key.clone())
+        };
+        let mut resolve = fallback.resolve.clone();
+        resolve.extend(self.resolve);
+        Transport {
+            ca_bundle: self.ca_bundle.or_else(|| fallback.ca_bundle.clone()),
+            client_cert,
+            client_key,
+            proxy: self.proxy.or_else(|| fallback.proxy.clone()),
+            http_version: self.http_version.or(fallback.http_version),
+            resolve,
+            pool: fallback.pool,
+        }
+    }
+
+    /// The explicit proxy with any password masked, for logging.
+    pub fn proxy_display(&self) -> Option<String> {
+        let proxy = self.proxy.as_deref()?;
+        match Url::parse(proxy) {
+            Ok(mut url) if url.password().is_some() => {
+                let _ = url.set_password(Some("***"));
+                Some(url.to_string())
+            }
+            _ => Some(proxy.to_string()),
+        }
+    }
+
+    /// Builds a client with the proxy's standard timeouts and these TLS and pool settings.
+    pub fn client(&self) -> anyhow::Result<Client> {
+        let builder = self.builder()?;
+        let builder = match self.http_version.unwrap_or_default() {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+            // TCP until an origin answers over QUIC; `http3::send` then uses `quic_client`.
+            #[cfg(feature = "http3")]
+            HttpVersion::Http3 => {
+                anyhow::ensure!(
+                    self.proxy.as_deref().is_none_or(|p| p.trim().eq_ignore_ascii_case(NO_PROXY)),
+                    "HTTP/3 upstreams can't go through an upstream proxy"
+                );
+                builder
+            }
+            #[cfg(not(feature = "http3"))]
+            HttpVersion::Http3 => {
+                anyhow::bail!("HTTP/3 upstreams need a build with HTTP/3 support (rebuild with --features http3)")
+            }
+        };
+        builder.build().context("Failed to build HTTP client")
+    }
+
+    /// A client speaking only HTTP/3 (QUIC offers just the `h3` ALPN), with the same TLS
+    /// settings as `client`.
+    #[cfg(feature = "http3")]
+    pub fn quic_client(&self) -> anyhow::Result<Client> {
+        self.builder()?
+            .http3_prior_knowledge()
+            .build()
+            .context("Failed to build HTTP/3 client")
+    }
+
+    fn builder(&self) -> anyhow::Result<ClientBuilder> {
+        let mut builder = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .pool_max_idle_per_host(self.pool.max_idle_per_host)
+            .pool_idle_timeout(self.pool.idle_timeout)
+            .tcp_nodelay(self.pool.tcp_nodelay)
+            .tcp_keepalive(self.pool.tcp_keepalive)
+            .tcp_keepalive_interval(self.pool.tcp_keepalive_interval)
+            .tcp_keepalive_retries(self.pool.tcp_keepalive_retries)
+            .connector_layer(ConnectTimer);
+        if let Some(ttl) = self.pool.dns_cache_ttl {
+            builder = builder.dns_resolver(Arc::new(CachingResolver { ttl }));
+        }
+        for (host, ips) in &self.resolve {
+            // Port 0: connect on the URL's own port.
+            let addrs: Vec<SocketAddr> = ips.iter().map(|&ip| SocketAddr::new(ip, 0)).collect();
+            builder = builder.resolve_to_addrs(host, &addrs);
+        }
+        if let Some(interval) = self.pool.http2_keepalive {
+            builder = builder
+                .http2_keep_alive_interval(interval)
+                .http2_keep_alive_while_idle(true);
+        }
+
+        if let Some(path) = &self.ca_bundle {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+            for cert in Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA bundle {}", path.display()))?
+            {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let mut pem = std::fs::read(cert)
+                    .with_context(|| format!("Failed to read client certificate {}", cert.display()))?;
+                pem.push(b'\n');
+                pem.extend(
+                    std::fs::read(key)
+                        .with_context(|| format!("Failed to read client key {}", key.display()))?,
+                );
+                let identity = Identity::from_pem(&pem).with_context(|| {
+                    format!("Invalid client certificate/key {} / {}", cert.display(), key.display())
+                })?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("An upstream client certificate and key must be set together"),
+        }
+
+        match self.proxy.as_deref().map(str::trim) {
+            Some(proxy) if proxy.eq_ignore_ascii_case(NO_PROXY) => builder = builder.no_proxy(),
+            Some(proxy) => {
+                let url = Url::parse(proxy).context("Invalid upstream proxy URL")?;
+                anyhow::ensure!(
+                    matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h"),
+                    "Unsupported upstream proxy scheme '{}' (expected http, https, socks5 or socks5h)",
+                    url.scheme()
+                );
+                builder = builder.proxy(Proxy::all(url).context("Invalid upstream proxy URL")?);
+            }
+            None => {}
+        }
+        Ok(builder)
+    }
+}
+
+/// Upstream exchanges in flight for HTTP/1.1, HTTP/2, HTTP/3 and other versions. Held while the
+/// gauge is set, so concurrent updates cannot leave a stale value behind.
+static STREAMS: Mutex<[u64; 4]> = Mutex::new([0; 4]);
+
+/// Counts one upstream exchange, from its response headers until dropped, in the
+/// `proxy_upstream_streams` gauge for the response's HTTP version. Over HTTP/2 the client
+/// multiplexes onto one connection per upstream host, so the `HTTP/2` value is the number of
+/// streams sharing it; `proxy_connect_seconds_count` counts the connections opened.
+pub struct StreamGuard(usize);
+
+impl StreamGuard {
+    pub fn new(version: Version) -> Self {
+        let slot = match version {
+            Version::HTTP_11 => 0,
+            Version::HTTP_2 => 1,
+            Version::HTTP_3 => 2,
+            _ => 3,
+        };
+        Self::record(slot, true);
+        Self(slot)
+    }
+
+    fn record(slot: usize, opened: bool) {
+        let mut streams = STREAMS.lock().unwrap_or_else(|e| e.into_inner());
+        streams[slot] = if opened { streams[slot] + 1 } else { streams[slot].saturating_sub(1) };
+        let version = ["HTTP/1.1", "HTTP/2", "HTTP/3", "other"][slot];
+        metrics::set_gauge("proxy_upstream_streams", &[("version", version)], streams[slot] as f64);
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        Self::record(self.0, false);
+    }
+}
@@ -0,0 +1,162 @@
+//! History truncation for prompts that would not fit the routed model's context window. With
+//! `[model_params."<pattern>"] truncate` set, whole turns (a user message and everything up to
+//! the next one, tool calls and results included) are dropped until the prompt fits, instead of
+//! the request being rejected. The system prompt, tools and latest turn are always kept.
+//!
+//! - `oldest`: drops turns from the start of the conversation.
+//! - `middle_out`: keeps the first turn and drops turns from the middle outwards, as OpenRouter's
+//!   middle-out transform does.
+//! - `keep_recent`: keeps only the turns within the last `keep_recent` messages.
+//!
+//! With `summarize = true` the dropped turns are replaced by a summary (see [`summarize`]). A
+//! truncated request's response carries `x-proxy-truncated: messages=12; tokens=53021`, with
+//! `; summarized` when a summary took their place.
+
+use crate::config::Config;
+use crate::configfile::Truncation;
+use crate::metrics;
+use crate::models::anthropic::{AnthropicRequest, ContentBlock, Message, MessageContent};
+use crate::summarize;
+use crate::tokens::TokenCounter;
+use crate::translate;
+use axum::http::HeaderValue;
+
+pub const TRUNCATED_HEADER: &str = "x-proxy-truncated";
+/// Messages `keep_recent` keeps when `[model_params]` doesn't say.
+pub const DEFAULT_KEEP_RECENT: usize = 10;
+
+/// What a truncation removed from a request.
+#[derive(Debug, Clone, Copy)]
+pub struct Truncated {
+    pub messages: usize,
+    pub tokens: u32,
+    /// Whether a summary replaced the dropped messages.
+    pub summarized: bool,
+}
+
+impl Truncated {
+    pub fn header_value(&self) -> HeaderValue {
+        let summarized = if self.summarized { "; summarized" } else { "" };
+        HeaderValue::from_str(&format!("messages={}; tokens={}{summarized}", self.messages, self.tokens))
+            .expect("digits and separators are valid header characters")
+    }
+}
+
+/// Drops turns from `req`, estimated at `tokens`, until it fits the context window of the model
+/// it is routed to, if it doesn't and that model's `[model_params]` choose a strategy.
+pub async fn fit(
+    config: &Config,
+    counter: &TokenCounter,
+    req: &mut AnthropicRequest,
+    tokens: u32,
+) -> Option<Truncated> {
+    let model = translate::upstream_model(config, req);
+    let window = config.capabilities(&model).context_window.filter(|&window| tokens > window)?;
+    let params = config
+        .model_params
+        .iter()
+        .chain(&config.preset_params)
+        .filter(|p| p.matches(&model))
+        .find(|p| p.truncate.is_some() || p.summarize)?;
+    let strategy = params.truncate.unwrap_or(Truncation::Oldest);
+    let keep_recent = params.keep_recent.unwrap_or(DEFAULT_KEEP_RECENT);
+    let summary = config.summary.as_ref().filter(|_| params.summarize);
+    // Room for the summary that will take the dropped turns' place.
+    let window = window.saturating_sub(summary.map_or(0, |s| s.max_tokens));
+
+    let starts: Vec<usize> = (0..req.messages.len())
+        .filter(|&i| i == 0 || starts_turn(&req.messages[i]))
+        .collect();
+    if starts.len() < 2 {
+        return None;
+    }
+    let ends: Vec<usize> = starts.iter().skip(1).copied().chain([req.messages.len()]).collect();
+    let turn_tokens: Vec<u32> = starts
+        .iter()
+        .zip(&ends)
+        .map(|(&start, &end)| req.messages[start..end].iter().map(|m| counter.count_message(m)).sum())
+        .collect();
+
+    let last = starts.len() - 1;
+    let mut kept = vec![true; starts.len()];
+    let mut remaining = tokens;
+    let mut remove = |turn: usize, remaining: &mut u32| {
+        kept[turn] = false;
+        *remaining = remaining.saturating_sub(turn_tokens[turn]);
+    };
+    match strategy {
+        Truncation::Oldest => {
+            for turn in 0..last {
+                if remaining <= window {
+                    break;
+                }
+                remove(turn, &mut remaining);
+            }
+        }
+        Truncation::MiddleOut => {
+            let mut middle: Vec<usize> = (1..last).collect();
+            while remaining > window && !middle.is_empty() {
+                let turn = middle.remove(middle.len() / 2);
+                remove(turn, &mut remaining);
+            }
+        }
+        Truncation::KeepRecent => {
+            let len = req.messages.len();
+            let first_kept = starts.iter().position(|&start| start + keep_recent >= len).unwrap_or(last);
+            for turn in 0..first_kept {
+                remove(turn, &mut remaining);
+            }
+        }
+    }
+    if kept.iter().all(|&k| k) {
+        return None;
+    }
+
+    let mut evicted = Vec::new();
+    let mut messages = std::mem::take(&mut req.messages).into_iter();
+    for ((&start, &end), &kept) in starts.iter().zip(&ends).zip(&kept) {
+        let turn = messages.by_ref().take(end - start);
+        if kept {
+            req.messages.extend(turn);
+        } else {
+            evicted.extend(turn);
+        }
+    }
+    let summarized = match summary {
+        Some(settings) => match summarize::summarize(config, settings, &evicted).await {
+            Ok(text) => {
+                summarize::inject(req, &text);
+                true
+            }
+            Err(e) => {
+                tracing::warn!("Could not summarize truncated history, dropping it: {}", e);
+                false
+            }
+        },
+        None => false,
+    };
+    let truncated = Truncated {
+        messages: evicted.len(),
+        tokens: tokens - remaining,
+        summarized,
+    };
+    tracing::info!(
+        "Truncated {} message(s), ~{} tokens, to fit the context window of {} ({}{})",
+        truncated.messages,
+        truncated.tokens,
+        model,
+        strategy.as_str(),
+        if summarized { ", summarized" } else { "" }
+    );
+    metrics::increment("proxy_truncations_total", &[("strategy", strategy.as_str())], 1);
+    Some(truncated)
+}
+
+/// Whether `message` begins a turn: a user message that isn't returning tool results.
+fn starts_turn(message: &Message) -> bool {
+    message.role == "user"
+        && match &message.content {
+            MessageContent::Text(_) => true,
+            MessageContent::Blocks(blocks) => !blocks.iter().any(|b| matches!(b, ContentBlock::ToolResult { .. })),
+        }
+}
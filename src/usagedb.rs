@@ -0,0 +1,270 @@
+//! Persistent usage accounting: one row per `/v1/messages` request in an embedded SQLite
+//! database (USAGE_DB, `sqlite` feature), pruned after USAGE_RETENTION_DAYS and summarised
+//! on `/usage`.
+
+use crate::accesslog::{Details, Record};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// USAGE_DB / USAGE_RETENTION_DAYS.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct UsageDbSettings {
+    pub path: PathBuf,
+    /// Rows older than this many days are deleted; `None` keeps everything.
+    pub retention_days: Option<u32>,
+}
+
+/// How `/usage` history rows are grouped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    #[default]
+    Day,
+    Model,
+    Client,
+}
+
+/// `/usage` query parameters: `from`/`to` are inclusive UTC dates (`YYYY-MM-DD`).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct UsageQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub group_by: GroupBy,
+    /// Only honoured when client auth is off; authenticated clients see their own rows.
+    pub client: Option<String>,
+}
+
+/// One aggregated `/usage` history row.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageSummary {
+    /// The day, model or client this row covers.
+    pub key: String,
+    pub requests: u64,
+    /// Requests answered with a 4xx/5xx status.
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+}
+
+/// A finished request, as stored.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+struct Row {
+    ts_ms: i64,
+    client: Option<String>,
+    incoming_model: Option<String>,
+    routed_model: Option<String>,
+    upstream: Option<String>,
+    stream: Option<bool>,
+    status: u16,
+    stop_reason: Option<String>,
+    input_tokens: Option<u32>,
+    output_tokens: Option<u32>,
+    cost_usd: Option<f64>,
+    duration_ms: f64,
+}
+
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+impl Row {
+    /// Only model requests are accounted; health checks, 404s and the like are skipped.
+    fn from_request(record: &Record, details: &Details) -> Option<Self> {
+        let incoming_model = details.incoming_model.clone()?;
+        Some(Self {
+            ts_ms: record.received.timestamp_millis(),
+            client: details.client.clone(),
+            incoming_model: Some(incoming_model),
+            routed_model: details.routed_model.clone(),
+            upstream: details.upstream.clone(),
+            stream: details.stream,
+            status: record.status,
+            stop_reason: details.stop_reason.clone(),
+            input_tokens: details.input_tokens,
+            output_tokens: details.output_tokens,
+            cost_usd: details.cost_usd,
+            duration_ms: record.duration.as_secs_f64() * 1000.0,
+        })
+    }
+}
+
+pub use imp::UsageDb;
+
+#[cfg(feature = "sqlite")]
+mod imp {
+    use super::{GroupBy, Row, UsageDbSettings, UsageQuery, UsageSummary};
+    use crate::accesslog::{Details, Record};
+    use anyhow::Context;
+    use rusqlite::{params, Connection};
+    use std::sync::{mpsc, Mutex};
+    use std::time::{Duration, Instant};
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS usage (
+            id INTEGER PRIMARY KEY,
+            ts INTEGER NOT NULL,
+            client TEXT,
+            incoming_model TEXT,
+            routed_model TEXT,
+            upstream TEXT,
+            stream INTEGER,
+            status INTEGER NOT NULL,
+            stop_reason TEXT,
+            input_tokens INTEGER,
+            output_tokens INTEGER,
+            cost_usd REAL,
+            duration_ms REAL NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS usage_ts ON usage (ts);
+        CREATE INDEX IF NOT EXISTS usage_client_ts ON usage (client, ts);
+    ";
+
+    /// How often the writer deletes rows past the retention period.
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+    const DAY_MS: i64 = 86_400_000;
+
+    /// Rows are written in batches by a dedicated thread; queries use a second connection.
+    pub struct UsageDb {
+        rows: mpsc::Sender<Row>,
+        reader: Mutex<Connection>,
+    }
+
+    fn open(settings: &UsageDbSettings) -> anyhow::Result<Connection> {
+        let conn = Connection::open(&settings.path)
+            .with_context(|| format!("Cannot open USAGE_DB {}", settings.path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(conn)
+    }
+
+    impl UsageDb {
+        pub fn open(settings: &UsageDbSettings) -> anyhow::Result<Self> {
+            let mut writer = open(settings)?;
+            writer.execute_batch(SCHEMA)?;
+            let reader = open(settings)?;
+            let retention_ms = settings.retention_days.map(|days| i64::from(days) * DAY_MS);
+
+            let (rows, rx) = mpsc::channel::<Row>();
+            std::thread::Builder::new()
+                .name("usage-db".to_string())
+                .spawn(move || {
+                    let mut pruned: Option<Instant> = None;
+                    while let Ok(first) = rx.recv() {
+                        let batch: Vec<Row> = std::iter::once(first).chain(rx.try_iter()).collect();
+                        if let Err(e) = insert(&mut writer, &batch) {
+                            tracing::error!("Failed to write {} usage row(s): {}", batch.len(), e);
+                        }
+                        if let Some(retention_ms) = retention_ms {
+                            if pruned.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                                pruned = Some(Instant::now());
+                                let cutoff = chrono::Utc::now().timestamp_millis() - retention_ms;
+                                match writer.execute("DELETE FROM usage WHERE ts < ?1", [cutoff]) {
+                                    Ok(0) => {}
+                                    Ok(n) => tracing::info!("Pruned {} usage row(s) past retention", n),
+                                    Err(e) => tracing::error!("Failed to prune usage rows: {}", e),
+                                }
+                            }
+                        }
+                    }
+                })?;
+            Ok(Self {
+                rows,
+                reader: Mutex::new(reader),
+            })
+        }
+
+        /// Queues a finished request for writing.
+        pub fn record(&self, record: &Record, details: &Details) {
+            if let Some(row) = Row::from_request(record, details) {
+                let _ = self.rows.send(row);
+            }
+        }
+
+        /// Aggregates stored rows for `/usage`.
+        pub fn summarize(&self, query: &UsageQuery) -> anyhow::Result<Vec<UsageSummary>> {
+            let key = match query.group_by {
+                GroupBy::Day => "strftime('%Y-%m-%d', ts / 1000, 'unixepoch')",
+                GroupBy::Model => "COALESCE(incoming_model, '')",
+                GroupBy::Client => "COALESCE(client, '')",
+            };
+            let sql = format!(
+                "SELECT {key} AS k, COUNT(*), SUM(status >= 400), COALESCE(SUM(input_tokens), 0),
+                        COALESCE(SUM(output_tokens), 0), SUM(cost_usd)
+                 FROM usage
+                 WHERE (?1 IS NULL OR ts >= ?1) AND (?2 IS NULL OR ts < ?2) AND (?3 IS NULL OR client = ?3)
+                 GROUP BY k ORDER BY k"
+            );
+            let day_start = |date: chrono::NaiveDate| date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp_millis();
+            let from = query.from.map(day_start);
+            let to = query.to.map(|date| day_start(date) + DAY_MS);
+
+            let conn = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+            let mut stmt = conn.prepare_cached(&sql)?;
+            let rows = stmt.query_map(params![from, to, query.client], |row| {
+                let count = |i| row.get::<_, i64>(i).map(|n| n.max(0) as u64);
+                Ok(UsageSummary {
+                    key: row.get(0)?,
+                    requests: count(1)?,
+                    errors: count(2)?,
+                    input_tokens: count(3)?,
+                    output_tokens: count(4)?,
+                    cost_usd: row.get(5)?,
+                })
+            })?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        }
+    }
+
+    fn insert(conn: &mut Connection, batch: &[Row]) -> rusqlite::Result<()> {
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO usage (ts, client, incoming_model, routed_model, upstream, stream, status,
+                                    stop_reason, input_tokens, output_tokens, cost_usd, duration_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            )?;
+            for row in batch {
+                stmt.execute(params![
+                    row.ts_ms,
+                    row.client,
+                    row.incoming_model,
+                    row.routed_model,
+                    row.upstream,
+                    row.stream,
+                    row.status,
+                    row.stop_reason,
+                    row.input_tokens,
+                    row.output_tokens,
+                    row.cost_usd,
+                    row.duration_ms,
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod imp {
+    use super::{UsageDbSettings, UsageQuery, UsageSummary};
+    use crate::accesslog::{Details, Record};
+
+    pub struct UsageDb;
+
+    impl UsageDb {
+        pub fn open(_settings: &UsageDbSettings) -> anyhow::Result<Self> {
+            anyhow::bail!("USAGE_DB is set but this build lacks SQLite support (rebuild with --features sqlite)")
+        }
+
+        pub fn record(&self, _record: &Record, _details: &Details) {}
+
+        pub fn summarize(&self, _query: &UsageQuery) -> anyhow::Result<Vec<UsageSummary>> {
+            Ok(Vec::new())
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Usage event stream: with USAGE_EVENTS set, every finished model request is published as a
+//! JSON usage event (the fields of a `request.completed` webhook) to Kafka
+//! (`kafka://broker1:9092,broker2:9092/<topic>`, `kafka` feature) or NATS
+//! (`nats://[user:pass@]host:4222/<subject>`, `nats` feature), so token and cost data can flow
+//! into an existing analytics stack as it happens.
+//!
+//! Events are queued in memory and published in batches by a background task that connects,
+//! and reconnects, on its own: a broker outage never slows requests down. When the queue is
+//! full, new events are dropped and counted in `proxy_usage_events_total{result="dropped"}`.
+//! Kafka records are keyed by client name, so one client's events stay in order on one
+//! partition; NATS messages carry it in a `Client` header.
+
+use crate::accesslog::{Details, Record};
+use crate::metrics;
+use futures::future::BoxFuture;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Events waiting to be published; further events are dropped.
+const QUEUE_CAPACITY: usize = 10_000;
+const MAX_BATCH: usize = 500;
+/// How long a batch waits for more events before it is sent.
+const LINGER: Duration = Duration::from_millis(200);
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Attempts at publishing one batch before it is dropped.
+const BATCH_ATTEMPTS: u32 = 3;
+
+/// Where usage events go (USAGE_EVENTS).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsageEventTarget {
+    Kafka { brokers: Vec<String>, topic: String },
+    Nats { url: String, subject: String },
+}
+
+impl UsageEventTarget {
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let raw = raw.trim();
+        let (scheme, rest) = raw.split_once("://").ok_or_else(|| {
+            anyhow::anyhow!("USAGE_EVENTS must be kafka://<brokers>/<topic> or nats://<server>/<subject>")
+        })?;
+        let (servers, name) = rest
+            .split_once('/')
+            .filter(|(servers, name)| !servers.is_empty() && !name.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("USAGE_EVENTS must name the servers and a topic or subject, got '{raw}'"))?;
+        match scheme {
+            "kafka" => Ok(Self::Kafka {
+                brokers: servers.split(',').map(str::trim).map(str::to_string).collect(),
+                topic: name.to_string(),
+            }),
+            "nats" | "tls" => Ok(Self::Nats {
+                url: format!("{scheme}://{servers}"),
+                subject: name.to_string(),
+            }),
+            other => anyhow::bail!("USAGE_EVENTS scheme must be kafka or nats, got '{other}'"),
+        }
+    }
+}
+
+impl std::fmt::Display for UsageEventTarget {
+    /// Without credentials, for logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Kafka { brokers, topic } => write!(f, "Kafka {} topic {}", brokers.join(","), topic),
+            Self::Nats { url, subject } => {
+                let host = url.rsplit_once('@').map_or(url.as_str(), |(_, host)| host);
+                write!(f, "NATS {} subject {}", host.trim_start_matches("nats://"), subject)
+            }
+        }
+    }
+}
+
+/// A usage event on its way out.
+#[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(dead_code))]
+struct UsageEvent {
+    /// Client name: the Kafka record key, a NATS message's `Client` header.
+    client: Option<String>,
+    payload: Vec<u8>,
+}
+
+/// A connected broker.
+trait Backend: Send + Sync {
+    fn publish<'a>(&'a self, batch: &'a [UsageEvent]) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Feeds finished requests to the background publisher.
+pub struct UsagePublisher {
+    events: mpsc::Sender<UsageEvent>,
+}
+
+impl UsagePublisher {
+    /// Starts the background publisher; it connects to `target` on its own. Fails when this
+    /// build lacks support for the target's broker.
+    pub fn start(target: UsageEventTarget) -> anyhow::Result<Self> {
+        match target {
+            UsageEventTarget::Kafka { .. } => kafka::check_support()?,
+            UsageEventTarget::Nats { .. } => nats::check_support()?,
+        }
+        let (events, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(target, rx));
+        Ok(Self { events })
+    }
+
+    /// Queues a usage event for a finished model request.
+    pub fn observe(&self, record: &Record, details: &Details) {
+        if details.incoming_model.is_none() {
+            return;
+        }
+        let event = UsageEvent {
+            client: details.client.clone(),
+            payload: record.summary(details).to_string().into_bytes(),
+        };
+        if self.events.try_send(event).is_err() {
+            metrics::increment("proxy_usage_events_total", &[("result", "dropped")], 1);
+        }
+    }
+}
+
+async fn run(target: UsageEventTarget, mut rx: mpsc::Receiver<UsageEvent>) {
+    let backend = connect(&target).await;
+    tracing::info!("Usage events: connected to {}", target);
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    while let Some(event) = rx.recv().await {
+        batch.push(event);
+        let linger = tokio::time::sleep(LINGER);
+        tokio::pin!(linger);
+        while batch.len() < MAX_BATCH {
+            tokio::select! {
+                Some(event) = rx.recv() => batch.push(event),
+                _ = &mut linger => break,
+            }
+        }
+        publish(backend.as_ref(), &target, &batch).await;
+        batch.clear();
+    }
+}
+
+/// Connects to `target`, retrying with backoff until it succeeds.
+async fn connect(target: &UsageEventTarget) -> Box<dyn Backend> {
+    let mut delay = FIRST_RETRY_DELAY;
+    loop {
+        let result = match target {
+            UsageEventTarget::Kafka { brokers, topic } => kafka::connect(brokers, topic).await,
+            UsageEventTarget::Nats { url, subject } => nats::connect(url, subject).await,
+        };
+        match result {
+            Ok(backend) => return backend,
+            Err(e) => tracing::warn!(
+                "Usage events: cannot connect to {}: {:#}; retrying in {}s",
+                target,
+                e,
+                delay.as_secs()
+            ),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+async fn publish(backend: &dyn Backend, target: &UsageEventTarget, batch: &[UsageEvent]) {
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 1..=BATCH_ATTEMPTS {
+        match backend.publish(batch).await {
+            Ok(()) => {
+                metrics::increment("proxy_usage_events_total", &[("result", "published")], batch.len() as u64);
+                return;
+            }
+            Err(e) => tracing::warn!(
+                "Usage events: publishing {} event(s) to {} failed (attempt {} of {}): {:#}",
+                batch.len(),
+                target,
+                attempt,
+                BATCH_ATTEMPTS,
+                e
+            ),
+        }
+        if attempt < BATCH_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+    tracing::error!("Usage events: dropped {} event(s) for {}", batch.len(), target);
+    metrics::increment("proxy_usage_events_total", &[("result", "failed")], batch.len() as u64);
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use super::{Backend, UsageEvent};
+    use futures::future::BoxFuture;
+    use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+    use rskafka::client::ClientBuilder;
+    use rskafka::record::Record;
+    use rskafka::BackoffConfig;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    /// How long the Kafka client keeps retrying one request.
+    const REQUEST_DEADLINE: Duration = Duration::from_secs(30);
+
+    pub fn check_support() -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    struct Kafka {
+        partitions: Vec<PartitionClient>,
+    }
+
+    pub async fn connect(brokers: &[String], topic: &str) -> anyhow::Result<Box<dyn Backend>> {
+        let backoff = BackoffConfig {
+            deadline: Some(REQUEST_DEADLINE),
+            ..BackoffConfig::default()
+        };
+        let client = ClientBuilder::new(brokers.to_vec())
+            .client_id("anthropic-proxy")
+            .backoff_config(backoff)
+            .build()
+            .await?;
+        let ids = client
+            .list_topics()
+            .await?
+            .into_iter()
+            .find(|t| t.name == topic)
+            .map(|t| t.partitions)
+            .ok_or_else(|| anyhow::anyhow!("topic {topic} does not exist"))?;
+        let mut partitions = Vec::with_capacity(ids.len());
+        for id in ids {
+            partitions.push(client.partition_client(topic, id, UnknownTopicHandling::Retry).await?);
+        }
+        anyhow::ensure!(!partitions.is_empty(), "topic {topic} has no partitions");
+        Ok(Box::new(Kafka { partitions }))
+    }
+
+    /// Stable across builds, unlike `DefaultHasher`, so a client keeps its partition.
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        bytes
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    impl Backend for Kafka {
+        fn publish<'a>(&'a self, batch: &'a [UsageEvent]) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                let mut by_partition: BTreeMap<usize, Vec<Record>> = BTreeMap::new();
+                for event in batch {
+                    let key = event.client.as_deref().unwrap_or_default();
+                    let partition = (fnv1a(key.as_bytes()) % self.partitions.len() as u64) as usize;
+                    by_partition.entry(partition).or_default().push(Record {
+                        key: event.client.as_ref().map(|c| c.as_bytes().to_vec()),
+                        value: Some(event.payload.clone()),
+                        headers: BTreeMap::new(),
+                        timestamp: chrono::Utc::now(),
+                    });
+                }
+                for (partition, records) in by_partition {
+                    self.partitions[partition].produce(records, Compression::NoCompression).await?;
+                }
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+mod kafka {
+    use super::Backend;
+
+    fn unsupported() -> anyhow::Error {
+        anyhow::anyhow!("USAGE_EVENTS names Kafka but this build lacks Kafka support (rebuild with --features kafka)")
+    }
+
+    pub fn check_support() -> anyhow::Result<()> {
+        Err(unsupported())
+    }
+
+    pub async fn connect(_brokers: &[String], _topic: &str) -> anyhow::Result<Box<dyn Backend>> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use super::{Backend, UsageEvent};
+    use futures::future::BoxFuture;
+
+    /// Header naming the client a NATS message is about.
+    const CLIENT_HEADER: &str = "Client";
+
+    pub fn check_support() -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    struct Nats {
+        client: async_nats::Client,
+        subject: String,
+    }
+
+    pub async fn connect(url: &str, subject: &str) -> anyhow::Result<Box<dyn Backend>> {
+        let client = async_nats::ConnectOptions::new().name("anthropic-proxy").connect(url).await?;
+        Ok(Box::new(Nats {
+            client,
+            subject: subject.to_string(),
+        }))
+    }
+
+    impl Backend for Nats {
+        fn publish<'a>(&'a self, batch: &'a [UsageEvent]) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                for event in batch {
+                    let payload = event.payload.clone().into();
+                    match &event.client {
+                        Some(client) => {
+                            let mut headers = async_nats::HeaderMap::new();
+                            headers.insert(CLIENT_HEADER, client.as_str());
+                            self.client.publish_with_headers(self.subject.clone(), headers, payload).await?;
+                        }
+                        None => self.client.publish(self.subject.clone(), payload).await?,
+                    }
+                }
+                self.client.flush().await?;
+                Ok(())
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "nats"))]
+mod nats {
+    use super::Backend;
+
+    fn unsupported() -> anyhow::Error {
+        anyhow::anyhow!("USAGE_EVENTS names NATS but this build lacks NATS support (rebuild with --features nats)")
+    }
+
+    pub fn check_support() -> anyhow::Result<()> {
+        Err(unsupported())
+    }
+
+    pub async fn connect(_url: &str, _subject: &str) -> anyhow::Result<Box<dyn Backend>> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,97 @@
+//! Upstream connection pre-warming (UPSTREAM_WARM_CONNECTIONS): at startup, and again whenever
+//! the proxy has sent nothing upstream for UPSTREAM_WARM_INTERVAL_SECS, concurrent `HEAD`
+//! requests open connections to the default upstream and leave them in the pool, so the next
+//! real request skips DNS, TCP and TLS setup.
+
+use crate::config::Upstream;
+use crate::reload::LiveConfig;
+use futures::future::join_all;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const WARM_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval when pooled connections never expire (UPSTREAM_POOL_IDLE_TIMEOUT_SECS=0).
+pub const DEFAULT_WARM_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the loop looks again while warming is off.
+const IDLE_POLL: Duration = Duration::from_secs(30);
+
+/// UPSTREAM_WARM_CONNECTIONS / UPSTREAM_WARM_INTERVAL_SECS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarmupSettings {
+    /// Connections to open, at most the pool's idle limit.
+    pub connections: usize,
+    /// Quiet time after which the connections are opened again; below the pool's idle
+    /// timeout, so warmed connections are refreshed before they expire.
+    pub interval: Duration,
+}
+
+/// When the proxy last sent a request upstream.
+static LAST_SENT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Records upstream traffic; connections in use need no warming.
+pub fn touch() {
+    *LAST_SENT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+}
+
+fn idle_for() -> Option<Duration> {
+    LAST_SENT.lock().unwrap_or_else(|e| e.into_inner()).map(|sent| sent.elapsed())
+}
+
+/// Warms the default upstream until the process exits, following config reloads.
+pub fn spawn(live: Arc<LiveConfig>) {
+    tokio::spawn(async move {
+        loop {
+            let config = live.current();
+            let Some(settings) = config.warmup else {
+                tokio::time::sleep(IDLE_POLL).await;
+                continue;
+            };
+            match idle_for() {
+                Some(idle) if idle < settings.interval => {
+                    tokio::time::sleep(settings.interval - idle).await;
+                    continue;
+                }
+                _ => {}
+            }
+            warm(&config.upstream, settings.connections).await;
+            // Warming counts as traffic, so the next round waits a full interval.
+            touch();
+        }
+    });
+}
+
+async fn warm(upstream: &Upstream, connections: usize) {
+    if upstream.is_mock() {
+        return;
+    }
+    let url = upstream.models_url().unwrap_or_else(|| upstream.base_url.clone());
+    let connections = connections.min(upstream.transport.pool.max_idle_per_host).max(1);
+    let started = Instant::now();
+    let results = join_all((0..connections).map(|_| async {
+        let response = upstream
+            .client
+            .head(&url)
+            .headers(upstream.headers.clone())
+            .timeout(WARM_TIMEOUT)
+            .send()
+            .await?;
+        // Reading the (empty) body hands the connection back to the pool.
+        response.bytes().await
+    }))
+    .await;
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    match results.iter().find_map(|r| r.as_ref().err()) {
+        Some(e) => tracing::warn!(
+            "Warming upstream connections: {} of {} failed ({})",
+            failed,
+            connections,
+            e
+        ),
+        None => tracing::debug!(
+            "Warmed {} upstream connection(s) to {} in {:?}",
+            connections,
+            upstream.base_url,
+            started.elapsed()
+        ),
+    }
+}
@@ -0,0 +1,192 @@
+//! WebAssembly plugins (WASM_PLUGINS, `wasm-plugins` feature): WASI preview 1 modules that
+//! rewrite or reject requests and responses, loaded at startup and run with wasmtime, so
+//! operators can add their own policies without rebuilding the proxy.
+//!
+//! A module exports `memory`, `alloc(len: i32) -> i32` and any of the hooks `on_request`,
+//! `on_upstream_request` and `on_upstream_response`, each `(ptr: i32, len: i32) -> i64`. The
+//! proxy writes the JSON payload into memory from `alloc` and calls the hook, which returns 0
+//! to leave the payload as it is, or `ptr << 32 | len` of a JSON reply: `{"replace": <payload>}`
+//! or `{"reject": "<message>"}`. Every call gets a fresh instance, so no state carries over
+//! between requests.
+
+pub use imp::register;
+
+#[cfg(feature = "wasm-plugins")]
+mod imp {
+    use crate::error::{ProxyError, ProxyResult};
+    use crate::models::{anthropic, openai};
+    use crate::plugin::{BoxFuture, Plugins, ProxyPlugin, RequestContext};
+    use anyhow::Context;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+    use serde_json::value::RawValue;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use wasmtime::{Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+    use wasmtime_wasi::p1::WasiP1Ctx;
+    use wasmtime_wasi::WasiCtxBuilder;
+
+    /// Instructions one hook call may execute before it is stopped.
+    const FUEL_PER_CALL: u64 = 1_000_000_000;
+    /// Linear memory one instance may grow to.
+    const MAX_MEMORY_BYTES: usize = 64 << 20;
+    const HOOKS: [&str; 3] = ["on_request", "on_upstream_request", "on_upstream_response"];
+
+    struct State {
+        wasi: WasiP1Ctx,
+        limits: StoreLimits,
+    }
+
+    /// What a hook returned.
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Reply {
+        Replace(Box<RawValue>),
+        Reject(String),
+    }
+
+    struct Loaded {
+        name: String,
+        engine: Engine,
+        pre: InstancePre<State>,
+        hooks: Vec<&'static str>,
+    }
+
+    /// One loaded module.
+    pub struct WasmPlugin(Arc<Loaded>);
+
+    /// Compiles the modules at `paths` and adds them to `plugins`, in order.
+    pub fn register(paths: &[PathBuf], mut plugins: Plugins) -> anyhow::Result<Plugins> {
+        if paths.is_empty() {
+            return Ok(plugins);
+        }
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        for path in paths {
+            let plugin = WasmPlugin::load(&engine, path).with_context(|| format!("WASM plugin {}", path.display()))?;
+            plugins = plugins.with(plugin);
+        }
+        Ok(plugins)
+    }
+
+    impl WasmPlugin {
+        fn load(engine: &Engine, path: &Path) -> anyhow::Result<Self> {
+            let module = Module::from_file(engine, path)?;
+            for export in ["memory", "alloc"] {
+                anyhow::ensure!(module.get_export(export).is_some(), "the module does not export `{export}`");
+            }
+            let hooks: Vec<_> = HOOKS.into_iter().filter(|hook| module.get_export(hook).is_some()).collect();
+            anyhow::ensure!(!hooks.is_empty(), "the module exports none of {}", HOOKS.join(", "));
+            let mut linker = Linker::new(engine);
+            wasmtime_wasi::p1::add_to_linker_sync(&mut linker, |state: &mut State| &mut state.wasi)?;
+            let name = path.file_stem().map_or_else(|| path.display().to_string(), |s| s.to_string_lossy().into_owned());
+            tracing::debug!("WASM plugin {} hooks: {}", name, hooks.join(", "));
+            Ok(Self(Arc::new(Loaded {
+                name,
+                engine: engine.clone(),
+                pre: linker.instantiate_pre(&module)?,
+                hooks,
+            })))
+        }
+
+        /// Runs `hook` on `payload` and applies its reply.
+        async fn run<T: Serialize + DeserializeOwned>(&self, hook: &'static str, payload: &mut T) -> ProxyResult<()> {
+            if !self.0.hooks.contains(&hook) {
+                return Ok(());
+            }
+            let input = serde_json::to_vec(payload)?;
+            let module = Arc::clone(&self.0);
+            let output = tokio::task::spawn_blocking(move || module.call(hook, &input))
+                .await
+                .map_err(|e| ProxyError::Internal(format!("WASM plugin {} panicked: {e}", self.0.name)))?
+                .map_err(|e| {
+                    tracing::error!("WASM plugin {} {} failed: {:#}", self.0.name, hook, e);
+                    ProxyError::Internal(format!("WASM plugin {} failed", self.0.name))
+                })?;
+            let Some(output) = output else { return Ok(()) };
+            let reply: Reply = serde_json::from_slice(&output).map_err(|e| {
+                ProxyError::Internal(format!("WASM plugin {} returned an invalid reply: {e}", self.0.name))
+            })?;
+            match reply {
+                Reply::Replace(value) => {
+                    *payload = serde_json::from_str(value.get()).map_err(|e| {
+                        ProxyError::Internal(format!("WASM plugin {} returned an invalid {hook} payload: {e}", self.0.name))
+                    })?;
+                    Ok(())
+                }
+                Reply::Reject(message) => Err(ProxyError::Permission(message)),
+            }
+        }
+    }
+
+    impl Loaded {
+        /// The hook's reply, or `None` to keep the payload.
+        fn call(&self, hook: &str, input: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+            let state = State {
+                wasi: WasiCtxBuilder::new().inherit_stderr().build_p1(),
+                limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).build(),
+            };
+            let mut store = Store::new(&self.engine, state);
+            store.limiter(|state| &mut state.limits);
+            store.set_fuel(FUEL_PER_CALL)?;
+            let instance = self.pre.instantiate(&mut store)?;
+            let memory = instance.get_memory(&mut store, "memory").context("`memory` is not a memory")?;
+            let len = i32::try_from(input.len()).context("payload too large")?;
+            let ptr = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?.call(&mut store, len)?;
+            memory.write(&mut store, ptr as u32 as usize, input)?;
+            let packed = instance
+                .get_typed_func::<(i32, i32), i64>(&mut store, hook)?
+                .call(&mut store, (ptr, len))? as u64;
+            if packed == 0 {
+                return Ok(None);
+            }
+            let mut output = vec![0; (packed & 0xffff_ffff) as usize];
+            memory.read(&store, (packed >> 32) as usize, &mut output)?;
+            Ok(Some(output))
+        }
+    }
+
+    impl ProxyPlugin for WasmPlugin {
+        fn name(&self) -> &str {
+            &self.0.name
+        }
+
+        fn on_request<'a>(
+            &'a self,
+            _ctx: &'a RequestContext,
+            req: &'a mut anthropic::AnthropicRequest,
+        ) -> BoxFuture<'a, ProxyResult<()>> {
+            Box::pin(self.run("on_request", req))
+        }
+
+        fn on_upstream_request<'a>(
+            &'a self,
+            _ctx: &'a RequestContext,
+            req: &'a mut openai::OpenAIRequest,
+        ) -> BoxFuture<'a, ProxyResult<()>> {
+            Box::pin(self.run("on_upstream_request", req))
+        }
+
+        fn on_upstream_response<'a>(
+            &'a self,
+            _ctx: &'a RequestContext,
+            resp: &'a mut openai::OpenAIResponse,
+        ) -> BoxFuture<'a, ProxyResult<()>> {
+            Box::pin(self.run("on_upstream_response", resp))
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+mod imp {
+    use crate::plugin::Plugins;
+    use std::path::PathBuf;
+
+    pub fn register(paths: &[PathBuf], plugins: Plugins) -> anyhow::Result<Plugins> {
+        if !paths.is_empty() {
+            anyhow::bail!("WASM_PLUGINS is set but this build lacks WASM plugin support (rebuild with --features wasm-plugins)");
+        }
+        Ok(plugins)
+    }
+}